@@ -0,0 +1,359 @@
+use crate::bundle::PublishedBundle;
+use crate::error::ChannelError;
+use crate::ChannelManager;
+use ockam_kex::{KeyExchanger, NewKeyExchanger};
+use ockam_message::message::{Address, Message, Route};
+use ockam_system::commands::{ChannelCommand, CommandSender, OckamCommand};
+use ockam_vault::types::SecretKeyContext;
+use ockam_vault::DynVault;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::time::timeout;
+
+/// Runs a ChannelManager as a tokio task. Commands arrive on a tokio queue
+/// instead of a std Receiver; channel handling is otherwise identical.
+pub struct AsyncChannelManager<
+    I: KeyExchanger + Send + 'static,
+    R: KeyExchanger + Send + 'static,
+    E: NewKeyExchanger<I, R>,
+> {
+    inner: ChannelManager<I, R, E>,
+    rx: Receiver<OckamCommand>,
+    tx: Sender<OckamCommand>,
+}
+
+impl<I: KeyExchanger + Send, R: KeyExchanger + Send, E: NewKeyExchanger<I, R>> std::fmt::Debug
+    for AsyncChannelManager<I, R, E>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "AsyncChannelManager {{ inner: {:?}, receiver, sender }}",
+            self.inner
+        )
+    }
+}
+
+impl<I: KeyExchanger + Send, R: KeyExchanger + Send, E: NewKeyExchanger<I, R>>
+    AsyncChannelManager<I, R, E>
+{
+    /// Create a new async Channel Manager and register it with the router
+    /// behind `router_tx`. Its command queue holds `capacity` commands.
+    pub fn new(
+        router_tx: CommandSender,
+        vault: Arc<Mutex<dyn DynVault + Send>>,
+        new_key_exchanger: E,
+        resp_key_ctx: Option<SecretKeyContext>,
+        init_key_ctx: Option<SecretKeyContext>,
+        capacity: usize,
+    ) -> Result<Self, ChannelError> {
+        let (tx, rx) = channel(capacity);
+        let inner = ChannelManager::from_parts(
+            None,
+            CommandSender::Async(tx.clone()),
+            router_tx,
+            vault,
+            new_key_exchanger,
+            resp_key_ctx,
+            init_key_ctx,
+        )?;
+        Ok(Self { inner, rx, tx })
+    }
+
+    /// Set when channels replace their sending keys
+    pub fn set_rekey_policy(&mut self, policy: crate::cipher::RekeyPolicy) {
+        self.inner.set_rekey_policy(policy);
+    }
+
+    /// Set how long key exchanges may take and how many may run at once
+    pub fn set_handshake_policy(&mut self, policy: crate::handshake::HandshakePolicy) {
+        self.inner.set_handshake_policy(policy);
+    }
+
+    /// Set which peers channels may be established with
+    pub fn set_trust_policy(&mut self, policy: impl crate::trust::TrustPolicy + 'static) {
+        self.inner.set_trust_policy(policy);
+    }
+
+    /// Bind channel establishment to a pre-shared key in the vault
+    pub fn set_psk(&mut self, psk: SecretKeyContext) -> Result<(), ChannelError> {
+        self.inner.set_psk(psk)
+    }
+
+    /// Attach an application payload to the handshake of every channel
+    /// created from now on
+    pub fn set_handshake_payload(&mut self, payload: Vec<u8>) {
+        self.inner.set_handshake_payload(payload);
+    }
+
+    /// The channel binding of an established channel
+    pub fn channel_binding(&self, address: &Address) -> Option<[u8; 32]> {
+        self.inner.channel_binding(address)
+    }
+
+    /// Derive keying material bound to an established channel
+    pub fn export_key(
+        &self,
+        address: &Address,
+        label: &[u8],
+        context: &[u8],
+        length: usize,
+    ) -> Result<Vec<u8>, ChannelError> {
+        self.inner.export_key(address, label, context, length)
+    }
+
+    /// The sender for commands to this Channel Manager
+    pub fn sender(&self) -> Sender<OckamCommand> {
+        self.tx.clone()
+    }
+
+    /// Start a key exchange with the far end of `route`. The worker at
+    /// `return_address` is notified once the channel is established.
+    pub async fn initiate(
+        &self,
+        route: Route,
+        return_address: Address,
+    ) -> Result<(), ChannelError> {
+        self.command(ChannelCommand::Initiate(route, return_address, None))
+            .await
+    }
+
+    /// Create a channel that waits for an initiator holding the returned
+    /// bundle
+    pub fn publish_bundle(&mut self) -> Result<PublishedBundle, ChannelError> {
+        self.inner.publish_bundle()
+    }
+
+    /// Establish a channel from a bundle the far end of `route` published.
+    /// The worker at `return_address` is notified once it can send.
+    pub async fn initiate_with_bundle(
+        &self,
+        route: Route,
+        return_address: Address,
+        bundle: &PublishedBundle,
+    ) -> Result<(), ChannelError> {
+        self.command(ChannelCommand::InitiateWithBundle(
+            route,
+            return_address,
+            bundle.to_bytes(),
+        ))
+        .await
+    }
+
+    /// Send a message over an established channel
+    pub async fn send(&self, m: Message) -> Result<(), ChannelError> {
+        self.command(ChannelCommand::SendMessage(m)).await
+    }
+
+    /// Close the channel at `address`, telling the peer and the bound worker
+    pub async fn close(&self, address: Address) -> Result<(), ChannelError> {
+        self.command(ChannelCommand::Close(address)).await
+    }
+
+    /// Do work as commands arrive, until told to stop or every sender has
+    /// gone away. Wakes up in between to retransmit or abandon handshakes
+    /// that have gone quiet.
+    pub async fn run(mut self) -> Result<(), ChannelError> {
+        loop {
+            let c = match self.inner.next_deadline() {
+                Some(deadline) => {
+                    let wait = deadline.saturating_duration_since(Instant::now());
+                    match timeout(wait, self.rx.recv()).await {
+                        Ok(Some(c)) => Some(c),
+                        Ok(None) => break,
+                        Err(_) => None,
+                    }
+                }
+                None => match self.rx.recv().await {
+                    Some(c) => Some(c),
+                    None => break,
+                },
+            };
+            if let Some(c) = c {
+                if !self.inner.handle_command(c)? {
+                    break;
+                }
+            }
+            self.inner.expire_handshakes()?;
+        }
+        Ok(())
+    }
+
+    async fn command(&self, c: ChannelCommand) -> Result<(), ChannelError> {
+        self.tx
+            .clone()
+            .send(OckamCommand::Channel(c))
+            .await
+            .map_err(|e| std::sync::mpsc::SendError(e.0).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::established::Established;
+    use crate::CHANNEL_ZERO;
+    use ockam_kex::xx::{XXInitiator, XXNewKeyExchanger, XXResponder};
+    use ockam_kex::CipherSuite;
+    use ockam_message::message::{MessageType, RouterAddress};
+    use ockam_system::commands::RouterCommand;
+    use ockam_vault::software::DefaultVault;
+    use std::time::Duration;
+
+    type XXAsyncManager = AsyncChannelManager<XXInitiator, XXResponder, XXNewKeyExchanger>;
+
+    /// What a manager asked its router to do, in the order it asked
+    struct Router {
+        rx: Receiver<OckamCommand>,
+        established: Vec<Established>,
+        received: Vec<Message>,
+    }
+
+    impl Router {
+        /// Take the manager's next command, noting whatever was delivered to
+        /// workers, and return it if it was sent towards the peer
+        async fn next(&mut self) -> Option<Message> {
+            let c = timeout(Duration::from_secs(5), self.rx.recv())
+                .await
+                .expect("nothing from the channel manager");
+            match c {
+                Some(OckamCommand::Router(RouterCommand::SendMessage(m))) => Some(m),
+                Some(OckamCommand::Router(RouterCommand::ReceiveMessage(m))) => {
+                    if let MessageType::None = m.message_type {
+                        self.established
+                            .push(Established::from_bytes(&m.message_body).unwrap());
+                    }
+                    self.received.push(m);
+                    None
+                }
+                Some(_) => None,
+                None => panic!("channel manager stopped"),
+            }
+        }
+
+        /// The next message sent towards the peer
+        async fn sent(&mut self) -> Message {
+            loop {
+                if let Some(m) = self.next().await {
+                    return m;
+                }
+            }
+        }
+
+        /// Wait for the manager to tell a worker something
+        async fn until(&mut self, done: impl Fn(&Self) -> bool) {
+            while !done(self) {
+                self.next().await;
+            }
+        }
+    }
+
+    fn manager() -> (XXAsyncManager, Router) {
+        let vault: Arc<Mutex<dyn DynVault + Send>> = Arc::new(Mutex::new(DefaultVault::default()));
+        let (router_tx, router_rx) = channel(64);
+        let new_key_exchanger = XXNewKeyExchanger::new(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault.clone(),
+            vault.clone(),
+        );
+        let manager = XXAsyncManager::new(
+            CommandSender::Async(router_tx),
+            vault,
+            new_key_exchanger,
+            None,
+            None,
+            64,
+        )
+        .unwrap();
+        let router = Router {
+            rx: router_rx,
+            established: vec![],
+            received: vec![],
+        };
+        (manager, router)
+    }
+
+    async fn deliver(to: &mut Sender<OckamCommand>, m: Message) {
+        to.send(OckamCommand::Channel(ChannelCommand::ReceiveMessage(m)))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn handshake_and_send() {
+        let (initiator, mut initiator_router) = manager();
+        let (responder, mut responder_router) = manager();
+        let mut to_initiator = initiator.sender();
+        let mut to_responder = responder.sender();
+
+        let worker = RouterAddress::worker_router_address_from_str("01020304").unwrap();
+        let route = Route {
+            addresses: vec![RouterAddress::channel_router_address_from_str(CHANNEL_ZERO).unwrap()],
+        };
+        initiator
+            .initiate(route, worker.address.clone())
+            .await
+            .unwrap();
+        tokio::spawn(initiator.run());
+        tokio::spawn(responder.run());
+
+        let m1 = initiator_router.sent().await;
+        assert!(matches!(m1.message_type, MessageType::KeyAgreementM1));
+        deliver(&mut to_responder, m1).await;
+        let m2 = responder_router.sent().await;
+        deliver(&mut to_initiator, m2).await;
+        let m3 = initiator_router.sent().await;
+        deliver(&mut to_responder, m3).await;
+
+        initiator_router.until(|r| !r.established.is_empty()).await;
+        responder_router.until(|r| !r.established.is_empty()).await;
+        assert_eq!(initiator_router.established.len(), 1);
+        assert_eq!(responder_router.established.len(), 1);
+        assert_eq!(
+            initiator_router.established[0].channel_binding,
+            responder_router.established[0].channel_binding
+        );
+
+        // the worker sends over the channel by its cleartext address
+        let notice = initiator_router
+            .received
+            .iter()
+            .find(|m| matches!(m.message_type, MessageType::None))
+            .unwrap();
+        let clear = notice.return_route.addresses[0].clone();
+        to_initiator
+            .send(OckamCommand::Channel(ChannelCommand::SendMessage(
+                Message {
+                    onward_route: Route {
+                        addresses: vec![
+                            clear,
+                            RouterAddress::worker_router_address_from_str("05060708").unwrap(),
+                        ],
+                    },
+                    return_route: Route {
+                        addresses: vec![worker],
+                    },
+                    message_type: MessageType::Payload,
+                    message_body: b"hello".to_vec(),
+                },
+            )))
+            .await
+            .unwrap();
+        let encrypted = initiator_router.sent().await;
+        assert_ne!(encrypted.message_body, b"hello".to_vec());
+        deliver(&mut to_responder, encrypted).await;
+        let is_payload = |m: &Message| matches!(m.message_type, MessageType::Payload);
+        responder_router
+            .until(|r| r.received.iter().any(is_payload))
+            .await;
+        let hello = responder_router.received.iter().find(|m| is_payload(m));
+        assert_eq!(hello.unwrap().message_body, b"hello".to_vec());
+
+        for tx in vec![&mut to_initiator, &mut to_responder] {
+            tx.send(OckamCommand::Channel(ChannelCommand::Stop))
+                .await
+                .unwrap();
+        }
+    }
+}
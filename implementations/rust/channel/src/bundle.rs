@@ -0,0 +1,70 @@
+use crate::error::{ChannelError, ChannelErrorKind};
+use crate::established::decode_channel_address;
+use ockam_message::message::{Codec, RouterAddress};
+
+/// What a responder publishes so that initiators can establish a channel to
+/// it without a round trip, e.g. while it is offline: the prekey bundle of a
+/// responder key exchange and the ciphertext address of the channel waiting
+/// for it. Each published bundle establishes at most one channel.
+#[derive(Clone, Debug)]
+pub struct PublishedBundle {
+    /// Ciphertext address of the responder's waiting channel
+    pub address: RouterAddress,
+    /// The bundle the responder's key exchange produced
+    pub bundle: Vec<u8>,
+}
+
+impl PublishedBundle {
+    /// Encode as the channel address followed by the bundle
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut v = vec![];
+        // a channel address always encodes
+        RouterAddress::encode(&self.address, &mut v).unwrap();
+        v.extend_from_slice(&self.bundle);
+        v
+    }
+
+    /// Decode what `to_bytes` produced, from an untrusted source
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ChannelError> {
+        let (address, bundle) = decode_channel_address(data)?;
+        if bundle.is_empty() {
+            return Err(ChannelErrorKind::InvalidParam(0).into());
+        }
+        Ok(Self {
+            address,
+            bundle: bundle.to_vec(),
+        })
+    }
+
+    /// Hex encoding of `to_bytes`, for files and command lines
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.to_bytes())
+    }
+
+    /// Decode what `to_hex` produced
+    pub fn from_hex(s: &str) -> Result<Self, ChannelError> {
+        let data = hex::decode(s.trim()).map_err(|_| ChannelErrorKind::InvalidParam(0))?;
+        Self::from_bytes(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let published = PublishedBundle {
+            address: RouterAddress::channel_router_address_from_str("01020304").unwrap(),
+            bundle: vec![7u8; 160],
+        };
+        let decoded = PublishedBundle::from_hex(&published.to_hex()).unwrap();
+        assert_eq!(decoded.address, published.address);
+        assert_eq!(decoded.bundle, published.bundle);
+        assert!(PublishedBundle::from_bytes(&[1, 2]).is_err());
+        assert!(PublishedBundle::from_bytes(&[]).is_err());
+        let bytes = published.to_bytes();
+        assert!(PublishedBundle::from_bytes(&bytes[..4]).is_err());
+        assert!(PublishedBundle::from_bytes(&bytes[..6]).is_err());
+    }
+}
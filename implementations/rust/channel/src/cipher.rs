@@ -0,0 +1,544 @@
+use crate::error::{ChannelError, ChannelErrorKind};
+use crate::replay::{Rejection, ReplayWindow, REPLAY_WINDOW};
+use ockam_vault::types::{SecretKey, SecretKeyContext, SecretKeyType};
+use ockam_vault::DynVault;
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+
+/// Length of the header in front of every encrypted payload: a 4 byte key
+/// epoch followed by the 8 byte nonce, both big endian
+pub const HEADER_LEN: usize = 12;
+
+/// Nonce reserved by Noise for REKEY. It is never used to encrypt a message.
+pub const REKEY_NONCE: u64 = u64::MAX;
+
+/// Default number of messages encrypted under one key before rekeying
+pub const DEFAULT_REKEY_MESSAGES: u64 = 1 << 16;
+
+/// Default longest time a key is used for before rekeying
+pub const DEFAULT_REKEY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Longest time the receiver keeps the previous epoch's key after the
+/// sender has moved on, for messages sealed before the rekey that arrive
+/// after it
+pub const PREVIOUS_KEY_GRACE: Duration = Duration::from_secs(30);
+
+/// When a channel replaces its sending key. Either limit triggers a rekey;
+/// `None` disables that limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RekeyPolicy {
+    /// Messages encrypted under one key
+    pub max_messages: Option<u64>,
+    /// Age of a key
+    pub max_age: Option<Duration>,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        Self {
+            max_messages: Some(DEFAULT_REKEY_MESSAGES),
+            max_age: Some(DEFAULT_REKEY_INTERVAL),
+        }
+    }
+}
+
+impl RekeyPolicy {
+    /// Never rekey
+    pub fn never() -> Self {
+        Self {
+            max_messages: None,
+            max_age: None,
+        }
+    }
+}
+
+/// The key and counters for one direction of a channel, in the spirit of a
+/// Noise CipherState. The nonce counts every message sent in this direction
+/// and is never reset, so it stays unique across rekeys. The epoch counts
+/// rekeys and travels with each message, so the receiver knows when the
+/// sender has moved on to the next key. Received nonces go through a replay
+/// window, which works across rekeys because the nonce is never reset.
+/// The AEAD follows the type of the key, so it is whichever the key
+/// exchange's cipher suite negotiated.
+///
+/// A receiver keeps the previous epoch's key for a while after moving on,
+/// so messages reordered around a rekey still open. It is destroyed once
+/// REPLAY_WINDOW messages have opened under the new key, when the window
+/// has passed every nonce the old key could have sealed, or after
+/// PREVIOUS_KEY_GRACE, whichever comes first.
+#[derive(Debug)]
+pub(crate) struct CipherState {
+    key: SecretKeyContext,
+    nonce: u64,
+    epoch: u32,
+    used: u64,
+    keyed_at: Instant,
+    replay: ReplayWindow,
+    previous: Option<RetiredKey>,
+}
+
+/// The receiving key of the epoch before the current one
+#[derive(Debug)]
+struct RetiredKey {
+    key: SecretKeyContext,
+    /// Messages opened under the current key since this one was retired
+    opened: u64,
+    retired_at: Instant,
+}
+
+impl RetiredKey {
+    fn expired(&self) -> bool {
+        self.opened >= REPLAY_WINDOW || self.retired_at.elapsed() >= PREVIOUS_KEY_GRACE
+    }
+}
+
+impl CipherState {
+    pub(crate) fn new(key: SecretKeyContext) -> Self {
+        Self {
+            key,
+            nonce: 0,
+            epoch: 0,
+            used: 0,
+            keyed_at: Instant::now(),
+            replay: ReplayWindow::default(),
+            previous: None,
+        }
+    }
+
+    /// Encrypt a payload, rekeying first if the policy says so. Refuses once
+    /// the nonce space is used up rather than ever repeating a nonce.
+    pub(crate) fn seal(
+        &mut self,
+        vault: &mut dyn DynVault,
+        policy: &RekeyPolicy,
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, ChannelError> {
+        if self.nonce == REKEY_NONCE {
+            return Err(ChannelError::from_msg(
+                ChannelErrorKind::NonceExhausted,
+                "nonce space exhausted, channel must be re-established",
+            ));
+        }
+        if self.rekey_due(policy) {
+            self.rekey(vault)?;
+        }
+
+        let mut body = Vec::with_capacity(HEADER_LEN + plaintext.len() + 16);
+        body.extend_from_slice(&self.epoch.to_be_bytes());
+        body.extend_from_slice(&self.nonce.to_be_bytes());
+        let aead = Aead::of(vault, self.key)?;
+        let mut ciphertext = aead.encrypt(vault, self.key, self.nonce, aad, plaintext)?;
+        body.append(&mut ciphertext);
+
+        self.nonce += 1;
+        self.used += 1;
+        Ok(body)
+    }
+
+    /// Decrypt a payload produced by the other side's `seal`. A message
+    /// under the next epoch moves this side to the next key, but only once
+    /// it has decrypted correctly; one under the previous epoch opens with
+    /// the retired key while it is kept. Replayed or too-old nonces are
+    /// refused with ChannelErrorKind::Replayed before any decryption is
+    /// attempted.
+    pub(crate) fn open(
+        &mut self,
+        vault: &mut dyn DynVault,
+        aad: &[u8],
+        body: &[u8],
+    ) -> Result<Vec<u8>, ChannelError> {
+        if body.len() < HEADER_LEN {
+            return Err(ChannelErrorKind::RecvError.into());
+        }
+        let epoch = u32::from_be_bytes(body[0..4].try_into().unwrap());
+        let nonce = u64::from_be_bytes(body[4..HEADER_LEN].try_into().unwrap());
+        let ciphertext = &body[HEADER_LEN..];
+        if nonce == REKEY_NONCE {
+            return Err(ChannelErrorKind::RecvError.into());
+        }
+        match self.replay.check(nonce) {
+            Ok(()) => {}
+            Err(Rejection::Duplicate) => {
+                return Err(ChannelError::from_msg(
+                    ChannelErrorKind::Replayed,
+                    "duplicate nonce",
+                ))
+            }
+            Err(Rejection::TooOld) => {
+                return Err(ChannelError::from_msg(
+                    ChannelErrorKind::Replayed,
+                    "nonce is behind the replay window",
+                ))
+            }
+        }
+
+        if self.previous.as_ref().map_or(false, RetiredKey::expired) {
+            self.destroy_previous(vault)?;
+        }
+
+        let aead = Aead::of(vault, self.key)?;
+        if epoch == self.epoch {
+            let plaintext = aead.decrypt(vault, self.key, nonce, aad, ciphertext)?;
+            self.replay.mark(nonce);
+            if let Some(previous) = &mut self.previous {
+                previous.opened += 1;
+            }
+            return Ok(plaintext);
+        }
+        if let Some(previous) = &self.previous {
+            if epoch == self.epoch.wrapping_sub(1) {
+                let plaintext = aead.decrypt(vault, previous.key, nonce, aad, ciphertext)?;
+                self.replay.mark(nonce);
+                return Ok(plaintext);
+            }
+        }
+        if epoch != self.epoch.wrapping_add(1) {
+            return Err(ChannelError::from_msg(
+                ChannelErrorKind::RecvError,
+                "message for unknown key epoch",
+            ));
+        }
+
+        let next = rekey(vault, self.key)?;
+        match aead.decrypt(vault, next, nonce, aad, ciphertext) {
+            Ok(plaintext) => {
+                self.destroy_previous(vault)?;
+                self.previous = Some(RetiredKey {
+                    key: self.key,
+                    opened: 1,
+                    retired_at: Instant::now(),
+                });
+                self.key = next;
+                self.epoch = epoch;
+                self.replay.mark(nonce);
+                Ok(plaintext)
+            }
+            Err(e) => {
+                vault.secret_destroy(next)?;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Remove the current key, and the previous one if it is still kept,
+    /// from the vault
+    pub(crate) fn destroy(mut self, vault: &mut dyn DynVault) -> Result<(), ChannelError> {
+        self.destroy_previous(vault)?;
+        Ok(vault.secret_destroy(self.key)?)
+    }
+
+    fn destroy_previous(&mut self, vault: &mut dyn DynVault) -> Result<(), ChannelError> {
+        if let Some(previous) = self.previous.take() {
+            vault.secret_destroy(previous.key)?;
+        }
+        Ok(())
+    }
+
+    fn rekey_due(&self, policy: &RekeyPolicy) -> bool {
+        policy.max_messages.map_or(false, |m| self.used >= m)
+            || policy
+                .max_age
+                .map_or(false, |a| self.keyed_at.elapsed() >= a)
+    }
+
+    fn rekey(&mut self, vault: &mut dyn DynVault) -> Result<(), ChannelError> {
+        let next = rekey(vault, self.key)?;
+        vault.secret_destroy(self.key)?;
+        self.key = next;
+        self.epoch = self.epoch.wrapping_add(1);
+        self.used = 0;
+        self.keyed_at = Instant::now();
+        Ok(())
+    }
+}
+
+/// Noise REKEY: the new key is the first key-length bytes of encrypting 32
+/// zero bytes under the old key with the maximum nonce and no associated
+/// data. The old key is left in the vault.
+pub(crate) fn rekey(
+    vault: &mut dyn DynVault,
+    key: SecretKeyContext,
+) -> Result<SecretKeyContext, ChannelError> {
+    let attributes = vault.secret_attributes_get(key)?;
+    let k = Aead::of(vault, key)?.encrypt(vault, key, REKEY_NONCE, &[], &[0u8; 32])?;
+    let secret = match attributes.xtype {
+        SecretKeyType::Aes128 => SecretKey::Aes128(k[..16].try_into().unwrap()),
+        SecretKeyType::Aes256 => SecretKey::Aes256(k[..32].try_into().unwrap()),
+        SecretKeyType::ChaCha20Poly1305 => SecretKey::ChaCha20Poly1305(k[..32].try_into().unwrap()),
+        _ => return Err(ChannelErrorKind::InvalidParam(0).into()),
+    };
+    Ok(vault.secret_import(&secret, attributes)?)
+}
+
+/// The 96-bit AES-GCM nonce for a counter: 32 bits of zeros followed by the
+/// big endian counter, as Noise specifies
+pub(crate) fn nonce_to_96(n: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&n.to_be_bytes());
+    nonce
+}
+
+/// The 96-bit ChaChaPoly nonce for a counter: 32 bits of zeros followed by
+/// the little endian counter, as Noise specifies
+pub(crate) fn nonce_to_96_le(n: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&n.to_le_bytes());
+    nonce
+}
+
+/// The AEAD a channel key is used with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Aead {
+    AesGcm,
+    ChaChaPoly,
+}
+
+impl Aead {
+    /// Pick the AEAD from the type of the key in the vault
+    fn of(vault: &mut dyn DynVault, key: SecretKeyContext) -> Result<Self, ChannelError> {
+        match vault.secret_attributes_get(key)?.xtype {
+            SecretKeyType::Aes128 | SecretKeyType::Aes256 => Ok(Aead::AesGcm),
+            SecretKeyType::ChaCha20Poly1305 => Ok(Aead::ChaChaPoly),
+            _ => Err(ChannelErrorKind::InvalidParam(0).into()),
+        }
+    }
+
+    fn encrypt(
+        self,
+        vault: &mut dyn DynVault,
+        key: SecretKeyContext,
+        nonce: u64,
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, ChannelError> {
+        Ok(match self {
+            Aead::AesGcm => vault.aead_aes_gcm_encrypt(key, plaintext, &nonce_to_96(nonce), aad)?,
+            Aead::ChaChaPoly => {
+                vault.aead_chacha20_poly1305_encrypt(key, plaintext, &nonce_to_96_le(nonce), aad)?
+            }
+        })
+    }
+
+    fn decrypt(
+        self,
+        vault: &mut dyn DynVault,
+        key: SecretKeyContext,
+        nonce: u64,
+        aad: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, ChannelError> {
+        Ok(match self {
+            Aead::AesGcm => {
+                vault.aead_aes_gcm_decrypt(key, ciphertext, &nonce_to_96(nonce), aad)?
+            }
+            Aead::ChaChaPoly => vault.aead_chacha20_poly1305_decrypt(
+                key,
+                ciphertext,
+                &nonce_to_96_le(nonce),
+                aad,
+            )?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ockam_vault::software::DefaultVault;
+    use ockam_vault::types::{SecretKeyAttributes, SecretPersistenceType, SecretPurposeType};
+
+    fn shared_key(a: &mut DefaultVault, b: &mut DefaultVault) -> (CipherState, CipherState) {
+        shared_key_of(a, b, SecretKey::Aes256([7u8; 32]), SecretKeyType::Aes256)
+    }
+
+    fn shared_key_of(
+        a: &mut DefaultVault,
+        b: &mut DefaultVault,
+        secret: SecretKey,
+        xtype: SecretKeyType,
+    ) -> (CipherState, CipherState) {
+        let attributes = SecretKeyAttributes {
+            xtype,
+            persistence: SecretPersistenceType::Ephemeral,
+            purpose: SecretPurposeType::KeyAgreement,
+        };
+        (
+            CipherState::new(a.secret_import(&secret, attributes).unwrap()),
+            CipherState::new(b.secret_import(&secret, attributes).unwrap()),
+        )
+    }
+
+    #[test]
+    fn nonce_layout() {
+        assert_eq!(
+            nonce_to_96(0x0102030405060708),
+            [0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8]
+        );
+    }
+
+    #[test]
+    fn counts_past_u16() {
+        let mut va = DefaultVault::default();
+        let mut vb = DefaultVault::default();
+        let (mut send, mut recv) = shared_key(&mut va, &mut vb);
+        send.nonce = 0x1_0000;
+        let body = send
+            .seal(&mut va, &RekeyPolicy::never(), b"h", b"hello")
+            .unwrap();
+        assert_eq!(&body[4..HEADER_LEN], &0x1_0000u64.to_be_bytes());
+        assert_eq!(recv.open(&mut vb, b"h", &body).unwrap(), b"hello".to_vec());
+    }
+
+    #[test]
+    fn refuses_at_exhaustion() {
+        let mut va = DefaultVault::default();
+        let mut vb = DefaultVault::default();
+        let (mut send, _) = shared_key(&mut va, &mut vb);
+        send.nonce = REKEY_NONCE;
+        let err = send
+            .seal(&mut va, &RekeyPolicy::never(), b"h", b"hello")
+            .unwrap_err();
+        let kind: ChannelErrorKind = err.into();
+        assert!(matches!(kind, ChannelErrorKind::NonceExhausted));
+    }
+
+    #[test]
+    fn receiver_follows_rekey() {
+        let mut va = DefaultVault::default();
+        let mut vb = DefaultVault::default();
+        let (mut send, mut recv) = shared_key(&mut va, &mut vb);
+        let policy = RekeyPolicy {
+            max_messages: Some(2),
+            max_age: None,
+        };
+        for i in 0..5u8 {
+            let body = send.seal(&mut va, &policy, b"h", &[i]).unwrap();
+            assert_eq!(recv.open(&mut vb, b"h", &body).unwrap(), vec![i]);
+        }
+        assert_eq!(send.epoch, 2);
+        assert_eq!(recv.epoch, 2);
+        assert_eq!(send.nonce, 5);
+    }
+
+    #[test]
+    fn chachapoly_keys_use_chachapoly() {
+        let mut va = DefaultVault::default();
+        let mut vb = DefaultVault::default();
+        let secret = SecretKey::ChaCha20Poly1305([7u8; 32]);
+        let (mut send, mut recv) =
+            shared_key_of(&mut va, &mut vb, secret, SecretKeyType::ChaCha20Poly1305);
+        let policy = RekeyPolicy {
+            max_messages: Some(2),
+            max_age: None,
+        };
+        for i in 0..3u8 {
+            let body = send.seal(&mut va, &policy, b"h", &[i]).unwrap();
+            assert_eq!(recv.open(&mut vb, b"h", &body).unwrap(), vec![i]);
+        }
+        assert_eq!(recv.epoch, 1);
+        assert_eq!(
+            vb.secret_attributes_get(recv.key).unwrap().xtype,
+            SecretKeyType::ChaCha20Poly1305
+        );
+
+        // the ciphertext is ChaCha20-Poly1305 under the counter's nonce
+        let body = send
+            .seal(&mut va, &RekeyPolicy::never(), b"h", b"hello")
+            .unwrap();
+        let nonce = u64::from_be_bytes(body[4..HEADER_LEN].try_into().unwrap());
+        let plaintext = vb
+            .aead_chacha20_poly1305_decrypt(
+                recv.key,
+                &body[HEADER_LEN..],
+                &nonce_to_96_le(nonce),
+                b"h",
+            )
+            .unwrap();
+        assert_eq!(plaintext, b"hello".to_vec());
+    }
+
+    #[test]
+    fn rejects_replayed_payload() {
+        let mut va = DefaultVault::default();
+        let mut vb = DefaultVault::default();
+        let (mut send, mut recv) = shared_key(&mut va, &mut vb);
+        let first = send
+            .seal(&mut va, &RekeyPolicy::never(), b"h", b"one")
+            .unwrap();
+        let second = send
+            .seal(&mut va, &RekeyPolicy::never(), b"h", b"two")
+            .unwrap();
+        assert_eq!(recv.open(&mut vb, b"h", &second).unwrap(), b"two".to_vec());
+        assert_eq!(recv.open(&mut vb, b"h", &first).unwrap(), b"one".to_vec());
+        let err = recv.open(&mut vb, b"h", &first).unwrap_err();
+        assert!(matches!(err.kind(), ChannelErrorKind::Replayed));
+    }
+
+    #[test]
+    fn destroy_removes_current_key() {
+        let mut va = DefaultVault::default();
+        let mut vb = DefaultVault::default();
+        let (mut send, _) = shared_key(&mut va, &mut vb);
+        send.rekey(&mut va).unwrap();
+        let key = send.key;
+        send.destroy(&mut va).unwrap();
+        assert!(va.secret_export(key).is_err());
+    }
+
+    #[test]
+    fn rejects_skipped_epoch() {
+        let mut va = DefaultVault::default();
+        let mut vb = DefaultVault::default();
+        let (mut send, mut recv) = shared_key(&mut va, &mut vb);
+        send.rekey(&mut va).unwrap();
+        send.rekey(&mut va).unwrap();
+        let body = send
+            .seal(&mut va, &RekeyPolicy::never(), b"h", b"hello")
+            .unwrap();
+        assert!(recv.open(&mut vb, b"h", &body).is_err());
+        assert_eq!(recv.epoch, 0);
+    }
+
+    #[test]
+    fn opens_previous_epoch_after_rekey() {
+        let mut va = DefaultVault::default();
+        let mut vb = DefaultVault::default();
+        let (mut send, mut recv) = shared_key(&mut va, &mut vb);
+        let old = send
+            .seal(&mut va, &RekeyPolicy::never(), b"h", b"old")
+            .unwrap();
+        send.rekey(&mut va).unwrap();
+        let new = send
+            .seal(&mut va, &RekeyPolicy::never(), b"h", b"new")
+            .unwrap();
+
+        assert_eq!(recv.open(&mut vb, b"h", &new).unwrap(), b"new".to_vec());
+        assert_eq!(recv.epoch, 1);
+        assert_eq!(recv.open(&mut vb, b"h", &old).unwrap(), b"old".to_vec());
+        let err = recv.open(&mut vb, b"h", &old).unwrap_err();
+        assert!(matches!(err.kind(), ChannelErrorKind::Replayed));
+    }
+
+    #[test]
+    fn destroys_previous_key_after_grace() {
+        let mut va = DefaultVault::default();
+        let mut vb = DefaultVault::default();
+        let (mut send, mut recv) = shared_key(&mut va, &mut vb);
+        let old = send
+            .seal(&mut va, &RekeyPolicy::never(), b"h", b"old")
+            .unwrap();
+        send.rekey(&mut va).unwrap();
+        let new = send
+            .seal(&mut va, &RekeyPolicy::never(), b"h", b"new")
+            .unwrap();
+        recv.open(&mut vb, b"h", &new).unwrap();
+        let previous = recv.previous.as_ref().unwrap().key;
+
+        // as if the replay window had moved on past every old nonce
+        recv.previous.as_mut().unwrap().opened = REPLAY_WINDOW;
+        assert!(recv.open(&mut vb, b"h", &old).is_err());
+        assert!(recv.previous.is_none());
+        assert!(vb.secret_export(previous).is_err());
+    }
+}
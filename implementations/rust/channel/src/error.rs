@@ -29,6 +29,15 @@ pub enum ChannelErrorKind {
     /// Couldn't receive message
     #[fail(display = "Couldn't receive message")]
     RecvError,
+    /// The channel has used every nonce and can't encrypt any more
+    #[fail(display = "The channel has exhausted its nonces")]
+    NonceExhausted,
+    /// The message was a replay of one already received
+    #[fail(display = "The message was replayed")]
+    Replayed,
+    /// The trust policy refused the peer's identity
+    #[fail(display = "The peer is not trusted")]
+    PeerRejected,
 }
 
 impl ChannelErrorKind {
@@ -42,6 +51,9 @@ impl ChannelErrorKind {
             ChannelErrorKind::State => Self::ERROR_INTERFACE_CHANNEL | 4,
             ChannelErrorKind::CantSend => Self::ERROR_INTERFACE_CHANNEL | 5,
             ChannelErrorKind::RecvError => Self::ERROR_INTERFACE_CHANNEL | 6,
+            ChannelErrorKind::NonceExhausted => Self::ERROR_INTERFACE_CHANNEL | 7,
+            ChannelErrorKind::Replayed => Self::ERROR_INTERFACE_CHANNEL | 8,
+            ChannelErrorKind::PeerRejected => Self::ERROR_INTERFACE_CHANNEL | 9,
         }
     }
 }
@@ -63,6 +75,11 @@ impl ChannelError {
         }
     }
 
+    /// The kind of failure this error wraps
+    pub fn kind(&self) -> &ChannelErrorKind {
+        self.inner.get_context()
+    }
+
     /// Convert to an integer, reused in From trait implementations
     fn to_usize(&self) -> usize {
         self.inner.get_context().to_usize()
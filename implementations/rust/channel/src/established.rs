@@ -0,0 +1,137 @@
+use crate::error::{ChannelError, ChannelErrorKind};
+use ockam_message::message::{AddressType, Codec, RouterAddress};
+
+/// Decode a channel address from the front of `data`, checking the lengths
+/// first as they come from the peer
+pub(crate) fn decode_channel_address(data: &[u8]) -> Result<(RouterAddress, &[u8]), ChannelError> {
+    match data {
+        [a_type, length, rest @ ..]
+            if *a_type == AddressType::Channel as u8 && rest.len() >= *length as usize =>
+        {
+            RouterAddress::decode(data)
+                .map_err(|e| ChannelError::from_msg(ChannelErrorKind::InvalidParam(0), e))
+        }
+        _ => Err(ChannelErrorKind::InvalidParam(0).into()),
+    }
+}
+
+/// What the worker bound to a channel is told once the channel is
+/// established, as the body of the MessageType::None message whose return
+/// route starts with the channel's cleartext address.
+///
+/// The payload is whatever the peer's Channel Manager was given with
+/// `set_handshake_payload`, e.g. a credential, a list of capabilities or
+/// the name of a service. It came through the key exchange encrypted, from
+/// the holder of `remote_public_key`, and the trust policy has already seen
+/// it, so the worker may act on it before the first message arrives.
+///
+/// The channel binding is the same on both ends of this channel and no
+/// other. Signing it, or a key exported with `ChannelManager::export_key`,
+/// proves to the peer that nobody relays the channel in between.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Established {
+    /// The peer's cleartext channel address; only initiators learn it
+    pub remote_address: Option<RouterAddress>,
+    /// The static public key the peer proved it holds
+    pub remote_public_key: Vec<u8>,
+    /// The handshake hash of the key exchange
+    pub channel_binding: [u8; 32],
+    /// The application payload the peer attached to the handshake
+    pub payload: Vec<u8>,
+}
+
+impl Established {
+    /// Encode as a byte saying whether a remote address follows, the
+    /// address, the key's length and the key, the channel binding and the
+    /// payload
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut v = vec![];
+        match &self.remote_address {
+            Some(address) => {
+                v.push(1);
+                // a channel address always encodes
+                RouterAddress::encode(address, &mut v).unwrap();
+            }
+            None => v.push(0),
+        }
+        // public keys are far below the largest u16 that encodes
+        (self.remote_public_key.len() as u16)
+            .encode(&mut v)
+            .unwrap();
+        v.extend_from_slice(&self.remote_public_key);
+        v.extend_from_slice(&self.channel_binding);
+        v.extend_from_slice(&self.payload);
+        v
+    }
+
+    /// Decode what `to_bytes` produced
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ChannelError> {
+        let invalid = |e| ChannelError::from_msg(ChannelErrorKind::InvalidParam(0), e);
+        let (remote_address, rest) = match data.split_first() {
+            Some((0, rest)) => (None, rest),
+            Some((1, rest)) => {
+                let (address, rest) = decode_channel_address(rest)?;
+                (Some(address), rest)
+            }
+            _ => return Err(ChannelErrorKind::InvalidParam(0).into()),
+        };
+        // a length of 0x80 or more takes a second byte
+        match rest {
+            [] => return Err(ChannelErrorKind::InvalidParam(0).into()),
+            [b] if b & 0x80 != 0 => return Err(ChannelErrorKind::InvalidParam(0).into()),
+            _ => {}
+        }
+        let (key_size, rest) = u16::decode(rest).map_err(invalid)?;
+        let key_size = key_size as usize;
+        if rest.len() < key_size + 32 {
+            return Err(ChannelErrorKind::InvalidParam(0).into());
+        }
+        let (key, rest) = rest.split_at(key_size);
+        let mut channel_binding = [0u8; 32];
+        channel_binding.copy_from_slice(&rest[..32]);
+        Ok(Self {
+            remote_address,
+            remote_public_key: key.to_vec(),
+            channel_binding,
+            payload: rest[32..].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let established = Established {
+            remote_address: Some(
+                RouterAddress::channel_router_address_from_str("01020304").unwrap(),
+            ),
+            remote_public_key: vec![9u8; 32],
+            channel_binding: [3u8; 32],
+            payload: b"service=telemetry".to_vec(),
+        };
+        assert_eq!(
+            Established::from_bytes(&established.to_bytes()).unwrap(),
+            established
+        );
+
+        let established = Established {
+            remote_address: None,
+            remote_public_key: vec![9u8; 65],
+            channel_binding: [4u8; 32],
+            payload: vec![],
+        };
+        assert_eq!(
+            Established::from_bytes(&established.to_bytes()).unwrap(),
+            established
+        );
+        assert!(Established::from_bytes(&[]).is_err());
+        assert!(Established::from_bytes(&[0]).is_err());
+        assert!(Established::from_bytes(&[0, 32, 1]).is_err());
+        assert!(Established::from_bytes(&[0, 0]).is_err());
+        assert!(Established::from_bytes(&[0, 0x80]).is_err());
+        assert!(Established::from_bytes(&[1, AddressType::Channel as u8, 4, 1]).is_err());
+    }
+}
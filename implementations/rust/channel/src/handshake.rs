@@ -0,0 +1,169 @@
+use ockam_message::message::Message;
+use std::time::{Duration, Instant};
+
+/// Default time to wait for the next handshake message before retransmitting
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default number of times the last handshake message is resent before the
+/// handshake is abandoned
+pub const DEFAULT_HANDSHAKE_RETRANSMITS: u32 = 3;
+
+/// Default number of handshakes that may be in progress at once
+pub const DEFAULT_MAX_PENDING_HANDSHAKES: usize = 1024;
+
+/// Default number of messages a channel holds while it is being established
+pub const DEFAULT_MAX_QUEUED_MESSAGES: usize = 256;
+
+/// How long a channel may take to finish its key exchange, and how many
+/// channels may be doing so at once
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HandshakePolicy {
+    /// Time to wait for the peer's next handshake message
+    pub timeout: Duration,
+    /// Times to resend the last handshake message before giving up
+    pub retransmits: u32,
+    /// Handshakes in progress beyond which new ones are refused
+    pub max_pending: usize,
+    /// Messages a channel holds until it is established; more are bounced
+    pub max_queued: usize,
+}
+
+impl Default for HandshakePolicy {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            retransmits: DEFAULT_HANDSHAKE_RETRANSMITS,
+            max_pending: DEFAULT_MAX_PENDING_HANDSHAKES,
+            max_queued: DEFAULT_MAX_QUEUED_MESSAGES,
+        }
+    }
+}
+
+/// What to do about a handshake whose deadline has passed
+#[derive(Debug)]
+pub(crate) enum Expiry {
+    /// Send this message again
+    Retransmit(Message),
+    /// Out of retransmissions, abandon the channel
+    Failed,
+}
+
+/// The handshake messages last sent and received on a channel, and when the
+/// next one is due. A duplicate of the last message received means the peer
+/// missed our reply, so it is answered with the last message sent rather
+/// than fed to the key exchange again.
+#[derive(Debug, Default)]
+pub(crate) struct Handshake {
+    last_sent: Option<Message>,
+    last_received: Option<Vec<u8>>,
+    deadline: Option<Instant>,
+    retransmits: u32,
+}
+
+impl Handshake {
+    /// Record a handshake message we sent and wait for the answer to it
+    pub(crate) fn sent(&mut self, m: Message, policy: &HandshakePolicy) {
+        self.last_sent = Some(m);
+        self.deadline = Some(Instant::now() + policy.timeout);
+        self.retransmits = 0;
+    }
+
+    /// Record a handshake message we sent that the peer doesn't answer. It
+    /// is only resent if the peer repeats the message before it.
+    pub(crate) fn sent_last(&mut self, m: Message) {
+        self.last_sent = Some(m);
+        self.deadline = None;
+    }
+
+    pub(crate) fn received(&mut self, body: &[u8]) {
+        self.last_received = Some(body.to_vec());
+    }
+
+    pub(crate) fn is_duplicate(&self, body: &[u8]) -> bool {
+        self.last_received.as_deref() == Some(body)
+    }
+
+    pub(crate) fn last_sent(&self) -> Option<Message> {
+        self.last_sent.clone()
+    }
+
+    /// Whether this side is still waiting on the peer
+    pub(crate) fn is_waiting(&self) -> bool {
+        self.deadline.is_some()
+    }
+
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub(crate) fn expire(&mut self, now: Instant, policy: &HandshakePolicy) -> Option<Expiry> {
+        match self.deadline {
+            Some(deadline) if deadline <= now => {}
+            _ => return None,
+        }
+        if self.retransmits >= policy.retransmits {
+            self.deadline = None;
+            return Some(Expiry::Failed);
+        }
+        self.retransmits += 1;
+        self.deadline = Some(now + policy.timeout);
+        self.last_sent.clone().map(Expiry::Retransmit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ockam_message::message::{MessageType, Route};
+
+    fn message() -> Message {
+        Message {
+            onward_route: Route { addresses: vec![] },
+            return_route: Route { addresses: vec![] },
+            message_type: MessageType::KeyAgreementM1,
+            message_body: vec![1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn retransmits_then_fails() {
+        let policy = HandshakePolicy {
+            timeout: Duration::from_secs(1),
+            retransmits: 2,
+            max_pending: 1,
+            max_queued: 1,
+        };
+        let mut h = Handshake::default();
+        h.sent(message(), &policy);
+        let start = Instant::now();
+        assert!(h.expire(start, &policy).is_none());
+
+        let mut now = start + policy.timeout;
+        for _ in 0..2 {
+            assert!(matches!(
+                h.expire(now, &policy),
+                Some(Expiry::Retransmit(_))
+            ));
+            now += policy.timeout;
+        }
+        assert!(matches!(h.expire(now, &policy), Some(Expiry::Failed)));
+        assert!(!h.is_waiting());
+    }
+
+    #[test]
+    fn recognizes_duplicates() {
+        let mut h = Handshake::default();
+        assert!(!h.is_duplicate(&[1]));
+        h.received(&[1]);
+        assert!(h.is_duplicate(&[1]));
+        assert!(!h.is_duplicate(&[2]));
+    }
+
+    #[test]
+    fn final_message_has_no_deadline() {
+        let mut h = Handshake::default();
+        h.sent_last(message());
+        assert!(!h.is_waiting());
+        assert!(h.last_sent().is_some());
+    }
+}
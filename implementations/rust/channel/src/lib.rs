@@ -25,24 +25,31 @@ while_true
 #[macro_use]
 extern crate ockam_common;
 
+use bundle::PublishedBundle;
+use cipher::{CipherState, RekeyPolicy};
 use core::marker::PhantomData;
 use error::*;
+use established::{decode_channel_address, Established};
+use handshake::{Expiry, Handshake, HandshakePolicy};
 use ockam_kex::{CompletedKeyExchange, KeyExchanger, NewKeyExchanger};
 use ockam_message::message::{
     Address, AddressType, Codec, Message, MessageType, Route, RouterAddress,
 };
 use ockam_system::commands::OckamCommand::Router;
-use ockam_system::commands::{ChannelCommand, OckamCommand, RouterCommand};
+use ockam_system::commands::{ChannelCommand, CommandSender, OckamCommand, RouterCommand};
+use ockam_system::stats::ChannelStats;
 use ockam_vault::types::{PublicKey, SecretKeyContext};
 use ockam_vault::DynVault;
 use rand::{thread_rng, Rng};
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, VecDeque},
     sync::{
-        mpsc::{Receiver, Sender},
+        mpsc::{Receiver, RecvTimeoutError},
         Arc, Mutex,
     },
+    time::Instant,
 };
+use trust::{AllowAll, TrustPolicy};
 
 /// A channel address of zero indicates to the channel manager that
 /// a new channel is being initiated
@@ -56,23 +63,29 @@ enum ExchangerRole {
 /// A Channel Manager creates secure channels on demand using the specified key exchange
 /// generic. All keys will be created in the associated vault object
 pub struct ChannelManager<
-    I: KeyExchanger + 'static,
-    R: KeyExchanger + 'static,
+    I: KeyExchanger + Send + 'static,
+    R: KeyExchanger + Send + 'static,
     E: NewKeyExchanger<I, R>,
 > {
     channels: BTreeMap<String, Arc<Mutex<Channel>>>,
-    rx: Receiver<OckamCommand>,
-    tx: Sender<OckamCommand>,
-    router_tx: Sender<OckamCommand>,
+    rx: Option<Receiver<OckamCommand>>,
+    tx: CommandSender,
+    router_tx: CommandSender,
     vault: Arc<Mutex<dyn DynVault + Send>>,
     new_key_exchanger: E,
     phantom_i: PhantomData<I>,
     phantom_r: PhantomData<R>,
     resp_key_ctx: Option<SecretKeyContext>,
     init_key_ctx: Option<SecretKeyContext>,
+    rekey_policy: RekeyPolicy,
+    handshake_policy: HandshakePolicy,
+    trust_policy: Box<dyn TrustPolicy>,
+    psk: Option<SecretKeyContext>,
+    handshake_payload: Vec<u8>,
+    stats: ChannelStats,
 }
 
-impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> std::fmt::Debug
+impl<I: KeyExchanger + Send, R: KeyExchanger + Send, E: NewKeyExchanger<I, R>> std::fmt::Debug
     for ChannelManager<I, R, E>
 {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -84,12 +97,36 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> std::fmt::Debug
     }
 }
 
-impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<I, R, E> {
+impl<I: KeyExchanger + Send, R: KeyExchanger + Send, E: NewKeyExchanger<I, R>>
+    ChannelManager<I, R, E>
+{
     /// Create a new Channel Manager
     pub fn new(
         rx: Receiver<OckamCommand>,
-        tx: Sender<OckamCommand>,
-        router_tx: Sender<OckamCommand>,
+        tx: impl Into<CommandSender>,
+        router_tx: impl Into<CommandSender>,
+        vault: Arc<Mutex<dyn DynVault + Send>>,
+        new_key_exchanger: E,
+        resp_key_ctx: Option<SecretKeyContext>,
+        init_key_ctx: Option<SecretKeyContext>,
+    ) -> Result<Self, ChannelError> {
+        Self::from_parts(
+            Some(rx),
+            tx.into(),
+            router_tx.into(),
+            vault,
+            new_key_exchanger,
+            resp_key_ctx,
+            init_key_ctx,
+        )
+    }
+
+    /// Build a Channel Manager whose commands may arrive some other way than
+    /// a std Receiver, e.g. from an async task
+    pub(crate) fn from_parts(
+        rx: Option<Receiver<OckamCommand>>,
+        tx: CommandSender,
+        router_tx: CommandSender,
         vault: Arc<Mutex<dyn DynVault + Send>>,
         new_key_exchanger: E,
         resp_key_ctx: Option<SecretKeyContext>,
@@ -115,108 +152,409 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
             phantom_r: PhantomData,
             resp_key_ctx,
             init_key_ctx,
+            rekey_policy: RekeyPolicy::default(),
+            handshake_policy: HandshakePolicy::default(),
+            trust_policy: Box::new(AllowAll),
+            psk: None,
+            handshake_payload: vec![],
+            stats: ChannelStats::default(),
         })
     }
 
-    /// Check for work to be done and do it
+    /// Set when channels replace their sending keys. Applies to messages
+    /// sent from now on, on new and existing channels.
+    pub fn set_rekey_policy(&mut self, policy: RekeyPolicy) {
+        self.rekey_policy = policy;
+    }
+
+    /// Set how long key exchanges may take and how many may run at once.
+    /// Deadlines already running keep their old timeout.
+    pub fn set_handshake_policy(&mut self, policy: HandshakePolicy) {
+        self.handshake_policy = policy;
+    }
+
+    /// Set which peers channels may be established with. Channels already
+    /// established are not checked again.
+    pub fn set_trust_policy(&mut self, policy: impl TrustPolicy + 'static) {
+        self.trust_policy = Box::new(policy);
+    }
+
+    /// Bind channel establishment to a pre-shared key, a 32 byte buffer
+    /// secret in the manager's vault, so only peers holding the same key
+    /// can complete a handshake. Fails if the key exchange has no psk
+    /// variant. Handshakes already running don't use it.
+    pub fn set_psk(&mut self, psk: SecretKeyContext) -> Result<(), ChannelError> {
+        let mut kex = self.new_key_exchanger.initiator(None);
+        let res = kex.set_psk(psk);
+        kex.destroy()?;
+        res?;
+        self.psk = Some(psk);
+        Ok(())
+    }
+
+    /// Attach an application payload, e.g. a credential, a list of
+    /// capabilities or a service name, to the handshake of every channel
+    /// created from now on. The peer's worker gets it in the `Established`
+    /// notice and its trust policy sees it before the channel is up.
+    ///
+    /// The responder's payload goes with M2. The initiator's goes with M1
+    /// as early data when the key exchange can already encrypt it, as in
+    /// IK, KK, NK or with a psk, and with M3 otherwise; it is never sent in
+    /// the clear. Exchanges from a published bundle carry no payloads.
+    pub fn set_handshake_payload(&mut self, payload: Vec<u8>) {
+        self.handshake_payload = payload;
+    }
+
+    /// The channel binding of an established channel, given either of its
+    /// addresses: the handshake hash, the same on both ends
+    pub fn channel_binding(&self, address: &Address) -> Option<[u8; 32]> {
+        let channel = self.channels.get(&address.as_string())?;
+        let channel = channel.lock().unwrap();
+        channel.completed_key_exchange.map(|kex| kex.h)
+    }
+
+    /// Derive `length` bytes from an established channel's key exchange
+    /// for `label` and `context`, as TLS exporters do. Both ends derive the
+    /// same bytes, so a token or signature bound to them is no good on any
+    /// other channel. The label must be 1 to 255 bytes and `length` at
+    /// most 8160.
+    pub fn export_key(
+        &self,
+        address: &Address,
+        label: &[u8],
+        context: &[u8],
+        length: usize,
+    ) -> Result<Vec<u8>, ChannelError> {
+        let channel = match self.channels.get(&address.as_string()) {
+            Some(channel) => channel.lock().unwrap(),
+            None => return Err(ChannelErrorKind::InvalidParam(0).into()),
+        };
+        let kex = match channel.completed_key_exchange {
+            Some(kex) => kex,
+            None => return Err(ChannelErrorKind::State.into()),
+        };
+        let mut vault = self.vault.lock().unwrap();
+        Ok(kex.export(&mut *vault, label, context, length)?)
+    }
+
+    /// Snapshot of the channel counters
+    pub fn stats(&self) -> ChannelStats {
+        ChannelStats {
+            // every channel is filed under both its addresses
+            channels: self.channels.len() / 2,
+            ..self.stats.clone()
+        }
+    }
+
+    /// Check for work to be done and do it, without blocking
     pub fn poll(&mut self) -> Result<bool, ChannelError> {
-        let keep_going = true;
-        let mut got_message = true;
-        while got_message {
-            match self.rx.try_recv() {
-                Ok(c) => match c {
-                    OckamCommand::Channel(ChannelCommand::Initiate(
-                        route,
-                        return_address,
-                        _key,
-                    )) => {
-                        self.initiate_new_channel(route, return_address)?;
-                    }
-                    OckamCommand::Channel(ChannelCommand::Stop) => {
-                        self.channels.clear();
-                        break;
-                    }
-                    OckamCommand::Channel(ChannelCommand::SendMessage(m)) => {
-                        self.handle_send(m)?;
-                    }
-                    OckamCommand::Channel(ChannelCommand::ReceiveMessage(m)) => {
-                        self.handle_recv(m)?;
+        loop {
+            let c = match self.rx.as_ref().map(|rx| rx.try_recv()) {
+                Some(Ok(c)) => c,
+                _ => break,
+            };
+            if !self.handle_command(c)? {
+                break;
+            }
+        }
+        self.expire_handshakes()?;
+        Ok(true)
+    }
+
+    /// Block on the command queue and do work as it arrives, until told to
+    /// stop or every sender has gone away. Wakes up in between to retransmit
+    /// or abandon handshakes that have gone quiet.
+    pub fn run(&mut self) -> Result<(), ChannelError> {
+        loop {
+            let rx = match self.rx.as_ref() {
+                Some(rx) => rx,
+                None => break,
+            };
+            let c = match self.next_deadline() {
+                Some(deadline) => {
+                    match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                        Ok(c) => Some(c),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => break,
                     }
-                    _ => return Err(ChannelErrorKind::InvalidParam(0).into()),
+                }
+                None => match rx.recv() {
+                    Ok(c) => Some(c),
+                    Err(_) => break,
                 },
-                Err(_) => {
-                    got_message = false;
+            };
+            if let Some(c) = c {
+                if !self.handle_command(c)? {
+                    break;
+                }
+            }
+            self.expire_handshakes()?;
+        }
+        Ok(())
+    }
+
+    /// The soonest a handshake needs attention
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.channels
+            .values()
+            .filter_map(|channel| {
+                let channel = channel.lock().unwrap();
+                channel.handshake.as_ref().and_then(|h| h.deadline())
+            })
+            .min()
+    }
+
+    /// Retransmit the last handshake message of every channel whose deadline
+    /// has passed, and abandon those out of retransmissions
+    pub(crate) fn expire_handshakes(&mut self) -> Result<(), ChannelError> {
+        let now = Instant::now();
+        let mut failed = vec![];
+        for (address, channel) in &self.channels {
+            let mut channel = channel.lock().unwrap();
+            // each channel is filed twice, look at it once
+            if *address != channel.as_ciphertext_address().as_string() {
+                continue;
+            }
+            let expiry = match channel.handshake.as_mut() {
+                Some(h) => h.expire(now, &self.handshake_policy),
+                None => None,
+            };
+            match expiry {
+                Some(Expiry::Retransmit(m)) => {
+                    self.router_tx.send(Router(RouterCommand::SendMessage(m)))?;
                 }
+                Some(Expiry::Failed) => failed.push(address.clone()),
+                None => {}
             }
         }
-        Ok(keep_going)
+        for address in failed {
+            println!("handshake on channel {} timed out", address);
+            self.fail_handshake(&address)?;
+        }
+        Ok(())
+    }
+
+    /// Channels waiting on the peer to continue their key exchange
+    fn pending_handshakes(&self) -> usize {
+        self.channels
+            .iter()
+            .filter(|(address, channel)| {
+                let channel = channel.lock().unwrap();
+                **address == channel.as_ciphertext_address().as_string()
+                    && channel.handshake.as_ref().map_or(false, |h| h.is_waiting())
+            })
+            .count()
+    }
+
+    /// The channel, if any, that already took this M1. The initiator resends
+    /// M1 when our M2 goes missing, and that mustn't start another channel.
+    fn find_m1(&self, body: &[u8]) -> Option<String> {
+        self.channels.iter().find_map(|(address, channel)| {
+            let channel = channel.lock().unwrap();
+            let duplicate = *address == channel.as_ciphertext_address().as_string()
+                && channel
+                    .handshake
+                    .as_ref()
+                    .map_or(false, |h| h.is_duplicate(body));
+            if duplicate {
+                Some(address.clone())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Do the work for one command. Returns Ok(false) once told to stop.
+    pub(crate) fn handle_command(&mut self, c: OckamCommand) -> Result<bool, ChannelError> {
+        match c {
+            OckamCommand::Channel(ChannelCommand::Initiate(route, return_address, _key)) => {
+                self.initiate_new_channel(route, return_address)?;
+            }
+            OckamCommand::Channel(ChannelCommand::InitiateWithBundle(
+                route,
+                return_address,
+                bundle,
+            )) => {
+                self.initiate_with_bundle(route, return_address, &bundle)?;
+            }
+            OckamCommand::Channel(ChannelCommand::Stop) => {
+                let channels = std::mem::take(&mut self.channels);
+                for (address, channel) in channels {
+                    // each channel is filed twice, tear it down once
+                    let mut channel = channel.lock().unwrap();
+                    if address == channel.as_cleartext_address().as_string() {
+                        self.destroy_secrets(&mut channel)?;
+                    }
+                }
+                return Ok(false);
+            }
+            OckamCommand::Channel(ChannelCommand::Close(address)) => {
+                self.close_channel(&address.as_string(), true)?;
+            }
+            OckamCommand::Channel(ChannelCommand::SendMessage(m)) => {
+                self.handle_send(m)?;
+            }
+            OckamCommand::Channel(ChannelCommand::ReceiveMessage(m)) => {
+                self.handle_recv(m)?;
+            }
+            OckamCommand::Channel(ChannelCommand::Stats(reply)) => {
+                let _ = reply.send(self.stats());
+            }
+            OckamCommand::Channel(ChannelCommand::ExportKey(
+                address,
+                label,
+                context,
+                length,
+                reply,
+            )) => {
+                let _ = reply.send(self.export_key(&address, &label, &context, length).ok());
+            }
+            _ => return Err(ChannelErrorKind::InvalidParam(0).into()),
+        }
+        Ok(true)
     }
 
     fn handle_send(&mut self, mut m: Message) -> Result<(), ChannelError> {
         if m.onward_route.addresses.is_empty() {
-            return Err(ChannelErrorKind::CantSend.into());
+            println!("dropped message to send with no onward route");
+            return Ok(());
+        }
+        let address = m.onward_route.addresses[0].clone();
+        let channel = match self.channels.get(&address.address.as_string()) {
+            Some(channel) => channel.clone(),
+            None => {
+                // e.g. the peer closed the channel while the worker was
+                // still sending on it
+                println!(
+                    "no channel {}, message bounced",
+                    address.address.as_string()
+                );
+                return self.return_to_sender(address, None, m);
+            }
+        };
+        let mut channel = channel.lock().unwrap();
+        if address.address != channel.as_cleartext_address() {
+            println!(
+                "got unexpected send on ciphertext address: {:?}",
+                m.message_type
+            );
+            return self.bounce(&channel, m);
         }
-        let address = &m.onward_route.addresses[0];
-        return match self.channels.get_mut(&address.address.as_string()) {
-            Some(channel) => {
-                let mut channel = channel.lock().unwrap();
-                if address.address == channel.as_cleartext_address() {
-                    // messages coming in on the cleartext channel need to be encrypted,
-                    // wrapped in an outer message, and sent on their way
-
-                    // 0th onward address should be ours, remove it
-                    m.onward_route.addresses.remove(0);
-
-                    // the message body will be the encoded & encrypted original message
-                    let mut encoded_mb: Vec<u8> = vec![];
-                    Message::encode(&m, &mut encoded_mb).unwrap();
-
-                    // encrypt it
-                    let mut encrypted_mb: Vec<u8> = vec![];
-                    if let Err(e) = u16::encode(&channel.nonce, &mut encrypted_mb)
-                        .map_err(|e| ChannelError::from_msg(ChannelErrorKind::CantSend, e))
-                    {
-                        return Err(e);
-                    }
 
-                    let cke = channel.completed_key_exchange.as_ref().unwrap();
-                    let nonce = Channel::nonce_16_to_96(channel.nonce);
-                    let mut vault = self.vault.lock().unwrap();
-                    let mut ciphertext_and_tag =
-                        vault.aead_aes_gcm_encrypt(cke.encrypt_key, &encoded_mb, &nonce, &cke.h)?;
-                    channel.nonce += 1;
-
-                    encrypted_mb.append(&mut ciphertext_and_tag);
-
-                    // construct the new message
-                    let new_m = Message {
-                        onward_route: channel.route.clone(),
-                        return_route: Route {
-                            addresses: vec![RouterAddress::from_address(
-                                channel.as_ciphertext_address(),
-                            )
-                            .unwrap()],
-                        },
-                        message_type: MessageType::Payload,
-                        message_body: encrypted_mb,
-                    };
-
-                    // and send
-                    self.router_tx
-                        .send(Router(RouterCommand::SendMessage(new_m)))
-                        .unwrap();
+        // messages coming in on the cleartext channel need to be encrypted,
+        // wrapped in an outer message, and sent on their way
 
-                    Ok(())
-                } else {
-                    println!(
-                        "got unexapected send on ciphertext address: {:?}",
-                        m.message_type
-                    );
-                    Err(ChannelErrorKind::NotImplemented.into())
-                }
+        // 0th onward address should be ours, remove it
+        m.onward_route.addresses.remove(0);
+
+        if channel.completed_key_exchange.is_none() {
+            // hold it until the channel is established
+            if channel.queued.len() >= self.handshake_policy.max_queued {
+                println!("channel queue full, message bounced");
+                return self.bounce(&channel, m);
             }
-            _ => Err(ChannelErrorKind::NotImplemented.into()),
+            channel.queued.push_back(m);
+            return Ok(());
+        }
+        self.seal_and_send(&mut channel, m)
+    }
+
+    /// Encrypt a message and send it to the far end of an established channel
+    fn seal_and_send(&mut self, channel: &mut Channel, m: Message) -> Result<(), ChannelError> {
+        // the message body will be the encoded & encrypted original message
+        let mut encoded_mb: Vec<u8> = vec![];
+        Message::encode(&m, &mut encoded_mb).unwrap();
+
+        // encrypt it
+        let h = match channel.completed_key_exchange.as_ref() {
+            Some(cke) => cke.h,
+            None => return Err(ChannelErrorKind::State.into()),
+        };
+        let mut vault = self.vault.lock().unwrap();
+        let encrypted_mb = channel.send.as_mut().unwrap().seal(
+            &mut *vault,
+            &self.rekey_policy,
+            &h,
+            &encoded_mb,
+        )?;
+
+        // construct the new message
+        let new_m = Message {
+            onward_route: channel.route.clone(),
+            return_route: Route {
+                addresses: vec![
+                    RouterAddress::from_address(channel.as_ciphertext_address()).unwrap()
+                ],
+            },
+            message_type: MessageType::Payload,
+            message_body: encrypted_mb,
+        };
+
+        // and send
+        self.router_tx
+            .send(Router(RouterCommand::SendMessage(new_m)))
+            .unwrap();
+        self.stats.messages_sent += 1;
+        Ok(())
+    }
+
+    /// Send the messages held while the channel was being established, in
+    /// the order they were sent
+    fn flush_queued(&mut self, channel: &mut Channel) -> Result<(), ChannelError> {
+        while let Some(m) = channel.queued.pop_front() {
+            self.seal_and_send(channel, m)?;
+        }
+        Ok(())
+    }
+
+    /// Return a message the channel couldn't deliver to whoever sent it, as
+    /// MessageType::SendFailed with the original body. Without a return
+    /// route it goes to the worker bound to the channel.
+    fn bounce(&mut self, channel: &Channel, m: Message) -> Result<(), ChannelError> {
+        let from = RouterAddress::from_address(channel.as_cleartext_address()).unwrap();
+        self.return_to_sender(from, channel.worker.clone(), m)
+    }
+
+    /// Send `m` back to its return route, or to `worker` without one, as
+    /// MessageType::SendFailed from the channel address `from`
+    fn return_to_sender(
+        &mut self,
+        from: RouterAddress,
+        worker: Option<RouterAddress>,
+        m: Message,
+    ) -> Result<(), ChannelError> {
+        self.stats.messages_bounced += 1;
+        let to = match m.return_route.addresses.first() {
+            Some(to) => to.clone(),
+            None => match worker {
+                Some(worker) => worker,
+                None => {
+                    println!("dropped undeliverable message with no return route");
+                    return Ok(());
+                }
+            },
+        };
+        let bounced = Message {
+            onward_route: Route {
+                addresses: vec![to],
+            },
+            return_route: Route {
+                addresses: vec![from],
+            },
+            message_type: MessageType::SendFailed,
+            message_body: m.message_body,
         };
+        self.router_tx
+            .send(Router(RouterCommand::ReceiveMessage(bounced)))?;
+        Ok(())
+    }
+
+    /// Bounce everything held for a channel that will never be established
+    fn bounce_queued(&mut self, channel: &mut Channel) -> Result<(), ChannelError> {
+        for m in std::mem::take(&mut channel.queued) {
+            self.bounce(channel, m)?;
+        }
+        Ok(())
     }
 
     fn handle_recv(&mut self, m: Message) -> Result<(), ChannelError> {
@@ -231,7 +569,13 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
         // Respond accordingly
         let mut recv_address_str = m.onward_route.addresses[0].address.as_string();
         if recv_address_str == CHANNEL_ZERO {
-            if let Some((_clear, cipher)) = self.create_channel(ExchangerRole::Responder) {
+            if let Some(cipher) = self.find_m1(&m.message_body) {
+                recv_address_str = cipher;
+            } else if self.pending_handshakes() >= self.handshake_policy.max_pending {
+                self.stats.handshakes_refused += 1;
+                println!("refused handshake, too many in progress");
+                return Ok(());
+            } else if let Some((_clear, cipher)) = self.create_channel(ExchangerRole::Responder) {
                 recv_address_str = cipher;
             } else {
                 return Err(ChannelErrorKind::State.into());
@@ -253,59 +597,102 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
                 }
 
                 return match m.message_type {
-                    MessageType::KeyAgreementM1 => {
-                        self.handle_m1_recv(channel, m)?;
-                        Ok(())
-                    }
-                    MessageType::KeyAgreementM2 => {
-                        self.handle_m2_recv(channel, m)?;
-                        Ok(())
-                    }
-                    MessageType::KeyAgreementM3 => {
-                        self.handle_m3_recv(channel, m)?;
-                        Ok(())
+                    MessageType::KeyAgreementM1
+                    | MessageType::KeyAgreementM2
+                    | MessageType::KeyAgreementM3 => {
+                        self.handle_handshake_recv(channel, &recv_address_str, m)
                     }
                     MessageType::Payload => {
                         self.handle_payload_recv(channel, m)?;
                         Ok(())
                     }
+                    MessageType::ChannelClose => {
+                        self.handle_close_recv(channel, m)?;
+                        Ok(())
+                    }
                     _ => {
-                        debug_assert!(false);
-                        Err(ChannelErrorKind::NotImplemented.into())
+                        println!("dropped message of unexpected type for channel");
+                        Ok(())
                     }
                 };
             }
             None => {
-                debug_assert!(false, "unknown channel address");
+                // e.g. a late message for a channel that has been closed
+                println!("dropped message for unknown channel {}", recv_address_str);
             }
         }
         Ok(())
     }
 
     fn handle_payload_recv(
-        &self,
+        &mut self,
         channel: Arc<Mutex<Channel>>,
         m: Message,
     ) -> Result<(), ChannelError> {
-        let channel = channel.lock().unwrap();
+        let mut channel = channel.lock().unwrap();
 
         match &m.onward_route.addresses[0].address {
             Address::ChannelAddress(ca) => {
                 if ca.as_slice() != channel.ciphertext_address.to_le_bytes() {
-                    return Err(ChannelErrorKind::NotImplemented.into());
+                    self.stats.payloads_rejected += 1;
+                    println!("dropped payload for another channel address");
+                    return Ok(());
                 }
             }
             _ => {}
         }
 
         // unwrap the payload and decode the message (payload *should* be an encrypted Message)
-        let (nonce, encrypted_msg) = u16::decode(&m.message_body).unwrap();
-        let nonce_96 = Channel::nonce_16_to_96(nonce);
-        let kex = channel.completed_key_exchange.as_ref().unwrap();
+        let h = match channel.completed_key_exchange.as_ref() {
+            Some(kex) => kex.h,
+            None => {
+                // e.g. overtook the enrollment to a published bundle
+                println!("dropped payload for channel not yet established");
+                return Ok(());
+            }
+        };
         let mut vault = self.vault.lock().unwrap();
         let encoded_msg =
-            vault.aead_aes_gcm_decrypt(kex.decrypt_key, encrypted_msg, &nonce_96, &kex.h)?;
-        let (mut decoded_msg, _) = Message::decode(&encoded_msg).unwrap();
+            match channel
+                .recv
+                .as_mut()
+                .unwrap()
+                .open(&mut *vault, &h, &m.message_body)
+            {
+                Ok(encoded_msg) => encoded_msg,
+                // drop it, an attacker on the path shouldn't be able to take
+                // the channel down by replaying or forging frames
+                Err(e) => {
+                    if let ChannelErrorKind::Replayed = e.kind() {
+                        self.stats.replays_rejected += 1;
+                        println!("dropped replayed message on channel");
+                    } else {
+                        self.stats.payloads_rejected += 1;
+                        println!("dropped payload that failed to open: {:?}", e);
+                    }
+                    return Ok(());
+                }
+            };
+        let mut decoded_msg = match Message::decode(&encoded_msg) {
+            Ok((decoded_msg, _)) => decoded_msg,
+            Err(_) => {
+                self.stats.payloads_rejected += 1;
+                println!("dropped payload that failed to decode");
+                return Ok(());
+            }
+        };
+        self.stats.messages_received += 1;
+        // the peer has finished the handshake, it won't need ours again
+        channel.handshake = None;
+        if channel.worker.is_none() {
+            // the responder learns which worker the channel serves from the
+            // first message through it
+            if let Some(to) = decoded_msg.onward_route.addresses.first() {
+                if matches!(to.a_type, AddressType::Worker) {
+                    channel.worker = Some(to.clone());
+                }
+            }
+        }
         decoded_msg.return_route.addresses.insert(
             0,
             RouterAddress::from_address(channel.as_cleartext_address()).unwrap(),
@@ -318,17 +705,276 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
         Ok(())
     }
 
-    fn handle_m1_recv(&self, channel: Arc<Mutex<Channel>>, m: Message) -> Result<(), ChannelError> {
+    /// Feed a handshake message to the channel's key exchange. A repeat of the
+    /// last one is answered by resending our reply, and a message the key
+    /// exchange rejects abandons the channel rather than stopping the manager.
+    fn handle_handshake_recv(
+        &mut self,
+        channel: Arc<Mutex<Channel>>,
+        address: &str,
+        m: Message,
+    ) -> Result<(), ChannelError> {
+        let resend = {
+            let mut channel = channel.lock().unwrap();
+            let established = channel.completed_key_exchange.is_some();
+            match channel.handshake.as_mut() {
+                Some(h) if h.is_duplicate(&m.message_body) => Some(h.last_sent()),
+                Some(h) if !established => {
+                    h.received(&m.message_body);
+                    None
+                }
+                _ => {
+                    println!("dropped handshake message for established channel");
+                    return Ok(());
+                }
+            }
+        };
+        if let Some(resend) = resend {
+            if let Some(resend) = resend {
+                self.router_tx
+                    .send(Router(RouterCommand::SendMessage(resend)))?;
+            }
+            return Ok(());
+        }
+
+        let result = match m.message_type {
+            MessageType::KeyAgreementM1 => self.handle_m1_recv(channel.clone(), m),
+            MessageType::KeyAgreementM2 => self.handle_m2_recv(channel.clone(), m),
+            _ => self.handle_m3_recv(channel.clone(), m),
+        };
+        if let Err(e) = result {
+            if let ChannelErrorKind::PeerRejected = e.kind() {
+                println!("rejected untrusted peer on channel {}", address);
+                self.reject_peer(address)?;
+            } else if Self::awaits_enrollment(&channel.lock().unwrap()) {
+                // anyone can send to a published address; a bad enrollment
+                // mustn't cost the bundle its channel
+                println!("dropped bad enrollment on channel {}: {:?}", address, e);
+                self.stats.handshakes_failed += 1;
+            } else {
+                println!("handshake on channel {} failed: {:?}", address, e);
+                self.fail_handshake(address)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the channel published a bundle that nobody has enrolled with
+    fn awaits_enrollment(channel: &Channel) -> bool {
+        channel.published && !channel.agreement.is_complete()
+    }
+
+    /// Refuses the channel unless the trust policy accepts the key the peer
+    /// proved it holds and the payload it attached to the handshake
+    fn check_trust(&self, channel: &Channel) -> Result<(), ChannelError> {
+        let key = match channel.completed_key_exchange.as_ref() {
+            Some(kex) => kex.remote_static_public_key,
+            None => match channel.agreement.remote_static_public_key() {
+                Some(key) => key,
+                None => return Err(ChannelErrorKind::State.into()),
+            },
+        };
+        if self.trust_policy.accepts(&key, &channel.remote_payload) {
+            Ok(())
+        } else {
+            Err(ChannelErrorKind::PeerRejected.into())
+        }
+    }
+
+    /// A close from the peer must decrypt under the channel key, so that
+    /// nobody else can tear the channel down. Anything else is dropped.
+    fn handle_close_recv(
+        &mut self,
+        channel: Arc<Mutex<Channel>>,
+        m: Message,
+    ) -> Result<(), ChannelError> {
+        let address = {
+            let mut channel = channel.lock().unwrap();
+            let h = match channel.completed_key_exchange.as_ref() {
+                Some(kex) => kex.h,
+                None => {
+                    println!("dropped close for channel still in key exchange");
+                    return Ok(());
+                }
+            };
+            let mut vault = self.vault.lock().unwrap();
+            if let Err(e) = channel
+                .recv
+                .as_mut()
+                .unwrap()
+                .open(&mut *vault, &h, &m.message_body)
+            {
+                println!("dropped unauthenticated channel close: {:?}", e);
+                return Ok(());
+            }
+            channel.as_cleartext_address().as_string()
+        };
+        self.close_channel(&address, false)
+    }
+
+    /// Tear down the channel filed under either of its addresses: tell the
+    /// peer if asked to, tell the worker bound to the channel, and destroy
+    /// the channel's secrets. Closing an unknown channel does nothing.
+    fn close_channel(&mut self, address: &str, notify_peer: bool) -> Result<(), ChannelError> {
+        let channel = match self.take_channel(address) {
+            Some(channel) => channel,
+            None => {
+                println!("close for unknown channel {}", address);
+                return Ok(());
+            }
+        };
+        let mut channel = channel.lock().unwrap();
+
+        if notify_peer {
+            self.send_close(&mut channel)?;
+        }
+        self.bounce_queued(&mut channel)?;
+        self.notify_worker(&channel, MessageType::ChannelClose)?;
+        self.destroy_secrets(&mut channel)
+    }
+
+    /// Close a channel to a peer the trust policy refused: the peer is told
+    /// the channel is closed, and the worker waiting on it that the handshake
+    /// failed
+    fn reject_peer(&mut self, address: &str) -> Result<(), ChannelError> {
+        let channel = match self.take_channel(address) {
+            Some(channel) => channel,
+            None => return Ok(()),
+        };
+        let mut channel = channel.lock().unwrap();
+        self.stats.peers_rejected += 1;
+        self.send_close(&mut channel)?;
+        self.bounce_queued(&mut channel)?;
+        self.notify_worker(&channel, MessageType::HandshakeFailed)?;
+        self.destroy_secrets(&mut channel)
+    }
+
+    /// Tell the peer of an established channel that it is closed
+    fn send_close(&self, channel: &mut Channel) -> Result<(), ChannelError> {
+        let kex = match channel.completed_key_exchange {
+            Some(kex) => kex,
+            None => return Ok(()),
+        };
+        // an empty payload sealed under the channel key proves the close came
+        // from this end
+        let mut vault = self.vault.lock().unwrap();
+        let sealed =
+            channel
+                .send
+                .as_mut()
+                .unwrap()
+                .seal(&mut *vault, &self.rekey_policy, &kex.h, &[]);
+        std::mem::drop(vault);
+        match sealed {
+            Ok(body) => {
+                let m = Message {
+                    onward_route: channel.route.clone(),
+                    return_route: Route {
+                        addresses: vec![RouterAddress::from_address(
+                            channel.as_ciphertext_address(),
+                        )
+                        .unwrap()],
+                    },
+                    message_type: MessageType::ChannelClose,
+                    message_body: body,
+                };
+                self.router_tx.send(Router(RouterCommand::SendMessage(m)))?;
+            }
+            Err(e) => println!("couldn't tell peer channel is closing: {:?}", e),
+        }
+        Ok(())
+    }
+
+    /// Give up on a channel whose key exchange didn't finish: tell the worker
+    /// waiting on it and destroy whatever the exchange had created
+    fn fail_handshake(&mut self, address: &str) -> Result<(), ChannelError> {
+        let channel = match self.take_channel(address) {
+            Some(channel) => channel,
+            None => return Ok(()),
+        };
+        let mut channel = channel.lock().unwrap();
+        self.stats.handshakes_failed += 1;
+        self.bounce_queued(&mut channel)?;
+        self.notify_worker(&channel, MessageType::HandshakeFailed)?;
+        self.destroy_secrets(&mut channel)
+    }
+
+    /// Remove the channel filed under either of its addresses
+    fn take_channel(&mut self, address: &str) -> Option<Arc<Mutex<Channel>>> {
+        let channel = self.channels.get(address)?.clone();
+        {
+            let c = channel.lock().unwrap();
+            self.channels.remove(&c.as_cleartext_address().as_string());
+            self.channels.remove(&c.as_ciphertext_address().as_string());
+        }
+        Some(channel)
+    }
+
+    /// Tell the worker bound to the channel, if there is one, what became of
+    /// it. The channel's cleartext address is the return route.
+    fn notify_worker(
+        &self,
+        channel: &Channel,
+        message_type: MessageType,
+    ) -> Result<(), ChannelError> {
+        if let Some(worker) = channel.worker.clone() {
+            let m = Message {
+                onward_route: Route {
+                    addresses: vec![worker],
+                },
+                return_route: Route {
+                    addresses: vec![
+                        RouterAddress::from_address(channel.as_cleartext_address()).unwrap()
+                    ],
+                },
+                message_type,
+                message_body: vec![],
+            };
+            self.router_tx
+                .send(Router(RouterCommand::ReceiveMessage(m)))?;
+        }
+        Ok(())
+    }
+
+    /// Remove every key the channel holds in the vault
+    fn destroy_secrets(&self, channel: &mut Channel) -> Result<(), ChannelError> {
+        // the key exchange takes the vault lock itself
+        channel.agreement.destroy()?;
+        let mut vault = self.vault.lock().unwrap();
+        for cipher in channel.send.take().into_iter().chain(channel.recv.take()) {
+            cipher.destroy(&mut *vault)?;
+        }
+        if let Some(kex) = channel.completed_key_exchange.as_ref() {
+            vault.secret_destroy(kex.exporter_secret)?;
+        }
+        Ok(())
+    }
+
+    fn handle_m1_recv(
+        &mut self,
+        channel: Arc<Mutex<Channel>>,
+        m: Message,
+    ) -> Result<(), ChannelError> {
         let channel = &mut *channel.lock().unwrap();
 
-        // send cleartext channel address as payload
+        // the initiator's payload, if it sent it as early data
+        channel.remote_payload = channel.agreement.process(&m.message_body)?;
+        if channel.agreement.is_complete() {
+            // an initiator enrolled with the bundle this channel published,
+            // there is nothing to answer
+            channel.handshake = None;
+            return self.responder_established(channel, m.return_route);
+        }
+
+        // send cleartext channel address as payload, followed by ours
         let cleartext_router_addr =
             RouterAddress::from_address(channel.as_cleartext_address()).unwrap();
         let mut cleartext_addr_encoded = vec![];
         RouterAddress::encode(&cleartext_router_addr, &mut cleartext_addr_encoded).unwrap();
-
-        channel.agreement.process(&m.message_body)?;
+        cleartext_addr_encoded.append(&mut channel.payload.take().unwrap_or_default());
         let mut m2 = channel.agreement.process(&cleartext_addr_encoded)?;
+        // two message patterns like IK finish with M2
+        let complete = channel.agreement.is_complete();
 
         let new_m = Message {
             onward_route: m.return_route.clone(),
@@ -340,43 +986,71 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
             message_type: MessageType::KeyAgreementM2,
             message_body: m2,
         };
+        if let Some(h) = channel.handshake.as_mut() {
+            if complete {
+                // nothing answers the last M2; it is only resent if M1
+                // arrives again, until the first payload shows it got there
+                h.sent_last(new_m.clone());
+            } else {
+                h.sent(new_m.clone(), &self.handshake_policy);
+            }
+        }
         self.router_tx
             .send(Router(RouterCommand::SendMessage(new_m)))
             .unwrap();
+        if complete {
+            return self.responder_established(channel, m.return_route);
+        }
         Ok(())
     }
 
-    fn handle_m2_recv(&self, channel: Arc<Mutex<Channel>>, m: Message) -> Result<(), ChannelError> {
+    fn handle_m2_recv(
+        &mut self,
+        channel: Arc<Mutex<Channel>>,
+        m: Message,
+    ) -> Result<(), ChannelError> {
         let mut channel = &mut *channel.lock().unwrap();
         let return_route = m.return_route.clone();
-        let channel_cleartext_addr_encoded = channel.agreement.process(&m.message_body)?;
-        let m3 = channel.agreement.process(&[])?;
-        let m = Message {
-            onward_route: return_route.clone(),
-            return_route: Route {
-                addresses: vec![m.onward_route.addresses[0].clone()],
-            },
-            message_type: MessageType::KeyAgreementM3,
-            message_body: m3,
-        };
-        self.router_tx
-            .send(Router(RouterCommand::SendMessage(m)))
-            .unwrap();
-        channel.completed_key_exchange = Some(channel.agreement.finalize()?);
+        let m2_payload = channel.agreement.process(&m.message_body)?;
+        let (remote_address, remote_payload) = decode_channel_address(&m2_payload)?;
+        channel.remote_payload = remote_payload.to_vec();
+        if channel.agreement.is_complete() {
+            // a two message pattern, the responder is already done
+            channel.handshake = None;
+        } else {
+            // M3 carries our static key and payload, so the peer has to be
+            // trusted before it is written
+            self.check_trust(channel)?;
+            // our payload, unless it went as early data in M1
+            let m3 = channel
+                .agreement
+                .process(&channel.payload.take().unwrap_or_default())?;
+            let m = Message {
+                onward_route: return_route.clone(),
+                return_route: Route {
+                    addresses: vec![m.onward_route.addresses[0].clone()],
+                },
+                message_type: MessageType::KeyAgreementM3,
+                message_body: m3,
+            };
+            if let Some(h) = channel.handshake.as_mut() {
+                // nothing answers M3; it is only resent if M2 arrives again
+                h.sent_last(m.clone());
+            }
+            self.router_tx
+                .send(Router(RouterCommand::SendMessage(m)))
+                .unwrap();
+        }
+        let completed = channel.agreement.finalize()?;
+        channel.complete(completed);
         channel.route = return_route;
+        self.check_trust(channel)?;
 
         // let the worker know the key exchange is done
         let pending = channel.pending.clone();
         match pending {
             Some(mut p) => {
-                // send the remote public key and remote channel cleartext address as the message body
-                let static_public_key = channel
-                    .completed_key_exchange
-                    .unwrap()
-                    .remote_static_public_key;
-                p.message_body = channel_cleartext_addr_encoded;
-                p.message_body
-                    .append(&mut static_public_key.as_ref().to_vec());
+                p.message_body = channel.established(Some(remote_address)).to_bytes();
                 self.router_tx
                     .send(Router(RouterCommand::ReceiveMessage(p)))
                     .unwrap();
@@ -385,77 +1059,112 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
                 return Err(ChannelErrorKind::NotImplemented.into());
             }
         }
-        Ok(())
+        self.flush_queued(channel)
     }
 
-    fn handle_m3_recv(&self, channel: Arc<Mutex<Channel>>, m: Message) -> Result<(), ChannelError> {
+    fn handle_m3_recv(
+        &mut self,
+        channel: Arc<Mutex<Channel>>,
+        m: Message,
+    ) -> Result<(), ChannelError> {
         let mut channel = channel.lock().unwrap();
         let return_route = m.return_route.clone();
-        // For now ignore anything returned from M3
-        let _ = channel.agreement.process(&m.message_body)?;
+        // the initiator's payload, unless it came as early data with M1
+        let payload = channel.agreement.process(&m.message_body)?;
+        if !payload.is_empty() {
+            channel.remote_payload = payload;
+        }
         debug_assert!(channel.agreement.is_complete());
         if channel.completed_key_exchange.is_none() {
-            // key agreement has finished, now can process any pending messages
-            let pending = channel.pending.clone();
-            channel.completed_key_exchange = Some(channel.agreement.finalize()?);
-            channel.route = return_route;
-            match pending {
-                Some(mut p) => {
-                    p.return_route = channel.route.clone();
-                    p.return_route.addresses.insert(
-                        0,
-                        RouterAddress::from_address(channel.as_cleartext_address()).unwrap(),
-                    );
-                    // add the channel's remote public key as the message body
-                    p.message_body = channel
-                        .completed_key_exchange
-                        .unwrap()
-                        .remote_static_public_key
-                        .as_ref()
-                        .to_vec();
+            channel.handshake = None;
+            self.responder_established(&mut channel, return_route)?;
+        }
+        Ok(())
+    }
 
-                    self.router_tx
-                        .send(Router(RouterCommand::ReceiveMessage(p)))
-                        .unwrap();
-                    channel.pending = None;
-                }
-                _ => {
-                    let mut return_route = channel.route.clone();
-                    return_route.addresses.insert(
-                        0,
-                        RouterAddress::from_address(channel.as_cleartext_address()).unwrap(),
-                    );
-                    let new_m = Message {
-                        onward_route: Route {
-                            addresses: vec![RouterAddress::worker_router_address_from_str(
-                                CHANNEL_ZERO,
-                            )
-                            .unwrap()],
-                        },
-                        return_route,
-                        message_type: MessageType::None,
-                        message_body: vec![],
-                    };
-                    self.router_tx
-                        .send(Router(RouterCommand::ReceiveMessage(new_m)))
-                        .unwrap();
-                }
+    /// The responder's key exchange has finished: check the peer, let the
+    /// worker bound to the channel (or the one at CHANNEL_ZERO) know, and
+    /// send anything held in the meantime
+    fn responder_established(
+        &mut self,
+        channel: &mut Channel,
+        return_route: Route,
+    ) -> Result<(), ChannelError> {
+        // key agreement has finished, now can process any pending messages
+        let pending = channel.pending.clone();
+        let completed = channel.agreement.finalize()?;
+        channel.complete(completed);
+        channel.route = return_route;
+        self.check_trust(channel)?;
+        match pending {
+            Some(mut p) => {
+                p.return_route = channel.route.clone();
+                p.return_route.addresses.insert(
+                    0,
+                    RouterAddress::from_address(channel.as_cleartext_address()).unwrap(),
+                );
+                p.message_body = channel.established(None).to_bytes();
+
+                self.router_tx
+                    .send(Router(RouterCommand::ReceiveMessage(p)))
+                    .unwrap();
+                channel.pending = None;
+            }
+            _ => {
+                let mut return_route = channel.route.clone();
+                return_route.addresses.insert(
+                    0,
+                    RouterAddress::from_address(channel.as_cleartext_address()).unwrap(),
+                );
+                let new_m = Message {
+                    onward_route: Route {
+                        addresses: vec![RouterAddress::worker_router_address_from_str(
+                            CHANNEL_ZERO,
+                        )
+                        .unwrap()],
+                    },
+                    return_route,
+                    message_type: MessageType::None,
+                    message_body: channel.established(None).to_bytes(),
+                };
+                self.router_tx
+                    .send(Router(RouterCommand::ReceiveMessage(new_m)))
+                    .unwrap();
             }
         }
-        Ok(())
+        self.flush_queued(channel)
     }
 
     /// Initiates key exchange to create new secure channel over supplied route.
     /// Upon completion of key exchange, a message is sent to return_address with
-    /// MessageType::None and the channel address in the return route.
+    /// MessageType::None and the channel address in the return route. Before
+    /// that, MessageType::ChannelOpening gives the worker the same address so
+    /// it can start sending; its messages are queued until the channel is up.
+    /// If too many handshakes are already in progress, the worker is sent
+    /// MessageType::HandshakeFailed instead and no channel is created.
     fn initiate_new_channel(
         &mut self,
         route: Route,
         return_address: Address,
-    ) -> Result<Address, ChannelError> {
+    ) -> Result<Option<Address>, ChannelError> {
         // Remember who to notify when the channel is secure
         let pending_return = RouterAddress::from_address(return_address).unwrap();
 
+        if self.pending_handshakes() >= self.handshake_policy.max_pending {
+            self.stats.handshakes_refused += 1;
+            let m = Message {
+                onward_route: Route {
+                    addresses: vec![pending_return],
+                },
+                return_route: Route { addresses: vec![] },
+                message_type: MessageType::HandshakeFailed,
+                message_body: vec![],
+            };
+            self.router_tx
+                .send(Router(RouterCommand::ReceiveMessage(m)))?;
+            return Ok(None);
+        }
+
         // Generate 2 channel addresses, one each for clear and cipher text
         let mut clear_address = String::from(CHANNEL_ZERO);
         let mut cipher_address = String::from(CHANNEL_ZERO);
@@ -464,8 +1173,9 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
             cipher_address = cipher;
         }
 
-        let channel = self.channels.get_mut(&cipher_address).unwrap();
-        let mut channel = &mut *channel.lock().unwrap();
+        let channel = self.channels.get(&cipher_address).unwrap().clone();
+        let mut channel = channel.lock().unwrap();
+        channel.worker = Some(pending_return.clone());
         channel.pending = Some(Message {
             onward_route: Route {
                 addresses: vec![pending_return],
@@ -478,7 +1188,13 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
             message_type: MessageType::None,
             message_body: vec![],
         });
-        let ka_m1 = channel.agreement.process(&[])?;
+        // early data, if M1 can keep it secret
+        let payload = if channel.agreement.payload_is_encrypted() {
+            channel.payload.take().unwrap_or_default()
+        } else {
+            vec![]
+        };
+        let ka_m1 = channel.agreement.process(&payload)?;
         let m = Message {
             onward_route: route,
             return_route: Route {
@@ -489,26 +1205,156 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
             message_type: MessageType::KeyAgreementM1,
             message_body: ka_m1,
         };
+        if let Some(h) = channel.handshake.as_mut() {
+            h.sent(m.clone(), &self.handshake_policy);
+        }
         self.router_tx.send(Router(RouterCommand::SendMessage(m)))?;
-        Ok(Address::channel_address_from_string(&clear_address).unwrap())
+
+        // the worker may send on the channel right away; messages are held
+        // until it is established
+        self.notify_worker(&channel, MessageType::ChannelOpening)?;
+        Ok(Some(
+            Address::channel_address_from_string(&clear_address).unwrap(),
+        ))
+    }
+
+    /// Create a responder channel that waits for an initiator holding its
+    /// bundle, and return what to publish. Only key exchanges whose
+    /// responder speaks first, like X3DH, produce one. The channel waits
+    /// without a deadline until an initiator enrolls or it is closed.
+    pub fn publish_bundle(&mut self) -> Result<PublishedBundle, ChannelError> {
+        let cipher_address = match self.create_channel(ExchangerRole::Responder) {
+            Some((_clear, cipher)) => cipher,
+            None => return Err(ChannelErrorKind::State.into()),
+        };
+        let channel = self.channels.get(&cipher_address).unwrap().clone();
+        let bundle = {
+            let mut channel = channel.lock().unwrap();
+            channel.published = true;
+            channel.agreement.process(&[])
+        };
+        match bundle {
+            Ok(bundle) => Ok(PublishedBundle {
+                address: RouterAddress::channel_router_address_from_str(&cipher_address).unwrap(),
+                bundle,
+            }),
+            Err(e) => {
+                let channel = self.take_channel(&cipher_address).unwrap();
+                self.destroy_secrets(&mut channel.lock().unwrap())?;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Establishes a channel from a responder's published bundle. The key
+    /// exchange finishes here at once and everything it produced goes to
+    /// the responder's waiting channel as a single M1, so nothing has to
+    /// come back before the worker can send. The worker at return_address
+    /// is sent MessageType::None straight away, as when an interactive
+    /// handshake completes; if the bundle is bad or its key untrusted it
+    /// is sent MessageType::HandshakeFailed instead.
+    fn initiate_with_bundle(
+        &mut self,
+        route: Route,
+        return_address: Address,
+        published: &[u8],
+    ) -> Result<Option<Address>, ChannelError> {
+        let worker = RouterAddress::from_address(return_address).unwrap();
+        let (clear_address, cipher_address) = match self.create_channel(ExchangerRole::Initiator) {
+            Some(addresses) => addresses,
+            None => return Err(ChannelErrorKind::State.into()),
+        };
+        let channel = self.channels.get(&cipher_address).unwrap().clone();
+        let mut channel = channel.lock().unwrap();
+        channel.worker = Some(worker);
+
+        if let Err(e) = self.enroll(&mut channel, route, published) {
+            if let ChannelErrorKind::PeerRejected = e.kind() {
+                self.stats.peers_rejected += 1;
+            }
+            println!("couldn't establish channel from bundle: {:?}", e);
+            std::mem::drop(channel);
+            self.fail_handshake(&cipher_address)?;
+            return Ok(None);
+        }
+        Ok(Some(
+            Address::channel_address_from_string(&clear_address).unwrap(),
+        ))
+    }
+
+    /// Run the initiator's whole key exchange against a published bundle,
+    /// send the result and tell the worker the channel is up
+    fn enroll(
+        &mut self,
+        channel: &mut Channel,
+        mut route: Route,
+        published: &[u8],
+    ) -> Result<(), ChannelError> {
+        let published = PublishedBundle::from_bytes(published)?;
+        let mut enrollment = channel.agreement.process(&[])?;
+        enrollment.append(&mut channel.agreement.process(&published.bundle)?);
+        if !channel.agreement.is_complete() {
+            return Err(ChannelErrorKind::State.into());
+        }
+        let completed = channel.agreement.finalize()?;
+        channel.complete(completed);
+        channel.handshake = None;
+        self.check_trust(channel)?;
+
+        route.addresses.push(published.address.clone());
+        channel.route = route.clone();
+        let m = Message {
+            onward_route: route,
+            return_route: Route {
+                addresses: vec![
+                    RouterAddress::from_address(channel.as_ciphertext_address()).unwrap()
+                ],
+            },
+            message_type: MessageType::KeyAgreementM1,
+            message_body: enrollment,
+        };
+        self.router_tx.send(Router(RouterCommand::SendMessage(m)))?;
+
+        // as after M2, without a payload
+        let body = channel.established(Some(published.address)).to_bytes();
+        let m = Message {
+            onward_route: Route {
+                addresses: vec![channel.worker.clone().unwrap()],
+            },
+            return_route: Route {
+                addresses: vec![
+                    RouterAddress::from_address(channel.as_cleartext_address()).unwrap()
+                ],
+            },
+            message_type: MessageType::None,
+            message_body: body,
+        };
+        self.router_tx
+            .send(Router(RouterCommand::ReceiveMessage(m)))?;
+        Ok(())
     }
 
     fn create_channel(&mut self, role: ExchangerRole) -> Option<(String, String)> {
         let mut rng = thread_rng();
         let clear_u32 = rng.gen::<u32>();
         let cipher_u32 = rng.gen::<u32>();
-        let channel = match role {
-            ExchangerRole::Initiator => Arc::new(Mutex::new(Channel::new(
-                clear_u32,
-                cipher_u32,
-                Box::new(self.new_key_exchanger.initiator(self.init_key_ctx)),
-            ))),
-            ExchangerRole::Responder => Arc::new(Mutex::new(Channel::new(
-                clear_u32,
-                cipher_u32,
-                Box::new(self.new_key_exchanger.responder(self.resp_key_ctx)),
-            ))),
+        let mut agreement: Box<dyn KeyExchanger + Send> = match role {
+            ExchangerRole::Initiator => {
+                Box::new(self.new_key_exchanger.initiator(self.init_key_ctx))
+            }
+            ExchangerRole::Responder => {
+                Box::new(self.new_key_exchanger.responder(self.resp_key_ctx))
+            }
         };
+        if let Some(psk) = self.psk {
+            // set_psk has checked the exchange takes one
+            if agreement.set_psk(psk).is_err() {
+                return None;
+            }
+        }
+        let mut channel = Channel::new(clear_u32, cipher_u32, agreement);
+        channel.payload = Some(self.handshake_payload.clone());
+        let channel = Arc::new(Mutex::new(channel));
         let clear_address = Address::ChannelAddress(clear_u32.to_le_bytes().to_vec());
         let cipher_address = Address::ChannelAddress(cipher_u32.to_le_bytes().to_vec());
         self.channels
@@ -523,18 +1369,26 @@ struct Channel {
     remote_public_key: Option<PublicKey>,
     cleartext_address: u32,
     ciphertext_address: u32,
-    agreement: Box<dyn KeyExchanger>,
-    nonce: u16,
+    agreement: Box<dyn KeyExchanger + Send>,
+    send: Option<CipherState>,
+    recv: Option<CipherState>,
     route: Route,
     pending: Option<Message>,
+    worker: Option<RouterAddress>,
+    handshake: Option<Handshake>,
+    queued: VecDeque<Message>,
+    payload: Option<Vec<u8>>,
+    remote_payload: Vec<u8>,
+    /// Waits for an initiator holding a bundle it published
+    published: bool,
 }
 
 impl std::fmt::Debug for Channel {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "Channel {{ completed_key_exchange: {:?}, id: {:?}, nonce: {:?}, agreement }}",
-            self.completed_key_exchange, self.cleartext_address, self.nonce
+            "Channel {{ completed_key_exchange: {:?}, id: {:?}, send: {:?}, recv: {:?}, agreement }}",
+            self.completed_key_exchange, self.cleartext_address, self.send, self.recv
         )
     }
 }
@@ -545,17 +1399,24 @@ impl Channel {
     pub fn new(
         cleartext_address: u32,
         ciphertext_address: u32,
-        agreement: Box<dyn KeyExchanger>,
+        agreement: Box<dyn KeyExchanger + Send>,
     ) -> Self {
         Self {
             cleartext_address,
             ciphertext_address,
             agreement,
             completed_key_exchange: None,
-            nonce: 0,
+            send: None,
+            recv: None,
             route: Route { addresses: vec![] },
             pending: None,
             remote_public_key: None,
+            worker: None,
+            handshake: Some(Handshake::default()),
+            queued: VecDeque::new(),
+            payload: None,
+            remote_payload: vec![],
+            published: false,
         }
     }
 
@@ -567,25 +1428,55 @@ impl Channel {
         Address::ChannelAddress(self.ciphertext_address.to_le_bytes().to_vec())
     }
 
-    pub fn nonce_16_to_96(n16: u16) -> [u8; 12] {
-        // the nonce value is an le u16, whereas the nonce
-        // byte array is 10 bytes of 0's follow by the be
-        // representation of the nonce
-        let mut n: [u8; 12] = [0; 12];
-        let b = n16.to_be_bytes();
-        n[10] = b[0];
-        n[11] = b[1];
-        n
+    /// Record the finished key exchange and start counting nonces in each
+    /// direction
+    pub fn complete(&mut self, completed: CompletedKeyExchange) {
+        self.send = Some(CipherState::new(completed.encrypt_key));
+        self.recv = Some(CipherState::new(completed.decrypt_key));
+        self.completed_key_exchange = Some(completed);
     }
 
-    pub fn nonce_from_96(n: &[u8; 12]) -> u16 {
-        let bytes: [u8; 2] = [n[10], n[11]];
-        u16::from_be_bytes(bytes)
+    /// The notice for the worker once the key exchange has completed
+    fn established(&self, remote_address: Option<RouterAddress>) -> Established {
+        Established {
+            remote_address,
+            remote_public_key: self
+                .completed_key_exchange
+                .map(|kex| kex.remote_static_public_key.as_ref().to_vec())
+                .unwrap_or_default(),
+            channel_binding: self
+                .completed_key_exchange
+                .map(|kex| kex.h)
+                .unwrap_or_default(),
+            payload: self.remote_payload.clone(),
+        }
     }
 }
 
 /// Represents the errors that occur within a channel
 pub mod error;
+
+/// Per-direction channel keys, nonces and rekeying
+pub mod cipher;
+
+/// Anti-replay window for received nonces
+pub mod replay;
+
+/// Handshake deadlines, retransmission and limits
+pub mod handshake;
+
+/// Deciding which peers to establish channels with
+pub mod trust;
+
+/// Prekey bundles published for establishing channels without a round trip
+pub mod bundle;
+
+/// What a worker is told when its channel is established
+pub mod established;
+
+/// An async Channel Manager for nodes embedded in a tokio application
+#[cfg(feature = "async")]
+pub mod async_manager;
 // #[cfg(test)]
 // mod tests {
 //     use super::*;
@@ -685,3 +1576,695 @@ pub mod error;
 //         assert!(res.unwrap());
 //     }
 // }
+
+#[cfg(test)]
+mod test_support {
+    use super::*;
+    use ockam_kex::xx::{XXInitiator, XXNewKeyExchanger, XXResponder};
+    use ockam_kex::CipherSuite;
+    use ockam_vault::software::DefaultVault;
+    use std::sync::mpsc::sync_channel;
+
+    pub(crate) type XXChannelManager = ChannelManager<XXInitiator, XXResponder, XXNewKeyExchanger>;
+
+    pub(crate) fn vault() -> Arc<Mutex<dyn DynVault + Send>> {
+        Arc::new(Mutex::new(DefaultVault::default()))
+    }
+
+    /// A Channel Manager on its own queues, and the queue of what it asks
+    /// the router to do
+    pub(crate) fn manager<
+        I: KeyExchanger + Send,
+        R: KeyExchanger + Send,
+        E: NewKeyExchanger<I, R>,
+    >(
+        vault: Arc<Mutex<dyn DynVault + Send>>,
+        new_key_exchanger: E,
+        resp_key_ctx: Option<SecretKeyContext>,
+    ) -> (ChannelManager<I, R, E>, Receiver<OckamCommand>) {
+        let (router_tx, router_rx) = sync_channel(64);
+        let (tx, rx) = sync_channel(64);
+        let manager = ChannelManager::new(
+            rx,
+            tx,
+            router_tx,
+            vault,
+            new_key_exchanger,
+            resp_key_ctx,
+            None,
+        )
+        .unwrap();
+        (manager, router_rx)
+    }
+
+    /// An XX Channel Manager with a vault of its own
+    pub(crate) fn xx_manager() -> (XXChannelManager, Receiver<OckamCommand>) {
+        let vault = vault();
+        let new_key_exchanger = XXNewKeyExchanger::new(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault.clone(),
+            vault.clone(),
+        );
+        manager(vault, new_key_exchanger, None)
+    }
+
+    /// The worker the tests' channels are bound to
+    pub(crate) fn worker() -> RouterAddress {
+        RouterAddress::worker_router_address_from_str("01020304").unwrap()
+    }
+
+    /// Start a channel for `worker()`, returning its cleartext address
+    pub(crate) fn initiate<
+        I: KeyExchanger + Send,
+        R: KeyExchanger + Send,
+        E: NewKeyExchanger<I, R>,
+    >(
+        manager: &mut ChannelManager<I, R, E>,
+    ) -> Address {
+        let route = Route {
+            addresses: vec![RouterAddress::channel_router_address_from_str(CHANNEL_ZERO).unwrap()],
+        };
+        manager
+            .initiate_new_channel(route, worker().address)
+            .unwrap()
+            .unwrap()
+    }
+
+    /// Everything queued for the router: the messages sent towards peers,
+    /// and those delivered to workers
+    pub(crate) fn routed(router_rx: &Receiver<OckamCommand>) -> (Vec<Message>, Vec<Message>) {
+        let mut sent = vec![];
+        let mut received = vec![];
+        for c in router_rx.try_iter() {
+            match c {
+                Router(RouterCommand::SendMessage(m)) => sent.push(m),
+                Router(RouterCommand::ReceiveMessage(m)) => received.push(m),
+                _ => {}
+            }
+        }
+        (sent, received)
+    }
+
+    /// The messages queued for the router to send towards peers
+    pub(crate) fn sent(router_rx: &Receiver<OckamCommand>) -> Vec<Message> {
+        routed(router_rx).0
+    }
+
+    /// What workers were told of channels being established
+    pub(crate) fn established(received: &[Message]) -> Vec<Established> {
+        received
+            .iter()
+            .filter(|m| matches!(m.message_type, MessageType::None))
+            .map(|m| Established::from_bytes(&m.message_body).unwrap())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod queue_tests {
+    use super::test_support::*;
+    use super::*;
+
+    #[test]
+    fn queues_until_established_and_bounces_the_rest() {
+        let (mut manager, router_rx) = xx_manager();
+        manager.set_handshake_policy(HandshakePolicy {
+            max_queued: 2,
+            ..HandshakePolicy::default()
+        });
+
+        let worker = worker();
+        let clear = initiate(&mut manager);
+        for i in 0..3u8 {
+            manager
+                .handle_send(Message {
+                    onward_route: Route {
+                        addresses: vec![RouterAddress::from_address(clear.clone()).unwrap()],
+                    },
+                    return_route: Route {
+                        addresses: vec![worker.clone()],
+                    },
+                    message_type: MessageType::Payload,
+                    message_body: vec![i],
+                })
+                .unwrap();
+        }
+        manager.fail_handshake(&clear.as_string()).unwrap();
+
+        let (_, to_worker) = routed(&router_rx);
+        let types: Vec<u8> = to_worker.iter().map(|m| m.message_type as u8).collect();
+        let bodies: Vec<Vec<u8>> = to_worker.into_iter().map(|m| m.message_body).collect();
+        // opening notice, the message that didn't fit, then the queue in
+        // order once the handshake is given up
+        assert_eq!(
+            types,
+            vec![
+                MessageType::ChannelOpening as u8,
+                MessageType::SendFailed as u8,
+                MessageType::SendFailed as u8,
+                MessageType::SendFailed as u8,
+                MessageType::HandshakeFailed as u8,
+            ]
+        );
+        assert_eq!(bodies[1..4].to_vec(), vec![vec![2], vec![0], vec![1]]);
+    }
+
+    #[test]
+    fn sends_after_close_are_bounced() {
+        let (mut manager, router_rx) = xx_manager();
+
+        let worker = worker();
+        let clear = initiate(&mut manager);
+        manager.close_channel(&clear.as_string(), true).unwrap();
+        routed(&router_rx);
+
+        let never = RouterAddress::channel_router_address_from_str("0a0b0c0d").unwrap();
+        for to in vec![RouterAddress::from_address(clear).unwrap(), never] {
+            let m = Message {
+                onward_route: Route {
+                    addresses: vec![to.clone()],
+                },
+                return_route: Route {
+                    addresses: vec![worker.clone()],
+                },
+                message_type: MessageType::Payload,
+                message_body: b"late".to_vec(),
+            };
+            assert!(manager.handle_send(m).is_ok());
+            let (_, bounced) = routed(&router_rx);
+            assert_eq!(bounced.len(), 1);
+            assert!(matches!(bounced[0].message_type, MessageType::SendFailed));
+            assert_eq!(bounced[0].onward_route.addresses, vec![worker.clone()]);
+            assert_eq!(bounced[0].return_route.addresses, vec![to]);
+            assert_eq!(bounced[0].message_body, b"late");
+        }
+        assert_eq!(manager.stats().messages_bounced, 2);
+    }
+}
+
+#[cfg(test)]
+mod bundle_tests {
+    use super::test_support::{self, routed, worker};
+    use super::*;
+    use ockam_kex::x3dh::{X3dhInitiator, X3dhNewKeyExchanger, X3dhResponder};
+    use ockam_kex::CipherSuite;
+
+    type X3dhChannelManager = ChannelManager<X3dhInitiator, X3dhResponder, X3dhNewKeyExchanger>;
+
+    fn manager() -> (X3dhChannelManager, Receiver<OckamCommand>) {
+        let vault = test_support::vault();
+        let new_key_exchanger = X3dhNewKeyExchanger::new(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault.clone(),
+            vault.clone(),
+        );
+        test_support::manager(vault, new_key_exchanger, None)
+    }
+
+    #[test]
+    fn establishes_from_published_bundle() {
+        let (mut responder, responder_router) = manager();
+        let (mut initiator, initiator_router) = manager();
+
+        let published = responder.publish_bundle().unwrap();
+        // a published channel isn't waiting on anyone
+        assert_eq!(responder.pending_handshakes(), 0);
+        assert!(responder.next_deadline().is_none());
+
+        let worker = worker();
+        let clear = initiator
+            .initiate_with_bundle(
+                Route { addresses: vec![] },
+                worker.address.clone(),
+                &published.to_bytes(),
+            )
+            .unwrap()
+            .unwrap();
+        initiator
+            .handle_send(Message {
+                onward_route: Route {
+                    addresses: vec![
+                        RouterAddress::from_address(clear).unwrap(),
+                        RouterAddress::worker_router_address_from_str("05060708").unwrap(),
+                    ],
+                },
+                return_route: Route {
+                    addresses: vec![worker.clone()],
+                },
+                message_type: MessageType::Payload,
+                message_body: b"hello".to_vec(),
+            })
+            .unwrap();
+
+        let (sent, received) = routed(&initiator_router);
+        assert_eq!(received.len(), 1);
+        assert!(matches!(received[0].message_type, MessageType::None));
+        assert_eq!(received[0].onward_route.addresses, vec![worker]);
+        assert_eq!(sent.len(), 2);
+        assert!(matches!(sent[0].message_type, MessageType::KeyAgreementM1));
+        assert_eq!(sent[0].onward_route.addresses, vec![published.address]);
+
+        for m in sent {
+            responder.handle_recv(m).unwrap();
+        }
+        let (_, received) = routed(&responder_router);
+        assert!(received
+            .iter()
+            .any(|m| matches!(m.message_type, MessageType::None)));
+        assert!(received
+            .iter()
+            .any(|m| matches!(m.message_type, MessageType::Payload) && m.message_body == b"hello"));
+    }
+
+    #[test]
+    fn published_bundle_survives_bad_enrollment() {
+        let (mut responder, responder_router) = manager();
+        let (mut initiator, initiator_router) = manager();
+
+        let published = responder.publish_bundle().unwrap();
+        let worker = worker();
+        initiator
+            .initiate_with_bundle(
+                Route { addresses: vec![] },
+                worker.address,
+                &published.to_bytes(),
+            )
+            .unwrap()
+            .unwrap();
+        let (sent, _) = routed(&initiator_router);
+        assert!(matches!(sent[0].message_type, MessageType::KeyAgreementM1));
+
+        let mut garbage = sent[0].clone();
+        garbage.message_body = vec![0x5a; garbage.message_body.len()];
+        responder.handle_recv(garbage).unwrap();
+        assert_eq!(responder.stats().handshakes_failed, 1);
+        let (_, received) = routed(&responder_router);
+        assert!(received.is_empty());
+
+        for m in sent {
+            responder.handle_recv(m).unwrap();
+        }
+        let (_, received) = routed(&responder_router);
+        assert!(received
+            .iter()
+            .any(|m| matches!(m.message_type, MessageType::None)));
+    }
+
+    #[test]
+    fn bad_bundle_fails_the_handshake() {
+        let (mut initiator, initiator_router) = manager();
+        let worker = worker();
+        let published = PublishedBundle {
+            address: RouterAddress::channel_router_address_from_str("01020304").unwrap(),
+            bundle: vec![0u8; 16],
+        };
+        let clear = initiator
+            .initiate_with_bundle(
+                Route { addresses: vec![] },
+                worker.address,
+                &published.to_bytes(),
+            )
+            .unwrap();
+        assert!(clear.is_none());
+
+        let (sent, received) = routed(&initiator_router);
+        assert!(sent.is_empty());
+        assert_eq!(received.len(), 1);
+        assert!(matches!(
+            received[0].message_type,
+            MessageType::HandshakeFailed
+        ));
+        assert_eq!(initiator.stats().handshakes_failed, 1);
+    }
+}
+
+#[cfg(test)]
+mod ik_tests {
+    use super::test_support::{self, initiate, sent};
+    use super::*;
+    use ockam_kex::ik::{IKInitiator, IKNewKeyExchanger, IKResponder};
+    use ockam_kex::CipherSuite;
+    use ockam_vault::types::{
+        SecretKeyAttributes, SecretKeyType, SecretPersistenceType, SecretPurposeType,
+    };
+
+    type IKChannelManager = ChannelManager<IKInitiator, IKResponder, IKNewKeyExchanger>;
+
+    #[test]
+    fn establishes_in_one_round_trip() {
+        let vault = test_support::vault();
+        let (secret, public) = {
+            let mut v = vault.lock().unwrap();
+            let secret = v
+                .secret_generate(SecretKeyAttributes {
+                    xtype: SecretKeyType::Curve25519,
+                    purpose: SecretPurposeType::KeyAgreement,
+                    persistence: SecretPersistenceType::Persistent,
+                })
+                .unwrap();
+            (secret, v.secret_public_key_get(secret).unwrap())
+        };
+        let mut managers: Vec<(IKChannelManager, Receiver<OckamCommand>)> = vec![];
+        for (remote, identity) in vec![(Some(public), None), (None, Some(secret))] {
+            let new_key_exchanger = IKNewKeyExchanger::new(
+                CipherSuite::Curve25519AesGcmSha256,
+                vault.clone(),
+                vault.clone(),
+                remote,
+            );
+            managers.push(test_support::manager(
+                vault.clone(),
+                new_key_exchanger,
+                identity,
+            ));
+        }
+        let (mut responder, responder_router) = managers.pop().unwrap();
+        let (mut initiator, initiator_router) = managers.pop().unwrap();
+
+        initiate(&mut initiator);
+        let m1 = sent(&initiator_router);
+        assert_eq!(m1.len(), 1);
+        responder.handle_recv(m1[0].clone()).unwrap();
+
+        // the responder is done as soon as it answers
+        let m2 = sent(&responder_router);
+        assert_eq!(m2.len(), 1);
+        assert!(matches!(m2[0].message_type, MessageType::KeyAgreementM2));
+        assert_eq!(responder.pending_handshakes(), 0);
+
+        // and the initiator once it has the answer, without an M3
+        initiator.handle_recv(m2[0].clone()).unwrap();
+        assert!(sent(&initiator_router).is_empty());
+        assert_eq!(initiator.pending_handshakes(), 0);
+
+        // a repeated M1 gets the same M2 rather than a second channel
+        responder.handle_recv(m1[0].clone()).unwrap();
+        assert_eq!(sent(&responder_router).len(), 1);
+        assert_eq!(responder.stats().channels, 1);
+    }
+}
+
+#[cfg(test)]
+mod psk_tests {
+    use super::test_support::{initiate, sent, xx_manager, XXChannelManager};
+    use super::*;
+    use ockam_vault::types::{
+        SecretKey, SecretKeyAttributes, SecretKeyType, SecretPersistenceType, SecretPurposeType,
+    };
+
+    fn manager(psk: u8) -> (XXChannelManager, Receiver<OckamCommand>) {
+        let (mut manager, router_rx) = xx_manager();
+        let psk = manager
+            .vault
+            .lock()
+            .unwrap()
+            .secret_import(
+                &SecretKey::Buffer(vec![psk; 32]),
+                SecretKeyAttributes {
+                    xtype: SecretKeyType::Buffer(32),
+                    purpose: SecretPurposeType::KeyAgreement,
+                    persistence: SecretPersistenceType::Ephemeral,
+                },
+            )
+            .unwrap();
+        manager.set_psk(psk).unwrap();
+        (manager, router_rx)
+    }
+
+    /// Run M1, M2 and M3 between the two managers
+    fn handshake(
+        initiator: &mut (XXChannelManager, Receiver<OckamCommand>),
+        responder: &mut (XXChannelManager, Receiver<OckamCommand>),
+    ) {
+        initiate(&mut initiator.0);
+        let m1 = sent(&initiator.1);
+        responder.0.handle_recv(m1[0].clone()).unwrap();
+        let m2 = sent(&responder.1);
+        initiator.0.handle_recv(m2[0].clone()).unwrap();
+        let m3 = sent(&initiator.1);
+        responder.0.handle_recv(m3[0].clone()).unwrap();
+    }
+
+    #[test]
+    fn same_psk_establishes() {
+        let mut initiator = manager(1);
+        let mut responder = manager(1);
+        handshake(&mut initiator, &mut responder);
+        assert_eq!(responder.0.pending_handshakes(), 0);
+        assert_eq!(responder.0.stats().channels, 1);
+        assert_eq!(responder.0.stats().handshakes_failed, 0);
+    }
+
+    #[test]
+    fn different_psk_fails_the_handshake() {
+        let mut initiator = manager(1);
+        let mut responder = manager(2);
+        handshake(&mut initiator, &mut responder);
+        assert_eq!(responder.0.stats().channels, 0);
+        assert_eq!(responder.0.stats().handshakes_failed, 1);
+    }
+}
+
+#[cfg(test)]
+mod payload_tests {
+    use super::test_support::{self, initiate, XXChannelManager};
+    use super::*;
+    use ockam_kex::ik::{IKInitiator, IKNewKeyExchanger, IKResponder};
+    use ockam_kex::CipherSuite;
+    use ockam_vault::types::{
+        SecretKeyAttributes, SecretKeyType, SecretPersistenceType, SecretPurposeType,
+    };
+
+    type IKChannelManager = ChannelManager<IKInitiator, IKResponder, IKNewKeyExchanger>;
+
+    /// Only peers presenting this payload are let in
+    struct RequirePayload(&'static [u8]);
+
+    impl TrustPolicy for RequirePayload {
+        fn is_trusted(&self, _key: &PublicKey) -> bool {
+            true
+        }
+
+        fn accepts(&self, _key: &PublicKey, payload: &[u8]) -> bool {
+            payload == self.0
+        }
+    }
+
+    /// The messages sent towards peers, and what workers were told of
+    /// channels being established
+    fn routed(router_rx: &Receiver<OckamCommand>) -> (Vec<Message>, Vec<Established>) {
+        let (sent, received) = test_support::routed(router_rx);
+        (sent, test_support::established(&received))
+    }
+
+    fn xx_manager(payload: &[u8]) -> (XXChannelManager, Receiver<OckamCommand>) {
+        let (mut manager, router_rx) = test_support::xx_manager();
+        manager.set_handshake_payload(payload.to_vec());
+        (manager, router_rx)
+    }
+
+    #[test]
+    fn xx_payloads_reach_both_workers() {
+        let (mut initiator, initiator_router) = xx_manager(b"credential");
+        let (mut responder, responder_router) = xx_manager(b"service=telemetry");
+        initiate(&mut initiator);
+
+        // the first XX message is in the clear, so it carries nothing
+        let (m1, _) = routed(&initiator_router);
+        responder.handle_recv(m1[0].clone()).unwrap();
+        let (m2, _) = routed(&responder_router);
+        initiator.handle_recv(m2[0].clone()).unwrap();
+        let (m3, established) = routed(&initiator_router);
+        assert_eq!(established.len(), 1);
+        assert_eq!(established[0].payload, b"service=telemetry");
+        assert!(established[0].remote_address.is_some());
+
+        responder.handle_recv(m3[0].clone()).unwrap();
+        let (_, established) = routed(&responder_router);
+        assert_eq!(established.len(), 1);
+        assert_eq!(established[0].payload, b"credential");
+        assert_eq!(established[0].remote_address, None);
+    }
+
+    #[test]
+    fn both_ends_share_binding_and_exported_keys() {
+        let (mut initiator, initiator_router) = xx_manager(&[]);
+        let (mut responder, responder_router) = xx_manager(&[]);
+        initiate(&mut initiator);
+        let (m1, _) = routed(&initiator_router);
+        responder.handle_recv(m1[0].clone()).unwrap();
+        let (m2, _) = routed(&responder_router);
+        initiator.handle_recv(m2[0].clone()).unwrap();
+        let (m3, initiator_established) = routed(&initiator_router);
+        responder.handle_recv(m3[0].clone()).unwrap();
+        let (_, responder_established) = routed(&responder_router);
+        assert_eq!(
+            initiator_established[0].channel_binding,
+            responder_established[0].channel_binding
+        );
+
+        // each end by the ciphertext address its handshake messages came from
+        let initiator_address = m3[0].return_route.addresses[0].address.clone();
+        let responder_address = m2[0].return_route.addresses[0].address.clone();
+        assert_eq!(
+            initiator.channel_binding(&initiator_address),
+            Some(initiator_established[0].channel_binding)
+        );
+        let key = initiator
+            .export_key(&initiator_address, b"token binding", b"", 32)
+            .unwrap();
+        assert_eq!(
+            responder
+                .export_key(&responder_address, b"token binding", b"", 32)
+                .unwrap(),
+            key
+        );
+        assert!(initiator
+            .export_key(&responder_address, b"token binding", b"", 32)
+            .is_err());
+    }
+
+    #[test]
+    fn drops_forged_payloads() {
+        let (mut initiator, initiator_router) = xx_manager(&[]);
+        let (mut responder, responder_router) = xx_manager(&[]);
+        initiate(&mut initiator);
+        let (m1, _) = routed(&initiator_router);
+        responder.handle_recv(m1[0].clone()).unwrap();
+        let (m2, _) = routed(&responder_router);
+        initiator.handle_recv(m2[0].clone()).unwrap();
+        let (m3, _) = routed(&initiator_router);
+        responder.handle_recv(m3[0].clone()).unwrap();
+        routed(&responder_router);
+
+        // a truncated frame, a bad tag and an epoch the channel never had
+        let mut bad_epoch = vec![0u8; crate::cipher::HEADER_LEN + 32];
+        bad_epoch[..4].copy_from_slice(&7u32.to_be_bytes());
+        for body in vec![vec![0u8; 5], vec![0u8; 64], bad_epoch] {
+            let forged = Message {
+                onward_route: Route {
+                    addresses: m3[0].onward_route.addresses.clone(),
+                },
+                return_route: Route {
+                    addresses: m3[0].return_route.addresses.clone(),
+                },
+                message_type: MessageType::Payload,
+                message_body: body,
+            };
+            assert!(responder.handle_recv(forged).is_ok());
+        }
+        assert_eq!(responder.stats().payloads_rejected, 3);
+        assert_eq!(responder.stats().messages_received, 0);
+    }
+
+    #[test]
+    fn drops_messages_for_closed_channels() {
+        let (mut initiator, initiator_router) = xx_manager(&[]);
+        let (mut responder, responder_router) = xx_manager(&[]);
+        initiate(&mut initiator);
+        let (m1, _) = routed(&initiator_router);
+        responder.handle_recv(m1[0].clone()).unwrap();
+        let (m2, _) = routed(&responder_router);
+        initiator.handle_recv(m2[0].clone()).unwrap();
+        let (m3, _) = routed(&initiator_router);
+        responder.handle_recv(m3[0].clone()).unwrap();
+
+        let address = m3[0].onward_route.addresses[0].address.as_string();
+        responder.close_channel(&address, false).unwrap();
+        assert_eq!(responder.stats().channels, 0);
+        let late = Message {
+            onward_route: Route {
+                addresses: m3[0].onward_route.addresses.clone(),
+            },
+            return_route: Route {
+                addresses: m3[0].return_route.addresses.clone(),
+            },
+            message_type: MessageType::Payload,
+            message_body: vec![0u8; 64],
+        };
+        assert!(responder.handle_recv(late).is_ok());
+    }
+
+    #[test]
+    fn payload_fails_the_trust_policy() {
+        let (mut initiator, initiator_router) = xx_manager(b"guest");
+        let (mut responder, responder_router) = xx_manager(&[]);
+        responder.set_trust_policy(RequirePayload(b"admin"));
+        initiate(&mut initiator);
+
+        let (m1, _) = routed(&initiator_router);
+        responder.handle_recv(m1[0].clone()).unwrap();
+        let (m2, _) = routed(&responder_router);
+        initiator.handle_recv(m2[0].clone()).unwrap();
+        let (m3, _) = routed(&initiator_router);
+        responder.handle_recv(m3[0].clone()).unwrap();
+        let (_, established) = routed(&responder_router);
+        assert!(established.is_empty());
+        assert_eq!(responder.stats().peers_rejected, 1);
+    }
+
+    #[test]
+    fn untrusted_responder_gets_no_m3() {
+        let (mut initiator, initiator_router) = xx_manager(b"credential");
+        let (mut responder, responder_router) = xx_manager(b"guest");
+        initiator.set_trust_policy(RequirePayload(b"admin"));
+        initiate(&mut initiator);
+
+        let (m1, _) = routed(&initiator_router);
+        responder.handle_recv(m1[0].clone()).unwrap();
+        let (m2, _) = routed(&responder_router);
+        initiator.handle_recv(m2[0].clone()).unwrap();
+        let (sent, established) = routed(&initiator_router);
+        // neither our static key nor our credential went out
+        assert!(sent.is_empty());
+        assert!(established.is_empty());
+        assert_eq!(initiator.stats().peers_rejected, 1);
+    }
+
+    #[test]
+    fn ik_sends_early_data() {
+        let vault = test_support::vault();
+        let (secret, public) = {
+            let mut v = vault.lock().unwrap();
+            let secret = v
+                .secret_generate(SecretKeyAttributes {
+                    xtype: SecretKeyType::Curve25519,
+                    purpose: SecretPurposeType::KeyAgreement,
+                    persistence: SecretPersistenceType::Persistent,
+                })
+                .unwrap();
+            (secret, v.secret_public_key_get(secret).unwrap())
+        };
+        let mut managers = vec![];
+        for (remote, identity, payload) in vec![
+            (Some(public), None, b"early".to_vec()),
+            (None, Some(secret), b"late".to_vec()),
+        ] {
+            let new_key_exchanger = IKNewKeyExchanger::new(
+                CipherSuite::Curve25519AesGcmSha256,
+                vault.clone(),
+                vault.clone(),
+                remote,
+            );
+            let (mut manager, router_rx): (IKChannelManager, _) =
+                test_support::manager(vault.clone(), new_key_exchanger, identity);
+            manager.set_handshake_payload(payload);
+            managers.push((manager, router_rx));
+        }
+        let (mut responder, responder_router) = managers.pop().unwrap();
+        let (mut initiator, initiator_router) = managers.pop().unwrap();
+        responder.set_trust_policy(RequirePayload(b"early"));
+
+        initiate(&mut initiator);
+        let (m1, _) = routed(&initiator_router);
+        // the payload went with M1 and the responder checked it at once
+        responder.handle_recv(m1[0].clone()).unwrap();
+        let (m2, established) = routed(&responder_router);
+        assert_eq!(established.len(), 1);
+        assert_eq!(established[0].payload, b"early");
+
+        initiator.handle_recv(m2[0].clone()).unwrap();
+        let (_, established) = routed(&initiator_router);
+        assert_eq!(established[0].payload, b"late");
+    }
+}
@@ -0,0 +1,144 @@
+/// Number of nonces behind the highest one seen that are still accepted
+pub const REPLAY_WINDOW: u64 = 2048;
+
+const WORDS: usize = (REPLAY_WINDOW / 64) as usize;
+
+/// Why a nonce was turned away
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Rejection {
+    /// The nonce has already been accepted
+    Duplicate,
+    /// The nonce is too far behind the newest one to tell
+    TooOld,
+}
+
+/// A sliding anti-replay window over received nonces, as in IPsec and
+/// WireGuard. Nonces may arrive out of order, as long as they are within
+/// REPLAY_WINDOW of the highest seen; each is accepted at most once.
+///
+/// Checking and marking are separate so that a nonce is only recorded once
+/// its message has been authenticated, and a forged frame can't burn it.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ReplayWindow {
+    highest: Option<u64>,
+    bitmap: [u64; WORDS],
+}
+
+impl ReplayWindow {
+    pub(crate) fn check(&self, nonce: u64) -> Result<(), Rejection> {
+        let highest = match self.highest {
+            None => return Ok(()),
+            Some(h) => h,
+        };
+        if nonce > highest {
+            return Ok(());
+        }
+        if highest - nonce >= REPLAY_WINDOW {
+            return Err(Rejection::TooOld);
+        }
+        if self.is_set(nonce) {
+            return Err(Rejection::Duplicate);
+        }
+        Ok(())
+    }
+
+    /// Record an authenticated nonce, sliding the window forward if it is
+    /// the newest seen
+    pub(crate) fn mark(&mut self, nonce: u64) {
+        match self.highest {
+            Some(h) if nonce <= h => {}
+            Some(h) => {
+                if nonce - h >= REPLAY_WINDOW {
+                    self.bitmap = [0; WORDS];
+                } else {
+                    for n in h + 1..nonce {
+                        self.clear(n);
+                    }
+                }
+                self.highest = Some(nonce);
+            }
+            None => self.highest = Some(nonce),
+        }
+        self.set(nonce);
+    }
+
+    fn position(nonce: u64) -> (usize, u64) {
+        let bit = nonce % REPLAY_WINDOW;
+        ((bit / 64) as usize, 1 << (bit % 64))
+    }
+
+    fn is_set(&self, nonce: u64) -> bool {
+        let (word, mask) = Self::position(nonce);
+        self.bitmap[word] & mask != 0
+    }
+
+    fn set(&mut self, nonce: u64) {
+        let (word, mask) = Self::position(nonce);
+        self.bitmap[word] |= mask;
+    }
+
+    fn clear(&mut self, nonce: u64) {
+        let (word, mask) = Self::position(nonce);
+        self.bitmap[word] &= !mask;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(w: &mut ReplayWindow, nonce: u64) -> Result<(), Rejection> {
+        w.check(nonce)?;
+        w.mark(nonce);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        let mut w = ReplayWindow::default();
+        assert_eq!(accept(&mut w, 0), Ok(()));
+        assert_eq!(accept(&mut w, 1), Ok(()));
+        assert_eq!(accept(&mut w, 0), Err(Rejection::Duplicate));
+        assert_eq!(accept(&mut w, 1), Err(Rejection::Duplicate));
+    }
+
+    #[test]
+    fn tolerates_reordering() {
+        let mut w = ReplayWindow::default();
+        for n in &[5, 3, 9, 4, 0, 8] {
+            assert_eq!(accept(&mut w, *n), Ok(()));
+        }
+        assert_eq!(accept(&mut w, 3), Err(Rejection::Duplicate));
+        assert_eq!(accept(&mut w, 7), Ok(()));
+    }
+
+    #[test]
+    fn rejects_too_old() {
+        let mut w = ReplayWindow::default();
+        assert_eq!(accept(&mut w, 10), Ok(()));
+        assert_eq!(accept(&mut w, 10 + REPLAY_WINDOW), Ok(()));
+        assert_eq!(accept(&mut w, 10), Err(Rejection::TooOld));
+        assert_eq!(accept(&mut w, 11), Ok(()));
+    }
+
+    #[test]
+    fn slide_clears_reused_bits() {
+        let mut w = ReplayWindow::default();
+        for n in 0..3 {
+            assert_eq!(accept(&mut w, n), Ok(()));
+        }
+        // sliding to REPLAY_WINDOW + 1 pushes 0 out of the window; its bit
+        // is the one REPLAY_WINDOW uses, so it must have been cleared
+        assert_eq!(accept(&mut w, REPLAY_WINDOW + 1), Ok(()));
+        assert_eq!(accept(&mut w, REPLAY_WINDOW), Ok(()));
+        assert_eq!(accept(&mut w, 0), Err(Rejection::TooOld));
+        assert_eq!(accept(&mut w, 2), Err(Rejection::Duplicate));
+    }
+
+    #[test]
+    fn check_does_not_mark() {
+        let w = ReplayWindow::default();
+        assert_eq!(w.check(3), Ok(()));
+        assert_eq!(w.check(3), Ok(()));
+    }
+}
@@ -0,0 +1,142 @@
+use crate::error::{ChannelError, ChannelErrorKind};
+use ockam_vault::types::PublicKey;
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+/// Decides which peers a channel may be established with. The Channel
+/// Manager asks once the key exchange has produced the peer's static public
+/// key, on both the initiator and the responder, before any payload flows.
+pub trait TrustPolicy: Send {
+    /// True if the peer holding this static key may use the channel
+    fn is_trusted(&self, key: &PublicKey) -> bool;
+
+    /// True if the peer may use the channel given the payload it attached
+    /// to the handshake as well, e.g. a credential or the service it wants.
+    /// The payload is empty if the peer sent none. Checks the key alone
+    /// unless overridden.
+    fn accepts(&self, key: &PublicKey, _payload: &[u8]) -> bool {
+        self.is_trusted(key)
+    }
+}
+
+/// Trusts every peer
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AllowAll;
+
+impl TrustPolicy for AllowAll {
+    fn is_trusted(&self, _key: &PublicKey) -> bool {
+        true
+    }
+}
+
+/// Trusts a fixed set of keys
+#[derive(Clone, Debug, Default)]
+pub struct TrustedKeys {
+    keys: HashSet<Vec<u8>>,
+}
+
+impl TrustedKeys {
+    /// A set that trusts nobody until keys are added
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust the key with these bytes
+    pub fn add(&mut self, key: &[u8]) {
+        self.keys.insert(key.to_vec());
+    }
+
+    /// Trust each of these hex encoded keys
+    pub fn from_hex<S: AsRef<str>>(
+        keys: impl IntoIterator<Item = S>,
+    ) -> Result<Self, ChannelError> {
+        let mut trusted = Self::new();
+        for (i, key) in keys.into_iter().enumerate() {
+            match hex::decode(key.as_ref().trim()) {
+                Ok(k) => trusted.add(&k),
+                Err(_) => return Err(ChannelErrorKind::InvalidParam(i).into()),
+            }
+        }
+        Ok(trusted)
+    }
+
+    /// Number of keys trusted
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// True if no key is trusted
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+impl TrustPolicy for TrustedKeys {
+    fn is_trusted(&self, key: &PublicKey) -> bool {
+        self.keys.contains(key.as_ref())
+    }
+}
+
+/// Trusts the keys listed in a file, one hex key per line. Blank lines and
+/// lines starting with `#` are skipped. The file is read again for every
+/// handshake, so edits apply to the next channel; while it can't be read or
+/// parsed, nobody is trusted.
+#[derive(Clone, Debug)]
+pub struct KeyListFile {
+    path: PathBuf,
+}
+
+impl KeyListFile {
+    /// Trust the keys listed in the file at `path`
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Read the keys the file currently lists
+    pub fn load(&self) -> Result<TrustedKeys, ChannelError> {
+        let contents = std::fs::read_to_string(&self.path)?;
+        TrustedKeys::from_hex(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#')),
+        )
+    }
+}
+
+impl TrustPolicy for KeyListFile {
+    fn is_trusted(&self, key: &PublicKey) -> bool {
+        match self.load() {
+            Ok(keys) => keys.is_trusted(key),
+            Err(e) => {
+                println!("can't read trusted keys from {:?}: {:?}", self.path, e);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_key_set() {
+        let keys = TrustedKeys::from_hex(&[hex::encode([1u8; 32])]).unwrap();
+        assert!(keys.is_trusted(&PublicKey::Curve25519([1u8; 32])));
+        assert!(!keys.is_trusted(&PublicKey::Curve25519([2u8; 32])));
+        assert!(TrustedKeys::from_hex(&["not hex"]).is_err());
+    }
+
+    #[test]
+    fn key_list_file() {
+        let path = std::env::temp_dir().join(format!("ockam-trust-{}", std::process::id()));
+        std::fs::write(&path, format!("# sink\n{}\n\n", hex::encode([3u8; 32]))).unwrap();
+        let policy = KeyListFile::new(&path);
+        assert!(policy.is_trusted(&PublicKey::Curve25519([3u8; 32])));
+        assert!(!policy.is_trusted(&PublicKey::Curve25519([4u8; 32])));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(!policy.is_trusted(&PublicKey::Curve25519([3u8; 32])));
+    }
+}
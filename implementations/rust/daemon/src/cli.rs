@@ -3,6 +3,7 @@ use std::path::PathBuf;
 use std::str::FromStr;
 
 use ockam_message::message::{Route, RouterAddress};
+use ockam_system::runtime::DEFAULT_QUEUE_CAPACITY;
 
 use structopt::{clap::ArgSettings::Hidden, StructOpt};
 use url::Url;
@@ -112,6 +113,68 @@ pub struct Args {
     )]
     addon: Option<Addon>,
 
+    /// Capacity of each internal command queue.
+    #[structopt(
+        long,
+        default_value = "1024",
+        help = "Capacity of each internal command queue; input is paused while a queue is full"
+    )]
+    queue_capacity: usize,
+
+    /// Drop messages for a full queue instead of pausing input.
+    #[structopt(
+        long,
+        help = "Drop, and count, messages the router can't queue for a busy component instead of pausing input until it catches up"
+    )]
+    drop_on_full_queue: bool,
+
+    /// Serve router metrics for Prometheus on this local port.
+    #[structopt(
+        long,
+        help = "Serve router metrics in Prometheus text format on 127.0.0.1:<port>"
+    )]
+    metrics_port: Option<u16>,
+
+    /// File listing the public keys of peers allowed to open channels.
+    #[structopt(
+        long,
+        help = "File of hex public keys, one per line, of peers allowed to open channels with this node"
+    )]
+    trusted_keys: Option<PathBuf>,
+
+    /// Key exchange used to establish channels.
+    #[structopt(
+        long,
+        default_value = "xx",
+        help = r#"Key exchange used to establish channels: "xx", "ik" to save a round trip when the responder's key is known, or "x3dh" for a source to reach a sink through its published prekey bundle"#
+    )]
+    kex: KexKind,
+
+    /// AEAD cipher for the channel handshake and payloads.
+    #[structopt(
+        long,
+        default_value = "aesgcm",
+        help = r#"Cipher for channel payloads: "aesgcm", "chachapoly" for hosts without AES hardware, or "aesgcm-kyber768" to add a post-quantum KEM to the handshake (built with --features kyber); both ends must agree (with --kex xx or ik)"#
+    )]
+    cipher: CipherKind,
+
+    /// Where the sink publishes its prekey bundle and the source fetches it from.
+    #[structopt(
+        parse(from_os_str),
+        long,
+        required_if("kex", "x3dh"),
+        help = "File the sink writes its prekey bundle to, and the source reads it from (with --kex x3dh)"
+    )]
+    prekey_bundle: Option<PathBuf>,
+
+    /// Pre-shared key peers must hold to establish channels.
+    #[structopt(
+        parse(from_os_str),
+        long,
+        help = "File holding a 32 byte pre-shared key in hex; only peers with the same key can establish channels (with --kex xx or ik)"
+    )]
+    psk_file: Option<PathBuf>,
+
     // TODO: expose `control` and `control_port` once runtime configuration is needed.
     #[structopt(
         short,
@@ -147,6 +210,14 @@ impl Default for Args {
             public_key_sink: None,
             public_key_hub: Some("default_key_vaule".into()),
             addon: None,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            drop_on_full_queue: false,
+            metrics_port: None,
+            trusted_keys: None,
+            kex: KexKind::Xx,
+            cipher: CipherKind::AesGcm,
+            prekey_bundle: None,
+            psk_file: None,
         }
     }
 }
@@ -218,6 +289,38 @@ impl Args {
     pub fn addon(&self) -> Option<Addon> {
         self.addon.clone()
     }
+
+    pub fn queue_capacity(&self) -> usize {
+        self.queue_capacity
+    }
+
+    pub fn drop_on_full_queue(&self) -> bool {
+        self.drop_on_full_queue
+    }
+
+    pub fn metrics_port(&self) -> Option<u16> {
+        self.metrics_port
+    }
+
+    pub fn trusted_keys(&self) -> Option<PathBuf> {
+        self.trusted_keys.clone()
+    }
+
+    pub fn kex(&self) -> KexKind {
+        self.kex
+    }
+
+    pub fn cipher(&self) -> CipherKind {
+        self.cipher
+    }
+
+    pub fn prekey_bundle(&self) -> Option<PathBuf> {
+        self.prekey_bundle.clone()
+    }
+
+    pub fn psk_file(&self) -> Option<PathBuf> {
+        self.psk_file.clone()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -262,6 +365,59 @@ impl FromStr for VaultKind {
     }
 }
 
+/// Specifies the key exchange used to establish secure channels.
+#[derive(Clone, Copy, Debug)]
+pub enum KexKind {
+    /// Noise XX, an interactive handshake with the peer
+    Xx,
+    /// Noise IK, one round trip to a responder whose key is known in advance
+    Ik,
+    /// X3DH against a prekey bundle the sink published, so the source can
+    /// send without waiting on the sink
+    X3dh,
+}
+
+impl FromStr for KexKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "xx" => Ok(KexKind::Xx),
+            "ik" => Ok(KexKind::Ik),
+            "x3dh" => Ok(KexKind::X3dh),
+            _ => Err("kex must be set to 'xx', 'ik' or 'x3dh'".into()),
+        }
+    }
+}
+
+/// Specifies the AEAD cipher channels encrypt with.
+#[derive(Clone, Copy, Debug)]
+pub enum CipherKind {
+    /// AES-GCM, fastest where the CPU has AES instructions
+    AesGcm,
+    /// ChaCha20-Poly1305, fastest in software
+    ChaChaPoly,
+    /// AES-GCM, with a Kyber768 secret mixed into the handshake as well
+    #[cfg(feature = "kyber")]
+    AesGcmKyber768,
+}
+
+impl FromStr for CipherKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aesgcm" => Ok(CipherKind::AesGcm),
+            "chachapoly" => Ok(CipherKind::ChaChaPoly),
+            #[cfg(feature = "kyber")]
+            "aesgcm-kyber768" => Ok(CipherKind::AesGcmKyber768),
+            #[cfg(not(feature = "kyber"))]
+            "aesgcm-kyber768" => Err("ockamd was built without the kyber feature".into()),
+            _ => Err("cipher must be set to 'aesgcm', 'chachapoly' or 'aesgcm-kyber768'".into()),
+        }
+    }
+}
+
 /// Specifies which end of the secure channel the instance of `ockamd` is prepared to run in.
 #[derive(Clone, Copy, Debug, StructOpt)]
 pub enum ChannelRole {
@@ -4,6 +4,7 @@ use std::path::PathBuf;
 use crate::cli;
 
 use ockam_message::message::Route;
+use ockam_system::runtime::QueueConfig;
 
 #[derive(Debug, Clone, Copy)]
 pub enum Role {
@@ -17,6 +18,21 @@ pub enum Input {
     Stdin,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub enum Kex {
+    Xx,
+    Ik,
+    X3dh,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cipher {
+    AesGcm,
+    ChaChaPoly,
+    #[cfg(feature = "kyber")]
+    AesGcmKyber768,
+}
+
 #[derive(Debug, Clone)]
 pub enum AddonKind {
     InfluxDb(url::Url, String),
@@ -38,6 +54,14 @@ pub struct Config {
     service_address: Option<String>,
     identity_name: String,
     addon: Option<AddonKind>,
+    queues: QueueConfig,
+    drop_on_full_queue: bool,
+    metrics_port: Option<u16>,
+    trusted_keys: Option<PathBuf>,
+    kex: Kex,
+    cipher: Cipher,
+    prekey_bundle: Option<PathBuf>,
+    psk_file: Option<PathBuf>,
 }
 
 impl Default for Config {
@@ -98,6 +122,38 @@ impl Config {
     pub fn addon(&self) -> Option<AddonKind> {
         self.addon.clone()
     }
+
+    pub fn queues(&self) -> QueueConfig {
+        self.queues
+    }
+
+    pub fn drop_on_full_queue(&self) -> bool {
+        self.drop_on_full_queue
+    }
+
+    pub fn metrics_port(&self) -> Option<u16> {
+        self.metrics_port
+    }
+
+    pub fn trusted_keys(&self) -> Option<PathBuf> {
+        self.trusted_keys.clone()
+    }
+
+    pub fn kex(&self) -> Kex {
+        self.kex
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    pub fn prekey_bundle(&self) -> Option<PathBuf> {
+        self.prekey_bundle.clone()
+    }
+
+    pub fn psk_file(&self) -> Option<PathBuf> {
+        self.psk_file.clone()
+    }
 }
 
 impl From<cli::Args> for Config {
@@ -123,6 +179,14 @@ impl From<cli::Args> for Config {
             } else {
                 None
             },
+            queues: QueueConfig::uniform(args.queue_capacity()),
+            drop_on_full_queue: args.drop_on_full_queue(),
+            metrics_port: args.metrics_port(),
+            trusted_keys: args.trusted_keys(),
+            kex: Kex::Xx,
+            cipher: Cipher::AesGcm,
+            prekey_bundle: args.prekey_bundle(),
+            psk_file: args.psk_file(),
         };
 
         match args.output_kind() {
@@ -140,6 +204,19 @@ impl From<cli::Args> for Config {
             cli::ChannelRole::Router => Role::Router,
         };
 
+        cfg.kex = match args.kex() {
+            cli::KexKind::Xx => Kex::Xx,
+            cli::KexKind::Ik => Kex::Ik,
+            cli::KexKind::X3dh => Kex::X3dh,
+        };
+
+        cfg.cipher = match args.cipher() {
+            cli::CipherKind::AesGcm => Cipher::AesGcm,
+            cli::CipherKind::ChaChaPoly => Cipher::ChaChaPoly,
+            #[cfg(feature = "kyber")]
+            cli::CipherKind::AesGcmKyber768 => Cipher::AesGcmKyber768,
+        };
+
         cfg.input_kind = match args.input_kind() {
             cli::InputKind::Stdin => Input::Stdin,
         };
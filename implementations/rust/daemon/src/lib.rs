@@ -1,5 +1,6 @@
 pub mod cli;
 pub mod config;
+pub mod metrics;
 pub mod node;
 pub mod sink;
 pub mod source;
@@ -0,0 +1,63 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{channel, SyncSender};
+use std::thread;
+use std::time::Duration;
+
+use ockam_router::metrics::prometheus_text;
+use ockam_system::commands::{OckamCommand, RouterCommand};
+
+/// How long to wait for the router to answer a Stats query
+const STATS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Serve the router's metrics as Prometheus text on 127.0.0.1:<port>, from a
+/// thread of its own. Every request, whatever its path, gets a fresh snapshot.
+pub fn serve(port: u16, router_tx: SyncSender<OckamCommand>) -> Result<(), String> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let listener =
+        TcpListener::bind(addr).map_err(|e| format!("failed to bind metrics port: {}", e))?;
+    println!("Serving metrics on http://{}/metrics", addr);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(s) => {
+                    if let Err(e) = respond(s, &router_tx) {
+                        println!("metrics request failed: {}", e);
+                    }
+                }
+                Err(e) => {
+                    println!("metrics listen error: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream, router_tx: &SyncSender<OckamCommand>) -> Result<(), String> {
+    // the request itself doesn't matter, but read it so the client isn't
+    // reset before it sees the response
+    let mut buf = [0u8; 1024];
+    stream.set_read_timeout(Some(STATS_TIMEOUT)).ok();
+    let _ = stream.read(&mut buf);
+
+    let (tx, rx) = channel();
+    router_tx
+        .send(OckamCommand::Router(RouterCommand::Stats(tx)))
+        .map_err(|_| "router is not running".to_string())?;
+    let response = match rx.recv_timeout(STATS_TIMEOUT) {
+        Ok(stats) => {
+            let body = prometheus_text(&stats);
+            format!(
+                "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        Err(_) => "HTTP/1.0 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n".to_string(),
+    };
+    stream
+        .write_all(response.as_bytes())
+        .map_err(|e| e.to_string())
+}
@@ -1,10 +1,11 @@
-use std::sync::mpsc::{self, Sender};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::SyncSender;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time;
 
 use crate::cli;
-use crate::config::{Config, Role};
+use crate::config::{Cipher, Config, Kex, Role};
+use crate::metrics;
 use crate::sink::SinkWorker;
 use crate::source::StdinWorker;
 
@@ -12,18 +13,24 @@ use crate::source::StdinWorker;
 //     ockam_daemon::initiator::StdinWorker
 // }
 
+use ockam_channel::error::ChannelError;
+use ockam_channel::trust::{KeyListFile, TrustPolicy, TrustedKeys};
 use ockam_channel::*;
 use ockam_kex::{
+    ik::{IKInitiator, IKNewKeyExchanger, IKResponder},
+    x3dh::{X3dhInitiator, X3dhNewKeyExchanger, X3dhResponder},
     xx::{XXInitiator, XXNewKeyExchanger, XXResponder},
     CipherSuite,
 };
 use ockam_message::message::{Address, RouterAddress};
-use ockam_router::router::Router;
+use ockam_router::router::{Overflow, Router};
 use ockam_system::commands::{OckamCommand, WorkerCommand};
+use ockam_system::runtime::{bounded_queue, NodeRuntime};
 use ockam_transport::tcp::TcpManager;
 use ockam_vault::types::*;
 use ockam_vault::{file::FilesystemVault, DynVault};
 use std::net::SocketAddr;
+use std::path::Path;
 use std::str::FromStr;
 
 pub enum OckamdWorker {
@@ -31,19 +38,56 @@ pub enum OckamdWorker {
     Sink(SinkWorker),
 }
 
+type XXChannelManager = ChannelManager<XXInitiator, XXResponder, XXNewKeyExchanger>;
+type IKChannelManager = ChannelManager<IKInitiator, IKResponder, IKNewKeyExchanger>;
+type X3dhChannelManager = ChannelManager<X3dhInitiator, X3dhResponder, X3dhNewKeyExchanger>;
+
+/// The channel manager for the key exchange the node was started with
+pub enum OckamdChannelManager {
+    Xx(XXChannelManager),
+    Ik(IKChannelManager),
+    X3dh(X3dhChannelManager),
+}
+
+impl OckamdChannelManager {
+    fn set_psk(&mut self, psk: SecretKeyContext) -> Result<(), ChannelError> {
+        match self {
+            OckamdChannelManager::Xx(m) => m.set_psk(psk),
+            OckamdChannelManager::Ik(m) => m.set_psk(psk),
+            OckamdChannelManager::X3dh(m) => m.set_psk(psk),
+        }
+    }
+
+    fn set_trust_policy(&mut self, policy: impl TrustPolicy + 'static) {
+        match self {
+            OckamdChannelManager::Xx(m) => m.set_trust_policy(policy),
+            OckamdChannelManager::Ik(m) => m.set_trust_policy(policy),
+            OckamdChannelManager::X3dh(m) => m.set_trust_policy(policy),
+        }
+    }
+
+    fn run(&mut self) -> Result<(), ChannelError> {
+        match self {
+            OckamdChannelManager::Xx(m) => m.run(),
+            OckamdChannelManager::Ik(m) => m.run(),
+            OckamdChannelManager::X3dh(m) => m.run(),
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub struct Node<'a> {
     config: &'a Config,
-    chan_manager: ChannelManager<XXInitiator, XXResponder, XXNewKeyExchanger>,
+    chan_manager: OckamdChannelManager,
     worker: Option<OckamdWorker>,
     router: Router,
-    router_tx: Sender<OckamCommand>,
+    router_tx: SyncSender<OckamCommand>,
     transport: TcpManager,
-    transport_tx: Sender<OckamCommand>,
-    pub channel_tx: Sender<OckamCommand>,
+    transport_tx: SyncSender<OckamCommand>,
+    pub channel_tx: SyncSender<OckamCommand>,
 }
 
-pub fn get_console_line(wtx: Sender<OckamCommand>) {
+pub fn get_console_line(wtx: SyncSender<OckamCommand>) {
     let mut buf: String = "".into();
     while buf != "q" {
         if std::io::stdin().read_line(&mut buf).is_ok() {
@@ -57,8 +101,8 @@ pub fn get_console_line(wtx: Sender<OckamCommand>) {
 impl<'a> Node<'a> {
     pub fn create_transport(
         config: &Config,
-        router_tx: Sender<OckamCommand>,
-    ) -> Result<(TcpManager, Sender<OckamCommand>), String> {
+        router_tx: SyncSender<OckamCommand>,
+    ) -> Result<(TcpManager, SyncSender<OckamCommand>), String> {
         // create the transport, currently TCP-only
         // if role == Router, give it a listen address
         let mut listen_addr: Option<SocketAddr> = None;
@@ -77,7 +121,7 @@ impl<'a> Node<'a> {
             _ => {}
         }
 
-        let (transport_tx, transport_rx) = mpsc::channel();
+        let (transport_tx, transport_rx) = bounded_queue(config.queues().transport);
         let mut transport = TcpManager::new(
             transport_rx,
             transport_tx.clone(),
@@ -110,8 +154,11 @@ impl<'a> Node<'a> {
 
     pub fn new(config: &'a Config) -> Result<Self, String> {
         // TODO: temporarily passed into the node, need to re-work
-        let (router_tx, router_rx) = std::sync::mpsc::channel();
-        let router = Router::new(router_rx);
+        let (router_tx, router_rx) = bounded_queue(config.queues().router);
+        let mut router = Router::new(router_rx);
+        if config.drop_on_full_queue() {
+            router.set_overflow(Overflow::Drop);
+        }
 
         // create the vault, using the FILESYSTEM implementation
         let mut vault =
@@ -151,35 +198,132 @@ impl<'a> Node<'a> {
             }
         }
 
+        // the pre-shared key is only kept in the vault while the node runs
+        let psk = match config.psk_file() {
+            Some(path) => Some(import_psk(&mut vault, &path)?),
+            None => None,
+        };
+
         // prepare the vault for use in key exchanger and channel manager
         let vault = Arc::new(Mutex::new(vault));
 
+        let cipher_suite = match config.cipher() {
+            Cipher::AesGcm => CipherSuite::Curve25519AesGcmSha256,
+            Cipher::ChaChaPoly => CipherSuite::Curve25519ChaChaPolySha256,
+            #[cfg(feature = "kyber")]
+            Cipher::AesGcmKyber768 => CipherSuite::Curve25519Kyber768AesGcmSha256,
+        };
+
         // create the channel manager
-        type XXChannelManager = ChannelManager<XXInitiator, XXResponder, XXNewKeyExchanger>;
-        let (channel_tx, channel_rx) = mpsc::channel();
-        let new_key_exchanger = XXNewKeyExchanger::new(
-            CipherSuite::Curve25519AesGcmSha256,
-            vault.clone(),
-            vault.clone(),
-        );
-
-        let chan_manager = XXChannelManager::new(
-            channel_rx,
-            channel_tx.clone(),
-            router_tx.clone(),
-            vault,
-            new_key_exchanger,
-            resp_key_ctx,
-            None,
-        )
-        .unwrap();
+        let (channel_tx, channel_rx) = bounded_queue(config.queues().channel);
+        let mut chan_manager = match config.kex() {
+            Kex::Xx => {
+                let new_key_exchanger =
+                    XXNewKeyExchanger::new(cipher_suite, vault.clone(), vault.clone());
+                OckamdChannelManager::Xx(
+                    XXChannelManager::new(
+                        channel_rx,
+                        channel_tx.clone(),
+                        router_tx.clone(),
+                        vault,
+                        new_key_exchanger,
+                        resp_key_ctx,
+                        None,
+                    )
+                    .unwrap(),
+                )
+            }
+            Kex::Ik => {
+                // the source initiates to the sink, and the sink to its hub;
+                // a node that only responds needs no key
+                let remote_key = match config.role() {
+                    Role::Source => config.public_key_sink(),
+                    Role::Sink if config.route_hub().is_some() => Some(
+                        config
+                            .public_key_hub()
+                            .ok_or_else(|| "--kex ik needs --public-key-hub".to_string())?,
+                    ),
+                    _ => None,
+                };
+                let remote_key = match remote_key {
+                    Some(key) => Some(public_key_from_hex(&key)?),
+                    None => None,
+                };
+                let new_key_exchanger =
+                    IKNewKeyExchanger::new(cipher_suite, vault.clone(), vault.clone(), remote_key);
+                OckamdChannelManager::Ik(
+                    IKChannelManager::new(
+                        channel_rx,
+                        channel_tx.clone(),
+                        router_tx.clone(),
+                        vault,
+                        new_key_exchanger,
+                        resp_key_ctx,
+                        None,
+                    )
+                    .unwrap(),
+                )
+            }
+            Kex::X3dh => {
+                // X3DH channels only go from a source to the sink whose
+                // bundle it holds; hubs and routers speak XX
+                if config.cipher() != Cipher::AesGcm {
+                    return Err("--cipher can only be used with --kex xx or ik".into());
+                }
+                if matches!(config.role(), Role::Router)
+                    || (matches!(config.role(), Role::Sink) && config.route_hub().is_some())
+                {
+                    return Err("--kex x3dh can't be used with a hub or router".into());
+                }
+                let new_key_exchanger = X3dhNewKeyExchanger::new(
+                    CipherSuite::Curve25519AesGcmSha256,
+                    vault.clone(),
+                    vault.clone(),
+                );
+                let mut m = X3dhChannelManager::new(
+                    channel_rx,
+                    channel_tx.clone(),
+                    router_tx.clone(),
+                    vault,
+                    new_key_exchanger,
+                    resp_key_ctx,
+                    None,
+                )
+                .unwrap();
+                if matches!(config.role(), Role::Sink) {
+                    publish_bundle(&mut m, config)?;
+                }
+                OckamdChannelManager::X3dh(m)
+            }
+        };
+
+        // only let the expected peers establish channels; a source knows
+        // the sink's key, anyone else may be given a list of keys
+        if let Some(path) = config.trusted_keys() {
+            chan_manager.set_trust_policy(KeyListFile::new(path));
+        } else if let Some(key) = config.public_key_sink() {
+            let keys =
+                TrustedKeys::from_hex(&[key]).map_err(|_| "invalid sink public key".to_string())?;
+            chan_manager.set_trust_policy(keys);
+        }
+
+        if let Some(psk) = psk {
+            chan_manager.set_psk(psk).map_err(|_| {
+                "--psk-file can only be used with --kex xx or ik, without a KEM cipher".to_string()
+            })?;
+        }
 
         if let Ok((transport, transport_tx)) = Node::create_transport(&config, router_tx.clone()) {
             // create the worker
             let mut worker: Option<OckamdWorker> = None;
             if matches!(config.role(), Role::Source) {
                 worker = Some(OckamdWorker::StdinWorker(
-                    StdinWorker::initialize(config, router_tx.clone(), channel_tx.clone()).unwrap(),
+                    StdinWorker::initialize(
+                        config,
+                        router_tx.clone().into(),
+                        channel_tx.clone().into(),
+                    )
+                    .ok_or_else(|| "failed to start the source worker".to_string())?,
                 ));
             }
             if matches!(config.role(), Role::Sink) {
@@ -189,8 +333,8 @@ impl<'a> Node<'a> {
                     SinkWorker::initialize(
                         &config,
                         worker_addr,
-                        router_tx.clone(),
-                        channel_tx.clone(),
+                        router_tx.clone().into(),
+                        channel_tx.clone().into(),
                     )
                     .unwrap(),
                 ));
@@ -210,51 +354,98 @@ impl<'a> Node<'a> {
         }
     }
 
-    pub fn run(mut self) {
-        match self.worker {
-            Some(worker) => match worker {
-                OckamdWorker::Sink(mut w) => {
-                    while self.router.poll()
-                        && self.transport.poll()
-                        && w.poll()
-                        && self
-                            .chan_manager
-                            .poll()
-                            .expect("channel manager poll failure")
-                    {
-                        thread::sleep(time::Duration::from_millis(1));
-                    }
-                }
-                OckamdWorker::StdinWorker(mut w) => {
-                    let worker_tx = w.get_tx();
-                    thread::spawn(move || get_console_line(worker_tx));
-                    while self.router.poll()
-                        && self.transport.poll()
-                        && w.poll()
-                        && self
-                            .chan_manager
-                            .poll()
-                            .expect("channel manager poll failure")
-                    {
-                        thread::sleep(time::Duration::from_millis(1));
-                    }
-                }
-            },
-            None => {
-                while self.router.poll()
-                    && self.transport.poll()
-                    && self
-                        .chan_manager
-                        .poll()
-                        .expect("channel manager poll failure")
-                {
-                    thread::sleep(time::Duration::from_millis(1));
-                }
+    pub fn run(self) {
+        let Node {
+            config,
+            mut router,
+            router_tx,
+            mut transport,
+            mut chan_manager,
+            worker,
+            ..
+        } = self;
+        let dropped = router.dropped_messages();
+
+        if let Some(port) = config.metrics_port() {
+            if let Err(e) = metrics::serve(port, router_tx) {
+                println!("{}", e);
+            }
+        }
+
+        // every component blocks on its own thread until it has work to do,
+        // and the node stops as soon as any of them does
+        let runtime = NodeRuntime::new();
+        match worker {
+            Some(OckamdWorker::Sink(mut w)) => {
+                runtime.spawn("worker", move || w.run());
+            }
+            Some(OckamdWorker::StdinWorker(mut w)) => {
+                let worker_tx = w.get_tx();
+                thread::spawn(move || get_console_line(worker_tx));
+                runtime.spawn("worker", move || w.run());
             }
+            None => {}
+        }
+        runtime.spawn("router", move || router.run());
+        runtime.spawn("transport", move || transport.run());
+        runtime.spawn("channel", move || {
+            chan_manager.run().expect("channel manager failure");
+        });
+        runtime.wait();
+
+        let dropped = dropped.load(Ordering::Relaxed);
+        if dropped > 0 {
+            println!("{} messages dropped under overload", dropped);
         }
     }
 }
 
+/// Create the channel a source will enroll with, and write its bundle to
+/// the file the source fetches it from
+fn publish_bundle(chan_manager: &mut X3dhChannelManager, config: &Config) -> Result<(), String> {
+    let path = config
+        .prekey_bundle()
+        .ok_or_else(|| "--kex x3dh needs --prekey-bundle".to_string())?;
+    let bundle = chan_manager
+        .publish_bundle()
+        .map_err(|e| format!("failed to create prekey bundle: {:?}", e))?;
+    std::fs::write(&path, bundle.to_hex())
+        .map_err(|e| format!("failed to write prekey bundle to {:?}: {}", path, e))?;
+    println!("Prekey bundle published to {}", path.display());
+    Ok(())
+}
+
+/// Import the hex pre-shared key in `path` into the vault, without
+/// persisting it there
+fn import_psk(vault: &mut dyn DynVault, path: &Path) -> Result<SecretKeyContext, String> {
+    let hex_psk = std::fs::read_to_string(path)
+        .map_err(|e| format!("can't read pre-shared key from {:?}: {}", path, e))?;
+    let psk = hex::decode(hex_psk.trim())
+        .map_err(|_| format!("pre-shared key in {:?} is not hex", path))?;
+    if psk.len() != 32 {
+        return Err(format!("pre-shared key in {:?} is not 32 bytes", path));
+    }
+    let attributes = SecretKeyAttributes {
+        xtype: SecretKeyType::Buffer(psk.len()),
+        purpose: SecretPurposeType::KeyAgreement,
+        persistence: SecretPersistenceType::Ephemeral,
+    };
+    vault
+        .secret_import(&SecretKey::Buffer(psk), attributes)
+        .map_err(|e| format!("failed to import pre-shared key: {:?}", e))
+}
+
+/// A Curve25519 public key given in hex on the command line
+fn public_key_from_hex(key: &str) -> Result<PublicKey, String> {
+    let bytes = hex::decode(key.trim()).map_err(|_| format!("invalid public key {}", key))?;
+    if bytes.len() != 32 {
+        return Err(format!("public key {} is not 32 bytes", key));
+    }
+    let mut public_key = [0u8; 32];
+    public_key.copy_from_slice(&bytes);
+    Ok(PublicKey::Curve25519(public_key))
+}
+
 fn as_key_ctx(key_name: &str) -> Result<SecretKeyContext, String> {
     if let Some(id) = key_name.strip_suffix(cli::FILENAME_KEY_SUFFIX) {
         return Ok(SecretKeyContext::Memory(
@@ -1,13 +1,16 @@
-use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::mpsc::{Receiver, SyncSender};
 
 use crate::config::{AddonKind, Config};
 use attohttpc::post;
+use ockam_channel::established::Established;
 use ockam_channel::CHANNEL_ZERO;
 use ockam_message::message::{
-    Address, AddressType, Codec, Message as OckamMessage, Message, MessageType, Route,
-    RouterAddress,
+    Address, AddressType, Message as OckamMessage, Message, MessageType, Route, RouterAddress,
 };
-use ockam_system::commands::{ChannelCommand, OckamCommand, RouterCommand, WorkerCommand};
+use ockam_system::commands::{
+    ChannelCommand, CommandSender, OckamCommand, RouterCommand, WorkerCommand,
+};
+use ockam_system::runtime::bounded_queue;
 use std::io::Write;
 
 type WorkFn = fn(self_worker: &SinkWorker, msg: OckamMessage);
@@ -15,10 +18,10 @@ type WorkFn = fn(self_worker: &SinkWorker, msg: OckamMessage);
 #[allow(dead_code)]
 
 pub struct SinkWorker {
-    router_tx: Sender<OckamCommand>,
-    channel_tx: Sender<OckamCommand>,
+    router_tx: CommandSender,
+    channel_tx: CommandSender,
     rx: Receiver<OckamCommand>,
-    tx: Sender<OckamCommand>,
+    tx: SyncSender<OckamCommand>,
     addr: RouterAddress,
     work_fn: WorkFn,
     config: Config,
@@ -29,8 +32,8 @@ impl SinkWorker {
     pub fn initialize(
         config: &Config,
         worker_addr: RouterAddress,
-        router_tx: Sender<OckamCommand>,
-        channel_tx: Sender<OckamCommand>,
+        router_tx: CommandSender,
+        channel_tx: CommandSender,
     ) -> Result<SinkWorker, String> {
         let worker = SinkWorker::new(
             worker_addr,
@@ -70,17 +73,22 @@ impl SinkWorker {
 
     pub fn new(
         addr: RouterAddress,
-        router_tx: Sender<OckamCommand>,
-        channel_tx: Sender<OckamCommand>,
+        router_tx: CommandSender,
+        channel_tx: CommandSender,
         config: Config,
         work_fn: WorkFn,
     ) -> Self {
         debug_assert!(matches!(addr.a_type, AddressType::Worker));
 
-        let (tx, rx) = mpsc::channel();
+        // bounded, so a slow addon makes the router wait, and in turn the
+        // transport stop reading, instead of growing this queue without limit
+        let (tx, rx) = bounded_queue(config.queues().worker);
 
         // register the worker with the router
-        let cmd = OckamCommand::Router(RouterCommand::Register(AddressType::Worker, tx.clone()));
+        let cmd = OckamCommand::Router(RouterCommand::Register(
+            AddressType::Worker,
+            tx.clone().into(),
+        ));
         router_tx.send(cmd).expect("failed to register worker");
 
         println!("Service address: {}", addr.address.as_string());
@@ -118,7 +126,7 @@ impl SinkWorker {
         }
     }
 
-    pub fn sender(&self) -> Sender<OckamCommand> {
+    pub fn sender(&self) -> SyncSender<OckamCommand> {
         self.tx.clone()
     }
 
@@ -128,54 +136,77 @@ impl SinkWorker {
 
     fn receive_channel(&mut self, m: Message) -> Result<(), String> {
         self.route = Some(m.return_route.clone());
-        if !m.message_body.is_empty() {
-            if let Ok((cleartext_channel_addr, _)) = RouterAddress::decode(&m.message_body) {
-                println!(
-                    "Channel cleartext address: {}",
-                    cleartext_channel_addr.address.as_string()
-                );
-            }
+        if let Ok(established) = Established::from_bytes(&m.message_body) {
+            println!(
+                "channel established with {}",
+                hex::encode(established.remote_public_key)
+            );
         }
         Ok(())
     }
 
-    pub fn poll(&mut self) -> bool {
-        match self.rx.try_recv() {
-            Ok(cmd) => match cmd {
-                OckamCommand::Worker(WorkerCommand::ReceiveMessage(msg)) => {
-                    match msg.message_type {
-                        MessageType::Payload => {
-                            // Confirm address
-                            if self.addr != msg.onward_route.addresses[0] {
-                                println!("Received bad worker address");
-                                return true;
-                            }
-                            (self.work_fn)(&self, msg);
-                            true
-                        }
-                        MessageType::None => {
-                            return if let Err(s) = self.receive_channel(msg) {
-                                println!("failed to receive channel: {}", s);
-                                false
-                            } else {
-                                true
-                            }
-                        }
-                        _ => unimplemented!(),
+    /// Block on the worker queue until a message can't be handled or every
+    /// sender has gone away
+    pub fn run(&mut self) {
+        loop {
+            match self.rx.recv() {
+                Ok(cmd) => {
+                    if !self.handle_command(cmd) {
+                        break;
                     }
                 }
-                _ => {
-                    eprintln!("unrecognized worker command: {:?}", cmd);
-                    false
+                Err(e) => {
+                    eprintln!("failed to recv worker rx: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn handle_command(&mut self, cmd: OckamCommand) -> bool {
+        match cmd {
+            OckamCommand::Worker(WorkerCommand::ReceiveMessage(msg)) => match msg.message_type {
+                MessageType::Payload => {
+                    // Confirm address
+                    if self.addr != msg.onward_route.addresses[0] {
+                        println!("Received bad worker address");
+                        return true;
+                    }
+                    (self.work_fn)(&self, msg);
+                    true
+                }
+                MessageType::None => {
+                    return if let Err(s) = self.receive_channel(msg) {
+                        println!("failed to receive channel: {}", s);
+                        false
+                    } else {
+                        true
+                    }
+                }
+                MessageType::ChannelOpening => true,
+                MessageType::SendFailed => {
+                    println!("message to hub not delivered");
+                    true
+                }
+                MessageType::ChannelClose => {
+                    println!("channel closed");
+                    self.route = None;
+                    true
+                }
+                MessageType::HandshakeFailed => {
+                    println!("failed to establish channel to hub");
+                    self.route = None;
+                    true
                 }
-            },
-            Err(e) => match e {
-                TryRecvError::Empty => true,
                 _ => {
-                    eprintln!("failed to recv worker rx: {:?}", e);
-                    false
+                    println!("ignored {:?} message", msg.message_type);
+                    true
                 }
             },
+            _ => {
+                eprintln!("unrecognized worker command: {:?}", cmd);
+                false
+            }
         }
     }
 }
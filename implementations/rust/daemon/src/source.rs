@@ -1,21 +1,25 @@
-use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::mpsc::{Receiver, SyncSender};
 
-use crate::config::Config;
+use crate::config::{Config, Kex};
 
 use hex::encode;
+use ockam_channel::bundle::PublishedBundle;
+use ockam_channel::established::Established;
 use ockam_channel::CHANNEL_ZERO;
 use ockam_message::message::{
-    Address, AddressType, Codec, Message as OckamMessage, Message, MessageType, Route,
-    RouterAddress,
+    Address, AddressType, Message as OckamMessage, Message, MessageType, Route, RouterAddress,
 };
-use ockam_system::commands::{ChannelCommand, OckamCommand, RouterCommand, WorkerCommand};
+use ockam_system::commands::{
+    ChannelCommand, CommandSender, OckamCommand, RouterCommand, WorkerCommand,
+};
+use ockam_system::runtime::bounded_queue;
 
 pub struct StdinWorker {
     route: Route,
     worker_addr: RouterAddress,
-    router_tx: Sender<OckamCommand>,
+    router_tx: CommandSender,
     rx: Receiver<OckamCommand>,
-    tx: Sender<OckamCommand>,
+    tx: SyncSender<OckamCommand>,
     buf: String,
     config: Config,
     lines_to_send: Vec<String>,
@@ -24,8 +28,8 @@ pub struct StdinWorker {
 impl StdinWorker {
     pub fn initialize(
         config: &Config,
-        router_tx: std::sync::mpsc::Sender<OckamCommand>,
-        channel_tx: std::sync::mpsc::Sender<OckamCommand>,
+        router_tx: CommandSender,
+        channel_tx: CommandSender,
     ) -> Option<StdinWorker> {
         let worker = StdinWorker::new(
             RouterAddress::worker_router_address_from_str(&config.service_address().unwrap())
@@ -37,33 +41,47 @@ impl StdinWorker {
         // kick off the key exchange process. The result will be that the worker is notified
         // when the secure channel is created.
         let mut onward_route = config.onward_route().unwrap_or(Route { addresses: vec![] });
-        onward_route
-            .addresses
-            .push(RouterAddress::channel_router_address_from_str(CHANNEL_ZERO).unwrap());
-
-        channel_tx
-            .send(OckamCommand::Channel(ChannelCommand::Initiate(
-                onward_route,
-                Address::WorkerAddress(hex::decode(config.service_address().unwrap()).unwrap()),
-                None,
-            )))
-            .unwrap();
+        let return_address =
+            Address::WorkerAddress(hex::decode(config.service_address().unwrap()).unwrap());
+        let command = match config.kex() {
+            Kex::Xx | Kex::Ik => {
+                onward_route
+                    .addresses
+                    .push(RouterAddress::channel_router_address_from_str(CHANNEL_ZERO).unwrap());
+                ChannelCommand::Initiate(onward_route, return_address, None)
+            }
+            Kex::X3dh => {
+                // fetch the bundle the sink published; the channel manager
+                // routes to the channel waiting behind it
+                let path = config.prekey_bundle()?;
+                let bundle = match std::fs::read_to_string(&path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|s| PublishedBundle::from_hex(&s).map_err(|e| format!("{:?}", e)))
+                {
+                    Ok(bundle) => bundle,
+                    Err(e) => {
+                        eprintln!("can't read prekey bundle from {:?}: {}", path, e);
+                        return None;
+                    }
+                };
+                ChannelCommand::InitiateWithBundle(onward_route, return_address, bundle.to_bytes())
+            }
+        };
+        channel_tx.send(OckamCommand::Channel(command)).unwrap();
 
         Some(worker)
     }
 
-    pub fn new(
-        worker_addr: RouterAddress,
-        router_tx: Sender<OckamCommand>,
-        config: Config,
-    ) -> Self {
-        let (tx, rx) = mpsc::channel();
+    pub fn new(worker_addr: RouterAddress, router_tx: CommandSender, config: Config) -> Self {
+        // bounded, so the console reader blocks rather than buffering
+        // without limit when the node can't keep up
+        let (tx, rx) = bounded_queue(config.queues().worker);
 
         // register the worker with the router
         router_tx
             .send(OckamCommand::Router(RouterCommand::Register(
                 AddressType::Worker,
-                tx.clone(),
+                tx.clone().into(),
             )))
             .expect("Stdin worker registration failed");
 
@@ -79,11 +97,12 @@ impl StdinWorker {
         }
     }
 
-    pub fn get_tx(&self) -> Sender<OckamCommand> {
+    pub fn get_tx(&self) -> SyncSender<OckamCommand> {
         self.tx.clone()
     }
 
-    fn receive_channel(&mut self, m: Message) -> Result<(), String> {
+    /// Route lines through the channel in `m`'s return route to the service
+    fn set_route(&mut self, m: &Message) {
         self.route = m.return_route.clone();
         self.route.addresses.push(self.worker_addr.clone());
 
@@ -92,37 +111,56 @@ impl StdinWorker {
             RouterAddress::worker_router_address_from_str(&self.config.service_address().unwrap())
                 .unwrap();
         self.route.addresses.push(service_address);
+    }
 
-        return match RouterAddress::decode(&m.message_body) {
-            Ok((rcc, mb)) => {
-                if let Some(rpk) = self.config.public_key_sink() {
-                    if rpk == encode(mb) {
-                        println!("keys agree");
-                        return Ok(());
-                    } else {
-                        println!("keys conflict");
-                        return Err(
-                            "remote public key doesn't match expected, possible spoofing".into(),
-                        );
-                    }
-                }
+    fn receive_channel(&mut self, m: Message) -> Result<(), String> {
+        self.set_route(&m);
+
+        // the channel manager's trust policy has already checked the sink's
+        // key, the channel wouldn't be here otherwise
+        return match Established::from_bytes(&m.message_body) {
+            Ok(established) => {
+                println!(
+                    "channel established with {}",
+                    encode(established.remote_public_key)
+                );
                 Ok(())
             }
-            _ => Err("receive channel: expected channel address in message body".into()),
+            _ => Err("receive channel: expected channel notice in message body".into()),
         };
     }
 
-    pub fn poll(&mut self) -> bool {
-        // await key exchange finalization
-        if let Ok(cmd) = self.rx.try_recv() {
+    /// Block on the worker queue, handling channel notifications and
+    /// sending stdin lines as they arrive
+    pub fn run(&mut self) {
+        while let Ok(cmd) = self.rx.recv() {
             match cmd {
                 OckamCommand::Worker(WorkerCommand::ReceiveMessage(msg)) => {
                     match msg.message_type {
-                        MessageType::None => match self.receive_channel(msg) {
-                            Ok(()) => {}
-                            Err(s) => panic!(s),
-                        },
-                        _ => unimplemented!(),
+                        // lines can be sent as soon as the channel has an
+                        // address, the channel holds them until it is up
+                        MessageType::ChannelOpening => self.set_route(&msg),
+                        MessageType::SendFailed => {
+                            println!(
+                                "line not sent: {}",
+                                String::from_utf8_lossy(&msg.message_body).trim_end()
+                            );
+                        }
+                        MessageType::None => {
+                            if let Err(s) = self.receive_channel(msg) {
+                                println!("{}", s);
+                            }
+                        }
+                        MessageType::ChannelClose => {
+                            // nothing more can be sent without a channel
+                            println!("channel closed");
+                            break;
+                        }
+                        MessageType::HandshakeFailed => {
+                            println!("failed to establish channel");
+                            break;
+                        }
+                        _ => println!("ignored {:?} message", msg.message_type),
                     }
                 }
                 OckamCommand::Worker(WorkerCommand::AddLine(s)) => {
@@ -130,23 +168,27 @@ impl StdinWorker {
                 }
                 _ => unimplemented!(),
             }
-        }
 
-        // read from stdin, pass each line to the router within the node
-        for s in &self.lines_to_send {
-            self.router_tx
-                .send(OckamCommand::Router(RouterCommand::SendMessage(
-                    OckamMessage {
-                        onward_route: self.route.clone(),
-                        return_route: Route { addresses: vec![] },
-                        message_type: MessageType::Payload,
-                        message_body: s.as_bytes().to_vec(),
-                    },
-                )))
-                .expect("failed to send input data to node");
-            self.buf.clear();
+            // keep lines read before there is anywhere to send them
+            if self.route.addresses.is_empty() {
+                continue;
+            }
+
+            // pass each line read from stdin to the router within the node
+            for s in &self.lines_to_send {
+                self.router_tx
+                    .send(OckamCommand::Router(RouterCommand::SendMessage(
+                        OckamMessage {
+                            onward_route: self.route.clone(),
+                            return_route: Route { addresses: vec![] },
+                            message_type: MessageType::Payload,
+                            message_body: s.as_bytes().to_vec(),
+                        },
+                    )))
+                    .expect("failed to send input data to node");
+                self.buf.clear();
+            }
+            self.lines_to_send.clear();
         }
-        self.lines_to_send.clear();
-        true
     }
 }
@@ -12,50 +12,112 @@ extern "C" {
 typedef uint64_t ockam_vault_t;
 typedef uint64_t ockam_kex_initiator_t;
 typedef uint64_t ockam_kex_responder_t;
-typedef uint64_t ockam_kex_t;
 typedef uint64_t ockam_vault_secret_t;
 
+#define OCKAM_KEX_X3DH_SUITE_CURVE25519 0u
+#define OCKAM_KEX_X3DH_SUITE_P256       1u
+
+#define OCKAM_KEX_X3DH_ENROLLMENT_KEY_SIZE 32u
+#define OCKAM_KEX_X3DH_PREKEY_BUNDLE_SIZE  168u
+#define OCKAM_KEX_X3DH_ENROLLMENT_SIZE     184u
+
+#define OCKAM_KEX_X3DH_P256_ENROLLMENT_KEY_SIZE 65u
+#define OCKAM_KEX_X3DH_P256_PREKEY_BUNDLE_SIZE  267u
+#define OCKAM_KEX_X3DH_P256_ENROLLMENT_SIZE     250u
+
+typedef struct {
+  uint8_t              h[32];
+  ockam_vault_secret_t encrypt_key;
+  ockam_vault_secret_t decrypt_key;
+  ockam_vault_secret_t local_static_secret;
+  uint8_t              remote_static_public_key[65];
+  uint32_t             remote_static_public_key_length;
+  ockam_vault_secret_t exporter_secret;
+} ockam_kex_completed_t;
+
 uint32_t ockam_kex_xx_initiator(ockam_kex_initiator_t* kex_initiator, ockam_vault_t vault);
 uint32_t ockam_kex_xx_responder(ockam_kex_responder_t* kex_responder, ockam_vault_t vault);
 
 uint32_t ockam_kex_xx_initiator_encode_message_1(ockam_kex_initiator_t kex_initiator,
-                                                 const uint8_t* payload,
-                                                 size_t payload_length,
-                                                 uint8_t* m1,
-                                                 size_t m1_size,
-                                                 size_t* m1_length);
+                                                 const uint8_t*        payload,
+                                                 uint32_t              payload_length,
+                                                 uint8_t*              m1,
+                                                 uint32_t              m1_size,
+                                                 uint32_t*             m1_length);
 
 uint32_t ockam_kex_xx_responder_encode_message_2(ockam_kex_responder_t kex_responder,
-                                                 const uint8_t* payload,
-                                                 size_t payload_length,
-                                                 uint8_t* m2,
-                                                 size_t m2_size,
-                                                 size_t* m2_length);
+                                                 const uint8_t*        payload,
+                                                 uint32_t              payload_length,
+                                                 uint8_t*              m2,
+                                                 uint32_t              m2_size,
+                                                 uint32_t*             m2_length);
 
 uint32_t ockam_kex_xx_initiator_encode_message_3(ockam_kex_initiator_t kex_initiator,
-                                                 const uint8_t* payload,
-                                                 size_t payload_length,
-                                                 uint8_t* m3,
-                                                 size_t m3_size,
-                                                 size_t* m3_length);
+                                                 const uint8_t*        payload,
+                                                 uint32_t              payload_length,
+                                                 uint8_t*              m3,
+                                                 uint32_t              m3_size,
+                                                 uint32_t*             m3_length);
 
 uint32_t ockam_kex_xx_responder_decode_message_1(ockam_kex_responder_t kex_responder,
-                                                 const uint8_t* m1,
-                                                 size_t m1_length);
+                                                 const uint8_t*        m1,
+                                                 uint32_t              m1_length);
 
 uint32_t ockam_kex_xx_initiator_decode_message_2(ockam_kex_initiator_t kex_initiator,
-                                                 const uint8_t* m2,
-                                                 size_t m2_length);
+                                                 const uint8_t*        m2,
+                                                 uint32_t              m2_length);
 
 uint32_t ockam_kex_xx_responder_decode_message_3(ockam_kex_responder_t kex_responder,
-                                                 const uint8_t* m3,
-                                                 size_t m3_length);
+                                                 const uint8_t*        m3,
+                                                 uint32_t              m3_length);
 
 uint32_t ockam_kex_xx_initiator_finalize(ockam_kex_initiator_t kex_initiator,
-                                         ockam_kex_t* kex);
+                                         ockam_kex_completed_t* kex);
 
 uint32_t ockam_kex_xx_responder_finalize(ockam_kex_responder_t kex_responder,
-                                         ockam_kex_t* kex);
+                                         ockam_kex_completed_t* kex);
+
+uint32_t ockam_kex_xx_initiator_deinit(ockam_kex_initiator_t kex_initiator);
+uint32_t ockam_kex_xx_responder_deinit(ockam_kex_responder_t kex_responder);
+
+uint32_t ockam_kex_x3dh_initiator(ockam_kex_initiator_t* kex_initiator,
+                                 ockam_vault_t         vault,
+                                 uint32_t              cipher_suite,
+                                 ockam_vault_secret_t  identity_key);
+uint32_t ockam_kex_x3dh_responder(ockam_kex_responder_t* kex_responder,
+                                 ockam_vault_t          vault,
+                                 uint32_t               cipher_suite,
+                                 ockam_vault_secret_t   identity_key);
+
+uint32_t ockam_kex_x3dh_initiator_enrollment_key(ockam_kex_initiator_t kex_initiator,
+                                                 uint8_t*              enrollment_key,
+                                                 uint32_t              enrollment_key_size,
+                                                 uint32_t*             enrollment_key_length);
+
+uint32_t ockam_kex_x3dh_responder_prekey_bundle(ockam_kex_responder_t kex_responder,
+                                                uint8_t*              bundle,
+                                                uint32_t              bundle_size,
+                                                uint32_t*             bundle_length);
+
+uint32_t ockam_kex_x3dh_initiator_process_prekey_bundle(ockam_kex_initiator_t kex_initiator,
+                                                        const uint8_t*        bundle,
+                                                        uint32_t              bundle_length,
+                                                        uint8_t*              enrollment,
+                                                        uint32_t              enrollment_size,
+                                                        uint32_t*             enrollment_length);
+
+uint32_t ockam_kex_x3dh_responder_process(ockam_kex_responder_t kex_responder,
+                                          const uint8_t*        message,
+                                          uint32_t              message_length);
+
+uint32_t ockam_kex_x3dh_initiator_finalize(ockam_kex_initiator_t kex_initiator,
+                                           ockam_kex_completed_t* kex);
+
+uint32_t ockam_kex_x3dh_responder_finalize(ockam_kex_responder_t kex_responder,
+                                           ockam_kex_completed_t* kex);
+
+uint32_t ockam_kex_x3dh_initiator_deinit(ockam_kex_initiator_t kex_initiator);
+uint32_t ockam_kex_x3dh_responder_deinit(ockam_kex_responder_t kex_responder);
 
 #ifdef __cplusplus
 } // extern "C"
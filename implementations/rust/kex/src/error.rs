@@ -110,6 +110,13 @@ impl From<KeyExchangeFailErrorKind> for ffi_support::ExternError {
     }
 }
 
+#[cfg(feature = "ffi")]
+impl From<ffi_support::HandleError> for KexExchangeFailError {
+    fn from(_: ffi_support::HandleError) -> Self {
+        KeyExchangeFailErrorKind::InvalidParam(0).into()
+    }
+}
+
 /// Wraps an error kind with context and backtrace logic
 #[derive(Debug)]
 pub struct KexExchangeFailError {
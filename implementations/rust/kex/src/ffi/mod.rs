@@ -1,10 +1,12 @@
-use crate::error::KeyExchangeFailErrorKind;
-use ffi_support::{ByteBuffer, ConcurrentHandleMap, ExternError, IntoFfi};
+use crate::error::{KexExchangeFailError, KeyExchangeFailErrorKind};
+use crate::{CipherSuite, CompletedKeyExchange, KeyExchanger};
+use ffi_support::IntoFfi;
+use ockam_vault::types::{PublicKey, SecretKeyContext};
 
 mod types;
+mod x3dh;
+mod xx;
 
-use crate::{Initiator, Responder, XXSymmetricState};
-use ockam_vault::software::DefaultVault;
 use types::*;
 
 fn write_bin_to_buffer(
@@ -22,231 +24,54 @@ fn write_bin_to_buffer(
     }
 }
 
-/// Create a new kex initiator and return it
-#[no_mangle]
-pub extern "C" fn ockam_kex_xx_initiator(context: &mut u64, vault: u64) -> KexError {
-    // TODO: obtain vault from storage using handle
-    let mut vault = DefaultVault::default();
-    let state = match XXSymmetricState::prologue(&mut vault) {
-        Ok(r) => r,
-        Err(_) => return 1,
-    };
-
-    // TODO: put initiator into storage and return proper handle
-    let initiator = Initiator::new(state);
-    *context = 0;
-
-    ERROR_NONE
-}
-
-/// Create a new kex responder and return it
-#[no_mangle]
-pub extern "C" fn ockam_kex_xx_responder(context: &mut u64, vault: u64) -> KexError {
-    // TODO: obtain vault from storage using handle
-    let mut vault = DefaultVault::default();
-    let state = match XXSymmetricState::prologue(&mut vault) {
-        Ok(r) => r,
-        Err(_) => return 1,
-    };
-
-    // TODO: put responder into storage and return proper handle
-    let responder = Responder::new(state);
-    *context = 0;
-
-    ERROR_NONE
-}
-
-/// Initiator encodes message 1
-#[no_mangle]
-pub extern "C" fn ockam_kex_xx_initiator_encode_message_1(
-    context: u64,
-    payload: *const u8,
-    payload_length: u32,
-    m1: &mut u8,
-    m1_size: u32,
-    m1_length: &mut u32,
-) -> KexError {
-    // TODO: obtain initiator from storage
-    let mut vault = DefaultVault::default();
-    let state = match XXSymmetricState::prologue(&mut vault) {
-        Ok(r) => r,
-        Err(_) => return 1,
-    };
-    let mut initiator = Initiator::new(state);
-
-    let payload = unsafe { std::slice::from_raw_parts(payload, payload_length as usize) };
-
-    let message1 = match initiator.encode_message_1(payload) {
-        Ok(r) => r,
-        Err(_) => return 1,
-    };
-
-    write_bin_to_buffer(&message1, m1, m1_size, m1_length)
-}
-
-/// Responder encodes message 2
-#[no_mangle]
-pub extern "C" fn ockam_kex_xx_responder_encode_message_2(
-    context: u64,
-    payload: *const u8,
-    payload_length: u32,
-    m2: &mut u8,
-    m2_size: u32,
-    m2_length: &mut u32,
-) -> KexError {
-    // TODO: obtain responder from storage
-    let mut vault = DefaultVault::default();
-    let state = match XXSymmetricState::prologue(&mut vault) {
-        Ok(r) => r,
-        Err(_) => return 1,
-    };
-    let mut responder = Responder::new(state);
-
-    let payload = unsafe { std::slice::from_raw_parts(payload, payload_length as usize) };
-
-    let message2 = match responder.encode_message_2(payload) {
-        Ok(r) => r,
-        Err(_) => return 1,
-    };
-
-    write_bin_to_buffer(&message2, m2, m2_size, m2_length)
+fn kex_error(err: KexExchangeFailError) -> KexError {
+    err.to_usize() as KexError
 }
 
-/// Initiator encodes message 3
-#[no_mangle]
-pub extern "C" fn ockam_kex_xx_initiator_encode_message_3(
-    context: u64,
-    payload: *const u8,
-    payload_length: u32,
-    m3: &mut u8,
-    m3_size: u32,
-    m3_length: &mut u32,
-) -> KexError {
-    // TODO: obtain initiator from storage
-    let mut vault = DefaultVault::default();
-    let state = match XXSymmetricState::prologue(&mut vault) {
-        Ok(r) => r,
-        Err(_) => return 1,
-    };
-    let mut initiator = Initiator::new(state);
-
-    let payload = unsafe { std::slice::from_raw_parts(payload, payload_length as usize) };
-
-    let message3 = match initiator.encode_message_3(payload) {
-        Ok(r) => r,
-        Err(_) => return 1,
-    };
-
-    write_bin_to_buffer(&message3, m3, m3_size, m3_length)
-}
-
-/// Responder decodes message 1
-#[no_mangle]
-pub extern "C" fn ockam_kex_xx_responder_decode_message_1(
-    context: u64,
-    m1: *const u8,
-    m1_length: u32,
-) -> KexError {
-    // TODO: obtain responder from storage
-    let mut vault = DefaultVault::default();
-    let state = match XXSymmetricState::prologue(&mut vault) {
-        Ok(r) => r,
-        Err(_) => return 1,
-    };
-    let mut responder = Responder::new(state);
-
-    let message1 = unsafe { std::slice::from_raw_parts(m1, m1_length as usize) };
-
-    match responder.decode_message_1(message1) {
-        Ok(_) => ERROR_NONE,
-        Err(_) => 1,
+/// A secret handle of 0 means no secret
+fn optional_secret(handle: u64) -> Option<SecretKeyContext> {
+    match handle {
+        0 => None,
+        id => Some(SecretKeyContext::Memory(id as usize)),
     }
 }
 
-/// Initiator decodes message 2
-#[no_mangle]
-pub extern "C" fn ockam_kex_xx_initiator_decode_message_2(
-    context: u64,
-    m2: *const u8,
-    m2_length: u32,
-) -> KexError {
-    // TODO: obtain initiator from storage
-    let mut vault = DefaultVault::default();
-    let state = match XXSymmetricState::prologue(&mut vault) {
-        Ok(r) => r,
-        Err(_) => return 1,
-    };
-    let mut initiator = Initiator::new(state);
-
-    let message2 = unsafe { std::slice::from_raw_parts(m2, m2_length as usize) };
-
-    match initiator.decode_message_2(message2) {
-        Ok(_) => ERROR_NONE,
-        Err(_) => 1,
+/// The suites as numbered in kex.h
+fn cipher_suite(suite: u32) -> Result<CipherSuite, KexExchangeFailError> {
+    match suite {
+        0 => Ok(CipherSuite::Curve25519AesGcmSha256),
+        1 => Ok(CipherSuite::P256Aes128GcmSha256),
+        _ => Err(KeyExchangeFailErrorKind::InvalidParam(2).into()),
     }
 }
 
-/// Responder decodes message 3
-#[no_mangle]
-pub extern "C" fn ockam_kex_xx_responder_decode_message_3(
-    context: u64,
-    m3: *const u8,
-    m3_length: u32,
-) -> KexError {
-    // TODO: obtain responder from storage
-    let mut vault = DefaultVault::default();
-    let state = match XXSymmetricState::prologue(&mut vault) {
-        Ok(r) => r,
-        Err(_) => return 1,
+fn completed_to_ffi(
+    completed: CompletedKeyExchange,
+) -> Result<FfiCompletedKeyExchange, KexExchangeFailError> {
+    let key = match &completed.remote_static_public_key {
+        PublicKey::Curve25519(key) => &key[..],
+        PublicKey::P256(key) => &key[..],
     };
-    let mut responder = Responder::new(state);
-
-    let message3 = unsafe { std::slice::from_raw_parts(m3, m3_length as usize) };
-
-    match responder.decode_message_3(message3) {
-        Ok(_) => ERROR_NONE,
-        Err(_) => 1,
-    }
+    let mut remote_static_public_key = [0u8; 65];
+    remote_static_public_key[..key.len()].copy_from_slice(key);
+    Ok(FfiCompletedKeyExchange {
+        h: completed.h,
+        encrypt_key: completed.encrypt_key.into_ffi_value(),
+        decrypt_key: completed.decrypt_key.into_ffi_value(),
+        local_static_secret: completed.local_static_secret.into_ffi_value(),
+        remote_static_public_key,
+        remote_static_public_key_length: key.len() as u32,
+        exporter_secret: completed.exporter_secret.into_ffi_value(),
+    })
 }
 
-/// Finalize initiator
-#[no_mangle]
-pub extern "C" fn ockam_kex_xx_initiator_finalize(context: u64, kex: &mut u64) -> KexError {
-    // TODO: obtain initiator from storage
-    let mut vault = DefaultVault::default();
-    let state = match XXSymmetricState::prologue(&mut vault) {
-        Ok(r) => r,
-        Err(_) => return 1,
-    };
-    let mut initiator = Initiator::new(state);
-
-    // TODO: Can we use vault from Initiator?
-    let mut vault2 = DefaultVault::default();
-    match initiator.finalize(&mut vault2) {
-        Ok(r) => *kex = 0, // TODO: Set proper context
-        Err(_) => return 1,
-    };
-
-    ERROR_NONE
-}
-
-/// Finalize responder
-#[no_mangle]
-pub extern "C" fn ockam_kex_xx_responder_finalize(context: u64, kex: &mut u64) -> KexError {
-    // TODO: obtain initiator from storage
-    let mut vault = DefaultVault::default();
-    let state = match XXSymmetricState::prologue(&mut vault) {
-        Ok(r) => r,
-        Err(_) => return 1,
-    };
-    let mut responder = Responder::new(state);
-
-    // TODO: Can we use vault from Initiator?
-    let mut vault2 = DefaultVault::default();
-    match responder.finalize(&mut vault2) {
-        Ok(r) => *kex = 0, // TODO: Set proper context
-        Err(_) => return 1,
-    };
-
-    ERROR_NONE
+fn finalize<K: KeyExchanger>(kex: &mut K) -> Result<FfiCompletedKeyExchange, KexExchangeFailError> {
+    if !kex.is_complete() {
+        return Err(KeyExchangeFailErrorKind::MethodCalledOutOfSequence {
+            actual: "finalize",
+            expected: "process",
+        }
+        .into());
+    }
+    completed_to_ffi(kex.finalize()?)
 }
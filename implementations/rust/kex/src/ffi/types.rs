@@ -2,3 +2,25 @@
 pub type KexError = u32;
 /// No error or success
 pub const ERROR_NONE: u32 = 0;
+
+/// The keys of a completed key exchange. The keys are handles of secrets
+/// in the vault the exchange was created with.
+#[repr(C)]
+#[derive(Debug)]
+pub struct FfiCompletedKeyExchange {
+    /// The handshake hash
+    pub h: [u8; 32],
+    /// The key encrypting messages to the peer
+    pub encrypt_key: u64,
+    /// The key decrypting messages from the peer
+    pub decrypt_key: u64,
+    /// This side's identity key
+    pub local_static_secret: u64,
+    /// The peer's identity key, in the first
+    /// `remote_static_public_key_length` bytes
+    pub remote_static_public_key: [u8; 65],
+    /// 32 for a Curve25519 key, 65 for an uncompressed P-256 key
+    pub remote_static_public_key_length: u32,
+    /// The secret exporter keys are derived from
+    pub exporter_secret: u64,
+}
@@ -0,0 +1,349 @@
+use super::types::*;
+use super::{cipher_suite, finalize, kex_error, optional_secret, write_bin_to_buffer};
+use crate::error::{KexExchangeFailError, KeyExchangeFailErrorKind};
+use crate::x3dh::{PreKeyBundle, X3dhInitiator, X3dhNewKeyExchanger, X3dhResponder};
+use crate::{KeyExchanger, NewKeyExchanger};
+use ffi_support::ConcurrentHandleMap;
+use lazy_static::lazy_static;
+use ockam_vault::{ffi::shared_vault, types::SecretKeyContext};
+use std::convert::TryFrom;
+
+lazy_static! {
+    static ref INITIATORS: ConcurrentHandleMap<X3dhInitiator> = ConcurrentHandleMap::new();
+    static ref RESPONDERS: ConcurrentHandleMap<X3dhResponder> = ConcurrentHandleMap::new();
+}
+
+fn new_key_exchanger(
+    vault: u64,
+    suite: u32,
+    identity_key: u64,
+) -> Result<(X3dhNewKeyExchanger, Option<SecretKeyContext>), KexExchangeFailError> {
+    let cipher_suite = cipher_suite(suite)?;
+    let vault = shared_vault(vault)?;
+    Ok((
+        X3dhNewKeyExchanger::new(cipher_suite, vault.clone(), vault),
+        optional_secret(identity_key),
+    ))
+}
+
+/// Create a new X3DH initiator, the enrollee, working with the secrets of
+/// `vault` in `cipher_suite`. `identity_key` is the secret the initiator
+/// enrolls with, on the suite's curve, or 0 to generate one.
+#[no_mangle]
+pub extern "C" fn ockam_kex_x3dh_initiator(
+    initiator: &mut u64,
+    vault: u64,
+    cipher_suite: u32,
+    identity_key: u64,
+) -> KexError {
+    match new_key_exchanger(vault, cipher_suite, identity_key) {
+        Ok((exchanger, identity_key)) => {
+            *initiator = INITIATORS
+                .insert(exchanger.initiator(identity_key))
+                .into_u64();
+            ERROR_NONE
+        }
+        Err(e) => kex_error(e),
+    }
+}
+
+/// Create a new X3DH responder, which hands out a prekey bundle, working
+/// with the secrets of `vault` in `cipher_suite`. `identity_key` is the
+/// secret that signs the bundle, on the suite's curve, or 0 to generate one.
+#[no_mangle]
+pub extern "C" fn ockam_kex_x3dh_responder(
+    responder: &mut u64,
+    vault: u64,
+    cipher_suite: u32,
+    identity_key: u64,
+) -> KexError {
+    match new_key_exchanger(vault, cipher_suite, identity_key) {
+        Ok((exchanger, identity_key)) => {
+            *responder = RESPONDERS
+                .insert(exchanger.responder(identity_key))
+                .into_u64();
+            ERROR_NONE
+        }
+        Err(e) => kex_error(e),
+    }
+}
+
+/// Initiator writes the public key of its ephemeral identity key, which the
+/// responder expects the enrollment from
+#[no_mangle]
+pub extern "C" fn ockam_kex_x3dh_initiator_enrollment_key(
+    initiator: u64,
+    enrollment_key: &mut u8,
+    enrollment_key_size: u32,
+    enrollment_key_length: &mut u32,
+) -> KexError {
+    match INITIATORS.get_mut_u64(initiator, |i| i.process(&[])) {
+        Ok(key) => write_bin_to_buffer(
+            &key,
+            enrollment_key,
+            enrollment_key_size,
+            enrollment_key_length,
+        ),
+        Err(e) => kex_error(e),
+    }
+}
+
+/// Responder exports its prekey bundle
+#[no_mangle]
+pub extern "C" fn ockam_kex_x3dh_responder_prekey_bundle(
+    responder: u64,
+    bundle: &mut u8,
+    bundle_size: u32,
+    bundle_length: &mut u32,
+) -> KexError {
+    match RESPONDERS.get_mut_u64(responder, |r| r.process(&[])) {
+        Ok(b) => write_bin_to_buffer(&b, bundle, bundle_size, bundle_length),
+        Err(e) => kex_error(e),
+    }
+}
+
+/// Initiator imports the responder's prekey bundle, checks its signature
+/// and writes the enrollment message. The initiator is complete after this.
+#[no_mangle]
+pub extern "C" fn ockam_kex_x3dh_initiator_process_prekey_bundle(
+    initiator: u64,
+    bundle: *const u8,
+    bundle_length: u32,
+    enrollment: &mut u8,
+    enrollment_size: u32,
+    enrollment_length: &mut u32,
+) -> KexError {
+    if bundle.is_null() {
+        return kex_error(KeyExchangeFailErrorKind::InvalidParam(1).into());
+    }
+    let bundle = unsafe { std::slice::from_raw_parts(bundle, bundle_length as usize) };
+    if let Err(e) = PreKeyBundle::try_from(bundle) {
+        return kex_error(e);
+    }
+
+    match INITIATORS.get_mut_u64(initiator, |i| i.process(bundle)) {
+        Ok(m) => write_bin_to_buffer(&m, enrollment, enrollment_size, enrollment_length),
+        Err(e) => kex_error(e),
+    }
+}
+
+/// Responder processes the initiator's enrollment key, then its enrollment
+/// message, or both at once with the key first. The responder is complete
+/// once the enrollment checks out.
+#[no_mangle]
+pub extern "C" fn ockam_kex_x3dh_responder_process(
+    responder: u64,
+    message: *const u8,
+    message_length: u32,
+) -> KexError {
+    if message.is_null() {
+        return kex_error(KeyExchangeFailErrorKind::InvalidParam(1).into());
+    }
+    let message = unsafe { std::slice::from_raw_parts(message, message_length as usize) };
+
+    match RESPONDERS.get_mut_u64(responder, |r| r.process(message)) {
+        Ok(_) => ERROR_NONE,
+        Err(e) => kex_error(e),
+    }
+}
+
+/// Finalize initiator
+#[no_mangle]
+pub extern "C" fn ockam_kex_x3dh_initiator_finalize(
+    initiator: u64,
+    kex: &mut FfiCompletedKeyExchange,
+) -> KexError {
+    match INITIATORS.get_mut_u64(initiator, finalize) {
+        Ok(completed) => {
+            *kex = completed;
+            ERROR_NONE
+        }
+        Err(e) => kex_error(e),
+    }
+}
+
+/// Finalize responder
+#[no_mangle]
+pub extern "C" fn ockam_kex_x3dh_responder_finalize(
+    responder: u64,
+    kex: &mut FfiCompletedKeyExchange,
+) -> KexError {
+    match RESPONDERS.get_mut_u64(responder, finalize) {
+        Ok(completed) => {
+            *kex = completed;
+            ERROR_NONE
+        }
+        Err(e) => kex_error(e),
+    }
+}
+
+/// Destroy the initiator's own secrets and release its handle. The keys of
+/// a finalized exchange stay in the vault.
+#[no_mangle]
+pub extern "C" fn ockam_kex_x3dh_initiator_deinit(initiator: u64) -> KexError {
+    match INITIATORS.remove_u64(initiator) {
+        Ok(Some(mut i)) => match i.destroy() {
+            Ok(()) => ERROR_NONE,
+            Err(e) => kex_error(e.into()),
+        },
+        _ => kex_error(KeyExchangeFailErrorKind::InvalidParam(0).into()),
+    }
+}
+
+/// Destroy the responder's prekeys and release its handle. The keys of a
+/// finalized exchange stay in the vault.
+#[no_mangle]
+pub extern "C" fn ockam_kex_x3dh_responder_deinit(responder: u64) -> KexError {
+    match RESPONDERS.remove_u64(responder) {
+        Ok(Some(mut r)) => match r.destroy() {
+            Ok(()) => ERROR_NONE,
+            Err(e) => kex_error(e.into()),
+        },
+        _ => kex_error(KeyExchangeFailErrorKind::InvalidParam(0).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ockam_vault::ffi::{ockam_vault_default_init, ockam_vault_deinit};
+
+    fn completed() -> FfiCompletedKeyExchange {
+        FfiCompletedKeyExchange {
+            h: [0u8; 32],
+            encrypt_key: 0,
+            decrypt_key: 0,
+            local_static_secret: 0,
+            remote_static_public_key: [0u8; 65],
+            remote_static_public_key_length: 0,
+            exporter_secret: 0,
+        }
+    }
+
+    #[test]
+    fn enrollment() {
+        enroll(0, 32);
+    }
+
+    #[test]
+    fn p256_enrollment() {
+        enroll(1, 65);
+    }
+
+    #[test]
+    fn unknown_suite() {
+        let mut vault = 0;
+        assert_eq!(ockam_vault_default_init(&mut vault), ERROR_NONE);
+        assert_ne!(ockam_kex_x3dh_initiator(&mut 0, vault, 2, 0), ERROR_NONE);
+        assert_eq!(ockam_vault_deinit(vault), ERROR_NONE);
+    }
+
+    fn enroll(suite: u32, key_size: u32) {
+        let mut vault = 0;
+        assert_eq!(ockam_vault_default_init(&mut vault), ERROR_NONE);
+        let (mut initiator, mut responder) = (0, 0);
+        assert_eq!(
+            ockam_kex_x3dh_initiator(&mut initiator, vault, suite, 0),
+            ERROR_NONE
+        );
+        assert_eq!(
+            ockam_kex_x3dh_responder(&mut responder, vault, suite, 0),
+            ERROR_NONE
+        );
+
+        let mut key = [0u8; 65];
+        let mut key_length = 0;
+        assert_eq!(
+            ockam_kex_x3dh_initiator_enrollment_key(initiator, &mut key[0], 65, &mut key_length),
+            ERROR_NONE
+        );
+        assert_eq!(key_length, key_size);
+        let mut bundle = [0u8; 512];
+        let mut bundle_length = 0;
+        assert_eq!(
+            ockam_kex_x3dh_responder_prekey_bundle(
+                responder,
+                &mut bundle[0],
+                512,
+                &mut bundle_length
+            ),
+            ERROR_NONE
+        );
+        assert_ne!(
+            ockam_kex_x3dh_initiator_process_prekey_bundle(
+                initiator,
+                bundle.as_ptr(),
+                bundle_length - 1,
+                &mut [0u8; 512][0],
+                512,
+                &mut 0
+            ),
+            ERROR_NONE
+        );
+        let mut enrollment = [0u8; 512];
+        let mut enrollment_length = 0;
+        assert_eq!(
+            ockam_kex_x3dh_initiator_process_prekey_bundle(
+                initiator,
+                bundle.as_ptr(),
+                bundle_length,
+                &mut enrollment[0],
+                512,
+                &mut enrollment_length
+            ),
+            ERROR_NONE
+        );
+
+        let mut kex = completed();
+        assert_ne!(
+            ockam_kex_x3dh_responder_finalize(responder, &mut kex),
+            ERROR_NONE
+        );
+        assert_eq!(
+            ockam_kex_x3dh_responder_process(responder, key.as_ptr(), key_length),
+            ERROR_NONE
+        );
+        assert_eq!(
+            ockam_kex_x3dh_responder_process(responder, enrollment.as_ptr(), enrollment_length),
+            ERROR_NONE
+        );
+
+        let mut initiator_kex = completed();
+        assert_eq!(
+            ockam_kex_x3dh_initiator_finalize(initiator, &mut initiator_kex),
+            ERROR_NONE
+        );
+        let mut responder_kex = completed();
+        assert_eq!(
+            ockam_kex_x3dh_responder_finalize(responder, &mut responder_kex),
+            ERROR_NONE
+        );
+        assert_eq!(initiator_kex.h, responder_kex.h);
+        assert_eq!(responder_kex.remote_static_public_key_length, key_size);
+        let shared = shared_vault(vault).unwrap();
+        let mut v = shared.lock().unwrap();
+        let ciphertext = v
+            .aead_aes_gcm_encrypt(
+                SecretKeyContext::Memory(initiator_kex.encrypt_key as usize),
+                b"hello",
+                &[0u8; 12],
+                &[],
+            )
+            .unwrap();
+        let plaintext = v
+            .aead_aes_gcm_decrypt(
+                SecretKeyContext::Memory(responder_kex.decrypt_key as usize),
+                &ciphertext,
+                &[0u8; 12],
+                &[],
+            )
+            .unwrap();
+        assert_eq!(plaintext, b"hello");
+        drop(v);
+
+        assert_eq!(ockam_kex_x3dh_initiator_deinit(initiator), ERROR_NONE);
+        assert_eq!(ockam_kex_x3dh_responder_deinit(responder), ERROR_NONE);
+        assert_ne!(ockam_kex_x3dh_responder_deinit(responder), ERROR_NONE);
+        assert_eq!(ockam_vault_deinit(vault), ERROR_NONE);
+    }
+}
@@ -0,0 +1,410 @@
+use super::types::*;
+use super::{finalize, kex_error, write_bin_to_buffer};
+use crate::error::{KexExchangeFailError, KeyExchangeFailErrorKind};
+use crate::pattern::HandshakeState;
+use crate::xx::{XXInitiator, XXNewKeyExchanger, XXResponder};
+use crate::{CipherSuite, KeyExchanger, NewKeyExchanger};
+use ffi_support::ConcurrentHandleMap;
+use lazy_static::lazy_static;
+use ockam_vault::ffi::shared_vault;
+
+lazy_static! {
+    static ref INITIATORS: ConcurrentHandleMap<XXInitiator> = ConcurrentHandleMap::new();
+    static ref RESPONDERS: ConcurrentHandleMap<XXResponder> = ConcurrentHandleMap::new();
+}
+
+fn new_key_exchanger(vault: u64) -> Result<XXNewKeyExchanger, KexExchangeFailError> {
+    let vault = shared_vault(vault)?;
+    Ok(XXNewKeyExchanger::new(
+        CipherSuite::Curve25519AesGcmSha256,
+        vault.clone(),
+        vault,
+    ))
+}
+
+/// A null pointer is only a valid buffer when it is empty
+fn bytes<'a>(data: *const u8, length: u32) -> Result<&'a [u8], KexExchangeFailError> {
+    if data.is_null() {
+        if length == 0 {
+            return Ok(&[]);
+        }
+        return Err(KeyExchangeFailErrorKind::InvalidParam(1).into());
+    }
+    Ok(unsafe { std::slice::from_raw_parts(data, length as usize) })
+}
+
+/// Write the next message, if it is this side's turn to write
+fn encode(kex: &mut HandshakeState, payload: &[u8]) -> Result<Vec<u8>, KexExchangeFailError> {
+    if kex.is_complete() || !kex.is_writer() {
+        return Err(KeyExchangeFailErrorKind::MethodCalledOutOfSequence {
+            actual: "encode",
+            expected: "decode",
+        }
+        .into());
+    }
+    kex.process(payload)
+}
+
+/// Read the next message, if it is this side's turn to read
+fn decode(kex: &mut HandshakeState, message: &[u8]) -> Result<Vec<u8>, KexExchangeFailError> {
+    if kex.is_complete() || kex.is_writer() {
+        return Err(KeyExchangeFailErrorKind::MethodCalledOutOfSequence {
+            actual: "decode",
+            expected: "encode",
+        }
+        .into());
+    }
+    kex.process(message)
+}
+
+fn encode_message(
+    kexs: &ConcurrentHandleMap<HandshakeState>,
+    context: u64,
+    payload: *const u8,
+    payload_length: u32,
+    m: &mut u8,
+    m_size: u32,
+    m_length: &mut u32,
+) -> KexError {
+    let payload = match bytes(payload, payload_length) {
+        Ok(p) => p,
+        Err(e) => return kex_error(e),
+    };
+    match kexs.get_mut_u64(context, |k| encode(k, payload)) {
+        Ok(message) => write_bin_to_buffer(&message, m, m_size, m_length),
+        Err(e) => kex_error(e),
+    }
+}
+
+fn decode_message(
+    kexs: &ConcurrentHandleMap<HandshakeState>,
+    context: u64,
+    m: *const u8,
+    m_length: u32,
+) -> KexError {
+    let message = match bytes(m, m_length) {
+        Ok(m) => m,
+        Err(e) => return kex_error(e),
+    };
+    match kexs.get_mut_u64(context, |k| decode(k, message)) {
+        Ok(_) => ERROR_NONE,
+        Err(e) => kex_error(e),
+    }
+}
+
+fn finalize_into(
+    kexs: &ConcurrentHandleMap<HandshakeState>,
+    context: u64,
+    kex: &mut FfiCompletedKeyExchange,
+) -> KexError {
+    match kexs.get_mut_u64(context, finalize) {
+        Ok(completed) => {
+            *kex = completed;
+            ERROR_NONE
+        }
+        Err(e) => kex_error(e),
+    }
+}
+
+fn deinit(kexs: &ConcurrentHandleMap<HandshakeState>, context: u64) -> KexError {
+    match kexs.remove_u64(context) {
+        Ok(Some(mut k)) => match k.destroy() {
+            Ok(()) => ERROR_NONE,
+            Err(e) => kex_error(e.into()),
+        },
+        _ => kex_error(KeyExchangeFailErrorKind::InvalidParam(0).into()),
+    }
+}
+
+/// Create a new XX initiator working with the secrets of `vault`. It
+/// generates an identity key for the handshake when it writes message 1.
+#[no_mangle]
+pub extern "C" fn ockam_kex_xx_initiator(context: &mut u64, vault: u64) -> KexError {
+    match new_key_exchanger(vault) {
+        Ok(exchanger) => {
+            *context = INITIATORS.insert(exchanger.initiator(None)).into_u64();
+            ERROR_NONE
+        }
+        Err(e) => kex_error(e),
+    }
+}
+
+/// Create a new XX responder working with the secrets of `vault`. It
+/// generates an identity key for the handshake when it reads message 1.
+#[no_mangle]
+pub extern "C" fn ockam_kex_xx_responder(context: &mut u64, vault: u64) -> KexError {
+    match new_key_exchanger(vault) {
+        Ok(exchanger) => {
+            *context = RESPONDERS.insert(exchanger.responder(None)).into_u64();
+            ERROR_NONE
+        }
+        Err(e) => kex_error(e),
+    }
+}
+
+/// Initiator encodes message 1
+#[no_mangle]
+pub extern "C" fn ockam_kex_xx_initiator_encode_message_1(
+    context: u64,
+    payload: *const u8,
+    payload_length: u32,
+    m1: &mut u8,
+    m1_size: u32,
+    m1_length: &mut u32,
+) -> KexError {
+    encode_message(
+        &INITIATORS,
+        context,
+        payload,
+        payload_length,
+        m1,
+        m1_size,
+        m1_length,
+    )
+}
+
+/// Responder encodes message 2
+#[no_mangle]
+pub extern "C" fn ockam_kex_xx_responder_encode_message_2(
+    context: u64,
+    payload: *const u8,
+    payload_length: u32,
+    m2: &mut u8,
+    m2_size: u32,
+    m2_length: &mut u32,
+) -> KexError {
+    encode_message(
+        &RESPONDERS,
+        context,
+        payload,
+        payload_length,
+        m2,
+        m2_size,
+        m2_length,
+    )
+}
+
+/// Initiator encodes message 3. The initiator is complete after this.
+#[no_mangle]
+pub extern "C" fn ockam_kex_xx_initiator_encode_message_3(
+    context: u64,
+    payload: *const u8,
+    payload_length: u32,
+    m3: &mut u8,
+    m3_size: u32,
+    m3_length: &mut u32,
+) -> KexError {
+    encode_message(
+        &INITIATORS,
+        context,
+        payload,
+        payload_length,
+        m3,
+        m3_size,
+        m3_length,
+    )
+}
+
+/// Responder decodes message 1
+#[no_mangle]
+pub extern "C" fn ockam_kex_xx_responder_decode_message_1(
+    context: u64,
+    m1: *const u8,
+    m1_length: u32,
+) -> KexError {
+    decode_message(&RESPONDERS, context, m1, m1_length)
+}
+
+/// Initiator decodes message 2
+#[no_mangle]
+pub extern "C" fn ockam_kex_xx_initiator_decode_message_2(
+    context: u64,
+    m2: *const u8,
+    m2_length: u32,
+) -> KexError {
+    decode_message(&INITIATORS, context, m2, m2_length)
+}
+
+/// Responder decodes message 3. The responder is complete after this.
+#[no_mangle]
+pub extern "C" fn ockam_kex_xx_responder_decode_message_3(
+    context: u64,
+    m3: *const u8,
+    m3_length: u32,
+) -> KexError {
+    decode_message(&RESPONDERS, context, m3, m3_length)
+}
+
+/// Finalize initiator
+#[no_mangle]
+pub extern "C" fn ockam_kex_xx_initiator_finalize(
+    context: u64,
+    kex: &mut FfiCompletedKeyExchange,
+) -> KexError {
+    finalize_into(&INITIATORS, context, kex)
+}
+
+/// Finalize responder
+#[no_mangle]
+pub extern "C" fn ockam_kex_xx_responder_finalize(
+    context: u64,
+    kex: &mut FfiCompletedKeyExchange,
+) -> KexError {
+    finalize_into(&RESPONDERS, context, kex)
+}
+
+/// Destroy the initiator's handshake secrets, including the identity key it
+/// generated, and release its handle. The encryption, decryption and
+/// exporter keys of a finalized exchange stay in the vault.
+#[no_mangle]
+pub extern "C" fn ockam_kex_xx_initiator_deinit(context: u64) -> KexError {
+    deinit(&INITIATORS, context)
+}
+
+/// Destroy the responder's handshake secrets, including the identity key it
+/// generated, and release its handle. The encryption, decryption and
+/// exporter keys of a finalized exchange stay in the vault.
+#[no_mangle]
+pub extern "C" fn ockam_kex_xx_responder_deinit(context: u64) -> KexError {
+    deinit(&RESPONDERS, context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ockam_vault::{
+        ffi::{ockam_vault_default_init, ockam_vault_deinit},
+        types::SecretKeyContext,
+    };
+
+    fn completed() -> FfiCompletedKeyExchange {
+        FfiCompletedKeyExchange {
+            h: [0u8; 32],
+            encrypt_key: 0,
+            decrypt_key: 0,
+            local_static_secret: 0,
+            remote_static_public_key: [0u8; 65],
+            remote_static_public_key_length: 0,
+            exporter_secret: 0,
+        }
+    }
+
+    #[test]
+    fn handshake() {
+        let mut vault = 0;
+        assert_eq!(ockam_vault_default_init(&mut vault), ERROR_NONE);
+        let (mut initiator, mut responder) = (0, 0);
+        assert_eq!(ockam_kex_xx_initiator(&mut initiator, vault), ERROR_NONE);
+        assert_eq!(ockam_kex_xx_responder(&mut responder, vault), ERROR_NONE);
+
+        let mut m = [0u8; 256];
+        let mut m_length = 0;
+        assert_eq!(
+            ockam_kex_xx_initiator_encode_message_1(
+                initiator,
+                std::ptr::null(),
+                0,
+                &mut m[0],
+                256,
+                &mut m_length
+            ),
+            ERROR_NONE
+        );
+        assert_ne!(
+            ockam_kex_xx_initiator_encode_message_3(
+                initiator,
+                std::ptr::null(),
+                0,
+                &mut [0u8; 256][0],
+                256,
+                &mut 0
+            ),
+            ERROR_NONE
+        );
+        assert_eq!(
+            ockam_kex_xx_responder_decode_message_1(responder, m.as_ptr(), m_length),
+            ERROR_NONE
+        );
+        assert_eq!(
+            ockam_kex_xx_responder_encode_message_2(
+                responder,
+                std::ptr::null(),
+                0,
+                &mut m[0],
+                256,
+                &mut m_length
+            ),
+            ERROR_NONE
+        );
+        assert_eq!(
+            ockam_kex_xx_initiator_decode_message_2(initiator, m.as_ptr(), m_length),
+            ERROR_NONE
+        );
+        let payload = b"hello";
+        assert_eq!(
+            ockam_kex_xx_initiator_encode_message_3(
+                initiator,
+                payload.as_ptr(),
+                payload.len() as u32,
+                &mut m[0],
+                256,
+                &mut m_length
+            ),
+            ERROR_NONE
+        );
+
+        let mut kex = completed();
+        assert_ne!(
+            ockam_kex_xx_responder_finalize(responder, &mut kex),
+            ERROR_NONE
+        );
+        assert_eq!(
+            ockam_kex_xx_responder_decode_message_3(responder, m.as_ptr(), m_length),
+            ERROR_NONE
+        );
+
+        let mut initiator_kex = completed();
+        assert_eq!(
+            ockam_kex_xx_initiator_finalize(initiator, &mut initiator_kex),
+            ERROR_NONE
+        );
+        let mut responder_kex = completed();
+        assert_eq!(
+            ockam_kex_xx_responder_finalize(responder, &mut responder_kex),
+            ERROR_NONE
+        );
+        assert_eq!(initiator_kex.h, responder_kex.h);
+        assert_eq!(initiator_kex.remote_static_public_key_length, 32);
+        assert_eq!(responder_kex.remote_static_public_key_length, 32);
+        let shared = shared_vault(vault).unwrap();
+        let mut v = shared.lock().unwrap();
+        let ciphertext = v
+            .aead_aes_gcm_encrypt(
+                SecretKeyContext::Memory(responder_kex.encrypt_key as usize),
+                b"hello",
+                &[0u8; 12],
+                &[],
+            )
+            .unwrap();
+        let plaintext = v
+            .aead_aes_gcm_decrypt(
+                SecretKeyContext::Memory(initiator_kex.decrypt_key as usize),
+                &ciphertext,
+                &[0u8; 12],
+                &[],
+            )
+            .unwrap();
+        assert_eq!(plaintext, b"hello");
+        drop(v);
+
+        assert_eq!(ockam_kex_xx_initiator_deinit(initiator), ERROR_NONE);
+        assert_eq!(ockam_kex_xx_responder_deinit(responder), ERROR_NONE);
+        assert_ne!(ockam_kex_xx_responder_deinit(responder), ERROR_NONE);
+        assert_eq!(ockam_vault_deinit(vault), ERROR_NONE);
+    }
+
+    #[test]
+    fn unknown_vault() {
+        assert_ne!(ockam_kex_xx_initiator(&mut 0, 0), ERROR_NONE);
+    }
+}
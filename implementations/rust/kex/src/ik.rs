@@ -0,0 +1,296 @@
+use crate::pattern::{HandshakePattern, HandshakeState, Token};
+use crate::{CipherSuite, NewKeyExchanger};
+use ockam_vault::{
+    types::{PublicKey, SecretKeyContext},
+    DynVault,
+};
+use std::sync::{Arc, Mutex};
+
+/// The Noise IK pattern: the initiator knows the responder's static key
+/// before it starts, so its own static key goes out encrypted to that key
+/// in the first message and the handshake takes one round trip
+pub static IK: HandshakePattern = HandshakePattern {
+    name: "IK",
+    initiator_pre_message: &[],
+    responder_pre_message: &[Token::S],
+    messages: &[
+        &[Token::E, Token::Es, Token::S, Token::Ss],
+        &[Token::E, Token::Ee, Token::Se],
+    ],
+    psk_variant: Some(&IK_PSK2),
+    hfs_variant: Some(&IK_HFS),
+};
+
+/// IK with a pre-shared key mixed in at the end of the second message
+pub static IK_PSK2: HandshakePattern = HandshakePattern {
+    name: "IKpsk2",
+    initiator_pre_message: &[],
+    responder_pre_message: &[Token::S],
+    messages: &[
+        &[Token::E, Token::Es, Token::S, Token::Ss],
+        &[Token::E, Token::Ee, Token::Se, Token::Psk],
+    ],
+    psk_variant: None,
+    hfs_variant: None,
+};
+
+/// IK with hybrid forward secrecy
+pub static IK_HFS: HandshakePattern = HandshakePattern {
+    name: "IKhfs",
+    initiator_pre_message: &[],
+    responder_pre_message: &[Token::S],
+    messages: &[
+        &[Token::E, Token::Es, Token::E1, Token::S, Token::Ss],
+        &[Token::E, Token::Ee, Token::Ekem1, Token::Se],
+    ],
+    psk_variant: None,
+    hfs_variant: None,
+};
+
+/// Represents an IK initiator
+pub type IKInitiator = HandshakeState;
+
+/// Represents an IK responder
+pub type IKResponder = HandshakeState;
+
+/// Represents an IK NewKeyExchanger. Initiators it creates handshake with
+/// the responder whose static public key it was given.
+pub struct IKNewKeyExchanger {
+    cipher_suite: CipherSuite,
+    vault_initiator: Arc<Mutex<dyn DynVault + Send>>,
+    vault_responder: Arc<Mutex<dyn DynVault + Send>>,
+    responder_public_key: Option<PublicKey>,
+}
+
+impl std::fmt::Debug for IKNewKeyExchanger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "IKNewKeyExchanger {{ cipher_suite: {:?}, responder_public_key: {:?} }}",
+            self.cipher_suite, self.responder_public_key
+        )
+    }
+}
+
+impl IKNewKeyExchanger {
+    /// Create a new IKNewKeyExchanger. Without the responder's public key
+    /// it can only create responders; initiators fail their first message.
+    pub fn new(
+        cipher_suite: CipherSuite,
+        vault_initiator: Arc<Mutex<dyn DynVault + Send>>,
+        vault_responder: Arc<Mutex<dyn DynVault + Send>>,
+        responder_public_key: Option<PublicKey>,
+    ) -> Self {
+        Self {
+            cipher_suite,
+            vault_initiator,
+            vault_responder,
+            responder_public_key,
+        }
+    }
+}
+
+impl NewKeyExchanger<IKInitiator, IKResponder> for IKNewKeyExchanger {
+    /// Create a new initiator using the provided backing vault
+    fn initiator(&self, identity_key: Option<SecretKeyContext>) -> IKInitiator {
+        HandshakeState::new(
+            &IK,
+            true,
+            self.cipher_suite,
+            self.vault_initiator.clone(),
+            identity_key,
+            self.responder_public_key,
+        )
+    }
+
+    /// Create a new responder using the provided backing vault
+    fn responder(&self, identity_key: Option<SecretKeyContext>) -> IKResponder {
+        HandshakeState::new(
+            &IK,
+            false,
+            self.cipher_suite,
+            self.vault_responder.clone(),
+            identity_key,
+            None,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xx::SymmetricState;
+    use crate::KeyExchanger;
+    use ockam_vault::software::DefaultVault;
+    use ockam_vault::types::{
+        SecretKey, SecretKeyAttributes, SecretKeyType, SecretPersistenceType, SecretPurposeType,
+    };
+
+    fn responder_key(vault: &Arc<Mutex<DefaultVault>>) -> (SecretKeyContext, PublicKey) {
+        let mut vault = vault.lock().unwrap();
+        let attributes = SecretKeyAttributes {
+            xtype: SecretKeyType::Curve25519,
+            purpose: SecretPurposeType::KeyAgreement,
+            persistence: SecretPersistenceType::Persistent,
+        };
+        let secret = vault.secret_generate(attributes).unwrap();
+        (secret, vault.secret_public_key_get(secret).unwrap())
+    }
+
+    #[test]
+    fn handshake() {
+        let vault_initiator = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_responder = Arc::new(Mutex::new(DefaultVault::default()));
+        let (secret, public) = responder_key(&vault_responder);
+        let key_exchanger = IKNewKeyExchanger::new(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault_initiator.clone(),
+            vault_responder.clone(),
+            Some(public),
+        );
+        let mut initiator = key_exchanger.initiator(None);
+        let mut responder = key_exchanger.responder(Some(secret));
+
+        let m1 = initiator.process(b"hello").unwrap();
+        assert_eq!(responder.process(&m1).unwrap(), b"hello");
+        let m2 = responder.process(b"world").unwrap();
+        assert!(responder.is_complete());
+        assert_eq!(initiator.process(&m2).unwrap(), b"world");
+        assert!(initiator.is_complete());
+
+        let initiator = initiator.finalize().unwrap();
+        let responder = responder.finalize().unwrap();
+        assert_eq!(initiator.h, responder.h);
+        assert_eq!(initiator.remote_static_public_key.as_ref(), public.as_ref());
+
+        let mut vault_in = vault_initiator.lock().unwrap();
+        let mut vault_re = vault_responder.lock().unwrap();
+        assert_eq!(
+            vault_in.secret_export(initiator.encrypt_key).unwrap(),
+            vault_re.secret_export(responder.decrypt_key).unwrap()
+        );
+        assert_eq!(
+            vault_in.secret_export(initiator.decrypt_key).unwrap(),
+            vault_re.secret_export(responder.encrypt_key).unwrap()
+        );
+    }
+
+    #[test]
+    fn handshake_vectors() {
+        const INIT_STATIC: &str =
+            "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        const INIT_EPH: &str = "202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f";
+        const RESP_STATIC: &str =
+            "0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20";
+        const RESP_EPH: &str = "4142434445464748494a4b4c4d4e4f505152535455565758595a5b5c5d5e5f60";
+        const MSG_1_PAYLOAD: &str = "746573745f6d73675f30";
+        const MSG_1_CIPHERTEXT: &str = "358072d6365880d1aeea329adf9121383851ed21a28e3b75e965d0d2cd16625419d6fab175300a577115c701c41ed681373f0432f81d3bf8676bd05216cd1919ba2eaa418fdd8e09ae59d7cf57869de4e6d8177aa9777fe9b843100e255aee76034f61b96b52af38660c";
+        const MSG_2_PAYLOAD: &str = "746573745f6d73675f31";
+        const MSG_2_CIPHERTEXT: &str = "64b101b1d0be5a8704bd078f9895001fc03e8e9f9522f188dd128d9846d4846658a7bb8caac509783390e5a04df4a3ca570b2bcdf65f8c1c40cd";
+
+        let vault_init = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_resp = Arc::new(Mutex::new(DefaultVault::default()));
+        let mut ss_init = SymmetricState::with_keys("IK", vault_init, INIT_STATIC, INIT_EPH);
+        let ss_resp = SymmetricState::with_keys("IK", vault_resp, RESP_STATIC, RESP_EPH);
+        ss_init.remote_static_public_key = ss_resp.static_key_pair.map(|kp| kp.public_key);
+        let mut initiator = HandshakeState::from_state(&IK, true, ss_init, false);
+        let mut responder = HandshakeState::from_state(&IK, false, ss_resp, false);
+
+        let msg1 = initiator
+            .process(&hex::decode(MSG_1_PAYLOAD).unwrap())
+            .unwrap();
+        assert_eq!(hex::encode(&msg1), MSG_1_CIPHERTEXT);
+        assert_eq!(
+            hex::encode(responder.process(&msg1).unwrap()),
+            MSG_1_PAYLOAD
+        );
+        let msg2 = responder
+            .process(&hex::decode(MSG_2_PAYLOAD).unwrap())
+            .unwrap();
+        assert_eq!(hex::encode(&msg2), MSG_2_CIPHERTEXT);
+        assert_eq!(
+            hex::encode(initiator.process(&msg2).unwrap()),
+            MSG_2_PAYLOAD
+        );
+    }
+
+    #[test]
+    fn handshake_psk2_vectors() {
+        const INIT_STATIC: &str =
+            "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        const INIT_EPH: &str = "202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f";
+        const RESP_STATIC: &str =
+            "0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20";
+        const RESP_EPH: &str = "4142434445464748494a4b4c4d4e4f505152535455565758595a5b5c5d5e5f60";
+        const PSK: &str = "54686973206973206d7920417573747269616e20706572737065637469766521";
+        const MSG_1_PAYLOAD: &str = "746573745f6d73675f30";
+        const MSG_1_CIPHERTEXT: &str = "358072d6365880d1aeea329adf9121383851ed21a28e3b75e965d0d2cd1662540322be5210eec7e84567f5b4ad376b908b7c38a587eb71776e0661a6ca9f3ef251962835db06e694781bcf163d3cb38d2dbe7ee0a408573e5466e702c802dce4b432bd175dff1a5caae4";
+        const MSG_2_PAYLOAD: &str = "746573745f6d73675f31";
+        const MSG_2_CIPHERTEXT: &str = "64b101b1d0be5a8704bd078f9895001fc03e8e9f9522f188dd128d9846d48466534997405ebed396386152aa316711e542d97815128573a8d672";
+        const H: &str = "c5111b269201f5ba74728dce3abda2a632f6819b2cbb4b8c877c39ac9da890bb";
+
+        let vault_init = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_resp = Arc::new(Mutex::new(DefaultVault::default()));
+        let import_psk = |vault: &Arc<Mutex<DefaultVault>>| {
+            let attributes = SecretKeyAttributes {
+                xtype: SecretKeyType::Buffer(32),
+                purpose: SecretPurposeType::KeyAgreement,
+                persistence: SecretPersistenceType::Ephemeral,
+            };
+            let psk = SecretKey::Buffer(hex::decode(PSK).unwrap());
+            vault
+                .lock()
+                .unwrap()
+                .secret_import(&psk, attributes)
+                .unwrap()
+        };
+        let psk_init = import_psk(&vault_init);
+        let psk_resp = import_psk(&vault_resp);
+        let mut ss_init = SymmetricState::with_keys("IKpsk2", vault_init, INIT_STATIC, INIT_EPH);
+        let mut ss_resp = SymmetricState::with_keys("IKpsk2", vault_resp, RESP_STATIC, RESP_EPH);
+        ss_init.remote_static_public_key = ss_resp.static_key_pair.map(|kp| kp.public_key);
+        ss_init.psk = Some(psk_init);
+        ss_resp.psk = Some(psk_resp);
+        let mut initiator = HandshakeState::from_state(&IK_PSK2, true, ss_init, false);
+        let mut responder = HandshakeState::from_state(&IK_PSK2, false, ss_resp, false);
+
+        let msg1 = initiator
+            .process(&hex::decode(MSG_1_PAYLOAD).unwrap())
+            .unwrap();
+        assert_eq!(hex::encode(&msg1), MSG_1_CIPHERTEXT);
+        assert_eq!(
+            hex::encode(responder.process(&msg1).unwrap()),
+            MSG_1_PAYLOAD
+        );
+        let msg2 = responder
+            .process(&hex::decode(MSG_2_PAYLOAD).unwrap())
+            .unwrap();
+        assert_eq!(hex::encode(&msg2), MSG_2_CIPHERTEXT);
+        assert_eq!(
+            hex::encode(initiator.process(&msg2).unwrap()),
+            MSG_2_PAYLOAD
+        );
+
+        assert_eq!(hex::encode(initiator.finalize().unwrap().h), H);
+        assert_eq!(hex::encode(responder.finalize().unwrap().h), H);
+    }
+
+    #[test]
+    fn wrong_responder_key_fails() {
+        let vault_initiator = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_responder = Arc::new(Mutex::new(DefaultVault::default()));
+        let (secret, _) = responder_key(&vault_responder);
+        let (_, other) = responder_key(&vault_responder);
+        let key_exchanger = IKNewKeyExchanger::new(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault_initiator,
+            vault_responder,
+            Some(other),
+        );
+        let mut initiator = key_exchanger.initiator(None);
+        let mut responder = key_exchanger.responder(Some(secret));
+
+        let m1 = initiator.process(&[]).unwrap();
+        assert!(responder.process(&m1).is_err());
+    }
+}
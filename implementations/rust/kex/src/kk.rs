@@ -0,0 +1,302 @@
+use crate::pattern::{HandshakePattern, HandshakeState, Token};
+use crate::{CipherSuite, NewKeyExchanger};
+use ockam_vault::{
+    types::{PublicKey, SecretKeyContext},
+    DynVault,
+};
+use std::sync::{Arc, Mutex};
+
+/// The Noise KK pattern: both parties know each other's static public key
+/// before they start, so neither key is sent and the handshake takes one
+/// round trip
+pub static KK: HandshakePattern = HandshakePattern {
+    name: "KK",
+    initiator_pre_message: &[Token::S],
+    responder_pre_message: &[Token::S],
+    messages: &[
+        &[Token::E, Token::Es, Token::Ss],
+        &[Token::E, Token::Ee, Token::Se],
+    ],
+    psk_variant: None,
+    hfs_variant: Some(&KK_HFS),
+};
+
+/// KK with hybrid forward secrecy
+pub static KK_HFS: HandshakePattern = HandshakePattern {
+    name: "KKhfs",
+    initiator_pre_message: &[Token::S],
+    responder_pre_message: &[Token::S],
+    messages: &[
+        &[Token::E, Token::Es, Token::Ss, Token::E1],
+        &[Token::E, Token::Ee, Token::Ekem1, Token::Se],
+    ],
+    psk_variant: None,
+    hfs_variant: None,
+};
+
+/// Represents a KK initiator
+pub type KKInitiator = HandshakeState;
+
+/// Represents a KK responder. It only completes handshakes with the
+/// initiator whose static public key it was given.
+pub type KKResponder = HandshakeState;
+
+/// Represents a KK NewKeyExchanger. Everything it creates handshakes with
+/// the one peer whose static public key it was given, whichever side
+/// starts the handshake.
+pub struct KKNewKeyExchanger {
+    cipher_suite: CipherSuite,
+    vault_initiator: Arc<Mutex<dyn DynVault + Send>>,
+    vault_responder: Arc<Mutex<dyn DynVault + Send>>,
+    remote_public_key: PublicKey,
+}
+
+impl std::fmt::Debug for KKNewKeyExchanger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "KKNewKeyExchanger {{ cipher_suite: {:?}, remote_public_key: {:?} }}",
+            self.cipher_suite, self.remote_public_key
+        )
+    }
+}
+
+impl KKNewKeyExchanger {
+    /// Create a new KKNewKeyExchanger for the peer with `remote_public_key`
+    pub fn new(
+        cipher_suite: CipherSuite,
+        vault_initiator: Arc<Mutex<dyn DynVault + Send>>,
+        vault_responder: Arc<Mutex<dyn DynVault + Send>>,
+        remote_public_key: PublicKey,
+    ) -> Self {
+        Self {
+            cipher_suite,
+            vault_initiator,
+            vault_responder,
+            remote_public_key,
+        }
+    }
+}
+
+impl NewKeyExchanger<KKInitiator, KKResponder> for KKNewKeyExchanger {
+    /// Create a new initiator using the provided backing vault
+    fn initiator(&self, identity_key: Option<SecretKeyContext>) -> KKInitiator {
+        HandshakeState::new(
+            &KK,
+            true,
+            self.cipher_suite,
+            self.vault_initiator.clone(),
+            identity_key,
+            Some(self.remote_public_key),
+        )
+    }
+
+    /// Create a new responder using the provided backing vault
+    fn responder(&self, identity_key: Option<SecretKeyContext>) -> KKResponder {
+        HandshakeState::new(
+            &KK,
+            false,
+            self.cipher_suite,
+            self.vault_responder.clone(),
+            identity_key,
+            Some(self.remote_public_key),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xx::SymmetricState;
+    use crate::KeyExchanger;
+    use ockam_vault::software::DefaultVault;
+    use ockam_vault::types::{
+        SecretKeyAttributes, SecretKeyType, SecretPersistenceType, SecretPurposeType,
+    };
+
+    /// The keys, prologue and handshake messages of a published
+    /// Noise_KK_25519_AESGCM_SHA256 vector, from the cacophony and snow
+    /// vector files
+    struct Vector {
+        init_static: Option<&'static str>,
+        init_ephemeral: &'static str,
+        resp_static: &'static str,
+        resp_ephemeral: &'static str,
+        prologue: &'static str,
+        messages: [(&'static str, &'static str); 2],
+        handshake_hash: Option<&'static str>,
+    }
+
+    const CACOPHONY: Vector = Vector {
+        init_static: Some("e61ef9919cde45dd5f82166404bd08e38bceb5dfdfded0a34c8df7ed542214d1"),
+        init_ephemeral: "893e28b9dc6ca8d611ab664754b8ceb7bac5117349a4439a6b0569da977c464a",
+        resp_static: "4a3acbfdb163dec651dfa3194dece676d437029c62a408b4c5ea9114246e4893",
+        resp_ephemeral: "bbdb4cdbd309f1a1f2e1456967fe288cadd6f712d65dc7b7793d5e63da6b375b",
+        prologue: "4a6f686e2047616c74",
+        messages: [
+            ("4c756477696720766f6e204d69736573", "ca35def5ae56cec33dc2036731ab14896bc4c75dbb07a61f879f8e3afa4c79448564738a841228e693caeec4c497a8bd562231c3e51a1f03c4fd45dfe3a67870"),
+            ("4d757272617920526f746862617264", "95ebc60d2b1fa672c1f46a8aa265ef51bfe38e7ccb39ec5be34069f144808843b94504c4d83506f5dd2568b68490eba8ed6f9cf2cca194a273c56d7ae3c558"),
+        ],
+        handshake_hash: Some("c03693acd830588fac76dd414c9e100e8c601d27511de855100239f7705fa3f2"),
+    };
+
+    const SNOW: Vector = Vector {
+        init_static: Some("faebb0194fee50fb819b7127c6f4a24dab85af4ac4ebb263eb1a44e8a7f60d41"),
+        init_ephemeral: "4747e3766bf863acda954fb093d1ed3d438019b9fc1f0dfcbe995d27ea14c825",
+        resp_static: "fae0883f3bcbb944236fbfeefaaa03427920d940b05c4cd1016070ce7c420c0a",
+        resp_ephemeral: "772bcda9330c8849a1763365a4faa47f6cf9c0ef8f6d170d41ddff6c0cfb1a37",
+        prologue: "5468657265206973206e6f20726967687420616e642077726f6e672e2054686572652773206f6e6c792066756e20616e6420626f72696e672e",
+        messages: [
+            ("cb0ff664bfede40c881d02768dc417d6a210606ab2a959ae029d292171691551", "32ca8ed53e4a68e52ecda4d0160bd8c6d22e736b28cee8d151c2c52e37a3123ee45510fd8c5f8caa63d7394d9dfe97fd40b22ec4b7bf0ea360c9f58a22a06e7ee1af57389c2651ee93e82d38ca1be1fc"),
+            ("e546bfd27b0c80b137af4ef3bcfb664c1e42edb732b65adb468ead8973a16d55", "6571c26d443f64388cb42967e73d4b09d9f496586d87f2517ec73507b4bd54579bc86e337166ca0b985745a18ba002eb0ed778e1ce2e9389d1095f3e6a14dccae295a8edb489c6b9b91ffd72d8bbe94a"),
+        ],
+        handshake_hash: None,
+    };
+
+    impl Vector {
+        fn state(
+            &self,
+            pattern: &'static str,
+            vault: Arc<Mutex<DefaultVault>>,
+            static_private: Option<&str>,
+            ephemeral_private: &str,
+        ) -> SymmetricState {
+            SymmetricState::with_vector_keys(
+                pattern,
+                CipherSuite::Curve25519AesGcmSha256,
+                vault,
+                static_private.map(|s| hex::decode(s).unwrap()).as_deref(),
+                &hex::decode(ephemeral_private).unwrap(),
+                &hex::decode(self.prologue).unwrap(),
+            )
+        }
+    }
+
+    fn mock_handshake(vector: &Vector) {
+        let vault_init = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_resp = Arc::new(Mutex::new(DefaultVault::default()));
+        let mut ss_init = vector.state("KK", vault_init, vector.init_static, vector.init_ephemeral);
+        let mut ss_resp = vector.state(
+            "KK",
+            vault_resp,
+            Some(vector.resp_static),
+            vector.resp_ephemeral,
+        );
+        ss_init.remote_static_public_key = ss_resp.static_key_pair.map(|kp| kp.public_key);
+        ss_resp.remote_static_public_key = ss_init.static_key_pair.map(|kp| kp.public_key);
+        let mut initiator = HandshakeState::from_state(&KK, true, ss_init, false);
+        let mut responder = HandshakeState::from_state(&KK, false, ss_resp, false);
+
+        let (msg_1_payload, msg_1_ciphertext) = vector.messages[0];
+        let msg1 = initiator
+            .process(&hex::decode(msg_1_payload).unwrap())
+            .unwrap();
+        assert_eq!(hex::encode(&msg1), msg_1_ciphertext);
+        assert_eq!(
+            hex::encode(responder.process(&msg1).unwrap()),
+            msg_1_payload
+        );
+        let (msg_2_payload, msg_2_ciphertext) = vector.messages[1];
+        let msg2 = responder
+            .process(&hex::decode(msg_2_payload).unwrap())
+            .unwrap();
+        assert_eq!(hex::encode(&msg2), msg_2_ciphertext);
+        assert_eq!(
+            hex::encode(initiator.process(&msg2).unwrap()),
+            msg_2_payload
+        );
+
+        let initiator = initiator.finalize().unwrap();
+        let responder = responder.finalize().unwrap();
+        if let Some(h) = vector.handshake_hash {
+            assert_eq!(hex::encode(initiator.h), h);
+        }
+        assert_eq!(initiator.h, responder.h);
+    }
+
+    #[test]
+    fn handshake_1() {
+        mock_handshake(&CACOPHONY);
+    }
+
+    #[test]
+    fn handshake_2() {
+        mock_handshake(&SNOW);
+    }
+
+    #[test]
+    fn handshake_p256() {
+        let vault_initiator = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_responder = Arc::new(Mutex::new(DefaultVault::default()));
+        let (secret_i, public_i) = static_key(&vault_initiator, SecretKeyType::P256);
+        let (secret_r, public_r) = static_key(&vault_responder, SecretKeyType::P256);
+        let mut initiator = KKNewKeyExchanger::new(
+            CipherSuite::P256Aes128GcmSha256,
+            vault_initiator.clone(),
+            vault_responder.clone(),
+            public_r,
+        )
+        .initiator(Some(secret_i));
+        let mut responder = KKNewKeyExchanger::new(
+            CipherSuite::P256Aes128GcmSha256,
+            vault_initiator.clone(),
+            vault_responder.clone(),
+            public_i,
+        )
+        .responder(Some(secret_r));
+
+        let m1 = initiator.process(b"hello").unwrap();
+        assert_eq!(responder.process(&m1).unwrap(), b"hello");
+        let m2 = responder.process(b"world").unwrap();
+        assert_eq!(initiator.process(&m2).unwrap(), b"world");
+
+        let initiator = initiator.finalize().unwrap();
+        let responder = responder.finalize().unwrap();
+        assert_eq!(initiator.h, responder.h);
+        assert_eq!(
+            initiator.remote_static_public_key.as_ref(),
+            public_r.as_ref()
+        );
+        assert_eq!(
+            responder.remote_static_public_key.as_ref(),
+            public_i.as_ref()
+        );
+
+        let mut vault_in = vault_initiator.lock().unwrap();
+        let mut vault_re = vault_responder.lock().unwrap();
+        assert_eq!(
+            vault_in.secret_export(initiator.encrypt_key).unwrap(),
+            vault_re.secret_export(responder.decrypt_key).unwrap()
+        );
+        assert_eq!(
+            vault_in.secret_export(initiator.decrypt_key).unwrap(),
+            vault_re.secret_export(responder.encrypt_key).unwrap()
+        );
+    }
+
+    #[test]
+    fn unknown_initiator_fails() {
+        let vault_initiator = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_responder = Arc::new(Mutex::new(DefaultVault::default()));
+        let (secret_i, _) = static_key(&vault_initiator, SecretKeyType::Curve25519);
+        let (_, other) = static_key(&vault_initiator, SecretKeyType::Curve25519);
+        let (secret_r, public_r) = static_key(&vault_responder, SecretKeyType::Curve25519);
+        let mut initiator = KKNewKeyExchanger::new(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault_initiator.clone(),
+            vault_responder.clone(),
+            public_r,
+        )
+        .initiator(Some(secret_i));
+        let mut responder = KKNewKeyExchanger::new(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault_initiator,
+            vault_responder,
+            other,
+        )
+        .responder(Some(secret_r));
+
+        let m1 = initiator.process(&[]).unwrap();
+        assert!(responder.process(&m1).is_err());
+    }
+}
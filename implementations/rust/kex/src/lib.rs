@@ -19,8 +19,12 @@ extern crate arrayref;
 use error::*;
 
 use ockam_vault::{
-    error::VaultFailError,
-    types::{PublicKey, SecretKeyContext},
+    error::{VaultFailError, VaultFailErrorKind},
+    types::{
+        PublicKey, SecretKey, SecretKeyAttributes, SecretKeyContext, SecretKeyType,
+        SecretPersistenceType, SecretPurposeType,
+    },
+    DynVault,
 };
 
 #[macro_use]
@@ -36,12 +40,23 @@ pub const AES128_KEYSIZE: usize = 16;
 pub const AES256_KEYSIZE: usize = 32;
 /// The number of bytes in AES-GCM tag
 pub const AES_GCM_TAGSIZE: usize = 16;
+/// HKDF info deriving the exporter secret, kept apart from the channel keys
+pub(crate) const EXPORTER_INFO: &[u8] = b"ockam exporter";
+
+/// Attributes of the exporter secret and of the keys derived from it
+pub(crate) fn exporter_attributes() -> SecretKeyAttributes {
+    SecretKeyAttributes {
+        xtype: SecretKeyType::Buffer(SHA256_SIZE),
+        purpose: SecretPurposeType::KeyAgreement,
+        persistence: SecretPersistenceType::Ephemeral,
+    }
+}
 
 /// A KeyExchange implements these methods
 /// A KeyExchange implementation should wrap a vault instance
 trait KeyExchange {
     /// Returns Noise protocol name
-    fn get_protocol_name(&self) -> &'static [u8];
+    fn get_protocol_name(&self) -> Vec<u8>;
 
     /// Create a new `HandshakeState` starting with the prologue
     fn prologue(&mut self) -> Result<(), VaultFailError>;
@@ -75,12 +90,37 @@ trait KeyExchange {
 
 /// Represents either the Initiator or the Responder
 pub trait KeyExchanger {
-    /// Handle the current step in the key exchange process
+    /// Handle the current step in the key exchange process. When it is
+    /// this side's turn, `data` is an application payload to attach to the
+    /// message written; otherwise it is the peer's message, and the payload
+    /// the peer attached is returned.
     fn process(&mut self, data: &[u8]) -> Result<Vec<u8>, KexExchangeFailError>;
+    /// True if the payload of the message `process` writes next is
+    /// encrypted, so it may carry early application data such as
+    /// credentials. Exchanges that don't carry payloads always say no.
+    fn payload_is_encrypted(&self) -> bool {
+        false
+    }
     /// Is the key exchange process completed yet
     fn is_complete(&self) -> bool;
+    /// The static key of the peer, once the exchange has learned it, so the
+    /// peer can be vetted before this side's next payload goes out
+    fn remote_static_public_key(&self) -> Option<PublicKey> {
+        None
+    }
     /// If completed, then return the data and keys needed for channels
     fn finalize(&mut self) -> Result<CompletedKeyExchange, VaultFailError>;
+    /// Remove the secrets this exchange created from the vault. The encrypt,
+    /// decrypt and exporter keys in the CompletedKeyExchange and any
+    /// identity key given to the exchange are left for their owners.
+    fn destroy(&mut self) -> Result<(), VaultFailError>;
+    /// Bind the exchange to a pre-shared key, a 32 byte buffer secret in the
+    /// exchange's vault, so it only completes with a peer holding the same
+    /// key. Must be called before the first `process`; exchanges without a
+    /// psk variant refuse.
+    fn set_psk(&mut self, _psk: SecretKeyContext) -> Result<(), VaultFailError> {
+        Err(VaultFailErrorKind::InvalidParam(0).into())
+    }
 }
 
 /// XX cipher suites
@@ -90,6 +130,29 @@ pub enum CipherSuite {
     Curve25519AesGcmSha256,
     /// P256 Aes128-GCM Sha256
     P256Aes128GcmSha256,
+    /// Curve25519 ChaCha20-Poly1305 Sha256, for hosts without AES hardware
+    Curve25519ChaChaPolySha256,
+    /// Curve25519 with a Kyber768 KEM, Aes256-GCM Sha256. Both shared
+    /// secrets are mixed into the keys, which stay safe as long as either
+    /// holds, also against a future quantum computer. Runs the `hfs`
+    /// variant of a pattern. Needs the `kyber` feature; this is round 3
+    /// Kyber768, which doesn't interoperate with ML-KEM-768.
+    #[cfg(feature = "kyber")]
+    Curve25519Kyber768AesGcmSha256,
+}
+
+impl CipherSuite {
+    /// Whether the suite mixes a KEM secret in along with the DH ones
+    #[cfg(feature = "kyber")]
+    pub fn is_hybrid(&self) -> bool {
+        matches!(self, CipherSuite::Curve25519Kyber768AesGcmSha256)
+    }
+
+    /// Whether the suite mixes a KEM secret in along with the DH ones
+    #[cfg(not(feature = "kyber"))]
+    pub fn is_hybrid(&self) -> bool {
+        false
+    }
 }
 
 /// Instantiate a stateful key exchange vault instance
@@ -113,6 +176,53 @@ pub struct CompletedKeyExchange {
     pub local_static_secret: SecretKeyContext,
     /// The long term static public key from remote party
     pub remote_static_public_key: PublicKey,
+    /// Secret both sides derive along with the channel keys, from which
+    /// `export` derives keying material
+    pub exporter_secret: SecretKeyContext,
+}
+
+impl CompletedKeyExchange {
+    /// Derive `length` bytes of keying material bound to this exchange, in
+    /// the manner of TLS exporters. Both sides derive the same bytes for
+    /// the same label and context, and different labels give independent
+    /// keys, so applications can bind tokens or signatures to the channel.
+    /// The label must be 1 to 255 bytes and `length` at most 8160.
+    pub fn export(
+        &self,
+        vault: &mut dyn DynVault,
+        label: &[u8],
+        context: &[u8],
+        length: usize,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        if label.is_empty() || label.len() > 255 {
+            return Err(VaultFailErrorKind::InvalidParam(1).into());
+        }
+        if length == 0 || length > 255 * SHA256_SIZE {
+            return Err(VaultFailErrorKind::InvalidParam(3).into());
+        }
+        let mut info = vec![label.len() as u8];
+        info.extend_from_slice(label);
+        info.extend_from_slice(&vault.sha256(context)?);
+        // the vault derives SHA256_SIZE bytes per output
+        let outputs = (length + SHA256_SIZE - 1) / SHA256_SIZE;
+        let secrets = vault.hkdf_sha256(
+            self.exporter_secret,
+            &info,
+            None,
+            vec![exporter_attributes(); outputs],
+        )?;
+        let mut output = Vec::with_capacity(outputs * SHA256_SIZE);
+        for secret in secrets {
+            let exported = vault.secret_export(secret);
+            vault.secret_destroy(secret)?;
+            match &exported? {
+                SecretKey::Buffer(bytes) => output.extend_from_slice(bytes),
+                _ => return Err(VaultFailErrorKind::HkdfSha256.into()),
+            }
+        }
+        output.truncate(length);
+        Ok(output)
+    }
 }
 
 /// Errors thrown by Key exchange
@@ -120,6 +230,20 @@ pub mod error;
 #[cfg(feature = "ffi")]
 /// FFI module
 pub mod ffi;
+/// Implementation of Noise IK Pattern
+pub mod ik;
+/// Implementation of Noise KK Pattern
+pub mod kk;
+/// Implementation of Noise NK Pattern
+pub mod nk;
+/// Generic Noise handshake engine driven by pattern descriptors
+pub mod pattern;
+/// X3DH prekey store with signed prekey rotation and a one-time prekey pool
+pub mod prekey;
+/// Double Ratchet sessions started from a completed key exchange
+pub mod ratchet;
+#[cfg(test)]
+mod vectors;
 /// Implementation of Signal's X3DH
 pub mod x3dh;
 /// Implementation of Noise XX Pattern
@@ -130,7 +254,6 @@ mod tests {
     use super::*;
     use crate::xx::XXNewKeyExchanger;
     use ockam_vault::software::DefaultVault;
-    use ockam_vault::Vault;
     use std::sync::{Arc, Mutex};
 
     #[allow(non_snake_case)]
@@ -172,4 +295,59 @@ mod tests {
 
         assert_eq!(s1, s2);
     }
+
+    #[test]
+    fn exported_keys_match_per_label() {
+        let vault_initiator = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_responder = Arc::new(Mutex::new(DefaultVault::default()));
+        let key_exchanger = XXNewKeyExchanger::new(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault_initiator.clone(),
+            vault_responder.clone(),
+        );
+        let mut initiator = key_exchanger.initiator(None);
+        let mut responder = key_exchanger.responder(None);
+        let m1 = initiator.process(&[]).unwrap();
+        responder.process(&m1).unwrap();
+        let m2 = responder.process(&[]).unwrap();
+        initiator.process(&m2).unwrap();
+        let m3 = initiator.process(&[]).unwrap();
+        responder.process(&m3).unwrap();
+        let initiator = initiator.finalize().unwrap();
+        let responder = responder.finalize().unwrap();
+
+        let mut vault_in = vault_initiator.lock().unwrap();
+        let mut vault_re = vault_responder.lock().unwrap();
+        let token = initiator
+            .export(&mut *vault_in, b"token binding", b"", 48)
+            .unwrap();
+        assert_eq!(token.len(), 48);
+        assert_eq!(
+            token,
+            responder
+                .export(&mut *vault_re, b"token binding", b"", 48)
+                .unwrap()
+        );
+        // a shorter key is a prefix, another label or context is unrelated
+        assert_eq!(
+            initiator
+                .export(&mut *vault_in, b"token binding", b"", 16)
+                .unwrap(),
+            token[..16].to_vec()
+        );
+        assert_ne!(
+            initiator
+                .export(&mut *vault_in, b"signatures", b"", 48)
+                .unwrap(),
+            token
+        );
+        assert_ne!(
+            initiator
+                .export(&mut *vault_in, b"token binding", b"user 1", 48)
+                .unwrap(),
+            token
+        );
+        assert!(initiator.export(&mut *vault_in, b"", b"", 16).is_err());
+        assert!(initiator.export(&mut *vault_in, b"x", b"", 0).is_err());
+    }
 }
@@ -0,0 +1,276 @@
+use crate::pattern::{HandshakePattern, HandshakeState, Token};
+use crate::{CipherSuite, NewKeyExchanger};
+use ockam_vault::{
+    types::{PublicKey, SecretKeyContext},
+    DynVault,
+};
+use std::sync::{Arc, Mutex};
+
+/// The Noise NK pattern: the initiator knows the responder's static public
+/// key before it starts and never reveals a static key of its own, so the
+/// responder can't tell initiators apart
+pub static NK: HandshakePattern = HandshakePattern {
+    name: "NK",
+    initiator_pre_message: &[],
+    responder_pre_message: &[Token::S],
+    messages: &[&[Token::E, Token::Es], &[Token::E, Token::Ee]],
+    psk_variant: None,
+    hfs_variant: Some(&NK_HFS),
+};
+
+/// NK with hybrid forward secrecy
+pub static NK_HFS: HandshakePattern = HandshakePattern {
+    name: "NKhfs",
+    initiator_pre_message: &[],
+    responder_pre_message: &[Token::S],
+    messages: &[
+        &[Token::E, Token::Es, Token::E1],
+        &[Token::E, Token::Ee, Token::Ekem1],
+    ],
+    psk_variant: None,
+    hfs_variant: None,
+};
+
+/// Represents an NK initiator
+pub type NKInitiator = HandshakeState;
+
+/// Represents an NK responder. Initiators are anonymous, so the remote
+/// static public key of its completed exchanges is the initiator's
+/// ephemeral key, which identifies the handshake and nothing more.
+pub type NKResponder = HandshakeState;
+
+/// Represents an NK NewKeyExchanger. Initiators it creates handshake with
+/// the responder whose static public key it was given.
+pub struct NKNewKeyExchanger {
+    cipher_suite: CipherSuite,
+    vault_initiator: Arc<Mutex<dyn DynVault + Send>>,
+    vault_responder: Arc<Mutex<dyn DynVault + Send>>,
+    responder_public_key: Option<PublicKey>,
+}
+
+impl std::fmt::Debug for NKNewKeyExchanger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "NKNewKeyExchanger {{ cipher_suite: {:?}, responder_public_key: {:?} }}",
+            self.cipher_suite, self.responder_public_key
+        )
+    }
+}
+
+impl NKNewKeyExchanger {
+    /// Create a new NKNewKeyExchanger. Without the responder's public key
+    /// it can only create responders; initiators fail their first message.
+    pub fn new(
+        cipher_suite: CipherSuite,
+        vault_initiator: Arc<Mutex<dyn DynVault + Send>>,
+        vault_responder: Arc<Mutex<dyn DynVault + Send>>,
+        responder_public_key: Option<PublicKey>,
+    ) -> Self {
+        Self {
+            cipher_suite,
+            vault_initiator,
+            vault_responder,
+            responder_public_key,
+        }
+    }
+}
+
+impl NewKeyExchanger<NKInitiator, NKResponder> for NKNewKeyExchanger {
+    /// Create a new initiator using the provided backing vault. The
+    /// identity key is not sent; a static key is only kept for the
+    /// completed exchange.
+    fn initiator(&self, identity_key: Option<SecretKeyContext>) -> NKInitiator {
+        HandshakeState::new(
+            &NK,
+            true,
+            self.cipher_suite,
+            self.vault_initiator.clone(),
+            identity_key,
+            self.responder_public_key,
+        )
+    }
+
+    /// Create a new responder using the provided backing vault
+    fn responder(&self, identity_key: Option<SecretKeyContext>) -> NKResponder {
+        HandshakeState::new(
+            &NK,
+            false,
+            self.cipher_suite,
+            self.vault_responder.clone(),
+            identity_key,
+            None,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xx::SymmetricState;
+    use crate::KeyExchanger;
+    use ockam_vault::software::DefaultVault;
+    use ockam_vault::types::{
+        SecretKeyAttributes, SecretKeyType, SecretPersistenceType, SecretPurposeType,
+    };
+
+    /// The keys, prologue and handshake messages of a published
+    /// Noise_NK_25519_AESGCM_SHA256 vector, from the cacophony and snow
+    /// vector files
+    struct Vector {
+        init_static: Option<&'static str>,
+        init_ephemeral: &'static str,
+        resp_static: &'static str,
+        resp_ephemeral: &'static str,
+        prologue: &'static str,
+        messages: [(&'static str, &'static str); 2],
+        handshake_hash: Option<&'static str>,
+    }
+
+    const CACOPHONY: Vector = Vector {
+        init_static: None,
+        init_ephemeral: "893e28b9dc6ca8d611ab664754b8ceb7bac5117349a4439a6b0569da977c464a",
+        resp_static: "4a3acbfdb163dec651dfa3194dece676d437029c62a408b4c5ea9114246e4893",
+        resp_ephemeral: "bbdb4cdbd309f1a1f2e1456967fe288cadd6f712d65dc7b7793d5e63da6b375b",
+        prologue: "4a6f686e2047616c74",
+        messages: [
+            ("4c756477696720766f6e204d69736573", "ca35def5ae56cec33dc2036731ab14896bc4c75dbb07a61f879f8e3afa4c794475ab4d66d222457dd414bc5f296bc7b4078cc7d72af5192628b68bca7d28844b"),
+            ("4d757272617920526f746862617264", "95ebc60d2b1fa672c1f46a8aa265ef51bfe38e7ccb39ec5be34069f14480884303c7d89310502baa8299520ba451624c3c0492e2698f8d457c32400b91fd8a"),
+        ],
+        handshake_hash: Some("f8a87aa8add4fea6e33365b89637486c2f6564546ce29d1df9ce9abf78c507d7"),
+    };
+
+    const SNOW: Vector = Vector {
+        init_static: None,
+        init_ephemeral: "e3eeb7a9e029df21d8b5d01cc2d568c3c1164bcb7e63d1568aa02500316afe33",
+        resp_static: "c388eac8d39730a2a2bf7a03834523dac579780f0bc87de41a6725791ec246b7",
+        resp_ephemeral: "3e69f0e556641032dbcc76d181fc210096ee62e7d59c0994c205d7da7f00f61a",
+        prologue: "5468657265206973206e6f20726967687420616e642077726f6e672e2054686572652773206f6e6c792066756e20616e6420626f72696e672e",
+        messages: [
+            ("7d58ed72b29b3cf1395f8ea4cc144e053e2fd421d605648c76f1d8d913a6c3da", "1135472678c20adfdc798e14cbaff7c5914b9d10c4b33da787c01acc3b9060750932a1ad5fabec50fcc3683cfda1e2d0c61b584f413a401f66547b81ea221b2adf89e2849d46eaebdbd2f13bfd97de79"),
+            ("3205860fd20fb1ef936ac9d963fa783f68a60d223ea11ac5111a2a3b4a05dbdc", "be844ada32b8a2e8f47e2e87d7eaf4c931851d65154a335ebf11af9142ae744f6fb8e48630cf2729234edb7bc1be0b1982e711805ec59985f35ced853491d77252214d3752a5f6d589b3a78fa8ab2e8d"),
+        ],
+        handshake_hash: None,
+    };
+
+    impl Vector {
+        fn state(
+            &self,
+            pattern: &'static str,
+            vault: Arc<Mutex<DefaultVault>>,
+            static_private: Option<&str>,
+            ephemeral_private: &str,
+        ) -> SymmetricState {
+            SymmetricState::with_vector_keys(
+                pattern,
+                CipherSuite::Curve25519AesGcmSha256,
+                vault,
+                static_private.map(|s| hex::decode(s).unwrap()).as_deref(),
+                &hex::decode(ephemeral_private).unwrap(),
+                &hex::decode(self.prologue).unwrap(),
+            )
+        }
+    }
+
+    fn mock_handshake(vector: &Vector) {
+        let vault_init = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_resp = Arc::new(Mutex::new(DefaultVault::default()));
+        // the initiator's static key takes no part in NK
+        let mut ss_init = vector.state("NK", vault_init, vector.init_static, vector.init_ephemeral);
+        let ss_resp = vector.state(
+            "NK",
+            vault_resp,
+            Some(vector.resp_static),
+            vector.resp_ephemeral,
+        );
+        ss_init.remote_static_public_key = ss_resp.static_key_pair.map(|kp| kp.public_key);
+        let initiator_ephemeral = ss_init.ephemeral_key_pair.unwrap().public_key;
+        let mut initiator = HandshakeState::from_state(&NK, true, ss_init, false);
+        let mut responder = HandshakeState::from_state(&NK, false, ss_resp, false);
+
+        let (msg_1_payload, msg_1_ciphertext) = vector.messages[0];
+        let msg1 = initiator
+            .process(&hex::decode(msg_1_payload).unwrap())
+            .unwrap();
+        assert_eq!(hex::encode(&msg1), msg_1_ciphertext);
+        assert_eq!(
+            hex::encode(responder.process(&msg1).unwrap()),
+            msg_1_payload
+        );
+        let (msg_2_payload, msg_2_ciphertext) = vector.messages[1];
+        let msg2 = responder
+            .process(&hex::decode(msg_2_payload).unwrap())
+            .unwrap();
+        assert_eq!(hex::encode(&msg2), msg_2_ciphertext);
+        assert_eq!(
+            hex::encode(initiator.process(&msg2).unwrap()),
+            msg_2_payload
+        );
+
+        let initiator = initiator.finalize().unwrap();
+        let responder = responder.finalize().unwrap();
+        if let Some(h) = vector.handshake_hash {
+            assert_eq!(hex::encode(initiator.h), h);
+        }
+        assert_eq!(initiator.h, responder.h);
+        assert_eq!(
+            responder.remote_static_public_key.as_ref(),
+            initiator_ephemeral.as_ref()
+        );
+    }
+
+    #[test]
+    fn handshake_1() {
+        mock_handshake(&CACOPHONY);
+    }
+
+    #[test]
+    fn handshake_2() {
+        mock_handshake(&SNOW);
+    }
+
+    #[test]
+    fn handshake_p256() {
+        let vault_initiator = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_responder = Arc::new(Mutex::new(DefaultVault::default()));
+        let (secret, public) = {
+            let mut vault = vault_responder.lock().unwrap();
+            let attributes = SecretKeyAttributes {
+                xtype: SecretKeyType::P256,
+                purpose: SecretPurposeType::KeyAgreement,
+                persistence: SecretPersistenceType::Persistent,
+            };
+            let secret = vault.secret_generate(attributes).unwrap();
+            (secret, vault.secret_public_key_get(secret).unwrap())
+        };
+        let key_exchanger = NKNewKeyExchanger::new(
+            CipherSuite::P256Aes128GcmSha256,
+            vault_initiator.clone(),
+            vault_responder.clone(),
+            Some(public),
+        );
+        let mut initiator = key_exchanger.initiator(None);
+        let mut responder = key_exchanger.responder(Some(secret));
+
+        let m1 = initiator.process(b"hello").unwrap();
+        assert_eq!(responder.process(&m1).unwrap(), b"hello");
+        let m2 = responder.process(b"world").unwrap();
+        assert_eq!(initiator.process(&m2).unwrap(), b"world");
+
+        let initiator = initiator.finalize().unwrap();
+        let responder = responder.finalize().unwrap();
+        assert_eq!(initiator.h, responder.h);
+        assert_eq!(initiator.remote_static_public_key.as_ref(), public.as_ref());
+
+        let mut vault_in = vault_initiator.lock().unwrap();
+        let mut vault_re = vault_responder.lock().unwrap();
+        assert_eq!(
+            vault_in.secret_export(initiator.encrypt_key).unwrap(),
+            vault_re.secret_export(responder.decrypt_key).unwrap()
+        );
+        assert_eq!(
+            vault_in.secret_export(initiator.decrypt_key).unwrap(),
+            vault_re.secret_export(responder.encrypt_key).unwrap()
+        );
+    }
+}
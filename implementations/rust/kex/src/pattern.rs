@@ -0,0 +1,615 @@
+use super::{CompletedKeyExchange, KeyExchange, KeyExchanger};
+use crate::error::KexExchangeFailError;
+use crate::xx::{KeyPair, SymmetricState};
+use crate::{CipherSuite, AES_GCM_TAGSIZE};
+use ockam_vault::{
+    error::{VaultFailError, VaultFailErrorKind},
+    types::{PublicKey, SecretKeyContext},
+    DynVault,
+};
+use std::sync::{Arc, Mutex};
+
+/// A Noise handshake token. In the DH tokens the first letter is the
+/// initiator's key and the second the responder's.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Token {
+    /// The sender's ephemeral public key, sent in the clear
+    E,
+    /// The sender's static public key, encrypted once there is a key
+    S,
+    /// DH between both ephemeral keys
+    Ee,
+    /// DH between the initiator's ephemeral and the responder's static key
+    Es,
+    /// DH between the initiator's static and the responder's ephemeral key
+    Se,
+    /// DH between both static keys
+    Ss,
+    /// Mix in the pre-shared key
+    Psk,
+    /// The sender's ephemeral KEM public key, encrypted once there is a key
+    E1,
+    /// A secret encapsulated to the peer's `e1` key: the ciphertext is sent
+    /// and the secret mixed in as a DH result would be
+    Ekem1,
+}
+
+/// A Noise handshake pattern, e.g. XX is
+/// `-> e`, `<- e, ee, s, es`, `-> s, se`
+#[derive(Debug)]
+pub struct HandshakePattern {
+    /// The pattern's name in the protocol name, e.g. "XX"
+    pub name: &'static str,
+    /// Keys of the initiator the responder knows before the handshake
+    pub initiator_pre_message: &'static [Token],
+    /// Keys of the responder the initiator knows before the handshake
+    pub responder_pre_message: &'static [Token],
+    /// The tokens of each message, the initiator's first
+    pub messages: &'static [&'static [Token]],
+    /// The pattern used instead once a pre-shared key is set
+    pub psk_variant: Option<&'static HandshakePattern>,
+    /// The pattern used instead with a hybrid cipher suite
+    pub hfs_variant: Option<&'static HandshakePattern>,
+}
+
+impl HandshakePattern {
+    /// Whether the pattern has KEM tokens, which need a hybrid cipher suite
+    pub fn is_hybrid(&self) -> bool {
+        self.messages
+            .iter()
+            .any(|tokens| tokens.contains(&Token::E1))
+    }
+}
+
+fn invalid_context() -> VaultFailError {
+    VaultFailError::from(VaultFailErrorKind::InvalidContext)
+}
+
+/// Runs one side of any handshake pattern against a `SymmetricState`.
+/// Messages alternate between the sides, starting with the initiator;
+/// `process` writes a message with the given payload when it is this
+/// side's turn and reads one otherwise.
+#[derive(Debug)]
+pub struct HandshakeState {
+    ss: SymmetricState,
+    pattern: &'static HandshakePattern,
+    initiator: bool,
+    message: usize,
+    run_prologue: bool,
+    started: bool,
+}
+
+impl HandshakeState {
+    /// Create one side of a handshake. `remote_static_public_key` is needed
+    /// when the pattern has the remote party's static key as a pre-message.
+    /// A hybrid cipher suite runs the pattern's `hfs` variant; the first
+    /// `process` fails if there is none.
+    pub fn new(
+        pattern: &'static HandshakePattern,
+        initiator: bool,
+        cipher_suite: CipherSuite,
+        vault: Arc<Mutex<dyn DynVault + Send>>,
+        identity_key: Option<SecretKeyContext>,
+        remote_static_public_key: Option<PublicKey>,
+    ) -> Self {
+        let pattern = match pattern.hfs_variant {
+            Some(variant) if cipher_suite.is_hybrid() => variant,
+            _ => pattern,
+        };
+        let mut ss = SymmetricState::with_pattern(pattern.name, cipher_suite, vault, identity_key);
+        ss.remote_static_public_key = remote_static_public_key;
+        Self::from_state(pattern, initiator, ss, true)
+    }
+
+    /// Run `pattern` from a symmetric state, which has already been through
+    /// its prologue unless `run_prologue` is set
+    pub(crate) fn from_state(
+        pattern: &'static HandshakePattern,
+        initiator: bool,
+        ss: SymmetricState,
+        run_prologue: bool,
+    ) -> Self {
+        Self {
+            ss,
+            pattern,
+            initiator,
+            message: 0,
+            run_prologue,
+            started: false,
+        }
+    }
+
+    /// Run the prologue and mix in the keys known before the handshake
+    fn start(&mut self) -> Result<(), VaultFailError> {
+        if self.started {
+            return Ok(());
+        }
+        if self.pattern.is_hybrid() != self.ss.is_hybrid() {
+            return Err(VaultFailErrorKind::InvalidParam(0).into());
+        }
+        if self.run_prologue {
+            self.ss.prologue()?;
+        }
+        let pre_messages = [
+            (self.pattern.initiator_pre_message, true),
+            (self.pattern.responder_pre_message, false),
+        ];
+        for &(tokens, initiator) in pre_messages.iter() {
+            for token in tokens {
+                let public_key = if initiator == self.initiator {
+                    self.local_key_pair(*token)?.public_key
+                } else {
+                    self.remote_public_key(*token)?
+                };
+                match token {
+                    Token::E => self.ss.mix_ephemeral(public_key)?,
+                    _ => self.ss.mix_hash(public_key)?,
+                }
+            }
+        }
+        self.started = true;
+        Ok(())
+    }
+
+    fn local_key_pair(&self, token: Token) -> Result<KeyPair, VaultFailError> {
+        match token {
+            Token::E => self.ss.ephemeral_key_pair,
+            Token::S => self.ss.static_key_pair,
+            _ => None,
+        }
+        .ok_or_else(invalid_context)
+    }
+
+    fn remote_public_key(&self, token: Token) -> Result<PublicKey, VaultFailError> {
+        match token {
+            Token::E => self.ss.remote_ephemeral_public_key,
+            Token::S => self.ss.remote_static_public_key,
+            _ => None,
+        }
+        .ok_or_else(invalid_context)
+    }
+
+    /// The `ee`, `es`, `se` and `ss` tokens
+    fn mix_dh(&mut self, token: Token) -> Result<(), VaultFailError> {
+        let (initiator_key, responder_key) = match token {
+            Token::Ee => (Token::E, Token::E),
+            Token::Es => (Token::E, Token::S),
+            Token::Se => (Token::S, Token::E),
+            Token::Ss => (Token::S, Token::S),
+            _ => return Err(invalid_context()),
+        };
+        let (local, remote) = if self.initiator {
+            (initiator_key, responder_key)
+        } else {
+            (responder_key, initiator_key)
+        };
+        let local = self.local_key_pair(local)?;
+        let remote = self.remote_public_key(remote)?;
+        self.ss.dh(local.secret_handle, remote)
+    }
+
+    pub(crate) fn is_writer(&self) -> bool {
+        (self.message % 2 == 0) == self.initiator
+    }
+
+    /// The smallest message that can hold `tokens` and an empty payload
+    fn min_message_size(&self, tokens: &[Token]) -> usize {
+        let public_key_size = self.ss.get_public_key_size();
+        let (kem_public_key_size, kem_ciphertext_size) = self.ss.get_kem_sizes();
+        let has_psk = self.ss.psk.is_some();
+        let tag_size = |has_key: bool| if has_key { AES_GCM_TAGSIZE } else { 0 };
+        let mut has_key = self.ss.has_key();
+        let mut size = 0;
+        for token in tokens {
+            match token {
+                Token::E => {
+                    size += public_key_size;
+                    has_key |= has_psk;
+                }
+                Token::S => size += public_key_size + tag_size(has_key),
+                Token::E1 => size += kem_public_key_size + tag_size(has_key),
+                Token::Ekem1 => {
+                    size += kem_ciphertext_size + tag_size(has_key);
+                    has_key = true;
+                }
+                Token::Psk => has_key |= has_psk,
+                _ => has_key = true,
+            }
+        }
+        size + tag_size(has_key)
+    }
+
+    fn write_message(&mut self, payload: &[u8]) -> Result<Vec<u8>, VaultFailError> {
+        let tokens = self.pattern.messages[self.message];
+        let mut output = Vec::new();
+        for token in tokens {
+            match token {
+                Token::E => {
+                    let e = self.local_key_pair(Token::E)?;
+                    self.ss.mix_ephemeral(e.public_key)?;
+                    output.extend_from_slice(e.public_key.as_ref());
+                }
+                Token::S => {
+                    let s = self.local_key_pair(Token::S)?;
+                    output.append(&mut self.ss.encrypt_and_mix_hash(s.public_key)?);
+                }
+                Token::E1 => output.append(&mut self.ss.write_kem_ephemeral()?),
+                Token::Ekem1 => output.append(&mut self.ss.write_kem_ciphertext()?),
+                Token::Psk => self.ss.mix_psk()?,
+                dh => self.mix_dh(*dh)?,
+            }
+        }
+        output.append(&mut self.ss.encrypt_and_mix_hash(payload)?);
+        self.message += 1;
+        Ok(output)
+    }
+
+    /// A message too short for its tokens is refused before the state
+    /// changes, so the exchange can go on with the right one
+    fn read_message(&mut self, message: &[u8]) -> Result<Vec<u8>, VaultFailError> {
+        let tokens = self.pattern.messages[self.message];
+        if message.len() < self.min_message_size(tokens) {
+            return Err(VaultFailErrorKind::SecretSizeMismatch.into());
+        }
+        let public_key_size = self.ss.get_public_key_size();
+        let (kem_public_key_size, kem_ciphertext_size) = self.ss.get_kem_sizes();
+        let tag_size = |ss: &SymmetricState| if ss.has_key() { AES_GCM_TAGSIZE } else { 0 };
+        let mut offset = 0;
+        for token in tokens {
+            match token {
+                Token::E => {
+                    let re = self
+                        .ss
+                        .create_public_key(&message[offset..offset + public_key_size])?;
+                    offset += public_key_size;
+                    self.ss.remote_ephemeral_public_key = Some(re);
+                    self.ss.mix_ephemeral(re)?;
+                }
+                Token::S => {
+                    let size = public_key_size + tag_size(&self.ss);
+                    let rs = self
+                        .ss
+                        .decrypt_and_mix_hash(&message[offset..offset + size])?;
+                    offset += size;
+                    self.ss.remote_static_public_key = Some(self.ss.create_public_key(&rs)?);
+                }
+                Token::E1 => {
+                    let size = kem_public_key_size + tag_size(&self.ss);
+                    self.ss
+                        .read_kem_ephemeral(&message[offset..offset + size])?;
+                    offset += size;
+                }
+                Token::Ekem1 => {
+                    let size = kem_ciphertext_size + tag_size(&self.ss);
+                    self.ss
+                        .read_kem_ciphertext(&message[offset..offset + size])?;
+                    offset += size;
+                }
+                Token::Psk => self.ss.mix_psk()?,
+                dh => self.mix_dh(*dh)?,
+            }
+        }
+        let payload = self.ss.decrypt_and_mix_hash(&message[offset..])?;
+        self.message += 1;
+        Ok(payload)
+    }
+}
+
+impl KeyExchanger for HandshakeState {
+    fn process(&mut self, data: &[u8]) -> Result<Vec<u8>, KexExchangeFailError> {
+        if self.is_complete() {
+            return Ok(vec![]);
+        }
+        self.start()?;
+        let message = if self.is_writer() {
+            self.write_message(data)?
+        } else {
+            self.read_message(data)?
+        };
+        Ok(message)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.message == self.pattern.messages.len()
+    }
+
+    fn remote_static_public_key(&self) -> Option<PublicKey> {
+        self.ss.remote_static_public_key
+    }
+
+    /// Once a DH or KEM token or a pre-shared key has been mixed in there
+    /// is a key, so the first payload of XX is in the clear but that of IK,
+    /// KK, NK and every psk variant is not
+    fn payload_is_encrypted(&self) -> bool {
+        if self.is_complete() || !self.is_writer() {
+            return false;
+        }
+        let has_psk = self.ss.psk.is_some();
+        self.ss.has_key()
+            || self.pattern.messages[self.message]
+                .iter()
+                .any(|token| match token {
+                    Token::E | Token::Psk => has_psk,
+                    Token::S | Token::E1 => false,
+                    _ => true,
+                })
+    }
+
+    /// Patterns where the initiator never sends a static key, like NK,
+    /// give the responder the initiator's ephemeral key as the remote
+    /// static key, which identifies the handshake and nothing more
+    fn finalize(&mut self) -> Result<CompletedKeyExchange, VaultFailError> {
+        if !self.is_complete() {
+            return Err(VaultFailErrorKind::IOError.into());
+        }
+        if self.ss.remote_static_public_key.is_none() {
+            self.ss.remote_static_public_key = self.ss.remote_ephemeral_public_key;
+        }
+        // the initiator sends with the first key, as Noise has it
+        let keys = self.ss.split()?;
+        if self.initiator {
+            self.ss.finalize(keys.0, keys.1)
+        } else {
+            self.ss.finalize(keys.1, keys.0)
+        }
+    }
+
+    fn destroy(&mut self) -> Result<(), VaultFailError> {
+        self.ss.destroy()
+    }
+
+    fn set_psk(&mut self, psk: SecretKeyContext) -> Result<(), VaultFailError> {
+        let variant = self
+            .pattern
+            .psk_variant
+            .ok_or_else(|| VaultFailError::from(VaultFailErrorKind::InvalidParam(0)))?;
+        self.ss.set_psk(variant.name, psk)?;
+        self.pattern = variant;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nk::NK;
+    use crate::xx::XX;
+    use ockam_vault::software::DefaultVault;
+    use ockam_vault::types::{
+        SecretKeyAttributes, SecretKeyType, SecretPersistenceType, SecretPurposeType,
+    };
+
+    const INIT_STATIC: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const INIT_EPH: &str = "202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f";
+    const RESP_STATIC: &str = "0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20";
+    const RESP_EPH: &str = "4142434445464748494a4b4c4d4e4f505152535455565758595a5b5c5d5e5f60";
+
+    /// A pattern with no exchanger of its own
+    static XK: HandshakePattern = HandshakePattern {
+        name: "XK",
+        initiator_pre_message: &[],
+        responder_pre_message: &[Token::S],
+        messages: &[
+            &[Token::E, Token::Es],
+            &[Token::E, Token::Ee],
+            &[Token::S, Token::Se],
+        ],
+        psk_variant: None,
+        hfs_variant: None,
+    };
+
+    fn handshake_states(pattern: &'static HandshakePattern) -> (HandshakeState, HandshakeState) {
+        let vault_init = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_resp = Arc::new(Mutex::new(DefaultVault::default()));
+        let mut ss_init =
+            SymmetricState::with_keys(pattern.name, vault_init, INIT_STATIC, INIT_EPH);
+        let ss_resp = SymmetricState::with_keys(pattern.name, vault_resp, RESP_STATIC, RESP_EPH);
+        ss_init.remote_static_public_key = ss_resp.static_key_pair.map(|kp| kp.public_key);
+        (
+            HandshakeState::from_state(pattern, true, ss_init, false),
+            HandshakeState::from_state(pattern, false, ss_resp, false),
+        )
+    }
+
+    #[test]
+    fn declared_pattern_vectors() {
+        const MSG_1_CIPHERTEXT: &str = "358072d6365880d1aeea329adf9121383851ed21a28e3b75e965d0d2cd166254496dd4fd65bcb73030e1a50f16936b093497a8bde429c662e917";
+        const MSG_2_CIPHERTEXT: &str = "64b101b1d0be5a8704bd078f9895001fc03e8e9f9522f188dd128d9846d48466ea95f04183becb2895dabdafa5b7d048a62812a4cdc993d14f40";
+        const MSG_3_CIPHERTEXT: &str = "065a23c2f62fb1bed15cb6ecbd9267c0dc7524d31ee9367258f443517df4b467b299f23b7566cb23e20882c3ec1a0f6aad26e0baa7ec90d7741c99f08177caf8918d56c694da03de81bd";
+        const H: &str = "48a7930c56c97623ff4129d7263cb5cd687a8958b598cc2f4004fd3effedb47a";
+
+        let (mut initiator, mut responder) = handshake_states(&XK);
+        let ciphertexts = [MSG_1_CIPHERTEXT, MSG_2_CIPHERTEXT, MSG_3_CIPHERTEXT];
+        for (i, ciphertext) in ciphertexts.iter().enumerate() {
+            let payload = format!("test_msg_{}", i).into_bytes();
+            let (sender, receiver) = if i % 2 == 0 {
+                (&mut initiator, &mut responder)
+            } else {
+                (&mut responder, &mut initiator)
+            };
+            let message = sender.process(&payload).unwrap();
+            assert_eq!(hex::encode(&message), *ciphertext);
+            assert_eq!(receiver.process(&message).unwrap(), payload);
+        }
+        assert!(initiator.is_complete());
+        assert!(responder.is_complete());
+        assert!(initiator.process(&[]).unwrap().is_empty());
+
+        let initiator_static = initiator.ss.static_key_pair.unwrap().public_key;
+        let initiator = initiator.finalize().unwrap();
+        let responder = responder.finalize().unwrap();
+        assert_eq!(hex::encode(initiator.h), H);
+        assert_eq!(hex::encode(responder.h), H);
+        assert_eq!(
+            responder.remote_static_public_key.as_ref(),
+            initiator_static.as_ref()
+        );
+    }
+
+    #[test]
+    fn short_message_leaves_state() {
+        let (mut initiator, mut responder) = handshake_states(&XX);
+        assert!(responder.process(&[0u8; 16]).is_err());
+        assert!(initiator.finalize().is_err());
+
+        let m1 = initiator.process(&[]).unwrap();
+        responder.process(&m1).unwrap();
+        let m2 = responder.process(&[]).unwrap();
+        // e, ee, s, es and the payload need two keys and two tags
+        assert!(initiator.process(&m2[..m2.len() - 1]).is_err());
+        initiator.process(&m2).unwrap();
+        let m3 = initiator.process(&[]).unwrap();
+        responder.process(&m3).unwrap();
+        assert_eq!(
+            initiator.finalize().unwrap().h,
+            responder.finalize().unwrap().h
+        );
+    }
+
+    #[test]
+    fn payload_encryption_follows_the_pattern() {
+        let (mut initiator, mut responder) = handshake_states(&XX);
+        assert!(!initiator.payload_is_encrypted());
+        assert!(!responder.payload_is_encrypted());
+        let m1 = initiator.process(&[]).unwrap();
+        responder.process(&m1).unwrap();
+        assert!(responder.payload_is_encrypted());
+
+        let (initiator, _) = handshake_states(&NK);
+        assert!(initiator.payload_is_encrypted());
+    }
+
+    #[test]
+    fn psk_needs_a_variant() {
+        let (mut initiator, _) = handshake_states(&NK);
+        let mut vault = DefaultVault::default();
+        let attributes = SecretKeyAttributes {
+            xtype: SecretKeyType::Buffer(crate::SHA256_SIZE),
+            purpose: SecretPurposeType::KeyAgreement,
+            persistence: SecretPersistenceType::Ephemeral,
+        };
+        let psk = vault.secret_generate(attributes).unwrap();
+        assert!(initiator.set_psk(psk).is_err());
+        assert_eq!(initiator.pattern.name, "NK");
+    }
+
+    /// Hybrid suites need the vault's Kyber768
+    #[cfg(feature = "kyber")]
+    mod hybrid {
+        use super::*;
+        use crate::ik::IK_PSK2;
+        use crate::kk::KK;
+        use crate::xx::XX_HFS;
+
+        /// Both sides of a handshake with fresh keys; the responder's static
+        /// key is known to the initiator
+        fn suite_states(
+            pattern: &'static HandshakePattern,
+            cipher_suite: CipherSuite,
+        ) -> (
+            HandshakeState,
+            HandshakeState,
+            Arc<Mutex<DefaultVault>>,
+            Arc<Mutex<DefaultVault>>,
+        ) {
+            let vault_init = Arc::new(Mutex::new(DefaultVault::default()));
+            let vault_resp = Arc::new(Mutex::new(DefaultVault::default()));
+            let attributes = SecretKeyAttributes {
+                xtype: SecretKeyType::Curve25519,
+                purpose: SecretPurposeType::KeyAgreement,
+                persistence: SecretPersistenceType::Persistent,
+            };
+            let identity = |vault: &Arc<Mutex<DefaultVault>>| {
+                let mut vault = vault.lock().unwrap();
+                let secret = vault.secret_generate(attributes).unwrap();
+                (secret, vault.secret_public_key_get(secret).unwrap())
+            };
+            let (init_secret, _) = identity(&vault_init);
+            let (resp_secret, resp_public) = identity(&vault_resp);
+            let initiator = HandshakeState::new(
+                pattern,
+                true,
+                cipher_suite,
+                vault_init.clone(),
+                Some(init_secret),
+                Some(resp_public),
+            );
+            let responder = HandshakeState::new(
+                pattern,
+                false,
+                cipher_suite,
+                vault_resp.clone(),
+                Some(resp_secret),
+                None,
+            );
+            (initiator, responder, vault_init, vault_resp)
+        }
+
+        #[test]
+        fn hybrid_suite_runs_the_hfs_variants() {
+            let suite = CipherSuite::Curve25519Kyber768AesGcmSha256;
+            for &pattern in [&XX, &NK].iter() {
+                let (mut initiator, mut responder, vault_init, vault_resp) =
+                    suite_states(pattern, suite);
+                assert!(initiator.pattern.is_hybrid());
+                assert_eq!(initiator.pattern.name, format!("{}hfs", pattern.name));
+
+                let mut messages = 0;
+                while !initiator.is_complete() {
+                    let (sender, receiver) = if messages % 2 == 0 {
+                        (&mut initiator, &mut responder)
+                    } else {
+                        (&mut responder, &mut initiator)
+                    };
+                    let payload = format!("payload_{}", messages).into_bytes();
+                    let message = sender.process(&payload).unwrap();
+                    assert_eq!(receiver.process(&message).unwrap(), payload);
+                    messages += 1;
+                }
+                assert!(responder.is_complete());
+
+                let initiator = initiator.finalize().unwrap();
+                let responder = responder.finalize().unwrap();
+                assert_eq!(initiator.h, responder.h);
+                let sent = vault_init
+                    .lock()
+                    .unwrap()
+                    .secret_export(initiator.encrypt_key)
+                    .unwrap();
+                let received = vault_resp
+                    .lock()
+                    .unwrap()
+                    .secret_export(responder.decrypt_key)
+                    .unwrap();
+                assert_eq!(sent, received);
+            }
+        }
+
+        #[test]
+        fn hybrid_ciphertext_is_authenticated() {
+            let suite = CipherSuite::Curve25519Kyber768AesGcmSha256;
+            let (mut initiator, mut responder, _, _) = suite_states(&XX, suite);
+            let m1 = initiator.process(&[]).unwrap();
+            // e and e1, both in the clear
+            assert_eq!(m1.len(), 32 + 1184);
+            assert!(responder.process(&m1[..m1.len() - 1]).is_err());
+            responder.process(&m1).unwrap();
+            let mut m2 = responder.process(&[]).unwrap();
+            // the KEM ciphertext follows e and is encrypted after ee
+            m2[40] ^= 1;
+            assert!(initiator.process(&m2).is_err());
+        }
+
+        #[test]
+        fn hybrid_suite_needs_a_hybrid_pattern() {
+            let suite = CipherSuite::Curve25519Kyber768AesGcmSha256;
+            let (mut initiator, _, _, _) = suite_states(&IK_PSK2, suite);
+            assert!(initiator.process(&[]).is_err());
+            let (mut initiator, _, _, _) =
+                suite_states(&XX_HFS, CipherSuite::Curve25519AesGcmSha256);
+            assert!(initiator.process(&[]).is_err());
+            let (initiator, _, _, _) = suite_states(&KK, suite);
+            assert_eq!(initiator.pattern.name, "KKhfs");
+        }
+    }
+}
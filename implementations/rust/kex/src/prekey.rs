@@ -0,0 +1,676 @@
+use crate::x3dh::{PreKeyBundle, Signature};
+use ockam_vault::{
+    error::{VaultFailError, VaultFailErrorKind},
+    types::{
+        SecretKey, SecretKeyAttributes, SecretKeyContext, SecretKeyType, SecretPersistenceType,
+        SecretPurposeType,
+    },
+    DynVault,
+};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default number of one-time prekeys kept ready to hand out
+pub const DEFAULT_ONE_TIME_PREKEYS: usize = 100;
+
+/// Default number of one-time prekeys left below which the pool is refilled
+pub const DEFAULT_REPLENISH_BELOW: usize = 20;
+
+/// Default time a signed prekey is handed out before it is replaced
+pub const DEFAULT_ROTATION_INTERVAL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Default time a replaced signed prekey still completes enrollments from
+/// the bundles that carry it
+pub const DEFAULT_ROTATION_GRACE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How many prekeys a `PrekeyStore` keeps and how often it rotates them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PrekeyPolicy {
+    /// One-time prekeys in the pool after it is refilled
+    pub one_time_prekeys: usize,
+    /// One-time prekeys left, not yet handed out, that trigger a refill
+    pub replenish_below: usize,
+    /// Age at which the signed prekey is replaced
+    pub rotation_interval: Duration,
+    /// Time a replaced signed prekey is kept for bundles already out
+    pub rotation_grace: Duration,
+}
+
+impl Default for PrekeyPolicy {
+    fn default() -> Self {
+        Self {
+            one_time_prekeys: DEFAULT_ONE_TIME_PREKEYS,
+            replenish_below: DEFAULT_REPLENISH_BELOW,
+            rotation_interval: DEFAULT_ROTATION_INTERVAL,
+            rotation_grace: DEFAULT_ROTATION_GRACE,
+        }
+    }
+}
+
+/// What a prekey in the store is for
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PrekeyKind {
+    Signed,
+    OneTime,
+    /// A one-time prekey that has gone out in a bundle
+    Issued,
+}
+
+impl PrekeyKind {
+    fn to_u8(self) -> u8 {
+        match self {
+            PrekeyKind::Signed => 0,
+            PrekeyKind::OneTime => 1,
+            PrekeyKind::Issued => 2,
+        }
+    }
+
+    fn from_u8(value: u8) -> Result<Self, VaultFailError> {
+        match value {
+            0 => Ok(PrekeyKind::Signed),
+            1 => Ok(PrekeyKind::OneTime),
+            2 => Ok(PrekeyKind::Issued),
+            _ => Err(VaultFailErrorKind::InvalidSecret.into()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Prekey {
+    kind: PrekeyKind,
+    id: u32,
+    secret: SecretKeyContext,
+    /// Seconds since the Unix epoch when it was created, or handed out
+    /// for an issued one-time prekey
+    created: u64,
+}
+
+impl Prekey {
+    /// Kind, id, vault id and creation time
+    const SIZE: usize = 1 + 4 + 8 + 8;
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The X3DH prekeys of one identity: a signed prekey, replaced on a
+/// schedule, and a pool of one-time prekeys that is refilled as bundles
+/// take them. Every bundle carries the current signed prekey and a one-time
+/// prekey no other bundle has, named by their ids, so a responder holding
+/// the store completes an enrollment from any bundle it handed out, once.
+///
+/// The prekeys are persistent secrets in the vault, and so is the list of
+/// them. The vault can't change a secret in place, so every change to the
+/// list writes a new secret, the index, and destroys the previous one. To
+/// `load` the store after a restart the owner has to keep the latest index:
+/// read `index` once the store is created or loaded and register
+/// `persist_index_with`, which hands over each new index before the old one
+/// is destroyed. A crash at any point leaves the persisted index readable.
+pub struct PrekeyStore {
+    vault: Arc<Mutex<dyn DynVault + Send>>,
+    identity_key: SecretKeyContext,
+    /// The curve of the identity key, and so of the prekeys
+    key_type: SecretKeyType,
+    policy: PrekeyPolicy,
+    /// The signed prekeys, the current one last
+    prekeys: Vec<Prekey>,
+    next_id: u32,
+    index: Option<SecretKeyContext>,
+    persist_index: Option<PersistIndex>,
+}
+
+/// Records where the list of prekeys went, see `PrekeyStore::persist_index_with`
+type PersistIndex = Box<dyn FnMut(SecretKeyContext) -> Result<(), VaultFailError> + Send>;
+
+impl std::fmt::Debug for PrekeyStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "PrekeyStore {{ identity_key: {:?}, key_type: {:?}, policy: {:?}, prekeys: {:?}, next_id: {}, index: {:?} }}",
+            self.identity_key, self.key_type, self.policy, self.prekeys, self.next_id, self.index
+        )
+    }
+}
+
+impl PrekeyStore {
+    /// Create a store for `identity_key` with a signed prekey and a full
+    /// pool of one-time prekeys
+    pub fn create(
+        vault: Arc<Mutex<dyn DynVault + Send>>,
+        identity_key: SecretKeyContext,
+        policy: PrekeyPolicy,
+    ) -> Result<Self, VaultFailError> {
+        let key_type = prekey_type(&vault, identity_key)?;
+        let mut store = Self {
+            vault,
+            identity_key,
+            key_type,
+            policy,
+            prekeys: vec![],
+            next_id: 0,
+            index: None,
+            persist_index: None,
+        };
+        let now = now();
+        store.generate(PrekeyKind::Signed, now)?;
+        store.replenish(now)?;
+        store.save()?;
+        Ok(store)
+    }
+
+    /// Open the store whose list of prekeys was last saved to `index`
+    pub fn load(
+        vault: Arc<Mutex<dyn DynVault + Send>>,
+        identity_key: SecretKeyContext,
+        index: SecretKeyContext,
+        policy: PrekeyPolicy,
+    ) -> Result<Self, VaultFailError> {
+        let key_type = prekey_type(&vault, identity_key)?;
+        let data = vault.lock().unwrap().secret_export(index)?;
+        let data = data.as_ref();
+        if data.len() < 4 || (data.len() - 4) % Prekey::SIZE != 0 {
+            return Err(VaultFailErrorKind::InvalidSecret.into());
+        }
+        let mut prekeys = vec![];
+        for entry in data[4..].chunks(Prekey::SIZE) {
+            prekeys.push(Prekey {
+                kind: PrekeyKind::from_u8(entry[0])?,
+                id: u32::from_be_bytes(*array_ref![entry, 1, 4]),
+                secret: SecretKeyContext::Memory(
+                    u64::from_be_bytes(*array_ref![entry, 5, 8]) as usize
+                ),
+                created: u64::from_be_bytes(*array_ref![entry, 13, 8]),
+            });
+        }
+        if !prekeys.iter().any(|p| p.kind == PrekeyKind::Signed) {
+            return Err(VaultFailErrorKind::InvalidSecret.into());
+        }
+        Ok(Self {
+            vault,
+            identity_key,
+            key_type,
+            policy,
+            prekeys,
+            next_id: u32::from_be_bytes(*array_ref![data, 0, 4]),
+            index: Some(index),
+            persist_index: None,
+        })
+    }
+
+    /// The vault secret holding the list of prekeys. It changes whenever
+    /// the store does.
+    pub fn index(&self) -> SecretKeyContext {
+        // set by both constructors
+        self.index.unwrap()
+    }
+
+    /// Call `persist` with every new index, before the previous one is
+    /// destroyed. If it fails the change is not saved and the error is
+    /// returned. It runs with the store's vault locked.
+    pub fn persist_index_with(
+        &mut self,
+        persist: impl FnMut(SecretKeyContext) -> Result<(), VaultFailError> + Send + 'static,
+    ) {
+        self.persist_index = Some(Box::new(persist));
+    }
+
+    /// The identity key that signs the prekeys
+    pub fn identity_key(&self) -> SecretKeyContext {
+        self.identity_key
+    }
+
+    pub(crate) fn vault(&self) -> Arc<Mutex<dyn DynVault + Send>> {
+        self.vault.clone()
+    }
+
+    /// One-time prekeys not yet handed out
+    pub fn one_time_prekeys_left(&self) -> usize {
+        self.count(PrekeyKind::OneTime)
+    }
+
+    /// Rotate the signed prekey if it is due, drop prekeys no bundle still
+    /// out can use and refill the pool of one-time prekeys. `bundle` does
+    /// this first; a responder that hands out few bundles should call it
+    /// on a timer too.
+    pub fn maintain(&mut self) -> Result<(), VaultFailError> {
+        self.maintain_at(now())
+    }
+
+    /// Hand out a bundle with the current signed prekey and a one-time
+    /// prekey no other bundle has
+    pub fn bundle(&mut self) -> Result<PreKeyBundle, VaultFailError> {
+        self.bundle_at(now())
+    }
+
+    pub(crate) fn maintain_at(&mut self, now: u64) -> Result<(), VaultFailError> {
+        let interval = self.policy.rotation_interval.as_secs();
+        let grace = self.policy.rotation_grace.as_secs();
+        let current = self.current_signed_prekey();
+        let mut changed = false;
+        if current.created.saturating_add(interval) <= now {
+            self.generate(PrekeyKind::Signed, now)?;
+            changed = true;
+        }
+
+        // a signed prekey is retired when the next one is created
+        let signed: Vec<Prekey> = self
+            .prekeys
+            .iter()
+            .filter(|p| p.kind == PrekeyKind::Signed)
+            .cloned()
+            .collect();
+        let mut expired: Vec<u32> = signed
+            .windows(2)
+            .filter(|pair| pair[1].created.saturating_add(grace) <= now)
+            .map(|pair| pair[0].id)
+            .collect();
+        // a bundle is no use once its signed prekey is gone
+        expired.extend(
+            self.prekeys
+                .iter()
+                .filter(|p| {
+                    p.kind == PrekeyKind::Issued
+                        && p.created.saturating_add(interval).saturating_add(grace) <= now
+                })
+                .map(|p| p.id),
+        );
+        for id in expired {
+            self.remove(id)?;
+            changed = true;
+        }
+
+        if self.one_time_prekeys_left() < self.policy.replenish_below {
+            self.replenish(now)?;
+            changed = true;
+        }
+        if changed {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn bundle_at(&mut self, now: u64) -> Result<PreKeyBundle, VaultFailError> {
+        self.maintain_at(now)?;
+        let position = match self
+            .prekeys
+            .iter()
+            .position(|p| p.kind == PrekeyKind::OneTime)
+        {
+            Some(position) => position,
+            None => {
+                self.generate(PrekeyKind::OneTime, now)?;
+                self.prekeys.len() - 1
+            }
+        };
+        // an issued prekey's age counts from when it went out
+        self.prekeys[position].kind = PrekeyKind::Issued;
+        self.prekeys[position].created = now;
+        let one_time = self.prekeys[position];
+        let signed = self.current_signed_prekey();
+        let bundle = {
+            let mut vault = self.vault.lock().unwrap();
+            let signed_prekey = vault.secret_public_key_get(signed.secret)?;
+            PreKeyBundle {
+                identity_key: vault.secret_public_key_get(self.identity_key)?,
+                signed_prekey_id: signed.id,
+                signed_prekey,
+                signature_prekey: Signature::from(
+                    vault.sign(self.identity_key, signed_prekey.as_ref())?,
+                ),
+                one_time_prekey_id: one_time.id,
+                one_time_prekey: vault.secret_public_key_get(one_time.secret)?,
+            }
+        };
+        self.save()?;
+        Ok(bundle)
+    }
+
+    /// The secrets of the prekeys an enrollment names, if the store still
+    /// holds them and the one-time prekey went out in a bundle
+    pub(crate) fn prekeys(
+        &self,
+        signed_prekey_id: u32,
+        one_time_prekey_id: u32,
+    ) -> Option<(SecretKeyContext, SecretKeyContext)> {
+        let find = |id: u32, kind: PrekeyKind| {
+            self.prekeys
+                .iter()
+                .find(|p| p.id == id && p.kind == kind)
+                .map(|p| p.secret)
+        };
+        Some((
+            find(signed_prekey_id, PrekeyKind::Signed)?,
+            find(one_time_prekey_id, PrekeyKind::Issued)?,
+        ))
+    }
+
+    /// Remove a one-time prekey once an enrollment has used it, so that
+    /// no other can
+    pub(crate) fn consume(&mut self, one_time_prekey_id: u32) -> Result<(), VaultFailError> {
+        self.remove(one_time_prekey_id)?;
+        self.save()
+    }
+
+    fn count(&self, kind: PrekeyKind) -> usize {
+        self.prekeys.iter().filter(|p| p.kind == kind).count()
+    }
+
+    fn current_signed_prekey(&self) -> Prekey {
+        // a store always has a signed prekey
+        *self
+            .prekeys
+            .iter()
+            .rev()
+            .find(|p| p.kind == PrekeyKind::Signed)
+            .unwrap()
+    }
+
+    fn generate(&mut self, kind: PrekeyKind, now: u64) -> Result<(), VaultFailError> {
+        let secret = self
+            .vault
+            .lock()
+            .unwrap()
+            .secret_generate(SecretKeyAttributes {
+                xtype: self.key_type,
+                purpose: SecretPurposeType::KeyAgreement,
+                persistence: SecretPersistenceType::Persistent,
+            })?;
+        self.prekeys.push(Prekey {
+            kind,
+            id: self.next_id,
+            secret,
+            created: now,
+        });
+        self.next_id = self.next_id.wrapping_add(1);
+        Ok(())
+    }
+
+    fn replenish(&mut self, now: u64) -> Result<(), VaultFailError> {
+        while self.one_time_prekeys_left() < self.policy.one_time_prekeys {
+            self.generate(PrekeyKind::OneTime, now)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, id: u32) -> Result<(), VaultFailError> {
+        let position = self
+            .prekeys
+            .iter()
+            .position(|p| p.id == id)
+            .ok_or_else(|| VaultFailError::from(VaultFailErrorKind::InvalidParam(0)))?;
+        let prekey = self.prekeys.remove(position);
+        self.vault.lock().unwrap().secret_destroy(prekey.secret)
+    }
+
+    /// Write the list of prekeys to a new secret, hand it to the owner and
+    /// destroy the old one
+    fn save(&mut self) -> Result<(), VaultFailError> {
+        let mut data = self.next_id.to_be_bytes().to_vec();
+        for prekey in &self.prekeys {
+            let id = match prekey.secret {
+                SecretKeyContext::Memory(id) => id as u64,
+                _ => return Err(VaultFailErrorKind::InvalidContext.into()),
+            };
+            data.push(prekey.kind.to_u8());
+            data.extend_from_slice(&prekey.id.to_be_bytes());
+            data.extend_from_slice(&id.to_be_bytes());
+            data.extend_from_slice(&prekey.created.to_be_bytes());
+        }
+        let mut vault = self.vault.lock().unwrap();
+        let index = vault.secret_import(
+            &SecretKey::Buffer(data.clone()),
+            SecretKeyAttributes {
+                xtype: SecretKeyType::Buffer(data.len()),
+                purpose: SecretPurposeType::KeyAgreement,
+                persistence: SecretPersistenceType::Persistent,
+            },
+        )?;
+        if let Some(persist) = self.persist_index.as_mut() {
+            if let Err(e) = persist(index) {
+                vault.secret_destroy(index)?;
+                return Err(e);
+            }
+        }
+        if let Some(old) = self.index.replace(index) {
+            vault.secret_destroy(old)?;
+        }
+        Ok(())
+    }
+}
+
+/// X3DH runs over Curve25519 or P-256, with prekeys on the identity key's curve
+fn prekey_type(
+    vault: &Arc<Mutex<dyn DynVault + Send>>,
+    identity_key: SecretKeyContext,
+) -> Result<SecretKeyType, VaultFailError> {
+    let attributes = vault.lock().unwrap().secret_attributes_get(identity_key)?;
+    match attributes.xtype {
+        xtype @ SecretKeyType::Curve25519 | xtype @ SecretKeyType::P256 => Ok(xtype),
+        _ => Err(VaultFailErrorKind::InvalidParam(0).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x3dh::X3dhNewKeyExchanger;
+    use crate::{CipherSuite, KeyExchanger, NewKeyExchanger};
+    use ockam_vault::software::DefaultVault;
+    use ockam_vault::types::PublicKey;
+
+    fn store(policy: PrekeyPolicy) -> (Arc<Mutex<DefaultVault>>, PrekeyStore) {
+        store_of(SecretKeyType::Curve25519, policy)
+    }
+
+    fn store_of(
+        xtype: SecretKeyType,
+        policy: PrekeyPolicy,
+    ) -> (Arc<Mutex<DefaultVault>>, PrekeyStore) {
+        let vault = Arc::new(Mutex::new(DefaultVault::default()));
+        let identity_key = vault
+            .lock()
+            .unwrap()
+            .secret_generate(SecretKeyAttributes {
+                xtype,
+                purpose: SecretPurposeType::KeyAgreement,
+                persistence: SecretPersistenceType::Persistent,
+            })
+            .unwrap();
+        let store = PrekeyStore::create(vault.clone(), identity_key, policy).unwrap();
+        (vault, store)
+    }
+
+    #[test]
+    fn one_store_enrolls_many_initiators_once_each() {
+        let policy = PrekeyPolicy {
+            one_time_prekeys: 3,
+            replenish_below: 1,
+            ..PrekeyPolicy::default()
+        };
+        let (_, store) = store(policy);
+        let store = Arc::new(Mutex::new(store));
+        let vault_i = Arc::new(Mutex::new(DefaultVault::default()));
+        let exchanger = X3dhNewKeyExchanger::with_prekey_store(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault_i,
+            store.clone(),
+        );
+
+        let mut enrollments = vec![];
+        for _ in 0..5 {
+            let mut initiator = exchanger.initiator(None);
+            let mut responder = exchanger.responder(None);
+            let eik = initiator.process(&[]).unwrap();
+            let bundle = responder.process(&[]).unwrap();
+            let enrollment = initiator.process(&bundle).unwrap();
+            responder.process(&eik).unwrap();
+            assert!(responder.process(&enrollment).is_ok());
+            assert!(responder.is_complete());
+            enrollments.push((eik, enrollment));
+        }
+
+        // the one-time prekey of a completed enrollment is gone
+        for (eik, enrollment) in enrollments {
+            let mut responder = exchanger.responder(None);
+            responder.process(&[]).unwrap();
+            responder.process(&eik).unwrap();
+            assert!(responder.process(&enrollment).is_err());
+            assert!(!responder.is_complete());
+        }
+    }
+
+    #[test]
+    fn signed_prekey_rotates_with_a_grace_period() {
+        let policy = PrekeyPolicy {
+            rotation_interval: Duration::from_secs(100),
+            rotation_grace: Duration::from_secs(50),
+            ..PrekeyPolicy::default()
+        };
+        let (_, mut store) = store(policy);
+        let start = now();
+        let first = store.bundle_at(start).unwrap();
+
+        let second = store.bundle_at(start + 100).unwrap();
+        assert_ne!(first.signed_prekey_id, second.signed_prekey_id);
+        assert_ne!(first.signed_prekey, second.signed_prekey);
+        assert!(store
+            .prekeys(first.signed_prekey_id, first.one_time_prekey_id)
+            .is_some());
+
+        store.maintain_at(start + 150).unwrap();
+        assert!(store
+            .prekeys(first.signed_prekey_id, first.one_time_prekey_id)
+            .is_none());
+        assert!(store
+            .prekeys(second.signed_prekey_id, second.one_time_prekey_id)
+            .is_some());
+    }
+
+    #[test]
+    fn one_time_prekeys_are_replenished() {
+        let policy = PrekeyPolicy {
+            one_time_prekeys: 4,
+            replenish_below: 2,
+            ..PrekeyPolicy::default()
+        };
+        let (_, mut store) = store(policy);
+        assert_eq!(store.one_time_prekeys_left(), 4);
+
+        let mut ids = vec![];
+        for left in &[3, 2, 1, 3] {
+            let bundle = store.bundle().unwrap();
+            assert_eq!(store.one_time_prekeys_left(), *left);
+            assert!(!ids.contains(&bundle.one_time_prekey_id));
+            ids.push(bundle.one_time_prekey_id);
+        }
+    }
+
+    #[test]
+    fn load_from_index() {
+        let (vault, mut store) = store(PrekeyPolicy::default());
+        let first_index = store.index();
+        let bundle = store.bundle().unwrap();
+        assert_ne!(store.index(), first_index);
+        assert!(vault.lock().unwrap().secret_export(first_index).is_err());
+
+        let loaded = PrekeyStore::load(
+            vault.clone(),
+            store.identity_key(),
+            store.index(),
+            PrekeyPolicy::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            loaded.one_time_prekeys_left(),
+            store.one_time_prekeys_left()
+        );
+        assert_eq!(
+            loaded.prekeys(bundle.signed_prekey_id, bundle.one_time_prekey_id),
+            store.prekeys(bundle.signed_prekey_id, bundle.one_time_prekey_id)
+        );
+        assert!(loaded
+            .prekeys(bundle.signed_prekey_id, bundle.one_time_prekey_id)
+            .is_some());
+    }
+
+    #[test]
+    fn persists_each_new_index() {
+        let (vault, mut store) = store(PrekeyPolicy::default());
+        let persisted = Arc::new(Mutex::new(store.index()));
+        let sink = persisted.clone();
+        store.persist_index_with(move |index| {
+            *sink.lock().unwrap() = index;
+            Ok(())
+        });
+
+        let bundle = store.bundle().unwrap();
+        assert_eq!(*persisted.lock().unwrap(), store.index());
+        store.consume(bundle.one_time_prekey_id).unwrap();
+        assert_eq!(*persisted.lock().unwrap(), store.index());
+
+        let index = *persisted.lock().unwrap();
+        let loaded =
+            PrekeyStore::load(vault, store.identity_key(), index, PrekeyPolicy::default()).unwrap();
+        assert!(loaded
+            .prekeys(bundle.signed_prekey_id, bundle.one_time_prekey_id)
+            .is_none());
+
+        // a failed hand-over keeps the previous index
+        let index = store.index();
+        store.persist_index_with(|_| Err(VaultFailErrorKind::IOError.into()));
+        assert!(store.bundle().is_err());
+        assert_eq!(store.index(), index);
+    }
+
+    #[test]
+    fn p256_store() {
+        let (_, store) = store_of(SecretKeyType::P256, PrekeyPolicy::default());
+        let store = Arc::new(Mutex::new(store));
+        let vault_i = Arc::new(Mutex::new(DefaultVault::default()));
+
+        // a Curve25519 responder won't hand out P-256 bundles
+        let exchanger = X3dhNewKeyExchanger::with_prekey_store(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault_i.clone(),
+            store.clone(),
+        );
+        assert!(exchanger.responder(None).process(&[]).is_err());
+
+        let exchanger = X3dhNewKeyExchanger::with_prekey_store(
+            CipherSuite::P256Aes128GcmSha256,
+            vault_i,
+            store,
+        );
+        let mut initiator = exchanger.initiator(None);
+        let mut responder = exchanger.responder(None);
+        let eik = initiator.process(&[]).unwrap();
+        let bundle = responder.process(&[]).unwrap();
+        let enrollment = initiator.process(&bundle).unwrap();
+        responder.process(&eik).unwrap();
+        responder.process(&enrollment).unwrap();
+        assert!(responder.is_complete());
+        assert!(matches!(
+            responder.finalize().unwrap().remote_static_public_key,
+            PublicKey::P256(_)
+        ));
+    }
+
+    #[test]
+    fn rejects_identity_keys_off_curve() {
+        let vault = Arc::new(Mutex::new(DefaultVault::default()));
+        let identity_key = vault
+            .lock()
+            .unwrap()
+            .secret_generate(SecretKeyAttributes {
+                xtype: SecretKeyType::Aes256,
+                purpose: SecretPurposeType::KeyAgreement,
+                persistence: SecretPersistenceType::Persistent,
+            })
+            .unwrap();
+        assert!(PrekeyStore::create(vault, identity_key, PrekeyPolicy::default()).is_err());
+    }
+}
@@ -0,0 +1,738 @@
+use crate::error::{KexExchangeFailError, KeyExchangeFailErrorKind};
+use crate::{CompletedKeyExchange, AES256_KEYSIZE, AES_GCM_TAGSIZE, SHA256_SIZE};
+use ockam_vault::{
+    error::VaultFailError,
+    types::{
+        PublicKey, SecretKey, SecretKeyAttributes, SecretKeyContext, SecretKeyType,
+        SecretPersistenceType, SecretPurposeType,
+    },
+    DynVault,
+};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Most message keys a session derives ahead for messages that have not
+/// arrived yet, per chain and in total
+pub const MAX_SKIP: usize = 1000;
+
+/// Bytes an encrypted header adds in front of every message
+pub const ENCRYPTED_HEADER_SIZE: usize = NONCE_SIZE + HEADER_SIZE + AES_GCM_TAGSIZE;
+
+/// Exporter label of the root key and the first header keys
+const EXPORTER_LABEL: &[u8] = b"ockam double ratchet";
+/// HKDF info of the root chain
+const ROOT_INFO: &[u8] = b"ockam ratchet root";
+/// HKDF info of the sending and receiving chains
+const CHAIN_INFO: &[u8] = b"ockam ratchet chain";
+/// The ratchet public key, the length of the previous sending chain and the
+/// message number
+const HEADER_SIZE: usize = 32 + 4 + 4;
+const NONCE_SIZE: usize = 12;
+/// Every message key encrypts one message, so it never sees another nonce
+const MESSAGE_NONCE: [u8; NONCE_SIZE] = [0u8; NONCE_SIZE];
+
+#[derive(Clone, Copy, Debug)]
+struct Header {
+    dh: PublicKey,
+    pn: u32,
+    n: u32,
+}
+
+impl Header {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut v = self.dh.as_ref().to_vec();
+        v.extend_from_slice(&self.pn.to_be_bytes());
+        v.extend_from_slice(&self.n.to_be_bytes());
+        v
+    }
+
+    fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() != HEADER_SIZE {
+            return None;
+        }
+        Some(Self {
+            dh: PublicKey::Curve25519(*array_ref![data, 0, 32]),
+            pn: u32::from_be_bytes(*array_ref![data, 32, 4]),
+            n: u32::from_be_bytes(*array_ref![data, 36, 4]),
+        })
+    }
+}
+
+/// The key of a message that has not arrived yet, and the header key of
+/// its chain to recognize it by
+#[derive(Clone, Copy, Debug)]
+struct SkippedKey {
+    header_key: SecretKeyContext,
+    n: u32,
+    message_key: SecretKeyContext,
+}
+
+fn chain_attributes() -> SecretKeyAttributes {
+    SecretKeyAttributes {
+        xtype: SecretKeyType::Buffer(SHA256_SIZE),
+        purpose: SecretPurposeType::KeyAgreement,
+        persistence: SecretPersistenceType::Ephemeral,
+    }
+}
+
+fn key_attributes() -> SecretKeyAttributes {
+    SecretKeyAttributes {
+        xtype: SecretKeyType::Aes256,
+        purpose: SecretPurposeType::KeyAgreement,
+        persistence: SecretPersistenceType::Ephemeral,
+    }
+}
+
+fn ratchet_key_attributes() -> SecretKeyAttributes {
+    SecretKeyAttributes {
+        xtype: SecretKeyType::Curve25519,
+        purpose: SecretPurposeType::KeyAgreement,
+        persistence: SecretPersistenceType::Ephemeral,
+    }
+}
+
+/// Step the root chain with a DH output, giving the next root key, a chain
+/// key and the header key of the chain after it. Consumes both inputs.
+fn kdf_rk(
+    vault: &mut dyn DynVault,
+    rk: SecretKeyContext,
+    dh: SecretKeyContext,
+) -> Result<(SecretKeyContext, SecretKeyContext, SecretKeyContext), VaultFailError> {
+    let keys = vault.hkdf_sha256(
+        rk,
+        ROOT_INFO,
+        Some(dh),
+        vec![chain_attributes(), chain_attributes(), key_attributes()],
+    )?;
+    vault.secret_destroy(rk)?;
+    vault.secret_destroy(dh)?;
+    Ok((keys[0], keys[1], keys[2]))
+}
+
+/// Step a sending or receiving chain, giving the next chain key and a
+/// message key. Consumes the chain key.
+fn kdf_ck(
+    vault: &mut dyn DynVault,
+    ck: SecretKeyContext,
+) -> Result<(SecretKeyContext, SecretKeyContext), VaultFailError> {
+    let keys = vault.hkdf_sha256(
+        ck,
+        CHAIN_INFO,
+        None,
+        vec![chain_attributes(), key_attributes()],
+    )?;
+    vault.secret_destroy(ck)?;
+    Ok((keys[0], keys[1]))
+}
+
+/// The root key and the first header keys of both sides, exported from the
+/// key exchange
+fn shared_keys(
+    vault: &mut dyn DynVault,
+    completed: &CompletedKeyExchange,
+) -> Result<(SecretKeyContext, SecretKeyContext, SecretKeyContext), VaultFailError> {
+    let bytes = completed.export(vault, EXPORTER_LABEL, &[], SHA256_SIZE + 2 * AES256_KEYSIZE)?;
+    let rk = vault.secret_import(
+        &SecretKey::Buffer(bytes[..SHA256_SIZE].to_vec()),
+        chain_attributes(),
+    )?;
+    let hka = vault.secret_import(
+        &SecretKey::Aes256(*array_ref![bytes, SHA256_SIZE, AES256_KEYSIZE]),
+        key_attributes(),
+    )?;
+    let nhkb = vault.secret_import(
+        &SecretKey::Aes256(*array_ref![
+            bytes,
+            SHA256_SIZE + AES256_KEYSIZE,
+            AES256_KEYSIZE
+        ]),
+        key_attributes(),
+    )?;
+    Ok((rk, hka, nhkb))
+}
+
+/// A Double Ratchet session, with header encryption, started from a
+/// completed key exchange such as X3DH.
+///
+/// Every message is encrypted under its own key, from a chain that a
+/// symmetric ratchet steps and deletes keys behind, so a compromised
+/// session cannot read earlier messages. Each time the sides take turns a
+/// DH ratchet mixes fresh ephemeral keys into the root of the chains, so a
+/// session recovers once the attacker stops holding its keys. Messages may
+/// arrive late, out of order or not at all: keys of skipped messages are
+/// kept, up to `MAX_SKIP`, until they do. Headers are encrypted too, so
+/// the ratchet keys and message numbers don't link messages to each other.
+///
+/// The initiator of the exchange sends first, to the responder's first
+/// ratchet key: with X3DH, the signed prekey of the bundle, as the Signal
+/// specification has it. The responder can send once it has received a
+/// message.
+///
+/// Sessions are a building block: channels and ockamd don't run one yet,
+/// so the application carries the messages a session encrypts itself.
+pub struct RatchetSession {
+    vault: Arc<Mutex<dyn DynVault + Send>>,
+    /// This side's ratchet key, which is the responder's signed prekey until
+    /// the first DH ratchet step and not ours to destroy
+    dhs: SecretKeyContext,
+    dhs_owned: bool,
+    dhr: Option<PublicKey>,
+    rk: SecretKeyContext,
+    cks: Option<SecretKeyContext>,
+    ckr: Option<SecretKeyContext>,
+    hks: Option<SecretKeyContext>,
+    hkr: Option<SecretKeyContext>,
+    nhks: SecretKeyContext,
+    nhkr: SecretKeyContext,
+    ns: u32,
+    nr: u32,
+    pn: u32,
+    skipped: VecDeque<SkippedKey>,
+}
+
+impl std::fmt::Debug for RatchetSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "RatchetSession {{ vault, dhr: {:?}, ns: {}, nr: {}, pn: {}, skipped: {} }}",
+            self.dhr,
+            self.ns,
+            self.nr,
+            self.pn,
+            self.skipped.len()
+        )
+    }
+}
+
+impl RatchetSession {
+    /// Start the session of the side that initiated `completed`, which
+    /// sends the first message. `ratchet_key` is the responder's first
+    /// ratchet key, for X3DH `X3dhInitiator::signed_prekey`.
+    pub fn initiator(
+        vault: Arc<Mutex<dyn DynVault + Send>>,
+        completed: &CompletedKeyExchange,
+        ratchet_key: PublicKey,
+    ) -> Result<Self, KexExchangeFailError> {
+        let dhr = match ratchet_key {
+            dhr @ PublicKey::Curve25519(_) => dhr,
+            _ => return Err(KeyExchangeFailErrorKind::InvalidParam(2).into()),
+        };
+        let session = {
+            let mut v = vault.lock().unwrap();
+            let (sk, hka, nhkb) = shared_keys(&mut *v, completed)?;
+            let dhs = v.secret_generate(ratchet_key_attributes())?;
+            let dh = v.ec_diffie_hellman(dhs, dhr)?;
+            let (rk, cks, nhks) = kdf_rk(&mut *v, sk, dh)?;
+            Self {
+                vault: vault.clone(),
+                dhs,
+                dhs_owned: true,
+                dhr: Some(dhr),
+                rk,
+                cks: Some(cks),
+                ckr: None,
+                hks: Some(hka),
+                hkr: None,
+                nhks,
+                nhkr: nhkb,
+                ns: 0,
+                nr: 0,
+                pn: 0,
+                skipped: VecDeque::new(),
+            }
+        };
+        Ok(session)
+    }
+
+    /// Start the session of the side that responded to `completed`, which
+    /// waits for the first message. `ratchet_key` is this side's first
+    /// ratchet key, for X3DH `X3dhResponder::signed_prekey`; it has to stay
+    /// in the vault until the first message arrives.
+    pub fn responder(
+        vault: Arc<Mutex<dyn DynVault + Send>>,
+        completed: &CompletedKeyExchange,
+        ratchet_key: SecretKeyContext,
+    ) -> Result<Self, KexExchangeFailError> {
+        let session = {
+            let mut v = vault.lock().unwrap();
+            let attributes = v.secret_attributes_get(ratchet_key)?;
+            if !matches!(attributes.xtype, SecretKeyType::Curve25519) {
+                return Err(KeyExchangeFailErrorKind::InvalidParam(2).into());
+            }
+            let (sk, hka, nhkb) = shared_keys(&mut *v, completed)?;
+            Self {
+                vault: vault.clone(),
+                dhs: ratchet_key,
+                dhs_owned: false,
+                dhr: None,
+                rk: sk,
+                cks: None,
+                ckr: None,
+                hks: None,
+                hkr: None,
+                nhks: nhkb,
+                nhkr: hka,
+                ns: 0,
+                nr: 0,
+                pn: 0,
+                skipped: VecDeque::new(),
+            }
+        };
+        Ok(session)
+    }
+
+    /// Encrypt a message, authenticating `ad` along with it. The message is
+    /// the encrypted header followed by the ciphertext and tag.
+    pub fn encrypt(
+        &mut self,
+        plaintext: &[u8],
+        ad: &[u8],
+    ) -> Result<Vec<u8>, KexExchangeFailError> {
+        let (cks, hks) = match (self.cks, self.hks) {
+            (Some(cks), Some(hks)) => (cks, hks),
+            _ => {
+                return Err(KeyExchangeFailErrorKind::MethodCalledOutOfSequence {
+                    actual: "encrypt",
+                    expected: "decrypt",
+                }
+                .into())
+            }
+        };
+        if self.ns == u32::MAX {
+            return Err(KeyExchangeFailErrorKind::GeneralError {
+                msg: "Sending chain exhausted".to_string(),
+            }
+            .into());
+        }
+        let vault = self.vault.clone();
+        let mut vault = vault.lock().unwrap();
+        let (ck, mk) = kdf_ck(&mut *vault, cks)?;
+        self.cks = Some(ck);
+        let header = Header {
+            dh: vault.secret_public_key_get(self.dhs)?,
+            pn: self.pn,
+            n: self.ns,
+        };
+        self.ns += 1;
+
+        // header keys encrypt a whole chain of headers, so each gets a
+        // random nonce
+        let mut message = vec![0u8; NONCE_SIZE];
+        vault.random(&mut message)?;
+        let mut encrypted_header =
+            vault.aead_aes_gcm_encrypt(hks, &header.to_bytes(), &message, &[])?;
+        message.append(&mut encrypted_header);
+
+        let mut aad = ad.to_vec();
+        aad.extend_from_slice(&message);
+        let ciphertext = vault.aead_aes_gcm_encrypt(mk, plaintext, &MESSAGE_NONCE, &aad);
+        vault.secret_destroy(mk)?;
+        message.append(&mut ciphertext?);
+        Ok(message)
+    }
+
+    /// Decrypt a message from the other side's `encrypt`, with the same
+    /// `ad`. A message that fails to decrypt leaves the session able to
+    /// decrypt the genuine one; a message already decrypted is refused.
+    pub fn decrypt(&mut self, message: &[u8], ad: &[u8]) -> Result<Vec<u8>, KexExchangeFailError> {
+        if message.len() < ENCRYPTED_HEADER_SIZE + AES_GCM_TAGSIZE {
+            return Err(KeyExchangeFailErrorKind::InvalidByteCount(
+                ENCRYPTED_HEADER_SIZE + AES_GCM_TAGSIZE,
+                message.len(),
+            )
+            .into());
+        }
+        let (encrypted_header, ciphertext) = message.split_at(ENCRYPTED_HEADER_SIZE);
+        let mut aad = ad.to_vec();
+        aad.extend_from_slice(encrypted_header);
+
+        let vault = self.vault.clone();
+        let mut vault = vault.lock().unwrap();
+        if let Some(plaintext) =
+            self.try_skipped_keys(&mut *vault, encrypted_header, ciphertext, &aad)?
+        {
+            return Ok(plaintext);
+        }
+
+        let (header, dh_ratchet) = self.decrypt_header(&mut *vault, encrypted_header)?;
+        // check the header before anything changes, so a message that
+        // fails leaves the session as it was
+        let (behind, ahead) = if dh_ratchet {
+            let behind = match self.ckr {
+                Some(_) => header.pn.saturating_sub(self.nr),
+                None => 0,
+            };
+            (behind, header.n)
+        } else if header.n < self.nr {
+            return Err(KeyExchangeFailErrorKind::GeneralError {
+                msg: "Message already received".to_string(),
+            }
+            .into());
+        } else {
+            (0, header.n - self.nr)
+        };
+        if behind as usize > MAX_SKIP || ahead as usize > MAX_SKIP {
+            return Err(KeyExchangeFailErrorKind::GeneralError {
+                msg: "Too many skipped messages".to_string(),
+            }
+            .into());
+        }
+
+        if dh_ratchet {
+            self.skip_message_keys(&mut *vault, header.pn)?;
+            self.dh_ratchet(&mut *vault, header.dh)?;
+        }
+        self.skip_message_keys(&mut *vault, header.n)?;
+        // set by the DH ratchet step above, if not before
+        let (ck, mk) = kdf_ck(&mut *vault, self.ckr.unwrap())?;
+        self.ckr = Some(ck);
+        self.nr += 1;
+
+        match vault.aead_aes_gcm_decrypt(mk, ciphertext, &MESSAGE_NONCE, &aad) {
+            Ok(plaintext) => {
+                vault.secret_destroy(mk)?;
+                Ok(plaintext)
+            }
+            Err(e) => {
+                // the header is genuine, so keep the key for the genuine
+                // message
+                self.skipped.push_back(SkippedKey {
+                    header_key: self.hkr.unwrap(),
+                    n: header.n,
+                    message_key: mk,
+                });
+                self.evict_skipped_keys(&mut *vault)?;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Remove every key of the session from the vault. The static key the
+    /// session started from is left to its owner.
+    pub fn destroy(&mut self) -> Result<(), VaultFailError> {
+        let vault = self.vault.clone();
+        let mut vault = vault.lock().unwrap();
+        let mut secrets = vec![self.rk, self.nhks, self.nhkr];
+        if self.dhs_owned {
+            secrets.push(self.dhs);
+            self.dhs_owned = false;
+        }
+        secrets.extend(
+            [
+                self.cks.take(),
+                self.ckr.take(),
+                self.hks.take(),
+                self.hkr.take(),
+            ]
+            .iter()
+            .flatten(),
+        );
+        for skipped in self.skipped.drain(..) {
+            secrets.push(skipped.header_key);
+            secrets.push(skipped.message_key);
+        }
+        secrets.sort();
+        secrets.dedup();
+        for secret in secrets {
+            vault.secret_destroy(secret)?;
+        }
+        Ok(())
+    }
+
+    /// Decrypt with the key of a skipped message, if the message is one
+    fn try_skipped_keys(
+        &mut self,
+        vault: &mut dyn DynVault,
+        encrypted_header: &[u8],
+        ciphertext: &[u8],
+        aad: &[u8],
+    ) -> Result<Option<Vec<u8>>, KexExchangeFailError> {
+        let mut tried = vec![];
+        for i in 0..self.skipped.len() {
+            let header_key = self.skipped[i].header_key;
+            if tried.contains(&header_key) {
+                continue;
+            }
+            tried.push(header_key);
+            let header = match open_header(vault, header_key, encrypted_header) {
+                Some(header) => header,
+                None => continue,
+            };
+            let position = match self
+                .skipped
+                .iter()
+                .position(|s| s.header_key == header_key && s.n == header.n)
+            {
+                Some(position) => position,
+                None => return Ok(None),
+            };
+            let skipped = self.skipped[position];
+            let plaintext =
+                vault.aead_aes_gcm_decrypt(skipped.message_key, ciphertext, &MESSAGE_NONCE, aad)?;
+            self.skipped.remove(position);
+            vault.secret_destroy(skipped.message_key)?;
+            self.release_header_key(vault, header_key)?;
+            return Ok(Some(plaintext));
+        }
+        Ok(None)
+    }
+
+    /// Decrypt the header with the receiving header key, or else with the
+    /// next one, which means the other side has taken a DH ratchet step
+    fn decrypt_header(
+        &self,
+        vault: &mut dyn DynVault,
+        encrypted_header: &[u8],
+    ) -> Result<(Header, bool), KexExchangeFailError> {
+        if let Some(hkr) = self.hkr {
+            if let Some(header) = open_header(vault, hkr, encrypted_header) {
+                return Ok((header, false));
+            }
+        }
+        if let Some(header) = open_header(vault, self.nhkr, encrypted_header) {
+            return Ok((header, true));
+        }
+        Err(KeyExchangeFailErrorKind::GeneralError {
+            msg: "Unknown message header".to_string(),
+        }
+        .into())
+    }
+
+    /// Keep the keys of the receiving chain's messages up to `until`
+    fn skip_message_keys(
+        &mut self,
+        vault: &mut dyn DynVault,
+        until: u32,
+    ) -> Result<(), VaultFailError> {
+        let (mut ckr, header_key) = match (self.ckr, self.hkr) {
+            (Some(ckr), Some(hkr)) => (ckr, hkr),
+            _ => return Ok(()),
+        };
+        while self.nr < until {
+            let (ck, mk) = kdf_ck(vault, ckr)?;
+            ckr = ck;
+            self.ckr = Some(ck);
+            self.skipped.push_back(SkippedKey {
+                header_key,
+                n: self.nr,
+                message_key: mk,
+            });
+            self.nr += 1;
+        }
+        self.evict_skipped_keys(vault)
+    }
+
+    /// Drop the oldest skipped keys beyond `MAX_SKIP`
+    fn evict_skipped_keys(&mut self, vault: &mut dyn DynVault) -> Result<(), VaultFailError> {
+        while self.skipped.len() > MAX_SKIP {
+            // not empty while above the limit
+            let skipped = self.skipped.pop_front().unwrap();
+            vault.secret_destroy(skipped.message_key)?;
+            self.release_header_key(vault, skipped.header_key)?;
+        }
+        Ok(())
+    }
+
+    fn dh_ratchet(
+        &mut self,
+        vault: &mut dyn DynVault,
+        dhr: PublicKey,
+    ) -> Result<(), VaultFailError> {
+        self.pn = self.ns;
+        self.ns = 0;
+        self.nr = 0;
+        let old_hks = self.hks.replace(self.nhks);
+        let old_hkr = self.hkr.replace(self.nhkr);
+        self.dhr = Some(dhr);
+
+        let dh = vault.ec_diffie_hellman(self.dhs, dhr)?;
+        let (rk, ckr, nhkr) = kdf_rk(vault, self.rk, dh)?;
+        self.rk = rk;
+        if let Some(old) = self.ckr.replace(ckr) {
+            vault.secret_destroy(old)?;
+        }
+        self.nhkr = nhkr;
+
+        if self.dhs_owned {
+            vault.secret_destroy(self.dhs)?;
+        }
+        self.dhs = vault.secret_generate(ratchet_key_attributes())?;
+        self.dhs_owned = true;
+        let dh = vault.ec_diffie_hellman(self.dhs, dhr)?;
+        let (rk, cks, nhks) = kdf_rk(vault, self.rk, dh)?;
+        self.rk = rk;
+        if let Some(old) = self.cks.replace(cks) {
+            vault.secret_destroy(old)?;
+        }
+        self.nhks = nhks;
+
+        for old in [old_hks, old_hkr].iter().flatten() {
+            self.release_header_key(vault, *old)?;
+        }
+        Ok(())
+    }
+
+    /// Destroy a header key the session no longer uses
+    fn release_header_key(
+        &self,
+        vault: &mut dyn DynVault,
+        header_key: SecretKeyContext,
+    ) -> Result<(), VaultFailError> {
+        let in_use = [self.hks, self.hkr, Some(self.nhks), Some(self.nhkr)]
+            .contains(&Some(header_key))
+            || self.skipped.iter().any(|s| s.header_key == header_key);
+        if in_use {
+            return Ok(());
+        }
+        vault.secret_destroy(header_key)
+    }
+}
+
+/// Decrypt a header with a header key, if it is the one
+fn open_header(
+    vault: &mut dyn DynVault,
+    header_key: SecretKeyContext,
+    encrypted_header: &[u8],
+) -> Option<Header> {
+    let (nonce, ciphertext) = encrypted_header.split_at(NONCE_SIZE);
+    let header = vault
+        .aead_aes_gcm_decrypt(header_key, ciphertext, nonce, &[])
+        .ok()?;
+    Header::from_bytes(&header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x3dh::X3dhNewKeyExchanger;
+    use crate::{CipherSuite, KeyExchanger, NewKeyExchanger};
+    use ockam_vault::software::DefaultVault;
+
+    fn sessions() -> (RatchetSession, RatchetSession) {
+        let vault_i = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_r = Arc::new(Mutex::new(DefaultVault::default()));
+        let exchanger = X3dhNewKeyExchanger::new(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault_i.clone(),
+            vault_r.clone(),
+        );
+        let mut initiator = exchanger.initiator(None);
+        let mut responder = exchanger.responder(None);
+        let eik = initiator.process(&[]).unwrap();
+        let bundle = responder.process(&[]).unwrap();
+        let enrollment = initiator.process(&bundle).unwrap();
+        responder.process(&eik).unwrap();
+        responder.process(&enrollment).unwrap();
+
+        let alice = RatchetSession::initiator(
+            vault_i,
+            &initiator.finalize().unwrap(),
+            initiator.signed_prekey().unwrap(),
+        )
+        .unwrap();
+        let bob = RatchetSession::responder(
+            vault_r,
+            &responder.finalize().unwrap(),
+            responder.signed_prekey().unwrap(),
+        )
+        .unwrap();
+        (alice, bob)
+    }
+
+    #[test]
+    fn conversation() {
+        let (mut alice, mut bob) = sessions();
+        assert!(bob.encrypt(b"too early", &[]).is_err());
+
+        for round in 0..4u8 {
+            for i in 0..3u8 {
+                let message = alice.encrypt(&[round, i], b"ad").unwrap();
+                assert_eq!(message.len(), ENCRYPTED_HEADER_SIZE + 2 + AES_GCM_TAGSIZE);
+                assert_eq!(bob.decrypt(&message, b"ad").unwrap(), vec![round, i]);
+            }
+            let message = bob.encrypt(&[round], &[]).unwrap();
+            assert_eq!(alice.decrypt(&message, &[]).unwrap(), vec![round]);
+        }
+    }
+
+    #[test]
+    fn out_of_order_messages() {
+        let (mut alice, mut bob) = sessions();
+        let first: Vec<Vec<u8>> = (0..5u8)
+            .map(|i| alice.encrypt(&[i], &[]).unwrap())
+            .collect();
+        for &i in &[3usize, 0, 4] {
+            assert_eq!(bob.decrypt(&first[i], &[]).unwrap(), vec![i as u8]);
+        }
+
+        // Alice moves on to a new chain before the rest arrive
+        let reply = bob.encrypt(b"reply", &[]).unwrap();
+        assert_eq!(alice.decrypt(&reply, &[]).unwrap(), b"reply");
+        let second = alice.encrypt(b"second", &[]).unwrap();
+        assert_eq!(bob.decrypt(&second, &[]).unwrap(), b"second");
+
+        assert_eq!(bob.decrypt(&first[2], &[]).unwrap(), vec![2]);
+        assert_eq!(bob.decrypt(&first[1], &[]).unwrap(), vec![1]);
+        assert!(bob.skipped.is_empty());
+    }
+
+    #[test]
+    fn replayed_and_tampered_messages() {
+        let (mut alice, mut bob) = sessions();
+        let message = alice.encrypt(b"hello", b"ad").unwrap();
+
+        let mut tampered = message.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(bob.decrypt(&tampered, b"ad").is_err());
+        assert!(bob.decrypt(&message, b"other ad").is_err());
+        let mut tampered = message.clone();
+        tampered[NONCE_SIZE] ^= 1;
+        assert!(bob.decrypt(&tampered, b"ad").is_err());
+
+        assert_eq!(bob.decrypt(&message, b"ad").unwrap(), b"hello");
+        assert!(bob.decrypt(&message, b"ad").is_err());
+
+        let next = alice.encrypt(b"next", &[]).unwrap();
+        assert_eq!(bob.decrypt(&next, &[]).unwrap(), b"next");
+        assert!(bob.decrypt(&message, b"ad").is_err());
+    }
+
+    #[test]
+    fn too_many_skipped_messages() {
+        let (mut alice, mut bob) = sessions();
+        for _ in 0..MAX_SKIP + 1 {
+            alice.encrypt(&[], &[]).unwrap();
+        }
+        let message = alice.encrypt(b"late", &[]).unwrap();
+        assert!(bob.decrypt(&message, &[]).is_err());
+
+        let (mut alice, mut bob) = sessions();
+        for _ in 0..MAX_SKIP {
+            alice.encrypt(&[], &[]).unwrap();
+        }
+        let message = alice.encrypt(b"late", &[]).unwrap();
+        assert_eq!(bob.decrypt(&message, &[]).unwrap(), b"late");
+        assert_eq!(bob.skipped.len(), MAX_SKIP);
+    }
+
+    #[test]
+    fn destroy_leaves_no_keys() {
+        let (mut alice, mut bob) = sessions();
+        let lost = alice.encrypt(b"lost", &[]).unwrap();
+        let message = alice.encrypt(b"hello", &[]).unwrap();
+        bob.decrypt(&message, &[]).unwrap();
+        let reply = bob.encrypt(b"hi", &[]).unwrap();
+        alice.decrypt(&reply, &[]).unwrap();
+        assert_eq!(bob.skipped.len(), 1);
+
+        let skipped = bob.skipped[0];
+        let rk = bob.rk;
+        bob.destroy().unwrap();
+        let mut vault = bob.vault.lock().unwrap();
+        for secret in &[rk, skipped.header_key, skipped.message_key] {
+            assert!(vault.secret_export(*secret).is_err());
+        }
+        drop(vault);
+        assert!(bob.decrypt(&lost, &[]).is_err());
+    }
+}
@@ -0,0 +1,288 @@
+//! Conformance with Noise test vectors in the JSON format of cacophony and
+//! snow. Every JSON file in `vectors/` is loaded, and each vector for a
+//! protocol we support runs through an initiator and a responder holding
+//! the vector's keys; every handshake and transport message has to match
+//! byte for byte. `vectors/` holds the published vectors of cacophony and
+//! snow for the protocols we support, which between them cover all of them.
+use crate::ik::{IK, IK_PSK2};
+use crate::kk::KK;
+use crate::nk::NK;
+use crate::pattern::{HandshakePattern, HandshakeState};
+use crate::xx::{SymmetricState, XX, XX_PSK3};
+use crate::{CipherSuite, CompletedKeyExchange, KeyExchanger, SHA256_SIZE};
+use ockam_vault::software::DefaultVault;
+use ockam_vault::types::{
+    SecretKey, SecretKeyAttributes, SecretKeyType, SecretPersistenceType, SecretPurposeType,
+};
+use ockam_vault::DynVault;
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+static PATTERNS: [&HandshakePattern; 6] = [&XX, &XX_PSK3, &IK, &IK_PSK2, &KK, &NK];
+
+#[derive(Deserialize)]
+struct VectorFile {
+    vectors: Vec<Vector>,
+}
+
+#[derive(Deserialize)]
+struct Vector {
+    #[serde(alias = "name")]
+    protocol_name: String,
+    #[serde(default)]
+    fallback: bool,
+    #[serde(default)]
+    init_prologue: String,
+    #[serde(default)]
+    init_psks: Vec<String>,
+    init_static: Option<String>,
+    init_ephemeral: Option<String>,
+    init_remote_static: Option<String>,
+    #[serde(default)]
+    resp_prologue: String,
+    #[serde(default)]
+    resp_psks: Vec<String>,
+    resp_static: Option<String>,
+    resp_ephemeral: Option<String>,
+    resp_remote_static: Option<String>,
+    handshake_hash: Option<String>,
+    messages: Vec<Message>,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    payload: String,
+    ciphertext: String,
+}
+
+/// The pattern and cipher suite of a protocol name such as
+/// `Noise_XX_25519_AESGCM_SHA256`, if we support it
+fn protocol(name: &str) -> Option<(&'static HandshakePattern, CipherSuite)> {
+    let parts: Vec<&str> = name.split('_').collect();
+    if parts.len() != 5 || parts[0] != "Noise" || parts[2] != "25519" || parts[4] != "SHA256" {
+        return None;
+    }
+    let cipher_suite = match parts[3] {
+        "AESGCM" => CipherSuite::Curve25519AesGcmSha256,
+        "ChaChaPoly" => CipherSuite::Curve25519ChaChaPolySha256,
+        _ => return None,
+    };
+    let pattern = PATTERNS.iter().find(|pattern| pattern.name == parts[1])?;
+    Some((*pattern, cipher_suite))
+}
+
+/// One side of the vector's handshake, past its prologue
+fn handshake_state(
+    vector: &Vector,
+    pattern: &'static HandshakePattern,
+    cipher_suite: CipherSuite,
+    initiator: bool,
+    vault: Arc<Mutex<DefaultVault>>,
+) -> HandshakeState {
+    let (static_private, ephemeral_private, remote_static, prologue, psks) = if initiator {
+        (
+            &vector.init_static,
+            &vector.init_ephemeral,
+            &vector.init_remote_static,
+            &vector.init_prologue,
+            &vector.init_psks,
+        )
+    } else {
+        (
+            &vector.resp_static,
+            &vector.resp_ephemeral,
+            &vector.resp_remote_static,
+            &vector.resp_prologue,
+            &vector.resp_psks,
+        )
+    };
+    let static_private = static_private.as_ref().map(|s| hex::decode(s).unwrap());
+    let ephemeral_private = hex::decode(ephemeral_private.as_ref().unwrap()).unwrap();
+    let mut ss = SymmetricState::with_vector_keys(
+        pattern.name,
+        cipher_suite,
+        vault.clone(),
+        static_private.as_deref(),
+        &ephemeral_private,
+        &hex::decode(prologue).unwrap(),
+    );
+    if let Some(remote_static) = remote_static {
+        let remote_static = hex::decode(remote_static).unwrap();
+        ss.remote_static_public_key = Some(ss.create_public_key(&remote_static).unwrap());
+    }
+    if let Some(psk) = psks.first() {
+        let attributes = SecretKeyAttributes {
+            xtype: SecretKeyType::Buffer(SHA256_SIZE),
+            purpose: SecretPurposeType::KeyAgreement,
+            persistence: SecretPersistenceType::Ephemeral,
+        };
+        let psk = SecretKey::Buffer(hex::decode(psk).unwrap());
+        ss.psk = Some(
+            vault
+                .lock()
+                .unwrap()
+                .secret_import(&psk, attributes)
+                .unwrap(),
+        );
+    }
+    HandshakeState::from_state(pattern, initiator, ss, false)
+}
+
+/// Transport messages after the handshake, each way with its own nonce
+struct Transport {
+    vault: Arc<Mutex<DefaultVault>>,
+    cipher_suite: CipherSuite,
+    keys: CompletedKeyExchange,
+    send_nonce: u64,
+    receive_nonce: u64,
+}
+
+impl Transport {
+    fn nonce(&self, counter: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        match self.cipher_suite {
+            CipherSuite::Curve25519ChaChaPolySha256 => {
+                nonce[4..].copy_from_slice(&counter.to_le_bytes())
+            }
+            _ => nonce[4..].copy_from_slice(&counter.to_be_bytes()),
+        }
+        nonce
+    }
+
+    fn encrypt(&mut self, payload: &[u8]) -> Vec<u8> {
+        let nonce = self.nonce(self.send_nonce);
+        self.send_nonce += 1;
+        let mut vault = self.vault.lock().unwrap();
+        let key = self.keys.encrypt_key;
+        match self.cipher_suite {
+            CipherSuite::Curve25519ChaChaPolySha256 => {
+                vault.aead_chacha20_poly1305_encrypt(key, payload, &nonce, &[])
+            }
+            _ => vault.aead_aes_gcm_encrypt(key, payload, &nonce, &[]),
+        }
+        .unwrap()
+    }
+
+    fn decrypt(&mut self, ciphertext: &[u8]) -> Vec<u8> {
+        let nonce = self.nonce(self.receive_nonce);
+        self.receive_nonce += 1;
+        let mut vault = self.vault.lock().unwrap();
+        let key = self.keys.decrypt_key;
+        match self.cipher_suite {
+            CipherSuite::Curve25519ChaChaPolySha256 => {
+                vault.aead_chacha20_poly1305_decrypt(key, ciphertext, &nonce, &[])
+            }
+            _ => vault.aead_aes_gcm_decrypt(key, ciphertext, &nonce, &[]),
+        }
+        .unwrap()
+    }
+}
+
+fn run_vector(vector: &Vector, pattern: &'static HandshakePattern, cipher_suite: CipherSuite) {
+    let name = &vector.protocol_name;
+    let vault_initiator = Arc::new(Mutex::new(DefaultVault::default()));
+    let vault_responder = Arc::new(Mutex::new(DefaultVault::default()));
+    let mut initiator =
+        handshake_state(vector, pattern, cipher_suite, true, vault_initiator.clone());
+    let mut responder = handshake_state(
+        vector,
+        pattern,
+        cipher_suite,
+        false,
+        vault_responder.clone(),
+    );
+
+    // messages alternate between the sides, the initiator's first
+    let handshake_messages = pattern.messages.len();
+    for (i, message) in vector.messages[..handshake_messages].iter().enumerate() {
+        let payload = hex::decode(&message.payload).unwrap();
+        let (sender, receiver) = if i % 2 == 0 {
+            (&mut initiator, &mut responder)
+        } else {
+            (&mut responder, &mut initiator)
+        };
+        let ciphertext = sender.process(&payload).unwrap();
+        assert_eq!(
+            hex::encode(&ciphertext),
+            message.ciphertext,
+            "{} {}",
+            name,
+            i
+        );
+        assert_eq!(
+            receiver.process(&ciphertext).unwrap(),
+            payload,
+            "{} {}",
+            name,
+            i
+        );
+    }
+
+    let initiator = initiator.finalize().unwrap();
+    let responder = responder.finalize().unwrap();
+    if let Some(handshake_hash) = &vector.handshake_hash {
+        assert_eq!(&hex::encode(initiator.h), handshake_hash, "{}", name);
+    }
+    assert_eq!(initiator.h, responder.h, "{}", name);
+
+    let mut initiator = Transport {
+        vault: vault_initiator,
+        cipher_suite,
+        keys: initiator,
+        send_nonce: 0,
+        receive_nonce: 0,
+    };
+    let mut responder = Transport {
+        vault: vault_responder,
+        cipher_suite,
+        keys: responder,
+        send_nonce: 0,
+        receive_nonce: 0,
+    };
+    for (i, message) in vector.messages.iter().enumerate().skip(handshake_messages) {
+        let payload = hex::decode(&message.payload).unwrap();
+        let (sender, receiver) = if i % 2 == 0 {
+            (&mut initiator, &mut responder)
+        } else {
+            (&mut responder, &mut initiator)
+        };
+        let ciphertext = sender.encrypt(&payload);
+        assert_eq!(
+            hex::encode(&ciphertext),
+            message.ciphertext,
+            "{} {}",
+            name,
+            i
+        );
+        assert_eq!(receiver.decrypt(&ciphertext), payload, "{} {}", name, i);
+    }
+}
+
+#[test]
+fn noise_vectors() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("vectors");
+    let mut protocols = BTreeSet::new();
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().map_or(true, |e| e != "json") {
+            continue;
+        }
+        let file: VectorFile =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        for vector in file.vectors.iter().filter(|vector| !vector.fallback) {
+            if let Some((pattern, cipher_suite)) = protocol(&vector.protocol_name) {
+                run_vector(vector, pattern, cipher_suite);
+                protocols.insert(vector.protocol_name.clone());
+            }
+        }
+    }
+
+    for pattern in PATTERNS.iter() {
+        for cipher in &["AESGCM", "ChaChaPoly"] {
+            let name = format!("Noise_{}_25519_{}_SHA256", pattern.name, cipher);
+            assert!(protocols.contains(&name), "no vectors for {}", name);
+        }
+    }
+}
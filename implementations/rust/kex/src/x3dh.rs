@@ -1,5 +1,9 @@
 use crate::error::{KexExchangeFailError, KeyExchangeFailErrorKind};
-use crate::{CompletedKeyExchange, KeyExchanger, NewKeyExchanger};
+use crate::prekey::PrekeyStore;
+use crate::{
+    exporter_attributes, CipherSuite, CompletedKeyExchange, KeyExchanger, NewKeyExchanger,
+    AES_GCM_TAGSIZE, SHA256_SIZE,
+};
 use ockam_vault::types::{
     SecretKey, SecretKeyAttributes, SecretKeyType, SecretPersistenceType, SecretPurposeType,
 };
@@ -43,23 +47,28 @@ impl std::fmt::Debug for Signature {
     }
 }
 
-/// Represents all the keys and signature to send to an enrollee
+/// Represents all the keys and signature to send to an enrollee. The
+/// prekeys are named by ids the enrollee sends back, so a responder with a
+/// `PrekeyStore` knows which ones the enrollment used.
 #[derive(Clone, Copy, Debug)]
 pub struct PreKeyBundle {
-    identity_key: PublicKey,
-    signed_prekey: PublicKey,
-    signature_prekey: Signature,
-    one_time_prekey: PublicKey,
+    pub(crate) identity_key: PublicKey,
+    pub(crate) signed_prekey_id: u32,
+    pub(crate) signed_prekey: PublicKey,
+    pub(crate) signature_prekey: Signature,
+    pub(crate) one_time_prekey_id: u32,
+    pub(crate) one_time_prekey: PublicKey,
 }
 
 impl PreKeyBundle {
-    const SIZE: usize = 32 + 32 + 64 + 32;
     /// Convert the prekey bundle to a byte array
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut output = Vec::new();
         output.extend_from_slice(self.identity_key.as_ref());
+        output.extend_from_slice(&self.signed_prekey_id.to_be_bytes());
         output.extend_from_slice(self.signed_prekey.as_ref());
         output.extend_from_slice(self.signature_prekey.as_ref());
+        output.extend_from_slice(&self.one_time_prekey_id.to_be_bytes());
         output.extend_from_slice(self.one_time_prekey.as_ref());
         output
     }
@@ -68,18 +77,30 @@ impl PreKeyBundle {
 impl TryFrom<&[u8]> for PreKeyBundle {
     type Error = KexExchangeFailError;
 
+    /// The size of the bundle tells its curve
     fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
-        if data.len() != Self::SIZE {
-            return Err(KeyExchangeFailErrorKind::InvalidByteCount(Self::SIZE, data.len()).into());
-        }
-        let identity_key = PublicKey::Curve25519(*array_ref![data, 0, 32]);
-        let signed_prekey = PublicKey::Curve25519(*array_ref![data, 32, 32]);
-        let signature_prekey = Signature(*array_ref![data, 64, 64]);
-        let one_time_prekey = PublicKey::Curve25519(*array_ref![data, 128, 32]);
+        let suite = [CURVE25519_SUITE, P256_SUITE]
+            .iter()
+            .find(|suite| suite.bundle_size() == data.len())
+            .ok_or_else(|| {
+                KeyExchangeFailErrorKind::InvalidByteCount(
+                    CURVE25519_SUITE.bundle_size(),
+                    data.len(),
+                )
+            })?;
+        let k = suite.key_size;
+        let identity_key = suite.public_key(data);
+        let signed_prekey_id = u32::from_be_bytes(*array_ref![data, k, 4]);
+        let signed_prekey = suite.public_key(&data[k + 4..]);
+        let signature_prekey = Signature(*array_ref![data, 2 * k + 4, 64]);
+        let one_time_prekey_id = u32::from_be_bytes(*array_ref![data, 2 * k + 68, 4]);
+        let one_time_prekey = suite.public_key(&data[2 * k + 72..]);
         Ok(Self {
             identity_key,
+            signed_prekey_id,
             signed_prekey,
             signature_prekey,
+            one_time_prekey_id,
             one_time_prekey,
         })
     }
@@ -97,72 +118,338 @@ enum ResponderState {
     Done,
 }
 
-const CSUITE: &[u8] = b"X3DH_25519_AESGCM_SHA256\0\0\0\0\0\0\0\0";
-/// EK, Hash(EIK), IK, EdDSA, AES_GCM_TAG
-const ENROLLMENT_MSG_SIZE: usize = 32 + 32 + 32 + 64 + 16;
+/// The curve, AEAD and name of an X3DH cipher suite
+#[derive(Clone, Copy, Debug)]
+struct Suite {
+    name: &'static [u8],
+    key_type: SecretKeyType,
+    key_size: usize,
+    aead_key_type: SecretKeyType,
+}
+
+/// X25519 with XEdDSA prekey signatures
+const CURVE25519_SUITE: Suite = Suite {
+    name: b"X3DH_25519_AESGCM_SHA256\0\0\0\0\0\0\0\0",
+    key_type: SecretKeyType::Curve25519,
+    key_size: 32,
+    aead_key_type: SecretKeyType::Aes256,
+};
+
+/// P-256 with ECDSA prekey signatures, for secure elements that only do
+/// P-256 and AES-128
+const P256_SUITE: Suite = Suite {
+    name: b"X3DH_P256_AES128GCM_SHA256\0\0\0\0\0\0",
+    key_type: SecretKeyType::P256,
+    key_size: 65,
+    aead_key_type: SecretKeyType::Aes128,
+};
+
+impl Suite {
+    fn of(cipher_suite: CipherSuite) -> Result<Self, KexExchangeFailError> {
+        match cipher_suite {
+            CipherSuite::Curve25519AesGcmSha256 => Ok(CURVE25519_SUITE),
+            CipherSuite::P256Aes128GcmSha256 => Ok(P256_SUITE),
+            _ => Err(KeyExchangeFailErrorKind::InvalidParam(0).into()),
+        }
+    }
+
+    /// Read a public key of this suite's curve from the front of `data`
+    fn public_key(&self, data: &[u8]) -> PublicKey {
+        match self.key_type {
+            SecretKeyType::P256 => PublicKey::P256(*array_ref![data, 0, 65]),
+            _ => PublicKey::Curve25519(*array_ref![data, 0, 32]),
+        }
+    }
+
+    fn is_on_curve(&self, key: &PublicKey) -> bool {
+        match key {
+            PublicKey::Curve25519(_) => self.key_type == SecretKeyType::Curve25519,
+            PublicKey::P256(_) => self.key_type == SecretKeyType::P256,
+        }
+    }
+
+    /// IK, the signed prekey id, SPK, its signature, the one-time prekey
+    /// id, OPK
+    fn bundle_size(&self) -> usize {
+        self.key_size + 4 + self.key_size + 64 + 4 + self.key_size
+    }
+
+    /// The cleartext start of the enrollment message: EK, Hash(EIK) and the
+    /// prekey ids
+    fn enrollment_header_size(&self) -> usize {
+        self.key_size + SHA256_SIZE + 4 + 4
+    }
+
+    /// The header, then IK and its signature encrypted
+    fn enrollment_size(&self) -> usize {
+        self.enrollment_header_size() + self.key_size + 64 + AES_GCM_TAGSIZE
+    }
+
+    fn key_attributes(&self, persistence: SecretPersistenceType) -> SecretKeyAttributes {
+        SecretKeyAttributes {
+            purpose: SecretPurposeType::KeyAgreement,
+            persistence,
+            xtype: self.key_type,
+        }
+    }
+
+    /// Decrypt an enrollment with the key derived for it and check that the
+    /// enrollment key signed the initiator's identity key. Returns the state
+    /// hash and the identity key.
+    fn open_enrollment(
+        &self,
+        vault: &mut dyn DynVault,
+        data: &[u8],
+        eik: PublicKey,
+        decrypt_key: SecretKeyContext,
+        mut ikm_bytes: Vec<u8>,
+    ) -> Result<([u8; 32], PublicKey), KexExchangeFailError> {
+        let k = self.key_size;
+        let mut state_hash = vault.sha256(self.name)?.to_vec();
+        state_hash.append(&mut ikm_bytes);
+        let state_hash = vault.sha256(state_hash.as_slice())?;
+
+        let mut aad = data[..self.enrollment_header_size()].to_vec();
+        aad.extend_from_slice(self.name);
+        aad.extend_from_slice(&state_hash);
+        //TODO: get the channel address from the message somehow if needed
+        let plaintext = vault.aead_aes_gcm_decrypt(
+            decrypt_key,
+            &data[self.enrollment_header_size()..],
+            &data[..12],
+            aad.as_slice(),
+        )?;
+        let ikb = self.public_key(&plaintext);
+        let signature = *array_ref![plaintext, k, 64];
+        vault.verify(signature, eik, &plaintext[..k])?;
+        Ok((state_hash, ikb))
+    }
+}
 
 /// The responder of X3DH creates a prekey bundle that can be used to establish a shared
 /// secret key with another party that can use
+///
+/// Without a `PrekeyStore` the responder makes its own prekeys and its bundle
+/// serves one enrollment. With one, the bundle comes from the store and
+/// the enrollment may use any bundle the store handed out.
 pub struct X3dhResponder {
+    cipher_suite: CipherSuite,
     identity_key: Option<SecretKeyContext>,
     signed_prekey: SecretKeyContext,
     one_time_prekey: SecretKeyContext,
+    prekeys: Option<Arc<Mutex<PrekeyStore>>>,
     expected_enrollment_key: Option<PublicKey>,
+    /// The signed prekey the enrollment was made to
+    enrolled_signed_prekey: Option<SecretKeyContext>,
     state: ResponderState,
     vault: Arc<Mutex<dyn DynVault + Send>>,
     completed_key_exchange: Option<CompletedKeyExchange>,
 }
 
 impl X3dhResponder {
-    fn new(v: Arc<Mutex<dyn DynVault + Send>>, identity_key: Option<SecretKeyContext>) -> Self {
+    fn new(
+        cipher_suite: CipherSuite,
+        v: Arc<Mutex<dyn DynVault + Send>>,
+        identity_key: Option<SecretKeyContext>,
+    ) -> Self {
         Self {
+            cipher_suite,
             identity_key,
             signed_prekey: SecretKeyContext::Memory(0),
             one_time_prekey: SecretKeyContext::Memory(0),
+            prekeys: None,
             expected_enrollment_key: None,
+            enrolled_signed_prekey: None,
             completed_key_exchange: None,
             state: ResponderState::GenerateBundle,
             vault: v,
         }
     }
 
+    fn with_prekey_store(cipher_suite: CipherSuite, prekeys: Arc<Mutex<PrekeyStore>>) -> Self {
+        let (vault, identity_key) = {
+            let store = prekeys.lock().unwrap();
+            (store.vault(), store.identity_key())
+        };
+        let mut responder = Self::new(cipher_suite, vault, Some(identity_key));
+        responder.prekeys = Some(prekeys);
+        responder
+    }
+
+    /// The secret of the signed prekey the completed enrollment was made to,
+    /// the first ratchet key of a `RatchetSession` responder. Without a
+    /// `PrekeyStore` it is destroyed along with the exchange.
+    pub fn signed_prekey(&self) -> Option<SecretKeyContext> {
+        self.enrolled_signed_prekey
+    }
+
     fn prologue(&mut self) -> Result<(), VaultFailError> {
+        self.expected_enrollment_key = None;
+        self.enrolled_signed_prekey = None;
+        self.completed_key_exchange = None;
+        if self.prekeys.is_some() {
+            return Ok(());
+        }
+        let suite = Suite::of(self.cipher_suite)?;
         let mut vault = self.vault.lock().unwrap();
-        let p_atts = SecretKeyAttributes {
-            purpose: SecretPurposeType::KeyAgreement,
-            persistence: SecretPersistenceType::Persistent,
-            xtype: SecretKeyType::Curve25519,
-        };
-        let e_atts = SecretKeyAttributes {
-            purpose: SecretPurposeType::KeyAgreement,
-            persistence: SecretPersistenceType::Ephemeral,
-            xtype: SecretKeyType::Curve25519,
-        };
+        let p_atts = suite.key_attributes(SecretPersistenceType::Persistent);
+        let e_atts = suite.key_attributes(SecretPersistenceType::Ephemeral);
         if self.identity_key.is_none() {
             self.identity_key = Some(vault.secret_generate(p_atts)?);
         }
         self.signed_prekey = vault.secret_generate(p_atts)?;
         self.one_time_prekey = vault.secret_generate(e_atts)?;
-        self.expected_enrollment_key = None;
-        self.completed_key_exchange = None;
         Ok(())
     }
+
+    /// Check an enrollment message against the expected enrollment key and
+    /// derive the channel keys from it
+    fn verify_enrollment(
+        &mut self,
+        data: &[u8],
+    ) -> Result<CompletedKeyExchange, KexExchangeFailError> {
+        debug_assert!(self.expected_enrollment_key.is_some());
+        let suite = Suite::of(self.cipher_suite)?;
+        let k = suite.key_size;
+        if data.len() != suite.enrollment_size() {
+            return Err(KeyExchangeFailErrorKind::InvalidByteCount(
+                suite.enrollment_size(),
+                data.len(),
+            )
+            .into());
+        }
+        let signed_prekey_id = u32::from_be_bytes(*array_ref![data, k + 32, 4]);
+        let one_time_prekey_id = u32::from_be_bytes(*array_ref![data, k + 36, 4]);
+        let (signed_prekey, one_time_prekey) = match &self.prekeys {
+            Some(prekeys) => prekeys
+                .lock()
+                .unwrap()
+                .prekeys(signed_prekey_id, one_time_prekey_id)
+                .ok_or(KeyExchangeFailErrorKind::GeneralError {
+                    msg: "Unknown or used prekeys".to_string(),
+                })?,
+            None if signed_prekey_id == 0 && one_time_prekey_id == 0 => {
+                (self.signed_prekey, self.one_time_prekey)
+            }
+            None => return Err(KeyExchangeFailErrorKind::InvalidParam(0).into()),
+        };
+        let mut vault = self.vault.lock().unwrap();
+        let eik = self.expected_enrollment_key.as_ref().unwrap();
+        let id = vault.sha256(eik.as_ref())?;
+        if id.ct_eq(&data[k..k + 32]).unwrap_u8() != 1 {
+            return Err(KeyExchangeFailErrorKind::InvalidHash {
+                expected: hex::encode(id),
+                actual: hex::encode(&data[k..k + 32]),
+            }
+            .into());
+        }
+        let ek = suite.public_key(data);
+        let local_static_secret =
+            self.identity_key
+                .ok_or(KeyExchangeFailErrorKind::GeneralError {
+                    msg: "Invalid identity key".to_string(),
+                })?;
+
+        let dh1 = vault.ec_diffie_hellman(signed_prekey, *eik)?;
+        let dh2 = vault.ec_diffie_hellman(local_static_secret, ek)?;
+        let dh3 = vault.ec_diffie_hellman(signed_prekey, ek)?;
+        let dh4 = vault.ec_diffie_hellman(one_time_prekey, ek)?;
+        let mut ikm_bytes = vec![0xFFu8; 32];
+        ikm_bytes.extend_from_slice(vault.secret_export(dh1)?.as_ref());
+        ikm_bytes.extend_from_slice(vault.secret_export(dh2)?.as_ref());
+        ikm_bytes.extend_from_slice(vault.secret_export(dh3)?.as_ref());
+        ikm_bytes.extend_from_slice(vault.secret_export(dh4)?.as_ref());
+
+        let ikm = vault.secret_import(
+            &SecretKey::Buffer(ikm_bytes.to_vec()),
+            SecretKeyAttributes {
+                purpose: SecretPurposeType::KeyAgreement,
+                persistence: SecretPersistenceType::Ephemeral,
+                xtype: SecretKeyType::Buffer(ikm_bytes.len()),
+            },
+        )?;
+        let salt = vault.secret_import(
+            &SecretKey::Buffer(vec![0u8; 32]),
+            SecretKeyAttributes {
+                purpose: SecretPurposeType::KeyAgreement,
+                persistence: SecretPersistenceType::Ephemeral,
+                xtype: SecretKeyType::Buffer(32),
+            },
+        )?;
+        let atts = SecretKeyAttributes {
+            purpose: SecretPurposeType::KeyAgreement,
+            persistence: SecretPersistenceType::Persistent,
+            xtype: suite.aead_key_type,
+        };
+
+        // the third output is the exporter secret
+        let keyrefs = vault.hkdf_sha256(
+            salt,
+            suite.name,
+            Some(ikm),
+            vec![atts, atts, exporter_attributes()],
+        )?;
+        for secret in &[dh1, dh2, dh3, dh4, ikm, salt] {
+            vault.secret_destroy(*secret)?;
+        }
+        let (encrypt_key, decrypt_key) = (keyrefs[0], keyrefs[1]);
+        let verified = suite.open_enrollment(&mut *vault, data, *eik, decrypt_key, ikm_bytes);
+        // the store locks the vault itself
+        drop(vault);
+        // the one-time prekey is only used up by an enrollment that checks
+        // out, so a forged one can't take it from the initiator it went to
+        let verified = match (verified, &self.prekeys) {
+            (Ok(verified), Some(prekeys)) => {
+                // fails if another enrollment got to the prekey first
+                match prekeys.lock().unwrap().consume(one_time_prekey_id) {
+                    Ok(()) => Ok(verified),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            (verified, _) => verified,
+        };
+        let (state_hash, ikb) = match verified {
+            Ok(verified) => verified,
+            Err(e) => {
+                // keys from an enrollment that failed mustn't outlive it
+                let mut vault = self.vault.lock().unwrap();
+                for secret in &keyrefs {
+                    vault.secret_destroy(*secret)?;
+                }
+                return Err(e);
+            }
+        };
+
+        self.enrolled_signed_prekey = Some(signed_prekey);
+        Ok(CompletedKeyExchange {
+            h: state_hash,
+            encrypt_key,
+            decrypt_key,
+            local_static_secret,
+            remote_static_public_key: ikb,
+            exporter_secret: keyrefs[2],
+        })
+    }
 }
 
 impl std::fmt::Debug for X3dhResponder {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            r#"X3dhResponder {{ identity_key: {:?},
+            r#"X3dhResponder {{ cipher_suite: {:?},
+                                      identity_key: {:?},
                                       signed_prekey: {:?},
                                       one_time_prekey: {:?},
+                                      prekeys: {:?},
                                       expected_enrollment_key: {:?},
                                       state: {:?},
                                       vault,
                                       completed_key_exchange: {:?} }}"#,
+            self.cipher_suite,
             self.identity_key,
             self.signed_prekey,
             self.one_time_prekey,
+            self.prekeys,
             self.expected_enrollment_key,
             self.state,
             self.completed_key_exchange
@@ -181,6 +468,7 @@ enum InitiatorState {
 /// to communicate the first message to the initiator
 #[derive(Clone)]
 pub struct X3dhInitiator {
+    cipher_suite: CipherSuite,
     ephemeral_identity_key: SecretKeyContext,
     prekey_bundle: Option<PreKeyBundle>,
     state: InitiatorState,
@@ -190,8 +478,13 @@ pub struct X3dhInitiator {
 }
 
 impl X3dhInitiator {
-    fn new(v: Arc<Mutex<dyn DynVault + Send>>, identity_key: Option<SecretKeyContext>) -> Self {
+    fn new(
+        cipher_suite: CipherSuite,
+        v: Arc<Mutex<dyn DynVault + Send>>,
+        identity_key: Option<SecretKeyContext>,
+    ) -> Self {
         Self {
+            cipher_suite,
             ephemeral_identity_key: SecretKeyContext::Memory(0),
             prekey_bundle: None,
             state: InitiatorState::GenerateEphemeralIdentityKey,
@@ -201,13 +494,18 @@ impl X3dhInitiator {
         }
     }
 
+    /// The signed prekey of the bundle the initiator enrolled with, the
+    /// responder's first ratchet key in a `RatchetSession`
+    pub fn signed_prekey(&self) -> Option<PublicKey> {
+        self.prekey_bundle
+            .as_ref()
+            .map(|bundle| bundle.signed_prekey)
+    }
+
     fn prologue(&mut self) -> Result<(), VaultFailError> {
+        let suite = Suite::of(self.cipher_suite)?;
         let mut vault = self.vault.lock().unwrap();
-        let p_atts = SecretKeyAttributes {
-            purpose: SecretPurposeType::KeyAgreement,
-            persistence: SecretPersistenceType::Persistent,
-            xtype: SecretKeyType::Curve25519,
-        };
+        let p_atts = suite.key_attributes(SecretPersistenceType::Persistent);
         self.ephemeral_identity_key = vault.secret_generate(p_atts)?;
         self.prekey_bundle = None;
         self.completed_key_exchange = None;
@@ -219,7 +517,8 @@ impl std::fmt::Debug for X3dhInitiator {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            r#"X3dhInitiator {{ ephemeral_identity_key: {:?}, prekey_bundle: {:?}, state: {:?}, vault, completed_key_exchange: {:?}, identity_key: {:?} }}"#,
+            r#"X3dhInitiator {{ cipher_suite: {:?}, ephemeral_identity_key: {:?}, prekey_bundle: {:?}, state: {:?}, vault, completed_key_exchange: {:?}, identity_key: {:?} }}"#,
+            self.cipher_suite,
             self.ephemeral_identity_key,
             self.prekey_bundle,
             self.state,
@@ -234,6 +533,14 @@ impl KeyExchanger for X3dhResponder {
         match self.state {
             ResponderState::GenerateBundle => {
                 self.prologue()?;
+                if let Some(prekeys) = &self.prekeys {
+                    let bundle = prekeys.lock().unwrap().bundle()?;
+                    if !Suite::of(self.cipher_suite)?.is_on_curve(&bundle.identity_key) {
+                        return Err(KeyExchangeFailErrorKind::InvalidParam(0).into());
+                    }
+                    self.state = ResponderState::SetEnrollmentKey;
+                    return Ok(bundle.to_bytes());
+                }
                 let mut vault = self.vault.lock().unwrap();
                 let identity_secret_key =
                     self.identity_key
@@ -246,110 +553,44 @@ impl KeyExchanger for X3dhResponder {
                 let one_time_prekey = vault.secret_public_key_get(self.one_time_prekey)?;
                 let bundle = PreKeyBundle {
                     identity_key,
+                    signed_prekey_id: 0,
                     signed_prekey,
                     signature_prekey: Signature(signature),
+                    one_time_prekey_id: 0,
                     one_time_prekey,
                 };
                 self.state = ResponderState::SetEnrollmentKey;
                 Ok(bundle.to_bytes())
             }
             ResponderState::SetEnrollmentKey => {
-                if data.len() != 32 {
-                    return Err(KeyExchangeFailErrorKind::InvalidByteCount(32, data.len()).into());
+                let suite = Suite::of(self.cipher_suite)?;
+                let k = suite.key_size;
+                if data.len() == k + suite.enrollment_size() {
+                    // the enrollment key may arrive with the enrollment message
+                    self.process(&data[..k])?;
+                    return self.process(&data[k..]);
+                }
+                if data.len() != k {
+                    return Err(KeyExchangeFailErrorKind::InvalidByteCount(k, data.len()).into());
                 }
-                self.expected_enrollment_key =
-                    Some(PublicKey::Curve25519(*array_ref![data, 0, 32]));
+                self.expected_enrollment_key = Some(suite.public_key(data));
                 self.state = ResponderState::VerifyEnrollment;
                 Ok(vec![])
             }
-            ResponderState::VerifyEnrollment => {
-                debug_assert!(self.expected_enrollment_key.is_some());
-                if data.len() != ENROLLMENT_MSG_SIZE {
-                    return Err(KeyExchangeFailErrorKind::InvalidByteCount(
-                        ENROLLMENT_MSG_SIZE,
-                        data.len(),
-                    )
-                    .into());
+            ResponderState::VerifyEnrollment => match self.verify_enrollment(data) {
+                Ok(completed_key_exchange) => {
+                    self.completed_key_exchange = Some(completed_key_exchange);
+                    self.state = ResponderState::Done;
+                    Ok(vec![])
                 }
-                let mut vault = self.vault.lock().unwrap();
-                let eik = self.expected_enrollment_key.as_ref().unwrap();
-                let id = vault.sha256(eik.as_ref())?;
-                if id.ct_eq(&data[32..64]).unwrap_u8() != 1 {
-                    return Err(KeyExchangeFailErrorKind::InvalidHash {
-                        expected: hex::encode(id),
-                        actual: hex::encode(&data[32..64]),
-                    }
-                    .into());
+                Err(e) => {
+                    // a bad enrollment doesn't use up the bundle; wait for
+                    // another enrollment key
+                    self.expected_enrollment_key = None;
+                    self.state = ResponderState::SetEnrollmentKey;
+                    Err(e)
                 }
-                let ek = PublicKey::Curve25519(*array_ref![data, 0, 32]);
-                let local_static_secret =
-                    self.identity_key
-                        .ok_or(KeyExchangeFailErrorKind::GeneralError {
-                            msg: "Invalid identity key".to_string(),
-                        })?;
-
-                let dh1 = vault.ec_diffie_hellman(self.signed_prekey, *eik)?;
-                let dh2 = vault.ec_diffie_hellman(local_static_secret, ek)?;
-                let dh3 = vault.ec_diffie_hellman(self.signed_prekey, ek)?;
-                let dh4 = vault.ec_diffie_hellman(self.one_time_prekey, ek)?;
-                let mut ikm_bytes = vec![0xFFu8; 32];
-                ikm_bytes.extend_from_slice(vault.secret_export(dh1)?.as_ref());
-                ikm_bytes.extend_from_slice(vault.secret_export(dh2)?.as_ref());
-                ikm_bytes.extend_from_slice(vault.secret_export(dh3)?.as_ref());
-                ikm_bytes.extend_from_slice(vault.secret_export(dh4)?.as_ref());
-
-                let ikm = vault.secret_import(
-                    &SecretKey::Buffer(ikm_bytes.to_vec()),
-                    SecretKeyAttributes {
-                        purpose: SecretPurposeType::KeyAgreement,
-                        persistence: SecretPersistenceType::Ephemeral,
-                        xtype: SecretKeyType::Buffer(ikm_bytes.len()),
-                    },
-                )?;
-                let salt = vault.secret_import(
-                    &SecretKey::Buffer(vec![0u8; 32]),
-                    SecretKeyAttributes {
-                        purpose: SecretPurposeType::KeyAgreement,
-                        persistence: SecretPersistenceType::Ephemeral,
-                        xtype: SecretKeyType::Buffer(32),
-                    },
-                )?;
-                let atts = SecretKeyAttributes {
-                    purpose: SecretPurposeType::KeyAgreement,
-                    persistence: SecretPersistenceType::Persistent,
-                    xtype: SecretKeyType::Aes256,
-                };
-
-                let keyrefs = vault.hkdf_sha256(salt, CSUITE, Some(ikm), vec![atts, atts])?;
-                let (encrypt_key, decrypt_key) = (keyrefs[0], keyrefs[1]);
-                let mut state_hash = vault.sha256(CSUITE)?.to_vec();
-                state_hash.append(&mut ikm_bytes);
-                let state_hash = vault.sha256(state_hash.as_slice())?;
-
-                let mut aad = data[..64].to_vec();
-                aad.extend_from_slice(CSUITE);
-                aad.extend_from_slice(&state_hash);
-                //TODO: get the channel address from the message somehow if needed
-                let plaintext = vault.aead_aes_gcm_decrypt(
-                    decrypt_key,
-                    &data[64..],
-                    &data[..12],
-                    aad.as_slice(),
-                )?;
-                let ikb = PublicKey::Curve25519(*array_ref![plaintext, 0, 32]);
-                let signature = *array_ref![plaintext, 32, 64];
-                vault.verify(signature, *eik, &plaintext[..32])?;
-
-                self.completed_key_exchange = Some(CompletedKeyExchange {
-                    h: state_hash,
-                    encrypt_key,
-                    decrypt_key,
-                    local_static_secret,
-                    remote_static_public_key: ikb,
-                });
-                self.state = ResponderState::Done;
-                Ok(vec![])
-            }
+            },
             ResponderState::Done => Ok(vec![]),
         }
     }
@@ -361,6 +602,17 @@ impl KeyExchanger for X3dhResponder {
     fn finalize(&mut self) -> Result<CompletedKeyExchange, VaultFailError> {
         Ok(*self.completed_key_exchange.as_ref().unwrap())
     }
+
+    fn destroy(&mut self) -> Result<(), VaultFailError> {
+        let mut vault = self.vault.lock().unwrap();
+        for prekey in &mut [&mut self.signed_prekey, &mut self.one_time_prekey] {
+            if **prekey != SecretKeyContext::Memory(0) {
+                vault.secret_destroy(**prekey)?;
+                **prekey = SecretKeyContext::Memory(0);
+            }
+        }
+        Ok(())
+    }
 }
 
 impl KeyExchanger for X3dhInitiator {
@@ -369,17 +621,16 @@ impl KeyExchanger for X3dhInitiator {
             InitiatorState::GenerateEphemeralIdentityKey => {
                 self.prologue()?;
                 let mut vault = self.vault.lock().unwrap();
-                self.ephemeral_identity_key = vault.secret_generate(SecretKeyAttributes {
-                    purpose: SecretPurposeType::KeyAgreement,
-                    persistence: SecretPersistenceType::Persistent,
-                    xtype: SecretKeyType::Curve25519,
-                })?;
                 let pubkey = vault.secret_public_key_get(self.ephemeral_identity_key)?;
                 self.state = InitiatorState::ProcessPreKeyBundle;
                 Ok(pubkey.as_ref().to_vec())
             }
             InitiatorState::ProcessPreKeyBundle => {
+                let suite = Suite::of(self.cipher_suite)?;
                 let prekey_bundle = PreKeyBundle::try_from(data)?;
+                if !suite.is_on_curve(&prekey_bundle.identity_key) {
+                    return Err(KeyExchangeFailErrorKind::InvalidParam(0).into());
+                }
 
                 let mut vault = self.vault.lock().unwrap();
 
@@ -389,12 +640,8 @@ impl KeyExchanger for X3dhInitiator {
                     prekey_bundle.identity_key,
                     prekey_bundle.signed_prekey.as_ref(),
                 )?;
-                let atts = SecretKeyAttributes {
-                    purpose: SecretPurposeType::KeyAgreement,
-                    persistence: SecretPersistenceType::Ephemeral,
-                    xtype: SecretKeyType::Curve25519,
-                };
-                let esk = vault.secret_generate(atts)?;
+                let esk = vault
+                    .secret_generate(suite.key_attributes(SecretPersistenceType::Ephemeral))?;
                 let dh1 = vault
                     .ec_diffie_hellman(self.ephemeral_identity_key, prekey_bundle.signed_prekey)?;
                 let dh2 = vault.ec_diffie_hellman(esk, prekey_bundle.identity_key)?;
@@ -423,31 +670,43 @@ impl KeyExchanger for X3dhInitiator {
                     },
                 )?;
 
-                let mut atts = SecretKeyAttributes {
-                    xtype: SecretKeyType::Aes256,
+                let atts = SecretKeyAttributes {
+                    xtype: suite.aead_key_type,
                     purpose: SecretPurposeType::KeyAgreement,
                     persistence: SecretPersistenceType::Persistent,
                 };
 
-                let keyrefs = vault.hkdf_sha256(salt, CSUITE, Some(ikm), vec![atts, atts])?;
+                // the third output is the exporter secret
+                let keyrefs = vault.hkdf_sha256(
+                    salt,
+                    suite.name,
+                    Some(ikm),
+                    vec![atts, atts, exporter_attributes()],
+                )?;
+                for secret in &[dh1, dh2, dh3, dh4, ikm, salt] {
+                    vault.secret_destroy(*secret)?;
+                }
                 let (decrypt_key, encrypt_key) = (keyrefs[0], keyrefs[1]);
                 let ek = vault.secret_public_key_get(esk)?;
+                vault.secret_destroy(esk)?;
                 let pubkey = vault.secret_public_key_get(self.ephemeral_identity_key)?;
 
-                let mut state_hash = vault.sha256(CSUITE)?.to_vec();
+                let mut state_hash = vault.sha256(suite.name)?.to_vec();
                 state_hash.append(&mut ikm_bytes);
                 let state_hash = vault.sha256(state_hash.as_slice())?;
 
                 let mut aad = ek.as_ref().to_vec();
                 aad.extend_from_slice(&vault.sha256(pubkey.as_ref())?);
-                aad.extend_from_slice(CSUITE);
+                aad.extend_from_slice(&prekey_bundle.signed_prekey_id.to_be_bytes());
+                aad.extend_from_slice(&prekey_bundle.one_time_prekey_id.to_be_bytes());
+                aad.extend_from_slice(suite.name);
                 aad.extend_from_slice(&state_hash);
 
-                atts.xtype = SecretKeyType::Curve25519;
                 let skb = if self.identity_key.is_some() {
                     self.identity_key.unwrap()
                 } else {
-                    vault.secret_generate(atts)?
+                    vault
+                        .secret_generate(suite.key_attributes(SecretPersistenceType::Persistent))?
                 };
                 let ikb = vault.secret_public_key_get(skb)?;
 
@@ -461,7 +720,7 @@ impl KeyExchanger for X3dhInitiator {
                     &ek.as_ref()[..12],
                     aad.as_slice(),
                 )?;
-                let mut output = aad[..64].to_vec();
+                let mut output = aad[..suite.enrollment_header_size()].to_vec();
                 output.append(&mut ciphertext_and_tag);
                 self.completed_key_exchange = Some(CompletedKeyExchange {
                     h: state_hash,
@@ -469,7 +728,9 @@ impl KeyExchanger for X3dhInitiator {
                     decrypt_key,
                     local_static_secret: skb,
                     remote_static_public_key: prekey_bundle.identity_key,
+                    exporter_secret: keyrefs[2],
                 });
+                self.prekey_bundle = Some(prekey_bundle);
                 self.state = InitiatorState::Done;
                 Ok(output)
             }
@@ -484,19 +745,31 @@ impl KeyExchanger for X3dhInitiator {
     fn finalize(&mut self) -> Result<CompletedKeyExchange, VaultFailError> {
         Ok(*self.completed_key_exchange.as_ref().unwrap())
     }
+
+    fn destroy(&mut self) -> Result<(), VaultFailError> {
+        if self.ephemeral_identity_key != SecretKeyContext::Memory(0) {
+            let mut vault = self.vault.lock().unwrap();
+            vault.secret_destroy(self.ephemeral_identity_key)?;
+            self.ephemeral_identity_key = SecretKeyContext::Memory(0);
+        }
+        Ok(())
+    }
 }
 
 /// Represents an XX NewKeyExchanger
 pub struct X3dhNewKeyExchanger {
+    cipher_suite: CipherSuite,
     vault_initiator: Arc<Mutex<dyn DynVault + Send>>,
     vault_responder: Arc<Mutex<dyn DynVault + Send>>,
+    prekeys: Option<Arc<Mutex<PrekeyStore>>>,
 }
 
 impl std::fmt::Debug for X3dhNewKeyExchanger {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "X3dhNewKeyExchanger {{ vault_initiator, vault_responder }}"
+            "X3dhNewKeyExchanger {{ cipher_suite: {:?}, vault_initiator, vault_responder }}",
+            self.cipher_suite
         )
     }
 }
@@ -504,23 +777,53 @@ impl std::fmt::Debug for X3dhNewKeyExchanger {
 impl X3dhNewKeyExchanger {
     /// Create a new XXNewKeyExchanger
     pub fn new(
+        cipher_suite: CipherSuite,
         vault_initiator: Arc<Mutex<dyn DynVault + Send>>,
         vault_responder: Arc<Mutex<dyn DynVault + Send>>,
     ) -> Self {
         Self {
+            cipher_suite,
             vault_initiator,
             vault_responder,
+            prekeys: None,
+        }
+    }
+
+    /// Create a new X3dhNewKeyExchanger whose responders hand out bundles
+    /// from `prekeys`, signed by the store's identity key
+    pub fn with_prekey_store(
+        cipher_suite: CipherSuite,
+        vault_initiator: Arc<Mutex<dyn DynVault + Send>>,
+        prekeys: Arc<Mutex<PrekeyStore>>,
+    ) -> Self {
+        let vault_responder = prekeys.lock().unwrap().vault();
+        Self {
+            cipher_suite,
+            vault_initiator,
+            vault_responder,
+            prekeys: Some(prekeys),
         }
     }
 }
 
 impl NewKeyExchanger<X3dhInitiator, X3dhResponder> for X3dhNewKeyExchanger {
     fn initiator(&self, identity_key: Option<SecretKeyContext>) -> X3dhInitiator {
-        X3dhInitiator::new(self.vault_initiator.clone(), identity_key)
+        X3dhInitiator::new(
+            self.cipher_suite,
+            self.vault_initiator.clone(),
+            identity_key,
+        )
     }
 
     fn responder(&self, identity_key: Option<SecretKeyContext>) -> X3dhResponder {
-        X3dhResponder::new(self.vault_responder.clone(), identity_key)
+        match &self.prekeys {
+            Some(prekeys) => X3dhResponder::with_prekey_store(self.cipher_suite, prekeys.clone()),
+            None => X3dhResponder::new(
+                self.cipher_suite,
+                self.vault_responder.clone(),
+                identity_key,
+            ),
+        }
     }
 }
 
@@ -533,8 +836,10 @@ mod tests {
     fn handshake() {
         let vault_i = Arc::new(Mutex::new(DefaultVault::default()));
         let vault_r = Arc::new(Mutex::new(DefaultVault::default()));
-        let mut initiator = X3dhInitiator::new(vault_i.clone(), None);
-        let mut responder = X3dhResponder::new(vault_r.clone(), None);
+        let mut initiator =
+            X3dhInitiator::new(CipherSuite::Curve25519AesGcmSha256, vault_i.clone(), None);
+        let mut responder =
+            X3dhResponder::new(CipherSuite::Curve25519AesGcmSha256, vault_r.clone(), None);
 
         assert!(initiator.prologue().is_ok());
         assert!(responder.prologue().is_ok());
@@ -574,4 +879,116 @@ mod tests {
             .unwrap();
         assert_eq!(plaintext, b"Hello Alice");
     }
+
+    #[test]
+    fn enrollment_in_one_message() {
+        let vault_i = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_r = Arc::new(Mutex::new(DefaultVault::default()));
+        let mut initiator = X3dhInitiator::new(CipherSuite::Curve25519AesGcmSha256, vault_i, None);
+        let mut responder = X3dhResponder::new(CipherSuite::Curve25519AesGcmSha256, vault_r, None);
+
+        let mut enrollment = initiator.process(&[]).unwrap();
+        let bundle = responder.process(&[]).unwrap();
+        enrollment.extend(initiator.process(&bundle).unwrap());
+        assert!(initiator.is_complete());
+
+        assert!(responder.process(&enrollment).unwrap().is_empty());
+        assert!(responder.is_complete());
+    }
+
+    #[test]
+    fn bad_enrollment_keeps_bundle() {
+        let vault_i = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_r = Arc::new(Mutex::new(DefaultVault::default()));
+        let mut initiator = X3dhInitiator::new(CipherSuite::Curve25519AesGcmSha256, vault_i, None);
+        let mut responder = X3dhResponder::new(CipherSuite::Curve25519AesGcmSha256, vault_r, None);
+
+        let mut enrollment = initiator.process(&[]).unwrap();
+        let bundle = responder.process(&[]).unwrap();
+        enrollment.extend(initiator.process(&bundle).unwrap());
+
+        let garbage = vec![0x5au8; enrollment.len()];
+        assert!(responder.process(&garbage).is_err());
+        assert!(!responder.is_complete());
+
+        assert!(responder.process(&enrollment).unwrap().is_empty());
+        assert!(responder.is_complete());
+    }
+
+    #[test]
+    fn tampered_enrollment_keeps_bundle() {
+        let vault_i = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_r = Arc::new(Mutex::new(DefaultVault::default()));
+        let mut initiator = X3dhInitiator::new(CipherSuite::Curve25519AesGcmSha256, vault_i, None);
+        let mut responder = X3dhResponder::new(CipherSuite::Curve25519AesGcmSha256, vault_r, None);
+
+        let mut enrollment = initiator.process(&[]).unwrap();
+        let bundle = responder.process(&[]).unwrap();
+        enrollment.extend(initiator.process(&bundle).unwrap());
+
+        // passes the enrollment key check, fails to decrypt
+        let mut tampered = enrollment.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(responder.process(&tampered).is_err());
+        assert!(responder.completed_key_exchange.is_none());
+
+        responder.process(&enrollment).unwrap();
+        assert!(responder.is_complete());
+    }
+
+    #[test]
+    fn p256_handshake() {
+        let vault_i = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_r = Arc::new(Mutex::new(DefaultVault::default()));
+        let mut initiator =
+            X3dhInitiator::new(CipherSuite::P256Aes128GcmSha256, vault_i.clone(), None);
+        let mut responder =
+            X3dhResponder::new(CipherSuite::P256Aes128GcmSha256, vault_r.clone(), None);
+
+        let eik = initiator.process(&[]).unwrap();
+        assert_eq!(eik.len(), P256_SUITE.key_size);
+        let bundle = responder.process(&[]).unwrap();
+        assert_eq!(bundle.len(), P256_SUITE.bundle_size());
+        let enrollment = initiator.process(&bundle).unwrap();
+        assert_eq!(enrollment.len(), P256_SUITE.enrollment_size());
+        responder.process(&eik).unwrap();
+        responder.process(&enrollment).unwrap();
+        assert!(responder.is_complete());
+
+        let init = initiator.finalize().unwrap();
+        let resp = responder.finalize().unwrap();
+        assert_eq!(init.h, resp.h);
+        assert_eq!(
+            vault_r
+                .lock()
+                .unwrap()
+                .secret_attributes_get(resp.decrypt_key)
+                .unwrap()
+                .xtype,
+            SecretKeyType::Aes128
+        );
+        let ciphertext = vault_i
+            .lock()
+            .unwrap()
+            .aead_aes_gcm_encrypt(init.encrypt_key, b"Hello Alice", &[1u8; 12], &[])
+            .unwrap();
+        let plaintext = vault_r
+            .lock()
+            .unwrap()
+            .aead_aes_gcm_decrypt(resp.decrypt_key, &ciphertext, &[1u8; 12], &[])
+            .unwrap();
+        assert_eq!(plaintext, b"Hello Alice");
+    }
+
+    #[test]
+    fn mismatched_suites() {
+        let vault_i = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_r = Arc::new(Mutex::new(DefaultVault::default()));
+        let mut initiator = X3dhInitiator::new(CipherSuite::P256Aes128GcmSha256, vault_i, None);
+        let mut responder = X3dhResponder::new(CipherSuite::Curve25519AesGcmSha256, vault_r, None);
+
+        initiator.process(&[]).unwrap();
+        let bundle = responder.process(&[]).unwrap();
+        assert!(initiator.process(&bundle).is_err());
+    }
 }
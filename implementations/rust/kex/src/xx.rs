@@ -1,11 +1,12 @@
-use super::{CompletedKeyExchange, KeyExchange, KeyExchanger, SHA256_SIZE};
-use crate::error::KexExchangeFailError;
-use crate::{CipherSuite, NewKeyExchanger, AES_GCM_TAGSIZE};
+use super::{exporter_attributes, CompletedKeyExchange, KeyExchange, EXPORTER_INFO, SHA256_SIZE};
+use crate::pattern::{HandshakePattern, HandshakeState, Token};
+use crate::{CipherSuite, NewKeyExchanger};
 use ockam_vault::{
     error::{VaultFailError, VaultFailErrorKind},
     types::{
         PublicKey, SecretKey, SecretKeyAttributes, SecretKeyContext, SecretKeyType,
-        SecretPersistenceType, SecretPurposeType,
+        SecretPersistenceType, SecretPurposeType, KYBER768_CIPHERTEXT_SIZE,
+        KYBER768_PUBLIC_KEY_SIZE,
     },
     DynVault,
 };
@@ -13,19 +14,24 @@ use std::sync::{Arc, Mutex};
 use zeroize::Zeroize;
 
 #[derive(Clone, Copy, Debug)]
-struct KeyPair {
-    public_key: PublicKey,
-    secret_handle: SecretKeyContext,
+pub(crate) struct KeyPair {
+    pub(crate) public_key: PublicKey,
+    pub(crate) secret_handle: SecretKeyContext,
 }
 
-/// Represents the XX Handshake]
-struct SymmetricState {
+/// Represents the state of a Noise handshake. The pattern only decides the
+/// protocol name; a `HandshakeState` works the message tokens.
+pub(crate) struct SymmetricState {
+    pattern: &'static str,
     cipher_suite: CipherSuite,
-    static_key_pair: Option<KeyPair>,
-    ephemeral_key_pair: Option<KeyPair>,
-    remote_static_public_key: Option<PublicKey>,
-    remote_ephemeral_public_key: Option<PublicKey>,
+    pub(crate) static_key_pair: Option<KeyPair>,
+    pub(crate) ephemeral_key_pair: Option<KeyPair>,
+    pub(crate) remote_static_public_key: Option<PublicKey>,
+    pub(crate) remote_ephemeral_public_key: Option<PublicKey>,
+    kem_key: Option<SecretKeyContext>,
+    remote_kem_public_key: Option<Vec<u8>>,
     identity_key: Option<SecretKeyContext>,
+    pub(crate) psk: Option<SecretKeyContext>,
     key: Option<SecretKeyContext>,
     nonce: u16,
     h: Option<[u8; SHA256_SIZE]>,
@@ -53,56 +59,178 @@ impl std::fmt::Debug for SymmetricState {
 }
 
 impl SymmetricState {
+    /// Remove the handshake secrets from the vault, and the static key too
+    /// if it was generated for this handshake rather than supplied
+    pub(crate) fn destroy(&mut self) -> Result<(), VaultFailError> {
+        let mut vault = self.vault.lock().unwrap();
+        if let Some(kp) = self.ephemeral_key_pair.take() {
+            vault.secret_destroy(kp.secret_handle)?;
+        }
+        if let Some(kem_key) = self.kem_key.take() {
+            vault.secret_destroy(kem_key)?;
+        }
+        if let Some(ck) = self.ck.take() {
+            vault.secret_destroy(ck)?;
+        }
+        if let Some(k) = self.key.take() {
+            vault.secret_destroy(k)?;
+        }
+        if self.identity_key.is_none() {
+            if let Some(kp) = self.static_key_pair.take() {
+                vault.secret_destroy(kp.secret_handle)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a DH or pre-shared key has been mixed in, so that payloads
+    /// are encrypted
+    pub(crate) fn has_key(&self) -> bool {
+        self.key.is_some()
+    }
+
+    pub(crate) fn is_hybrid(&self) -> bool {
+        self.cipher_suite.is_hybrid()
+    }
+
     fn get_secret_key_type(&self) -> SecretKeyType {
         match self.cipher_suite {
-            CipherSuite::Curve25519AesGcmSha256 => SecretKeyType::Curve25519,
+            CipherSuite::Curve25519AesGcmSha256 | CipherSuite::Curve25519ChaChaPolySha256 => {
+                SecretKeyType::Curve25519
+            }
+            #[cfg(feature = "kyber")]
+            CipherSuite::Curve25519Kyber768AesGcmSha256 => SecretKeyType::Curve25519,
             CipherSuite::P256Aes128GcmSha256 => SecretKeyType::P256,
         }
     }
 
+    fn get_kem_key_type(&self) -> Result<SecretKeyType, VaultFailError> {
+        // Kyber768 is the only KEM
+        if self.is_hybrid() {
+            Ok(SecretKeyType::Kyber768)
+        } else {
+            Err(VaultFailErrorKind::InvalidSecretType.into())
+        }
+    }
+
     fn get_symmetric_key_type(&self) -> SecretKeyType {
         match self.cipher_suite {
             CipherSuite::Curve25519AesGcmSha256 => SecretKeyType::Aes256,
+            #[cfg(feature = "kyber")]
+            CipherSuite::Curve25519Kyber768AesGcmSha256 => SecretKeyType::Aes256,
             CipherSuite::P256Aes128GcmSha256 => SecretKeyType::Aes128,
+            CipherSuite::Curve25519ChaChaPolySha256 => SecretKeyType::ChaCha20Poly1305,
         }
     }
 
-    fn create_public_key(&self, public_key: &[u8]) -> Result<PublicKey, VaultFailError> {
+    pub(crate) fn create_public_key(&self, public_key: &[u8]) -> Result<PublicKey, VaultFailError> {
         match self.cipher_suite {
-            CipherSuite::Curve25519AesGcmSha256 => {
-                if public_key.len() != 32 {
-                    return Err(VaultFailError::from(VaultFailErrorKind::InvalidSize));
-                }
-                Ok(PublicKey::Curve25519(*array_ref![public_key, 0, 32]))
-            }
             CipherSuite::P256Aes128GcmSha256 => {
                 if public_key.len() != 65 {
                     return Err(VaultFailError::from(VaultFailErrorKind::InvalidSize));
                 }
                 Ok(PublicKey::P256(*array_ref![public_key, 0, 65]))
             }
+            // every other suite is on Curve25519
+            _ => {
+                if public_key.len() != 32 {
+                    return Err(VaultFailError::from(VaultFailErrorKind::InvalidSize));
+                }
+                Ok(PublicKey::Curve25519(*array_ref![public_key, 0, 32]))
+            }
+        }
+    }
+
+    /// The 96-bit nonce for the current counter: 32 bits of zeros followed by
+    /// the counter, big endian for AES-GCM and little endian for ChaChaPoly
+    fn nonce_bytes(&self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        let counter = u64::from(self.nonce);
+        match self.cipher_suite {
+            CipherSuite::Curve25519ChaChaPolySha256 => {
+                nonce[4..].copy_from_slice(&counter.to_le_bytes())
+            }
+            _ => nonce[4..].copy_from_slice(&counter.to_be_bytes()),
         }
+        nonce
     }
 
-    fn get_public_key_size(&self) -> usize {
+    pub(crate) fn get_public_key_size(&self) -> usize {
         match self.cipher_suite {
-            CipherSuite::Curve25519AesGcmSha256 => 32,
             CipherSuite::P256Aes128GcmSha256 => 65,
+            _ => 32,
+        }
+    }
+
+    /// The sizes of the KEM public key and ciphertext, none without a KEM
+    pub(crate) fn get_kem_sizes(&self) -> (usize, usize) {
+        if self.is_hybrid() {
+            (KYBER768_PUBLIC_KEY_SIZE, KYBER768_CIPHERTEXT_SIZE)
+        } else {
+            (0, 0)
         }
     }
 
+    /// Steps 3 to 5 of the prologue, once the key pairs are in place
+    fn initialize_symmetric(
+        &mut self,
+        vault: &mut (dyn DynVault + Send),
+        prologue: &[u8],
+    ) -> Result<(), VaultFailError> {
+        // 3. Set k to empty, Set n to 0
+        // let nonce = 0;
+        self.key = None;
+        self.nonce = 0;
+
+        // 4. Set h and ck to protocol name, zero padded, or its hash if it
+        // is longer than a hash
+        // 5. h = SHA256(h || prologue)
+        let protocol_name = self.get_protocol_name();
+        let mut h = [0u8; SHA256_SIZE];
+        if protocol_name.len() <= SHA256_SIZE {
+            h[..protocol_name.len()].copy_from_slice(&protocol_name);
+        } else {
+            h = vault.sha256(&protocol_name)?;
+        }
+        let attributes = SecretKeyAttributes {
+            xtype: SecretKeyType::Buffer(SHA256_SIZE),
+            persistence: SecretPersistenceType::Ephemeral,
+            purpose: SecretPurposeType::KeyAgreement,
+        };
+        self.ck = Some(vault.secret_import(&SecretKey::Buffer(h.to_vec()), attributes)?);
+        let mut input = h.to_vec();
+        input.extend_from_slice(prologue);
+        self.h = Some(vault.sha256(&input)?);
+
+        Ok(())
+    }
+
     pub fn new(
         cipher_suite: CipherSuite,
         vault: Arc<Mutex<dyn DynVault + Send>>,
         identity_key: Option<SecretKeyContext>,
+    ) -> Self {
+        Self::with_pattern("XX", cipher_suite, vault, identity_key)
+    }
+
+    /// A handshake state for the named Noise pattern, e.g. "IK"
+    pub(crate) fn with_pattern(
+        pattern: &'static str,
+        cipher_suite: CipherSuite,
+        vault: Arc<Mutex<dyn DynVault + Send>>,
+        identity_key: Option<SecretKeyContext>,
     ) -> Self {
         Self {
+            pattern,
             cipher_suite,
             static_key_pair: None,
             ephemeral_key_pair: None,
             remote_static_public_key: None,
             remote_ephemeral_public_key: None,
+            kem_key: None,
+            remote_kem_public_key: None,
             identity_key,
+            psk: None,
             key: None,
             nonce: 0,
             h: None,
@@ -112,12 +240,261 @@ impl SymmetricState {
     }
 }
 
-impl KeyExchange for SymmetricState {
-    fn get_protocol_name(&self) -> &'static [u8] {
-        match self.cipher_suite {
-            CipherSuite::Curve25519AesGcmSha256 => b"Noise_XX_25519_AESGCM_SHA256\0\0\0\0",
-            CipherSuite::P256Aes128GcmSha256 => b"Noise_XX_P256_AES128GCM_SHA256\0\0",
+impl SymmetricState {
+    /// Use a pre-shared key, which renames the protocol to `pattern`, e.g.
+    /// "XXpsk3". The key is a 32 byte buffer secret in this state's vault,
+    /// and it stays there when the handshake is destroyed.
+    pub(crate) fn set_psk(
+        &mut self,
+        pattern: &'static str,
+        psk: SecretKeyContext,
+    ) -> Result<(), VaultFailError> {
+        // the protocol name has been hashed once the handshake starts
+        if self.h.is_some() {
+            return Err(VaultFailErrorKind::InvalidContext.into());
+        }
+        let attributes = self.vault.lock().unwrap().secret_attributes_get(psk)?;
+        if !matches!(attributes.xtype, SecretKeyType::Buffer(SHA256_SIZE)) {
+            return Err(VaultFailErrorKind::InvalidSecretType.into());
+        }
+        self.pattern = pattern;
+        self.psk = Some(psk);
+        Ok(())
+    }
+
+    /// The `e` token: with a pre-shared key the ephemeral key is mixed into
+    /// the chaining key as well as the hash
+    pub(crate) fn mix_ephemeral(&mut self, public_key: PublicKey) -> Result<(), VaultFailError> {
+        self.mix_hash(public_key)?;
+        if self.psk.is_none() {
+            return Ok(());
+        }
+        let attributes = SecretKeyAttributes {
+            xtype: SecretKeyType::Buffer(public_key.as_ref().len()),
+            purpose: SecretPurposeType::KeyAgreement,
+            persistence: SecretPersistenceType::Ephemeral,
+        };
+        let ikm = self
+            .vault
+            .lock()
+            .unwrap()
+            .secret_import(&SecretKey::Buffer(public_key.as_ref().to_vec()), attributes)?;
+        let res = self.mix_key(ikm, false);
+        self.vault.lock().unwrap().secret_destroy(ikm)?;
+        res.map(|_| ())
+    }
+
+    /// The `psk` token. Does nothing without a pre-shared key.
+    pub(crate) fn mix_psk(&mut self) -> Result<(), VaultFailError> {
+        let psk = match self.psk {
+            Some(psk) => psk,
+            None => return Ok(()),
+        };
+        let temp_h = self
+            .mix_key(psk, true)?
+            .ok_or_else(|| VaultFailError::from(VaultFailErrorKind::HkdfSha256))?;
+        let temp_h = {
+            let mut vault = self.vault.lock().unwrap();
+            let exported = vault.secret_export(temp_h);
+            vault.secret_destroy(temp_h)?;
+            exported?
+        };
+        self.mix_hash(temp_h)
+    }
+
+    /// The `e1` token: a fresh KEM key pair whose public key is sent,
+    /// encrypted once there is a key
+    pub(crate) fn write_kem_ephemeral(&mut self) -> Result<Vec<u8>, VaultFailError> {
+        let attributes = SecretKeyAttributes {
+            xtype: self.get_kem_key_type()?,
+            purpose: SecretPurposeType::KeyAgreement,
+            persistence: SecretPersistenceType::Ephemeral,
+        };
+        let public_key = {
+            let mut vault = self.vault.lock().unwrap();
+            let kem_key = vault.secret_generate(attributes)?;
+            self.kem_key = Some(kem_key);
+            vault.kem_public_key_get(kem_key)?
+        };
+        self.encrypt_and_mix_hash(public_key)
+    }
+
+    /// The `e1` token on the receiving side
+    pub(crate) fn read_kem_ephemeral(&mut self, message: &[u8]) -> Result<(), VaultFailError> {
+        self.remote_kem_public_key = Some(self.decrypt_and_mix_hash(message)?);
+        Ok(())
+    }
+
+    /// The `ekem1` token: encapsulate a secret to the peer's `e1`, send the
+    /// ciphertext, then mix the secret into the chaining key
+    pub(crate) fn write_kem_ciphertext(&mut self) -> Result<Vec<u8>, VaultFailError> {
+        let xtype = self.get_kem_key_type()?;
+        let remote_public_key = self
+            .remote_kem_public_key
+            .take()
+            .ok_or_else(|| VaultFailError::from(VaultFailErrorKind::InvalidContext))?;
+        let (ciphertext, secret) = self
+            .vault
+            .lock()
+            .unwrap()
+            .kem_encapsulate(xtype, &remote_public_key)?;
+        let res = self
+            .encrypt_and_mix_hash(ciphertext)
+            .and_then(|output| self.mix_key(secret, false).map(|_| output));
+        self.vault.lock().unwrap().secret_destroy(secret)?;
+        res
+    }
+
+    /// The `ekem1` token on the receiving side: decapsulate the secret
+    /// with the `e1` key, which is no longer needed after
+    pub(crate) fn read_kem_ciphertext(&mut self, message: &[u8]) -> Result<(), VaultFailError> {
+        let kem_key = self
+            .kem_key
+            .ok_or_else(|| VaultFailError::from(VaultFailErrorKind::InvalidContext))?;
+        let ciphertext = self.decrypt_and_mix_hash(message)?;
+        let secret = {
+            let mut vault = self.vault.lock().unwrap();
+            let secret = vault.kem_decapsulate(kem_key, &ciphertext)?;
+            vault.secret_destroy(kem_key)?;
+            self.kem_key = None;
+            secret
+        };
+        let res = self.mix_key(secret, false);
+        self.vault.lock().unwrap().secret_destroy(secret)?;
+        res.map(|_| ())
+    }
+
+    /// HKDF the chaining key with `ikm` into a new chaining key and `k`,
+    /// and with `temp_h` a hash to mix in between them, as the `psk` token
+    /// needs. The caller destroys the returned hash.
+    fn mix_key(
+        &mut self,
+        ikm: SecretKeyContext,
+        temp_h: bool,
+    ) -> Result<Option<SecretKeyContext>, VaultFailError> {
+        let ck = self
+            .ck
+            .ok_or_else(|| VaultFailError::from(VaultFailErrorKind::InvalidContext))?;
+
+        let attributes_buffer = SecretKeyAttributes {
+            xtype: SecretKeyType::Buffer(SHA256_SIZE),
+            purpose: SecretPurposeType::KeyAgreement,
+            persistence: SecretPersistenceType::Ephemeral,
+        };
+        let attributes_k = SecretKeyAttributes {
+            xtype: self.get_symmetric_key_type(),
+            purpose: SecretPurposeType::KeyAgreement,
+            persistence: SecretPersistenceType::Ephemeral,
+        };
+        let mut attributes = vec![attributes_buffer];
+        if temp_h {
+            attributes.push(attributes_buffer);
+        }
+        attributes.push(attributes_k);
+        let outputs = attributes.len();
+
+        let mut vault = self.vault.lock().unwrap();
+        let hkdf_output = vault.hkdf_sha256(ck, b"", Some(ikm), attributes)?;
+        if hkdf_output.len() != outputs {
+            return Err(VaultFailError::from(VaultFailErrorKind::HkdfSha256));
+        }
+
+        vault.secret_destroy(ck)?;
+        self.ck = Some(hkdf_output[0]);
+        if let Some(k) = self.key.take() {
+            vault.secret_destroy(k)?;
         }
+        self.key = Some(hkdf_output[outputs - 1]);
+        self.nonce = 0;
+        Ok(if temp_h { Some(hkdf_output[1]) } else { None })
+    }
+}
+
+#[cfg(test)]
+impl SymmetricState {
+    /// A Curve25519 state past its prologue that uses the given private
+    /// keys rather than generated ones, for checking test vectors. Roles
+    /// without a static key still get one, as they do from `prologue`.
+    pub(crate) fn with_keys(
+        pattern: &'static str,
+        vault: Arc<Mutex<dyn DynVault + Send>>,
+        static_private: &str,
+        ephemeral_private: &str,
+    ) -> Self {
+        Self::with_suite_keys(
+            pattern,
+            CipherSuite::Curve25519AesGcmSha256,
+            vault,
+            static_private,
+            ephemeral_private,
+        )
+    }
+
+    pub(crate) fn with_suite_keys(
+        pattern: &'static str,
+        cipher_suite: CipherSuite,
+        vault: Arc<Mutex<dyn DynVault + Send>>,
+        static_private: &str,
+        ephemeral_private: &str,
+    ) -> Self {
+        Self::with_vector_keys(
+            pattern,
+            cipher_suite,
+            vault,
+            Some(hex::decode(static_private).unwrap().as_slice()),
+            &hex::decode(ephemeral_private).unwrap(),
+            &[],
+        )
+    }
+
+    /// As `with_suite_keys`, with a prologue and maybe without a static
+    /// key, as Noise test vectors have them. A static key is generated
+    /// if there isn't one, as `prologue` would.
+    pub(crate) fn with_vector_keys(
+        pattern: &'static str,
+        cipher_suite: CipherSuite,
+        vault: Arc<Mutex<dyn DynVault + Send>>,
+        static_private: Option<&[u8]>,
+        ephemeral_private: &[u8],
+        prologue: &[u8],
+    ) -> Self {
+        let mut ss = Self::with_pattern(pattern, cipher_suite, vault.clone(), None);
+        let mut vault = vault.lock().unwrap();
+        let attributes = SecretKeyAttributes {
+            xtype: SecretKeyType::Curve25519,
+            purpose: SecretPurposeType::KeyAgreement,
+            persistence: SecretPersistenceType::Ephemeral,
+        };
+        let mut import = |private: Option<&[u8]>| {
+            let secret_handle = match private {
+                Some(bytes) => {
+                    let key = SecretKey::Curve25519(*array_ref![bytes, 0, 32]);
+                    vault.secret_import(&key, attributes).unwrap()
+                }
+                None => vault.secret_generate(attributes).unwrap(),
+            };
+            KeyPair {
+                public_key: vault.secret_public_key_get(secret_handle).unwrap(),
+                secret_handle,
+            }
+        };
+        ss.static_key_pair = Some(import(static_private));
+        ss.ephemeral_key_pair = Some(import(Some(ephemeral_private)));
+        ss.initialize_symmetric(&mut *vault, prologue).unwrap();
+        ss
+    }
+}
+
+impl KeyExchange for SymmetricState {
+    fn get_protocol_name(&self) -> Vec<u8> {
+        let suite = match self.cipher_suite {
+            CipherSuite::Curve25519AesGcmSha256 => "25519_AESGCM_SHA256",
+            CipherSuite::P256Aes128GcmSha256 => "P256_AES128GCM_SHA256",
+            CipherSuite::Curve25519ChaChaPolySha256 => "25519_ChaChaPoly_SHA256",
+            #[cfg(feature = "kyber")]
+            CipherSuite::Curve25519Kyber768AesGcmSha256 => "25519+Kyber768_AESGCM_SHA256",
+        };
+        format!("Noise_{}_{}", self.pattern, suite).into_bytes()
     }
 
     /// Create a new `HandshakeState` starting with the prologue
@@ -130,7 +507,8 @@ impl KeyExchange for SymmetricState {
             persistence: SecretPersistenceType::Persistent,
         };
         // 1. Generate a static key pair for this handshake and set it to `s`
-        let mut vault = self.vault.lock().unwrap();
+        let vault = self.vault.clone();
+        let mut vault = vault.lock().unwrap();
         match self.identity_key {
             None => {
                 let static_secret_handle = vault.secret_generate(attributes)?;
@@ -157,26 +535,7 @@ impl KeyExchange for SymmetricState {
             secret_handle: ephemeral_secret_handle,
         });
 
-        // 3. Set k to empty, Set n to 0
-        // let nonce = 0;
-        self.key = None;
-        self.nonce = 0;
-
-        // 4. Set h and ck to protocol name
-        // 5. h = SHA256(h || prologue),
-        // prologue is empty
-        // mix_hash(xx, NULL, 0);
-        let mut h = [0u8; SHA256_SIZE];
-        h[..self.get_protocol_name().len()].copy_from_slice(self.get_protocol_name());
-        let attributes = SecretKeyAttributes {
-            xtype: SecretKeyType::Buffer(SHA256_SIZE),
-            persistence: SecretPersistenceType::Ephemeral,
-            purpose: SecretPurposeType::KeyAgreement,
-        };
-        self.ck = Some(vault.secret_import(&SecretKey::Buffer(h.to_vec()), attributes)?);
-        self.h = Some(vault.sha256(&h)?);
-
-        Ok(())
+        self.initialize_symmetric(&mut *vault, &[])
     }
 
     /// Perform the diffie-hellman computation
@@ -243,7 +602,8 @@ impl KeyExchange for SymmetricState {
         Ok(())
     }
 
-    /// Encrypt and mix step in Noise protocol
+    /// Encrypt and mix step in Noise protocol. Before there is a key the
+    /// plaintext is only mixed in.
     fn encrypt_and_mix_hash<B: AsRef<[u8]>>(
         &mut self,
         plaintext: B,
@@ -251,24 +611,32 @@ impl KeyExchange for SymmetricState {
         let h = &self
             .h
             .ok_or_else(|| VaultFailError::from(VaultFailErrorKind::InvalidContext))?;
+        if self.key.is_none() {
+            self.mix_hash(&plaintext)?;
+            return Ok(plaintext.as_ref().to_vec());
+        }
 
-        let mut nonce = [0u8; 12];
-        nonce[10..].copy_from_slice(&self.nonce.to_be_bytes());
+        let nonce = self.nonce_bytes();
         let ciphertext_and_tag = {
+            let key = self.key.ok_or(VaultFailErrorKind::AeadAesGcmEncrypt)?;
             let mut vault = self.vault.lock().unwrap();
-            vault.aead_aes_gcm_encrypt(
-                self.key.ok_or(VaultFailErrorKind::AeadAesGcmEncrypt)?,
-                plaintext.as_ref(),
-                nonce.as_ref(),
-                h,
-            )?
+            match self.cipher_suite {
+                CipherSuite::Curve25519ChaChaPolySha256 => vault.aead_chacha20_poly1305_encrypt(
+                    key,
+                    plaintext.as_ref(),
+                    nonce.as_ref(),
+                    h,
+                )?,
+                _ => vault.aead_aes_gcm_encrypt(key, plaintext.as_ref(), nonce.as_ref(), h)?,
+            }
         };
         self.mix_hash(&ciphertext_and_tag)?;
         self.nonce += 1;
         Ok(ciphertext_and_tag)
     }
 
-    /// Decrypt and mix step in Noise protocol
+    /// Decrypt and mix step in Noise protocol. Before there is a key the
+    /// ciphertext is plaintext and only mixed in.
     fn decrypt_and_mix_hash<B: AsRef<[u8]>>(
         &mut self,
         ciphertext: B,
@@ -276,18 +644,22 @@ impl KeyExchange for SymmetricState {
         let h = &self
             .h
             .ok_or_else(|| VaultFailError::from(VaultFailErrorKind::InvalidContext))?;
+        if self.key.is_none() {
+            self.mix_hash(&ciphertext)?;
+            return Ok(ciphertext.as_ref().to_vec());
+        }
 
-        let mut nonce = [0u8; 12];
-        nonce[10..].copy_from_slice(&self.nonce.to_be_bytes());
+        let nonce = self.nonce_bytes();
         let ciphertext = ciphertext.as_ref();
         let plaintext = {
+            let key = self.key.ok_or(VaultFailErrorKind::AeadAesGcmDecrypt)?;
             let mut vault = self.vault.lock().unwrap();
-            vault.aead_aes_gcm_decrypt(
-                self.key.ok_or(VaultFailErrorKind::AeadAesGcmDecrypt)?,
-                ciphertext,
-                nonce.as_ref(),
-                h,
-            )?
+            match self.cipher_suite {
+                CipherSuite::Curve25519ChaChaPolySha256 => {
+                    vault.aead_chacha20_poly1305_decrypt(key, ciphertext, nonce.as_ref(), h)?
+                }
+                _ => vault.aead_aes_gcm_decrypt(key, ciphertext, nonce.as_ref(), h)?,
+            }
         };
         self.mix_hash(ciphertext)?;
         self.nonce += 1;
@@ -335,241 +707,78 @@ impl KeyExchange for SymmetricState {
             .remote_static_public_key
             .ok_or_else(|| VaultFailError::from(VaultFailErrorKind::InvalidContext))?;
 
+        // from the final chaining key too, but with its own info, so the
+        // channel keys stay as Noise has them
+        let ck = self
+            .ck
+            .ok_or_else(|| VaultFailError::from(VaultFailErrorKind::InvalidContext))?;
+        let exporter_secret = self.vault.lock().unwrap().hkdf_sha256(
+            ck,
+            EXPORTER_INFO,
+            None,
+            vec![exporter_attributes()],
+        )?[0];
+
         Ok(CompletedKeyExchange {
             h,
             encrypt_key,
             decrypt_key,
             local_static_secret,
             remote_static_public_key,
+            exporter_secret,
         })
     }
 }
 
-/// Provides methods for handling the initiator role
-#[derive(Debug)]
-struct Initiator(SymmetricState);
-
-impl Initiator {
-    /// Encode the first message to be sent
-    pub fn encode_message_1<B: AsRef<[u8]>>(
-        &mut self,
-        payload: B,
-    ) -> Result<Vec<u8>, VaultFailError> {
-        let ephemeral_public_key = self
-            .0
-            .ephemeral_key_pair
-            .as_ref()
-            .ok_or_else(|| VaultFailError::from(VaultFailErrorKind::InvalidContext))?
-            .public_key;
-
-        let payload = payload.as_ref();
-        self.0.mix_hash(ephemeral_public_key)?;
-        self.0.mix_hash(payload)?;
-
-        let mut output = ephemeral_public_key.as_ref().to_vec();
-        output.extend_from_slice(payload);
-        Ok(output)
-    }
-
-    /// Decode the second message in the sequence, sent from the responder
-    pub fn decode_message_2<B: AsRef<[u8]>>(
-        &mut self,
-        message: B,
-    ) -> Result<Vec<u8>, VaultFailError> {
-        let public_key_size = self.0.get_public_key_size();
-        let message = message.as_ref();
-        if message.len() < 2 * public_key_size + AES_GCM_TAGSIZE {
-            return Err(VaultFailErrorKind::SecretSizeMismatch.into());
-        }
-
-        let ephemeral_secret_handle = self
-            .0
-            .ephemeral_key_pair
-            .as_ref()
-            .ok_or_else(|| VaultFailError::from(VaultFailErrorKind::InvalidContext))?
-            .secret_handle;
-
-        let mut index_l = 0;
-        let mut index_r = public_key_size;
-        let re = &message[..index_r];
-        index_l += public_key_size;
-        index_r += public_key_size + AES_GCM_TAGSIZE;
-        let encrypted_rs_and_tag = &message[index_l..index_r];
-        let encrypted_payload_and_tag = &message[index_r..];
-
-        let re = self.0.create_public_key(re)?;
-
-        self.0.remote_ephemeral_public_key = Some(re);
-
-        self.0.mix_hash(&re)?;
-        self.0.dh(ephemeral_secret_handle, re)?;
-        let rs = self.0.decrypt_and_mix_hash(encrypted_rs_and_tag)?;
-        let rs = self.0.create_public_key(&rs)?;
-        self.0.remote_static_public_key = Some(rs);
-        self.0.dh(ephemeral_secret_handle, rs)?;
-        let payload = self.0.decrypt_and_mix_hash(encrypted_payload_and_tag)?;
-        Ok(payload)
-    }
-
-    /// Encode the final message to be sent
-    pub fn encode_message_3<B: AsRef<[u8]>>(
-        &mut self,
-        payload: B,
-    ) -> Result<Vec<u8>, VaultFailError> {
-        let static_key_pair = self
-            .0
-            .static_key_pair
-            .ok_or_else(|| VaultFailError::from(VaultFailErrorKind::InvalidContext))?;
-
-        let remote_ephemeral_public_key = self
-            .0
-            .remote_ephemeral_public_key
-            .ok_or_else(|| VaultFailError::from(VaultFailErrorKind::InvalidContext))?;
-
-        let mut encrypted_s_and_tag = self.0.encrypt_and_mix_hash(static_key_pair.public_key)?;
-        self.0
-            .dh(static_key_pair.secret_handle, remote_ephemeral_public_key)?;
-        let mut encrypted_payload_and_tag = self.0.encrypt_and_mix_hash(payload)?;
-        encrypted_s_and_tag.append(&mut encrypted_payload_and_tag);
-        Ok(encrypted_s_and_tag)
-    }
-
-    /// Setup this initiator to send and receive messages
-    /// after encoding message 3
-    pub fn finalize(&mut self) -> Result<CompletedKeyExchange, VaultFailError> {
-        let keys = self.0.split()?;
-        self.0.finalize(keys.1, keys.0)
-    }
-}
-
-/// Provides methods for handling the responder role
-#[derive(Debug)]
-struct Responder(SymmetricState);
-
-impl Responder {
-    /// Decode the first message sent
-    pub fn decode_message_1<B: AsRef<[u8]>>(
-        &mut self,
-        message_1: B,
-    ) -> Result<Vec<u8>, VaultFailError> {
-        let public_key_size = self.0.get_public_key_size();
-        let message_1 = message_1.as_ref();
-        if message_1.len() < public_key_size {
-            return Err(VaultFailErrorKind::SecretSizeMismatch.into());
-        }
-
-        let re = &message_1[..public_key_size];
-        self.0.remote_ephemeral_public_key = Some(self.0.create_public_key(re)?);
-        self.0.mix_hash(&re)?;
-        self.0.mix_hash(&message_1[public_key_size..])?;
-        Ok(message_1[public_key_size..].to_vec())
-    }
-
-    /// Encode the second message to be sent
-    pub fn encode_message_2<B: AsRef<[u8]>>(
-        &mut self,
-        payload: B,
-    ) -> Result<Vec<u8>, VaultFailError> {
-        let static_key_pair = self
-            .0
-            .static_key_pair
-            .ok_or_else(|| VaultFailError::from(VaultFailErrorKind::InvalidContext))?;
-        let ephemeral_key_pair = self
-            .0
-            .ephemeral_key_pair
-            .ok_or_else(|| VaultFailError::from(VaultFailErrorKind::InvalidContext))?;
-        let remote_ephemeral_public_key = self
-            .0
-            .remote_ephemeral_public_key
-            .ok_or_else(|| VaultFailError::from(VaultFailErrorKind::InvalidContext))?;
-
-        self.0.mix_hash(ephemeral_key_pair.public_key)?;
-        self.0.dh(
-            ephemeral_key_pair.secret_handle,
-            remote_ephemeral_public_key,
-        )?;
-
-        let mut encrypted_s_and_tag = self.0.encrypt_and_mix_hash(static_key_pair.public_key)?;
-        self.0
-            .dh(static_key_pair.secret_handle, remote_ephemeral_public_key)?;
-        let mut encrypted_payload_and_tag = self.0.encrypt_and_mix_hash(payload)?;
-
-        let mut output = ephemeral_key_pair.public_key.as_ref().to_vec();
-        output.append(&mut encrypted_s_and_tag);
-        output.append(&mut encrypted_payload_and_tag);
-        Ok(output)
-    }
-
-    /// Decode the final message received for the handshake
-    pub fn decode_message_3<B: AsRef<[u8]>>(
-        &mut self,
-        message_3: B,
-    ) -> Result<Vec<u8>, VaultFailError> {
-        let public_key_size = self.0.get_public_key_size();
-        let message_3 = message_3.as_ref();
-        if message_3.len() < public_key_size + AES_GCM_TAGSIZE {
-            return Err(VaultFailErrorKind::SecretSizeMismatch.into());
-        }
-
-        let ephemeral_secret_handle = self
-            .0
-            .ephemeral_key_pair
-            .ok_or_else(|| VaultFailError::from(VaultFailErrorKind::InvalidContext))?
-            .secret_handle;
-
-        let rs = self
-            .0
-            .decrypt_and_mix_hash(&message_3[..public_key_size + AES_GCM_TAGSIZE])?;
-        let rs = self.0.create_public_key(&rs)?;
-        self.0.dh(ephemeral_secret_handle, rs)?;
-        let payload = self
-            .0
-            .decrypt_and_mix_hash(&message_3[public_key_size + AES_GCM_TAGSIZE..])?;
-        self.0.remote_static_public_key = Some(rs);
-        Ok(payload)
-    }
-
-    /// Setup this responder to send and receive messages
-    /// after decoding message 3
-    pub fn finalize(&mut self) -> Result<CompletedKeyExchange, VaultFailError> {
-        let keys = self.0.split()?;
-        self.0.finalize(keys.0, keys.1)
-    }
-}
+/// The Noise XX pattern: neither side knows the other's static key, and
+/// each sends its own encrypted
+pub static XX: HandshakePattern = HandshakePattern {
+    name: "XX",
+    initiator_pre_message: &[],
+    responder_pre_message: &[],
+    messages: &[
+        &[Token::E],
+        &[Token::E, Token::Ee, Token::S, Token::Es],
+        &[Token::S, Token::Se],
+    ],
+    psk_variant: Some(&XX_PSK3),
+    hfs_variant: Some(&XX_HFS),
+};
 
-/// The states the connection XX pattern initiator completes
-#[derive(Debug)]
-enum InitiatorState {
-    /// Run encode message 1
-    EncodeMessage1,
-    /// Run decode message 2
-    DecodeMessage2,
-    /// Run encode message 3
-    EncodeMessage3,
-    /// Finished
-    Done,
-}
+/// XX with a pre-shared key mixed in at the end of the third message
+pub static XX_PSK3: HandshakePattern = HandshakePattern {
+    name: "XXpsk3",
+    initiator_pre_message: &[],
+    responder_pre_message: &[],
+    messages: &[
+        &[Token::E],
+        &[Token::E, Token::Ee, Token::S, Token::Es],
+        &[Token::S, Token::Se, Token::Psk],
+    ],
+    psk_variant: None,
+    hfs_variant: None,
+};
 
-/// The states the connection XX pattern responder completes
-#[derive(Debug)]
-enum ResponderState {
-    /// Run decode message 1
-    DecodeMessage1,
-    /// Run encode message 2
-    EncodeMessage2,
-    /// Run decode message 3
-    DecodeMessage3,
-    /// Finished
-    Done,
-}
+/// XX with hybrid forward secrecy: the initiator adds a KEM public key to
+/// the first message and the responder encapsulates a secret to it
+pub static XX_HFS: HandshakePattern = HandshakePattern {
+    name: "XXhfs",
+    initiator_pre_message: &[],
+    responder_pre_message: &[],
+    messages: &[
+        &[Token::E, Token::E1],
+        &[Token::E, Token::Ee, Token::Ekem1, Token::S, Token::Es],
+        &[Token::S, Token::Se],
+    ],
+    psk_variant: None,
+    hfs_variant: None,
+};
 
 /// Represents an XX initiator
-#[derive(Debug)]
-pub struct XXInitiator {
-    state: InitiatorState,
-    initiator: Initiator,
-    run_prologue: bool,
-}
+pub type XXInitiator = HandshakeState;
+
+/// Represents an XX responder
+pub type XXResponder = HandshakeState;
 
 /// Represents an XX NewKeyExchanger
 pub struct XXNewKeyExchanger {
@@ -602,118 +811,33 @@ impl XXNewKeyExchanger {
 impl NewKeyExchanger<XXInitiator, XXResponder> for XXNewKeyExchanger {
     /// Create a new initiator using the provided backing vault
     fn initiator(&self, identity_key: Option<SecretKeyContext>) -> XXInitiator {
-        let ss = SymmetricState::new(
+        HandshakeState::new(
+            &XX,
+            true,
             self.cipher_suite,
             self.vault_initiator.clone(),
             identity_key,
-        );
-        XXInitiator {
-            state: InitiatorState::EncodeMessage1,
-            initiator: Initiator(ss),
-            run_prologue: true,
-        }
+            None,
+        )
     }
 
     /// Create a new responder using the provided backing vault
     fn responder(&self, identity_key: Option<SecretKeyContext>) -> XXResponder {
-        let ss = SymmetricState::new(
+        HandshakeState::new(
+            &XX,
+            false,
             self.cipher_suite,
             self.vault_responder.clone(),
             identity_key,
-        );
-        XXResponder {
-            state: ResponderState::DecodeMessage1,
-            responder: Responder(ss),
-            run_prologue: true,
-        }
-    }
-}
-
-/// Represents an XX responder
-#[derive(Debug)]
-pub struct XXResponder {
-    state: ResponderState,
-    responder: Responder,
-    run_prologue: bool,
-}
-
-impl KeyExchanger for XXInitiator {
-    fn process(&mut self, data: &[u8]) -> Result<Vec<u8>, KexExchangeFailError> {
-        match self.state {
-            InitiatorState::EncodeMessage1 => {
-                if self.run_prologue {
-                    self.initiator.0.prologue()?;
-                }
-                let msg = self.initiator.encode_message_1(data)?;
-                self.state = InitiatorState::DecodeMessage2;
-                Ok(msg)
-            }
-            InitiatorState::DecodeMessage2 => {
-                let msg = self.initiator.decode_message_2(data)?;
-                self.state = InitiatorState::EncodeMessage3;
-                Ok(msg)
-            }
-            InitiatorState::EncodeMessage3 => {
-                let msg = self.initiator.encode_message_3(data)?;
-                self.state = InitiatorState::Done;
-                Ok(msg)
-            }
-            InitiatorState::Done => Ok(vec![]),
-        }
-    }
-
-    fn is_complete(&self) -> bool {
-        matches!(self.state, InitiatorState::Done)
-    }
-
-    fn finalize(&mut self) -> Result<CompletedKeyExchange, VaultFailError> {
-        match self.state {
-            InitiatorState::Done => self.initiator.finalize(),
-            _ => Err(VaultFailErrorKind::IOError.into()),
-        }
-    }
-}
-
-impl KeyExchanger for XXResponder {
-    fn process(&mut self, data: &[u8]) -> Result<Vec<u8>, KexExchangeFailError> {
-        match self.state {
-            ResponderState::DecodeMessage1 => {
-                if self.run_prologue {
-                    self.responder.0.prologue()?;
-                }
-                let msg = self.responder.decode_message_1(data)?;
-                self.state = ResponderState::EncodeMessage2;
-                Ok(msg)
-            }
-            ResponderState::EncodeMessage2 => {
-                let msg = self.responder.encode_message_2(data)?;
-                self.state = ResponderState::DecodeMessage3;
-                Ok(msg)
-            }
-            ResponderState::DecodeMessage3 => {
-                let msg = self.responder.decode_message_3(data)?;
-                self.state = ResponderState::Done;
-                Ok(msg)
-            }
-            ResponderState::Done => Ok(vec![]),
-        }
-    }
-
-    fn is_complete(&self) -> bool {
-        matches!(self.state, ResponderState::Done)
-    }
-
-    fn finalize(&mut self) -> Result<CompletedKeyExchange, VaultFailError> {
-        match self.state {
-            ResponderState::Done => self.responder.finalize(),
-            _ => Err(VaultFailErrorKind::IOError.into()),
-        }
+            None,
+        )
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::KeyExchanger;
     use ockam_vault::software::DefaultVault;
 
     #[test]
@@ -823,16 +947,8 @@ mod tests {
 
         let ss_init = mock_prologue(vault_init.clone(), INIT_STATIC, INIT_EPH);
         let ss_resp = mock_prologue(vault_resp.clone(), RESP_STATIC, RESP_EPH);
-        let mut initiator = XXInitiator {
-            state: InitiatorState::EncodeMessage1,
-            initiator: Initiator(ss_init),
-            run_prologue: false,
-        };
-        let mut responder = XXResponder {
-            state: ResponderState::DecodeMessage1,
-            responder: Responder(ss_resp),
-            run_prologue: false,
-        };
+        let mut initiator = HandshakeState::from_state(&XX, true, ss_init, false);
+        let mut responder = HandshakeState::from_state(&XX, false, ss_resp, false);
 
         assert!(!initiator.is_complete());
         assert!(!responder.is_complete());
@@ -891,6 +1007,193 @@ mod tests {
         assert_eq!(plaintext, b"hello alice");
     }
 
+    #[test]
+    fn handshake_chachapoly() {
+        const INIT_STATIC: &str =
+            "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        const INIT_EPH: &str = "202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f";
+        const RESP_STATIC: &str =
+            "0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20";
+        const RESP_EPH: &str = "4142434445464748494a4b4c4d4e4f505152535455565758595a5b5c5d5e5f60";
+        const MSG_1_PAYLOAD: &str = "746573745f6d73675f30";
+        const MSG_1_CIPHERTEXT: &str =
+            "358072d6365880d1aeea329adf9121383851ed21a28e3b75e965d0d2cd166254746573745f6d73675f30";
+        const MSG_2_PAYLOAD: &str = "746573745f6d73675f31";
+        const MSG_2_CIPHERTEXT: &str = "64b101b1d0be5a8704bd078f9895001fc03e8e9f9522f188dd128d9846d484663414af878d3e46a2f58911a816d6e8346d4ea17a6f2a0bb4ef4ed56c133cff4572e7a2ba5123ac30618b3d205f5c2d17f50cbca216483ac56bcc78e33bf520303278db641e5e731b2e3a";
+        const MSG_3_PAYLOAD: &str = "746573745f6d73675f32";
+        const MSG_3_CIPHERTEXT: &str = "87f864c11ba449f46a0a4f4e2eacbb7b0457784f4fca1937f572c93603e9c4d9f27e318e43ba630594c4d08eeb3b36d97c7377a2f4f9144b2f0c8095ad92140505b2ab53eff244b14138";
+        const H: &str = "a57169038f6d1d499872805e7f4abeb2592d035bb7aa9f78dcb2a61ef9b3807c";
+        const TRANSPORT_CIPHERTEXT: &str = "b42ef02ba65903736dc88498d3152b4a202654e44909f2f3a6";
+
+        let vault_init = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_resp = Arc::new(Mutex::new(DefaultVault::default()));
+        let suite = CipherSuite::Curve25519ChaChaPolySha256;
+        let ss_init =
+            SymmetricState::with_suite_keys("XX", suite, vault_init.clone(), INIT_STATIC, INIT_EPH);
+        let ss_resp =
+            SymmetricState::with_suite_keys("XX", suite, vault_resp.clone(), RESP_STATIC, RESP_EPH);
+        let mut initiator = HandshakeState::from_state(&XX, true, ss_init, false);
+        let mut responder = HandshakeState::from_state(&XX, false, ss_resp, false);
+
+        let msg1 = initiator
+            .process(&hex::decode(MSG_1_PAYLOAD).unwrap())
+            .unwrap();
+        assert_eq!(hex::encode(&msg1), MSG_1_CIPHERTEXT);
+        responder.process(&msg1).unwrap();
+        let msg2 = responder
+            .process(&hex::decode(MSG_2_PAYLOAD).unwrap())
+            .unwrap();
+        assert_eq!(hex::encode(&msg2), MSG_2_CIPHERTEXT);
+        assert_eq!(
+            hex::encode(initiator.process(&msg2).unwrap()),
+            MSG_2_PAYLOAD
+        );
+        let msg3 = initiator
+            .process(&hex::decode(MSG_3_PAYLOAD).unwrap())
+            .unwrap();
+        assert_eq!(hex::encode(&msg3), MSG_3_CIPHERTEXT);
+        assert_eq!(
+            hex::encode(responder.process(&msg3).unwrap()),
+            MSG_3_PAYLOAD
+        );
+
+        let alice = initiator.finalize().unwrap();
+        let bob = responder.finalize().unwrap();
+        assert_eq!(hex::encode(alice.h), H);
+        assert_eq!(hex::encode(bob.h), H);
+
+        let mut vault_in = vault_init.lock().unwrap();
+        let ciphertext = vault_in
+            .aead_chacha20_poly1305_encrypt(alice.encrypt_key, b"hello bob", &[0u8; 12], &alice.h)
+            .unwrap();
+        assert_eq!(hex::encode(&ciphertext), TRANSPORT_CIPHERTEXT);
+        let mut vault_re = vault_resp.lock().unwrap();
+        let plaintext = vault_re
+            .aead_chacha20_poly1305_decrypt(bob.decrypt_key, &ciphertext, &[0u8; 12], &bob.h)
+            .unwrap();
+        assert_eq!(plaintext, b"hello bob");
+        assert_eq!(
+            vault_re
+                .secret_attributes_get(bob.decrypt_key)
+                .unwrap()
+                .xtype,
+            SecretKeyType::ChaCha20Poly1305
+        );
+    }
+
+    const PSK: &str = "54686973206973206d7920417573747269616e20706572737065637469766521";
+
+    fn import_psk(vault: &Arc<Mutex<DefaultVault>>, psk: &str) -> SecretKeyContext {
+        let attributes = SecretKeyAttributes {
+            xtype: SecretKeyType::Buffer(SHA256_SIZE),
+            purpose: SecretPurposeType::KeyAgreement,
+            persistence: SecretPersistenceType::Ephemeral,
+        };
+        let psk = SecretKey::Buffer(hex::decode(psk).unwrap());
+        vault
+            .lock()
+            .unwrap()
+            .secret_import(&psk, attributes)
+            .unwrap()
+    }
+
+    #[test]
+    fn handshake_psk3() {
+        const INIT_STATIC: &str =
+            "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        const INIT_EPH: &str = "202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f";
+        const RESP_STATIC: &str =
+            "0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20";
+        const RESP_EPH: &str = "4142434445464748494a4b4c4d4e4f505152535455565758595a5b5c5d5e5f60";
+        const MSG_1_PAYLOAD: &str = "746573745f6d73675f30";
+        const MSG_1_CIPHERTEXT: &str = "358072d6365880d1aeea329adf9121383851ed21a28e3b75e965d0d2cd1662545d791aebd7b1ff3a73ba5c27944833f62facffa3b38f7ec45f9f";
+        const MSG_2_PAYLOAD: &str = "746573745f6d73675f31";
+        const MSG_2_CIPHERTEXT: &str = "64b101b1d0be5a8704bd078f9895001fc03e8e9f9522f188dd128d9846d48466bb1259f77345353d70dcef1e97d161dd9c3324e72b46203ebe87dcb40159eb66954fa813c5770391ff0e4006fa005462b1bfa8057f6e8f62584a272b0eec13b6cf6a75a736ddf5d0d3cd";
+        const MSG_3_PAYLOAD: &str = "746573745f6d73675f32";
+        const MSG_3_CIPHERTEXT: &str = "a702c30239110afbb8afacb639f961e5c2574c3fe59ee6069c0f5f5414ea2493ab8dbfb3e1f6148b9c0d93e8d9ebf5189c77b3dbbc25ca36552834c180fc6d118325552c77adc938ded2";
+        const H: &str = "ec6ec247be6c94c17ac362532f58d1e33897f2d6381ffeb4744d13c189346aa2";
+
+        let vault_init = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_resp = Arc::new(Mutex::new(DefaultVault::default()));
+        let psk_init = import_psk(&vault_init, PSK);
+        let psk_resp = import_psk(&vault_resp, PSK);
+        let mut ss_init = SymmetricState::with_keys("XXpsk3", vault_init, INIT_STATIC, INIT_EPH);
+        let mut ss_resp = SymmetricState::with_keys("XXpsk3", vault_resp, RESP_STATIC, RESP_EPH);
+        ss_init.psk = Some(psk_init);
+        ss_resp.psk = Some(psk_resp);
+        let mut initiator = HandshakeState::from_state(&XX_PSK3, true, ss_init, false);
+        let mut responder = HandshakeState::from_state(&XX_PSK3, false, ss_resp, false);
+
+        let msg1 = initiator
+            .process(&hex::decode(MSG_1_PAYLOAD).unwrap())
+            .unwrap();
+        assert_eq!(hex::encode(&msg1), MSG_1_CIPHERTEXT);
+        assert_eq!(
+            hex::encode(responder.process(&msg1).unwrap()),
+            MSG_1_PAYLOAD
+        );
+        let msg2 = responder
+            .process(&hex::decode(MSG_2_PAYLOAD).unwrap())
+            .unwrap();
+        assert_eq!(hex::encode(&msg2), MSG_2_CIPHERTEXT);
+        assert_eq!(
+            hex::encode(initiator.process(&msg2).unwrap()),
+            MSG_2_PAYLOAD
+        );
+        let msg3 = initiator
+            .process(&hex::decode(MSG_3_PAYLOAD).unwrap())
+            .unwrap();
+        assert_eq!(hex::encode(&msg3), MSG_3_CIPHERTEXT);
+        assert_eq!(
+            hex::encode(responder.process(&msg3).unwrap()),
+            MSG_3_PAYLOAD
+        );
+
+        assert_eq!(hex::encode(initiator.finalize().unwrap().h), H);
+        assert_eq!(hex::encode(responder.finalize().unwrap().h), H);
+    }
+
+    #[test]
+    fn psk_mismatch_fails() {
+        let vault_initiator = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_responder = Arc::new(Mutex::new(DefaultVault::default()));
+        let psk_init = import_psk(&vault_initiator, PSK);
+        let psk_resp = import_psk(&vault_responder, &"00".repeat(SHA256_SIZE));
+        let key_exchanger = XXNewKeyExchanger::new(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault_initiator.clone(),
+            vault_responder,
+        );
+        let mut initiator = key_exchanger.initiator(None);
+        let mut responder = key_exchanger.responder(None);
+        initiator.set_psk(psk_init).unwrap();
+        responder.set_psk(psk_resp).unwrap();
+
+        // a different psk goes unnoticed until it is mixed in, in message 3
+        let m1 = initiator.process(&[]).unwrap();
+        assert!(responder.process(&m1).is_ok());
+        let m2 = responder.process(&[]).unwrap();
+        assert!(initiator.process(&m2).is_ok());
+        let m3 = initiator.process(&[]).unwrap();
+        assert!(responder.process(&m3).is_err());
+
+        // a psk must be set before the exchange starts, and be a 32 byte buffer
+        let mut initiator = key_exchanger.initiator(None);
+        initiator.process(&[]).unwrap();
+        assert!(initiator.set_psk(psk_init).is_err());
+        let mut initiator = key_exchanger.initiator(None);
+        let not_a_psk = {
+            let mut vault = vault_initiator.lock().unwrap();
+            let attributes = SecretKeyAttributes {
+                xtype: SecretKeyType::Curve25519,
+                purpose: SecretPurposeType::KeyAgreement,
+                persistence: SecretPersistenceType::Ephemeral,
+            };
+            vault.secret_generate(attributes).unwrap()
+        };
+        assert!(initiator.set_psk(not_a_psk).is_err());
+    }
+
     fn mock_handshake(
         init_static: &str,
         init_eph: &str,
@@ -908,30 +1211,30 @@ mod tests {
 
         let ss_init = mock_prologue(vault_init.clone(), init_static, init_eph);
         let ss_resp = mock_prologue(vault_resp.clone(), resp_static, resp_eph);
-        let mut initiator = Initiator(ss_init);
-        let mut responder = Responder(ss_resp);
+        let mut initiator = HandshakeState::from_state(&XX, true, ss_init, false);
+        let mut responder = HandshakeState::from_state(&XX, false, ss_resp, false);
 
-        let res = initiator.encode_message_1(hex::decode(msg_1_payload).unwrap());
+        let res = initiator.process(&hex::decode(msg_1_payload).unwrap());
         assert!(res.is_ok());
         let msg1 = res.unwrap();
         assert_eq!(hex::encode(&msg1), msg_1_ciphertext);
 
-        let res = responder.decode_message_1(msg1);
+        let res = responder.process(&msg1);
         assert!(res.is_ok());
 
-        let res = responder.encode_message_2(hex::decode(msg_2_payload).unwrap());
+        let res = responder.process(&hex::decode(msg_2_payload).unwrap());
         assert!(res.is_ok());
         let msg2 = res.unwrap();
         assert_eq!(hex::encode(&msg2), msg_2_ciphertext);
 
-        let res = initiator.decode_message_2(msg2);
+        let res = initiator.process(&msg2);
         assert!(res.is_ok());
-        let res = initiator.encode_message_3(hex::decode(msg_3_payload).unwrap());
+        let res = initiator.process(&hex::decode(msg_3_payload).unwrap());
         assert!(res.is_ok());
         let msg3 = res.unwrap();
         assert_eq!(hex::encode(&msg3), msg_3_ciphertext);
 
-        let res = responder.decode_message_3(msg3);
+        let res = responder.process(&msg3);
         assert!(res.is_ok());
 
         let res = initiator.finalize();
@@ -986,6 +1289,7 @@ mod tests {
             .secret_import(&SecretKey::Buffer(ck.to_vec()), attributes)
             .unwrap();
         SymmetricState {
+            pattern: "XX",
             cipher_suite: CipherSuite::Curve25519AesGcmSha256,
             static_key_pair: Some(KeyPair {
                 public_key: static_public_key,
@@ -997,7 +1301,10 @@ mod tests {
             }),
             remote_ephemeral_public_key: None,
             remote_static_public_key: None,
+            kem_key: None,
+            remote_kem_public_key: None,
             identity_key: None,
+            psk: None,
             key: None,
             nonce,
             h: Some(h),
@@ -52,7 +52,11 @@ pub mod message {
         KeyAgreementM1 = 3,
         KeyAgreementM2 = 4,
         KeyAgreementM3 = 5,
+        ChannelClose = 6,
+        HandshakeFailed = 7,
+        ChannelOpening = 8,
         NoSuchChannel = 9,
+        SendFailed = 10,
         None = 255,
     }
 
@@ -226,6 +230,10 @@ pub mod message {
                 3 => Ok(MessageType::KeyAgreementM1),
                 4 => Ok(MessageType::KeyAgreementM2),
                 5 => Ok(MessageType::KeyAgreementM3),
+                6 => Ok(MessageType::ChannelClose),
+                7 => Ok(MessageType::HandshakeFailed),
+                8 => Ok(MessageType::ChannelOpening),
+                10 => Ok(MessageType::SendFailed),
                 _ => Err("Unknown message type".to_string()),
             }
         }
@@ -5,14 +5,16 @@ use ockam_kex::xx::{XXInitiator, XXNewKeyExchanger, XXResponder};
 use ockam_kex::CipherSuite;
 use ockam_message::message::*;
 use ockam_router::router::*;
-use ockam_system::commands::{ChannelCommand, OckamCommand, RouterCommand, WorkerCommand};
+use ockam_system::commands::{
+    ChannelCommand, CommandSender, OckamCommand, RouterCommand, WorkerCommand,
+};
+use ockam_system::runtime::{bounded_queue, NodeRuntime, QueueConfig};
 use ockam_transport::tcp::TcpManager;
 use ockam_transport::udp::UdpTransport;
 use ockam_vault::software::DefaultVault;
 use std::net::SocketAddr;
 use std::str;
 use std::sync::{Arc, Mutex};
-use std::{thread, time};
 
 pub enum IpProtocol {
     Tcp,
@@ -27,8 +29,8 @@ pub enum Role {
 
 pub struct TestWorker {
     rx: std::sync::mpsc::Receiver<OckamCommand>,
-    _tx: std::sync::mpsc::Sender<OckamCommand>,
-    router_tx: std::sync::mpsc::Sender<OckamCommand>,
+    _tx: CommandSender,
+    router_tx: CommandSender,
     address: Address,
     channel_address: Address,
     pending_message: Option<Message>,
@@ -41,10 +43,12 @@ pub struct TestWorker {
 impl TestWorker {
     pub fn new(
         rx: std::sync::mpsc::Receiver<OckamCommand>,
-        tx: std::sync::mpsc::Sender<OckamCommand>,
-        router_tx: std::sync::mpsc::Sender<OckamCommand>,
+        tx: impl Into<CommandSender>,
+        router_tx: impl Into<CommandSender>,
         address: Address,
     ) -> Result<Self, String> {
+        let tx = tx.into();
+        let router_tx = router_tx.into();
         if router_tx
             .send(OckamCommand::Router(RouterCommand::Register(
                 AddressType::Worker,
@@ -167,41 +171,45 @@ impl TestWorker {
                 self.handle_payload(m).unwrap();
                 Ok(())
             }
+            MessageType::ChannelOpening => Ok(()),
+            MessageType::SendFailed => {
+                println!("Worker's message was not delivered");
+                Ok(())
+            }
+            MessageType::ChannelClose => {
+                println!("Worker's channel closed");
+                Ok(())
+            }
+            MessageType::HandshakeFailed => Err("worker's channel handshake failed".into()),
             _ => Err("worker got bad message type".into()),
         }
     }
 
-    pub fn poll(&mut self) -> bool {
-        let mut keep_going = true;
-        let mut got = true;
-        while got {
-            got = false;
-            if let Ok(c) = self.rx.try_recv() {
-                got = true;
-                match c {
-                    OckamCommand::Worker(WorkerCommand::Test) => {
-                        println!("Worker got test command");
-                    }
-                    OckamCommand::Worker(WorkerCommand::SendMessage(m)) => {
-                        self.handle_send(m).unwrap();
-                    }
-                    OckamCommand::Worker(WorkerCommand::ReceiveMessage(m)) => {
-                        if let MessageType::Payload = m.message_type {
-                            println!(
-                                "Worker received: {}",
-                                str::from_utf8(&m.message_body).unwrap()
-                            );
-                        }
-                        self.handle_receive(m).unwrap();
-                    }
-                    OckamCommand::Worker(WorkerCommand::Stop) => {
-                        keep_going = false;
+    /// Block on the worker queue until told to stop
+    pub fn run(&mut self) {
+        while let Ok(c) = self.rx.recv() {
+            match c {
+                OckamCommand::Worker(WorkerCommand::Test) => {
+                    println!("Worker got test command");
+                }
+                OckamCommand::Worker(WorkerCommand::SendMessage(m)) => {
+                    self.handle_send(m).unwrap();
+                }
+                OckamCommand::Worker(WorkerCommand::ReceiveMessage(m)) => {
+                    if let MessageType::Payload = m.message_type {
+                        println!(
+                            "Worker received: {}",
+                            str::from_utf8(&m.message_body).unwrap()
+                        );
                     }
-                    _ => println!("Worker got bad message"),
+                    self.handle_receive(m).unwrap();
+                }
+                OckamCommand::Worker(WorkerCommand::Stop) => {
+                    break;
                 }
+                _ => println!("Worker got bad message"),
             }
         }
-        keep_going
     }
 }
 
@@ -213,11 +221,12 @@ pub fn start_node(
     listen_addr: Option<SocketAddr>,
     role: Role,
 ) -> Result<(), String> {
-    let (udp_transport_tx, udp_transport_rx) = std::sync::mpsc::channel();
-    let (tcp_transport_tx, tcp_transport_rx) = std::sync::mpsc::channel();
-    let (router_tx, router_rx) = std::sync::mpsc::channel();
-    let (worker_tx, worker_rx) = std::sync::mpsc::channel();
-    let (channel_tx, channel_rx) = std::sync::mpsc::channel();
+    let queues = QueueConfig::default();
+    let (udp_transport_tx, udp_transport_rx) = bounded_queue(queues.transport);
+    let (tcp_transport_tx, tcp_transport_rx) = bounded_queue(queues.transport);
+    let (router_tx, router_rx) = bounded_queue(queues.router);
+    let (worker_tx, worker_rx) = bounded_queue(queues.worker);
+    let (channel_tx, channel_rx) = bounded_queue(queues.channel);
 
     let mut router = Router::new(router_rx);
 
@@ -318,21 +327,21 @@ pub fn start_node(
             .unwrap();
     }
 
-    while router.poll() && channel_handler.poll().unwrap() {
-        if let Some(w) = worker.as_mut() {
-            if !w.poll() {
-                break;
-            }
+    // every component blocks on its own thread until it has work to do
+    let runtime = NodeRuntime::new();
+    runtime.spawn("router", move || router.run());
+    runtime.spawn("channel", move || {
+        if let Err(e) = channel_handler.run() {
+            println!("channel manager failed: {:?}", e);
         }
-        if let Some(u) = udp_transport.as_mut() {
-            if !u.poll() {
-                break;
-            }
-        }
-        if !tcp_manager.poll() {
-            break;
-        }
-        thread::sleep(time::Duration::from_millis(100));
+    });
+    if let Some(mut w) = worker {
+        runtime.spawn("worker", move || w.run());
+    }
+    if let Some(mut u) = udp_transport {
+        runtime.spawn("udp", move || u.run());
     }
+    runtime.spawn("tcp", move || tcp_manager.run());
+    runtime.wait();
     Ok(())
 }
@@ -0,0 +1,300 @@
+//! An async Router for nodes embedded in a tokio application. It routes with
+//! the same rules as the blocking Router, and accepts registrations from
+//! either blocking or async handlers.
+use crate::metrics::RouterMetrics;
+use crate::router::{
+    local_worker_key, register_worker, resolve, Direction, LocalWorkers, Overflow, RouteError,
+};
+use futures::stream::Stream;
+use ockam_message::message::*;
+use ockam_system::commands::{
+    ChannelCommand, CommandSender, OckamCommand, RouterCommand, WorkerCommand,
+};
+use ockam_system::stats::RouterStats;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::TrySendError;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+pub struct AsyncRouter {
+    registry: Vec<Option<CommandSender>>,
+    workers: LocalWorkers,
+    rx: Receiver<OckamCommand>,
+    dropped: Arc<AtomicU64>,
+    overflow: Overflow,
+    metrics: RouterMetrics,
+}
+
+/// A cloneable handle for talking to a running AsyncRouter
+#[derive(Clone, Debug)]
+pub struct RouterHandle {
+    tx: Sender<OckamCommand>,
+    capacity: usize,
+}
+
+impl AsyncRouter {
+    /// Create a router whose queue, and the queue of every stream registered
+    /// through its handle, holds `capacity` commands
+    pub fn new(capacity: usize) -> (AsyncRouter, RouterHandle) {
+        let (tx, rx) = channel(capacity);
+        (
+            AsyncRouter {
+                registry: vec![Option::None; 256],
+                workers: LocalWorkers::new(),
+                rx,
+                dropped: Arc::new(AtomicU64::new(0)),
+                overflow: Overflow::default(),
+                metrics: RouterMetrics::default(),
+            },
+            RouterHandle { tx, capacity },
+        )
+    }
+
+    /// Choose what happens to a message whose handler's queue is full. By
+    /// default the router waits for room; only a full blocking handler's
+    /// queue holds up the executor while it does.
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.overflow = overflow;
+    }
+
+    /// Count of messages dropped because a bounded handler queue was full.
+    /// Only ever counts with Overflow::Drop.
+    pub fn dropped_messages(&self) -> Arc<AtomicU64> {
+        self.dropped.clone()
+    }
+
+    /// Snapshot of the router's counters and gauges
+    pub fn stats(&self) -> RouterStats {
+        self.metrics
+            .snapshot(&self.registry, self.dropped.load(Ordering::Relaxed))
+    }
+
+    async fn route(&mut self, m: Message, direction: Direction) {
+        let address_type = m.onward_route.addresses.first().map(|a| a.a_type);
+        let local = local_worker_key(&m);
+        // own the sender, so nothing borrowed from the router is held while
+        // waiting
+        let resolved =
+            resolve(&self.registry, &self.workers, m, direction).map(|(tx, c)| (tx.clone(), c));
+        let r = match resolved {
+            Ok((handler_tx, c)) => match self.overflow {
+                Overflow::Block => handler_tx
+                    .send_async(c)
+                    .await
+                    .map_err(|_| RouteError::HandlerGone),
+                Overflow::Drop => match handler_tx.try_send(c) {
+                    Ok(()) => Ok(()),
+                    Err(TrySendError::Full(_)) => Err(RouteError::QueueFull),
+                    Err(TrySendError::Disconnected(_)) => Err(RouteError::HandlerGone),
+                },
+            },
+            Err(e) => Err(e),
+        };
+        self.metrics.record(address_type, &direction, &r);
+        match r {
+            Ok(()) => {}
+            Err(RouteError::QueueFull) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(RouteError::HandlerGone) if local.is_some() => {
+                self.workers.remove(&local.unwrap());
+            }
+            Err(e) => println!("router failed to route message: {:?}", e),
+        }
+    }
+
+    /// Route messages as they arrive, until told to stop or every handle has
+    /// gone away
+    pub async fn run(mut self) {
+        while let Some(first) = self.rx.recv().await {
+            let mut batch = vec![first];
+            while let Ok(rc) = self.rx.try_recv() {
+                batch.push(rc);
+            }
+            self.metrics.observe_queue_depth(batch.len());
+            for rc in batch {
+                if !self.handle_command(rc).await {
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn handle_command(&mut self, rc: OckamCommand) -> bool {
+        match rc {
+            OckamCommand::Router(RouterCommand::Stop) => return false,
+            OckamCommand::Router(RouterCommand::Register(a_type, tx)) => {
+                self.registry[a_type as usize] = Option::Some(tx);
+            }
+            OckamCommand::Router(RouterCommand::RegisterWorker(address, tx)) => {
+                register_worker(&mut self.workers, address, tx);
+            }
+            OckamCommand::Router(RouterCommand::ReceiveMessage(m)) => {
+                self.route(m, Direction::Incoming).await;
+            }
+            OckamCommand::Router(RouterCommand::SendMessage(m)) => {
+                self.route(m, Direction::Outgoing).await;
+            }
+            OckamCommand::Router(RouterCommand::Stats(reply)) => {
+                let _ = reply.send(self.stats());
+            }
+            _ => println!("Router received bad command"),
+        }
+        true
+    }
+}
+
+impl RouterHandle {
+    /// The sender to give components (blocking or async) that talk to this
+    /// router
+    pub fn sender(&self) -> CommandSender {
+        CommandSender::Async(self.tx.clone())
+    }
+
+    /// Route a message outward, waiting while the router's queue is full
+    pub async fn send(&self, m: Message) -> Result<(), String> {
+        self.command(RouterCommand::SendMessage(m)).await
+    }
+
+    /// Register as the handler for an address type and get back the stream
+    /// of messages the router delivers to it. Fails if the router's queue is
+    /// full.
+    pub fn register(&self, a_type: AddressType) -> Result<MessageStream, String> {
+        let (tx, rx) = channel(self.capacity);
+        self.try_command(RouterCommand::Register(a_type, CommandSender::Async(tx)))?;
+        Ok(MessageStream { rx })
+    }
+
+    /// Register a single worker by its address and get back the stream of
+    /// messages addressed to it, from this node or elsewhere. Fails if the
+    /// router's queue is full.
+    pub fn register_worker(&self, address: Address) -> Result<MessageStream, String> {
+        let (tx, rx) = channel(self.capacity);
+        self.try_command(RouterCommand::RegisterWorker(
+            address,
+            CommandSender::Async(tx),
+        ))?;
+        Ok(MessageStream { rx })
+    }
+
+    pub async fn stop(&self) -> Result<(), String> {
+        self.command(RouterCommand::Stop).await
+    }
+
+    async fn command(&self, c: RouterCommand) -> Result<(), String> {
+        self.tx
+            .clone()
+            .send(OckamCommand::Router(c))
+            .await
+            .map_err(|_| "router is not running".to_string())
+    }
+
+    fn try_command(&self, c: RouterCommand) -> Result<(), String> {
+        self.sender()
+            .try_send(OckamCommand::Router(c))
+            .map_err(|e| match e {
+                TrySendError::Full(_) => "router queue is full".to_string(),
+                TrySendError::Disconnected(_) => "router is not running".to_string(),
+            })
+    }
+}
+
+/// Messages received by a registered handler. Commands that don't carry a
+/// received message are skipped.
+#[derive(Debug)]
+pub struct MessageStream {
+    rx: Receiver<OckamCommand>,
+}
+
+impl Stream for MessageStream {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        loop {
+            match self.rx.poll_recv(cx) {
+                Poll::Ready(Some(OckamCommand::Worker(WorkerCommand::ReceiveMessage(m))))
+                | Poll::Ready(Some(OckamCommand::Channel(ChannelCommand::ReceiveMessage(m)))) => {
+                    return Poll::Ready(Some(m))
+                }
+                Poll::Ready(Some(_)) => continue,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::StreamExt;
+
+    fn message(body: &[u8]) -> Message {
+        Message {
+            onward_route: Route {
+                addresses: vec![RouterAddress::worker_router_address_from_str("00010203").unwrap()],
+            },
+            return_route: Route { addresses: vec![] },
+            message_type: MessageType::Payload,
+            message_body: body.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn delivers_to_registered_stream() {
+        let (router, handle) = AsyncRouter::new(16);
+        let mut stream = handle.register(AddressType::Worker).unwrap();
+        tokio::spawn(router.run());
+
+        handle
+            .sender()
+            .send_async(OckamCommand::Router(RouterCommand::ReceiveMessage(
+                message(b"hello"),
+            )))
+            .await
+            .unwrap();
+
+        let received = stream.next().await.unwrap();
+        assert_eq!(received.message_body, b"hello".to_vec());
+        handle.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn counts_messages_dropped_on_a_full_stream() {
+        let (mut router, handle) = AsyncRouter::new(2);
+        router.set_overflow(Overflow::Drop);
+        let dropped = router.dropped_messages();
+        let mut stream = handle.register(AddressType::Worker).unwrap();
+        tokio::spawn(router.run());
+
+        for _ in 0..5 {
+            handle
+                .sender()
+                .send_async(OckamCommand::Router(RouterCommand::ReceiveMessage(
+                    message(b"hello"),
+                )))
+                .await
+                .unwrap();
+        }
+        handle.stop().await.unwrap();
+        while dropped.load(Ordering::Relaxed) < 3 {
+            tokio::task::yield_now().await;
+        }
+
+        assert!(stream.next().await.is_some());
+        assert!(stream.next().await.is_some());
+        assert!(stream.next().await.is_none());
+    }
+
+    #[test]
+    fn register_fails_while_the_router_queue_is_full() {
+        let (_router, handle) = AsyncRouter::new(1);
+        assert!(handle.register(AddressType::Worker).is_ok());
+        assert_eq!(
+            handle.register(AddressType::Udp).unwrap_err(),
+            "router queue is full"
+        );
+    }
+}
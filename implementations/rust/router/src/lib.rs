@@ -1,33 +1,106 @@
 #![allow(unused)]
 pub mod router {
+    use crate::metrics::RouterMetrics;
     use ockam_message::message::*;
     use ockam_system::commands::{
-        ChannelCommand, OckamCommand, RouterCommand, TransportCommand, WorkerCommand,
+        ChannelCommand, CommandSender, OckamCommand, RouterCommand, TransportCommand, WorkerCommand,
     };
+    use ockam_system::stats::RouterStats;
+    use std::collections::HashMap;
     use std::convert::TryFrom;
     use std::fs::OpenOptions;
-    use std::sync::mpsc::channel;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::mpsc::{channel, TrySendError};
     use std::sync::{Arc, Mutex};
     use std::{thread, time};
 
+    /// Workers registered by address, keyed by the raw worker address
+    pub(crate) type LocalWorkers = HashMap<Vec<u8>, CommandSender>;
+
     pub struct Router {
-        registry: Vec<Option<std::sync::mpsc::Sender<OckamCommand>>>,
+        registry: Vec<Option<CommandSender>>,
+        workers: LocalWorkers,
         rx: std::sync::mpsc::Receiver<OckamCommand>,
+        dropped: Arc<AtomicU64>,
+        overflow: Overflow,
+        metrics: RouterMetrics,
     }
 
+    /// Most commands taken off the queue in one go. The number taken is also
+    /// what the router reports as its queue depth.
+    const MAX_BATCH: usize = 4096;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
     pub enum Direction {
         Outgoing,
         Incoming,
     }
 
+    /// What the router does with a message whose handler's queue is full
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Overflow {
+        /// Wait until the handler has room, which stops the router taking
+        /// more off its own queue and so pushes back on whoever fills it
+        Block,
+        /// Drop the message and count it, keeping the router going for every
+        /// other handler while one is overloaded
+        Drop,
+    }
+
+    impl Default for Overflow {
+        fn default() -> Self {
+            Overflow::Block
+        }
+    }
+
+    /// Why a message could not be handed to its handler
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum RouteError {
+        NoRoute,
+        NoHandler,
+        NotImplemented,
+        /// The handler's queue was full, so the message was dropped
+        QueueFull,
+        HandlerGone,
+    }
+
+    impl RouteError {
+        /// Label used for this cause in metrics
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                RouteError::NoRoute => "no_route",
+                RouteError::NoHandler => "no_handler",
+                RouteError::NotImplemented => "not_implemented",
+                RouteError::QueueFull => "queue_full",
+                RouteError::HandlerGone => "handler_gone",
+            }
+        }
+    }
+
     impl Router {
         pub fn new(rx: std::sync::mpsc::Receiver<OckamCommand>) -> Router {
             Router {
                 registry: vec![Option::None; 256],
+                workers: HashMap::new(),
                 rx,
+                dropped: Arc::new(AtomicU64::new(0)),
+                overflow: Overflow::default(),
+                metrics: RouterMetrics::default(),
             }
         }
 
+        /// Choose what happens to a message whose handler's queue is full.
+        /// By default the router waits for room.
+        pub fn set_overflow(&mut self, overflow: Overflow) {
+            self.overflow = overflow;
+        }
+
+        /// Count of messages dropped because a handler's queue was full. Only
+        /// ever counts with Overflow::Drop.
+        pub fn dropped_messages(&self) -> Arc<AtomicU64> {
+            self.dropped.clone()
+        }
+
         pub fn register(
             &mut self,
             address: Address,
@@ -36,82 +109,367 @@ pub mod router {
             Err("not implemented".into())
         }
 
+        /// Handle any commands already queued, without blocking
         pub fn poll(&mut self) -> bool {
-            let mut keep_going = true;
-            let mut got = true;
-            while got {
-                got = false;
-                match self.rx.try_recv() {
-                    Ok(rc) => match rc {
-                        OckamCommand::Router(RouterCommand::Stop) => {
-                            println!("quit!");
-                            got = true;
-                            keep_going = false;
-                            break;
-                        }
-                        OckamCommand::Router(RouterCommand::Register(a_type, tx)) => {
-                            got = true;
-                            self.registry[a_type as usize] = Option::Some(tx);
-                        }
-                        OckamCommand::Router(RouterCommand::ReceiveMessage(m)) => {
-                            got = true;
-                            self.route(m, Direction::Incoming);
-                        }
-                        OckamCommand::Router(RouterCommand::SendMessage(m)) => {
-                            got = true;
-                            self.route(m, Direction::Outgoing);
-                        }
-                        _ => println!("Router received bad command"),
-                    },
-                    Err(e) => {}
+            match self.rx.try_recv() {
+                Ok(rc) => self.handle_batch(rc),
+                Err(_) => {
+                    self.metrics.observe_queue_depth(0);
+                    true
                 }
             }
-            keep_going
         }
 
-        fn route(&mut self, m: Message, direction: Direction) -> Result<(), String> {
-            if m.onward_route.addresses.is_empty() {
-                return Err("no route supplied".to_string());
+        /// Block on the command queue and route messages as they arrive, until
+        /// told to stop or every sender has gone away
+        pub fn run(&mut self) {
+            while let Ok(rc) = self.rx.recv() {
+                if !self.handle_batch(rc) {
+                    break;
+                }
             }
+        }
 
-            let destination_address = m.onward_route.addresses[0].clone();
-            let address_type = destination_address.a_type;
-            let at = address_type as usize;
-            let handler_tx = match &self.registry[at] {
-                Some(a) => a,
-                None => return Err("no handler".to_string()),
-            };
-            match address_type {
-                AddressType::Worker => match direction {
-                    Direction::Incoming => {
-                        handler_tx.send(OckamCommand::Worker(WorkerCommand::ReceiveMessage(m)));
-                        Ok(())
-                    }
-                    Direction::Outgoing => {
-                        handler_tx.send(OckamCommand::Worker(WorkerCommand::SendMessage(m)));
-                        Ok(())
-                    }
-                },
-                AddressType::Tcp => {
-                    handler_tx.send(OckamCommand::Transport(TransportCommand::SendMessage(m)));
-                    Ok(())
+        /// Take whatever else is already queued behind the first command,
+        /// note how much there was, then handle it all
+        fn handle_batch(&mut self, first: OckamCommand) -> bool {
+            let mut batch = vec![first];
+            while batch.len() < MAX_BATCH {
+                match self.rx.try_recv() {
+                    Ok(rc) => batch.push(rc),
+                    Err(_) => break,
                 }
-                AddressType::Udp => {
-                    handler_tx.send(OckamCommand::Transport(TransportCommand::SendMessage(m)));
-                    Ok(())
+            }
+            self.metrics.observe_queue_depth(batch.len());
+            for rc in batch {
+                if !self.handle_command(rc) {
+                    return false;
                 }
-                AddressType::Channel => match direction {
-                    Direction::Incoming => {
-                        handler_tx.send(OckamCommand::Channel(ChannelCommand::ReceiveMessage(m)));
-                        Ok(())
-                    }
-                    Direction::Outgoing => {
-                        handler_tx.send(OckamCommand::Channel(ChannelCommand::SendMessage(m)));
-                        Ok(())
+            }
+            true
+        }
+
+        fn handle_command(&mut self, rc: OckamCommand) -> bool {
+            match rc {
+                OckamCommand::Router(RouterCommand::Stop) => {
+                    println!("quit!");
+                    return false;
+                }
+                OckamCommand::Router(RouterCommand::Register(a_type, tx)) => {
+                    self.registry[a_type as usize] = Option::Some(tx);
+                }
+                OckamCommand::Router(RouterCommand::RegisterWorker(address, tx)) => {
+                    register_worker(&mut self.workers, address, tx);
+                }
+                OckamCommand::Router(RouterCommand::ReceiveMessage(m)) => {
+                    self.route(m, Direction::Incoming);
+                }
+                OckamCommand::Router(RouterCommand::SendMessage(m)) => {
+                    self.route(m, Direction::Outgoing);
+                }
+                OckamCommand::Router(RouterCommand::Stats(reply)) => {
+                    let _ = reply.send(self.stats());
+                }
+                _ => println!("Router received bad command"),
+            }
+            true
+        }
+
+        /// Snapshot of the router's counters and gauges
+        pub fn stats(&self) -> RouterStats {
+            self.metrics
+                .snapshot(&self.registry, self.dropped.load(Ordering::Relaxed))
+        }
+
+        fn route(&mut self, m: Message, direction: Direction) -> Result<(), RouteError> {
+            let address_type = m.onward_route.addresses.first().map(|a| a.a_type);
+            let local = local_worker_key(&m);
+            let r = resolve(&self.registry, &self.workers, m, direction)
+                .and_then(|(handler_tx, c)| deliver(handler_tx, c, self.overflow));
+            match r {
+                Err(RouteError::QueueFull) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(RouteError::HandlerGone) => {
+                    if let Some(key) = local {
+                        self.workers.remove(&key);
                     }
-                },
-                _ => Err("not implemented".to_string()),
+                }
+                _ => {}
+            }
+            self.metrics.record(address_type, &direction, &r);
+            r
+        }
+    }
+
+    pub(crate) fn register_worker(workers: &mut LocalWorkers, address: Address, tx: CommandSender) {
+        match address {
+            Address::WorkerAddress(a) => {
+                workers.insert(a, tx);
+            }
+            _ => println!("Router can only register workers by worker address"),
+        }
+    }
+
+    /// The local worker a message is addressed to, if its first onward
+    /// address is a worker address
+    pub(crate) fn local_worker_key(m: &Message) -> Option<Vec<u8>> {
+        match m.onward_route.addresses.first().map(|a| &a.address) {
+            Some(Address::WorkerAddress(a)) => Some(a.clone()),
+            _ => None,
+        }
+    }
+
+    /// Find the handler registered for the address type of a message's
+    /// first onward address, and the command to hand it. A worker registered
+    /// by address on this node takes precedence and always receives the
+    /// message, whichever way it was headed, so local workers can talk
+    /// without a channel or transport.
+    pub(crate) fn resolve<'a>(
+        registry: &'a [Option<CommandSender>],
+        workers: &'a LocalWorkers,
+        m: Message,
+        direction: Direction,
+    ) -> Result<(&'a CommandSender, OckamCommand), RouteError> {
+        if m.onward_route.addresses.is_empty() {
+            return Err(RouteError::NoRoute);
+        }
+
+        if let Address::WorkerAddress(a) = &m.onward_route.addresses[0].address {
+            if let Some(worker_tx) = workers.get(a) {
+                return Ok((
+                    worker_tx,
+                    OckamCommand::Worker(WorkerCommand::ReceiveMessage(m)),
+                ));
+            }
+        }
+
+        let destination_address = m.onward_route.addresses[0].clone();
+        let address_type = destination_address.a_type;
+        let at = address_type as usize;
+        let handler_tx = match &registry[at] {
+            Some(a) => a,
+            None => return Err(RouteError::NoHandler),
+        };
+        let c = match address_type {
+            AddressType::Worker => match direction {
+                Direction::Incoming => OckamCommand::Worker(WorkerCommand::ReceiveMessage(m)),
+                Direction::Outgoing => OckamCommand::Worker(WorkerCommand::SendMessage(m)),
+            },
+            AddressType::Tcp => OckamCommand::Transport(TransportCommand::SendMessage(m)),
+            AddressType::Udp => OckamCommand::Transport(TransportCommand::SendMessage(m)),
+            AddressType::Channel => match direction {
+                Direction::Incoming => OckamCommand::Channel(ChannelCommand::ReceiveMessage(m)),
+                Direction::Outgoing => OckamCommand::Channel(ChannelCommand::SendMessage(m)),
+            },
+            _ => return Err(RouteError::NotImplemented),
+        };
+        Ok((handler_tx, c))
+    }
+
+    /// Queue a command for its handler, waiting for room or dropping it as
+    /// the overflow policy says
+    fn deliver(
+        handler_tx: &CommandSender,
+        c: OckamCommand,
+        overflow: Overflow,
+    ) -> Result<(), RouteError> {
+        match overflow {
+            Overflow::Block => handler_tx.send(c).map_err(|_| RouteError::HandlerGone),
+            Overflow::Drop => match handler_tx.try_send(c) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) => Err(RouteError::QueueFull),
+                Err(TrySendError::Disconnected(_)) => Err(RouteError::HandlerGone),
+            },
+        }
+    }
+}
+
+pub mod metrics;
+
+#[cfg(feature = "async")]
+pub mod async_router;
+
+#[cfg(test)]
+mod tests {
+    use crate::router::*;
+    use ockam_message::message::*;
+    use ockam_system::commands::{OckamCommand, RouterCommand, WorkerCommand};
+    use ockam_system::runtime::bounded_queue;
+    use std::sync::atomic::Ordering;
+
+    fn worker_message() -> Message {
+        Message {
+            onward_route: Route {
+                addresses: vec![RouterAddress::worker_router_address_from_str("00010203").unwrap()],
+            },
+            return_route: Route { addresses: vec![] },
+            message_type: MessageType::Payload,
+            message_body: vec![],
+        }
+    }
+
+    #[test]
+    fn full_handler_queue_drops_message_when_asked() {
+        let (router_tx, router_rx) = bounded_queue(8);
+        let mut router = Router::new(router_rx);
+        router.set_overflow(Overflow::Drop);
+        let (worker_tx, worker_rx) = bounded_queue(1);
+        router_tx
+            .send(OckamCommand::Router(RouterCommand::Register(
+                AddressType::Worker,
+                worker_tx.into(),
+            )))
+            .unwrap();
+        for _ in 0..3 {
+            router_tx
+                .send(OckamCommand::Router(RouterCommand::ReceiveMessage(
+                    worker_message(),
+                )))
+                .unwrap();
+        }
+        assert!(router.poll());
+        assert_eq!(router.dropped_messages().load(Ordering::Relaxed), 2);
+        assert!(worker_rx.try_recv().is_ok());
+        assert!(worker_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn full_handler_queue_blocks_router() {
+        let (router_tx, router_rx) = bounded_queue(8);
+        let mut router = Router::new(router_rx);
+        let dropped = router.dropped_messages();
+        let (worker_tx, worker_rx) = bounded_queue(1);
+        router_tx
+            .send(OckamCommand::Router(RouterCommand::Register(
+                AddressType::Worker,
+                worker_tx.into(),
+            )))
+            .unwrap();
+        for _ in 0..3 {
+            router_tx
+                .send(OckamCommand::Router(RouterCommand::ReceiveMessage(
+                    worker_message(),
+                )))
+                .unwrap();
+        }
+        router_tx
+            .send(OckamCommand::Router(RouterCommand::Stop))
+            .unwrap();
+        let router = std::thread::spawn(move || router.run());
+
+        // the router can only get past the second message once the worker
+        // takes the first
+        for _ in 0..3 {
+            match worker_rx.recv() {
+                Ok(OckamCommand::Worker(WorkerCommand::ReceiveMessage(_))) => {}
+                other => panic!("worker got {:?}", other),
+            }
+        }
+        router.join().unwrap();
+        assert_eq!(dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn stats_command_reports_counters() {
+        let (router_tx, router_rx) = bounded_queue(8);
+        let mut router = Router::new(router_rx);
+        let (worker_tx, _worker_rx) = bounded_queue(8);
+        router_tx
+            .send(OckamCommand::Router(RouterCommand::Register(
+                AddressType::Worker,
+                worker_tx.into(),
+            )))
+            .unwrap();
+        router_tx
+            .send(OckamCommand::Router(RouterCommand::SendMessage(
+                worker_message(),
+            )))
+            .unwrap();
+        let mut unroutable = worker_message();
+        unroutable.onward_route.addresses.clear();
+        router_tx
+            .send(OckamCommand::Router(RouterCommand::ReceiveMessage(
+                unroutable,
+            )))
+            .unwrap();
+        let (stats_tx, stats_rx) = std::sync::mpsc::channel();
+        router_tx
+            .send(OckamCommand::Router(RouterCommand::Stats(stats_tx)))
+            .unwrap();
+        assert!(router.poll());
+
+        let stats = stats_rx.try_recv().unwrap();
+        assert_eq!(stats.routed.len(), 1);
+        assert_eq!(stats.routed[0].address_type, AddressType::Worker);
+        assert_eq!(stats.routed[0].outgoing, 1);
+        assert_eq!(stats.routed[0].incoming, 0);
+        assert_eq!(stats.errors, vec![("no_route".to_string(), 1)]);
+        assert_eq!(stats.queue_depth, 4);
+        assert_eq!(stats.handlers, vec![AddressType::Worker]);
+    }
+
+    #[test]
+    fn local_workers_exchange_request_and_reply() {
+        let (router_tx, router_rx) = bounded_queue(8);
+        let mut router = Router::new(router_rx);
+        let client = RouterAddress::worker_router_address_from_str("0a0a0a0a").unwrap();
+        let server = RouterAddress::worker_router_address_from_str("0b0b0b0b").unwrap();
+        let (client_tx, client_rx) = bounded_queue(8);
+        let (server_tx, server_rx) = bounded_queue(8);
+        router_tx
+            .send(OckamCommand::Router(RouterCommand::RegisterWorker(
+                client.address.clone(),
+                client_tx.into(),
+            )))
+            .unwrap();
+        router_tx
+            .send(OckamCommand::Router(RouterCommand::RegisterWorker(
+                server.address.clone(),
+                server_tx.into(),
+            )))
+            .unwrap();
+
+        let request = Message {
+            onward_route: Route {
+                addresses: vec![server.clone()],
+            },
+            return_route: Route {
+                addresses: vec![client.clone()],
+            },
+            message_type: MessageType::Payload,
+            message_body: b"ping".to_vec(),
+        };
+        router_tx
+            .send(OckamCommand::Router(RouterCommand::SendMessage(request)))
+            .unwrap();
+        assert!(router.poll());
+
+        let request = match server_rx.try_recv() {
+            Ok(OckamCommand::Worker(WorkerCommand::ReceiveMessage(m))) => m,
+            other => panic!("server got {:?}", other),
+        };
+        assert_eq!(request.message_body, b"ping".to_vec());
+
+        let reply = Message {
+            onward_route: request.return_route.clone(),
+            return_route: Route {
+                addresses: vec![server.clone()],
+            },
+            message_type: MessageType::Payload,
+            message_body: b"pong".to_vec(),
+        };
+        router_tx
+            .send(OckamCommand::Router(RouterCommand::SendMessage(reply)))
+            .unwrap();
+        assert!(router.poll());
+
+        match client_rx.try_recv() {
+            Ok(OckamCommand::Worker(WorkerCommand::ReceiveMessage(m))) => {
+                assert_eq!(m.message_body, b"pong".to_vec());
+                assert_eq!(m.return_route.addresses, vec![server]);
             }
+            other => panic!("client got {:?}", other),
         }
     }
 }
@@ -0,0 +1,200 @@
+use crate::router::{Direction, RouteError};
+use ockam_message::message::AddressType;
+use ockam_system::commands::CommandSender;
+use ockam_system::stats::{RoutedCount, RouterStats};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::Write;
+
+/// Counters and gauges kept by a router as it works
+#[derive(Debug, Default)]
+pub(crate) struct RouterMetrics {
+    routed: BTreeMap<u8, (u64, u64)>,
+    errors: BTreeMap<&'static str, u64>,
+    queue_depth: usize,
+    queue_depth_max: usize,
+}
+
+impl RouterMetrics {
+    pub(crate) fn record(
+        &mut self,
+        address_type: Option<AddressType>,
+        direction: &Direction,
+        result: &Result<(), RouteError>,
+    ) {
+        match (result, address_type) {
+            (Ok(()), Some(a_type)) => {
+                let counts = self.routed.entry(a_type as u8).or_insert((0, 0));
+                match direction {
+                    Direction::Incoming => counts.0 += 1,
+                    Direction::Outgoing => counts.1 += 1,
+                }
+            }
+            (Ok(()), None) => {}
+            (Err(e), _) => *self.errors.entry(e.as_str()).or_insert(0) += 1,
+        }
+    }
+
+    pub(crate) fn observe_queue_depth(&mut self, depth: usize) {
+        self.queue_depth = depth;
+        self.queue_depth_max = self.queue_depth_max.max(depth);
+    }
+
+    pub(crate) fn snapshot(&self, registry: &[Option<CommandSender>], dropped: u64) -> RouterStats {
+        RouterStats {
+            routed: self
+                .routed
+                .iter()
+                .filter_map(|(t, (incoming, outgoing))| {
+                    AddressType::try_from(*t)
+                        .ok()
+                        .map(|address_type| RoutedCount {
+                            address_type,
+                            incoming: *incoming,
+                            outgoing: *outgoing,
+                        })
+                })
+                .collect(),
+            errors: self
+                .errors
+                .iter()
+                .map(|(cause, n)| (cause.to_string(), *n))
+                .collect(),
+            dropped,
+            queue_depth: self.queue_depth,
+            queue_depth_max: self.queue_depth_max,
+            handlers: registry
+                .iter()
+                .enumerate()
+                .filter(|(_, h)| h.is_some())
+                .filter_map(|(t, _)| AddressType::try_from(t as u8).ok())
+                .collect(),
+        }
+    }
+}
+
+fn address_type_label(a_type: AddressType) -> &'static str {
+    match a_type {
+        AddressType::Tcp => "tcp",
+        AddressType::Udp => "udp",
+        AddressType::Channel => "channel",
+        AddressType::Worker => "worker",
+        AddressType::Undefined => "undefined",
+    }
+}
+
+/// Render a stats snapshot in the Prometheus text exposition format
+pub fn prometheus_text(stats: &RouterStats) -> String {
+    let mut out = String::new();
+
+    out.push_str("# HELP ockam_router_messages_routed_total Messages handed to a handler.\n");
+    out.push_str("# TYPE ockam_router_messages_routed_total counter\n");
+    for r in &stats.routed {
+        let label = address_type_label(r.address_type);
+        for (direction, n) in &[("incoming", r.incoming), ("outgoing", r.outgoing)] {
+            let _ = writeln!(
+                out,
+                "ockam_router_messages_routed_total{{address_type=\"{}\",direction=\"{}\"}} {}",
+                label, direction, n
+            );
+        }
+    }
+
+    out.push_str("# HELP ockam_router_errors_total Messages that could not be routed.\n");
+    out.push_str("# TYPE ockam_router_errors_total counter\n");
+    for (cause, n) in &stats.errors {
+        let _ = writeln!(
+            out,
+            "ockam_router_errors_total{{cause=\"{}\"}} {}",
+            cause, n
+        );
+    }
+
+    out.push_str("# HELP ockam_router_messages_dropped_total Messages dropped on a full queue.\n");
+    out.push_str("# TYPE ockam_router_messages_dropped_total counter\n");
+    let _ = writeln!(out, "ockam_router_messages_dropped_total {}", stats.dropped);
+
+    out.push_str("# HELP ockam_router_queue_depth Commands waiting in the router queue.\n");
+    out.push_str("# TYPE ockam_router_queue_depth gauge\n");
+    let _ = writeln!(out, "ockam_router_queue_depth {}", stats.queue_depth);
+
+    out.push_str("# HELP ockam_router_queue_depth_max Largest router queue depth seen.\n");
+    out.push_str("# TYPE ockam_router_queue_depth_max gauge\n");
+    let _ = writeln!(
+        out,
+        "ockam_router_queue_depth_max {}",
+        stats.queue_depth_max
+    );
+
+    out.push_str("# HELP ockam_router_handlers Registered handlers by address type.\n");
+    out.push_str("# TYPE ockam_router_handlers gauge\n");
+    for h in &stats.handlers {
+        let _ = writeln!(
+            out,
+            "ockam_router_handlers{{address_type=\"{}\"}} 1",
+            address_type_label(*h)
+        );
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_routed_and_errors() {
+        let mut metrics = RouterMetrics::default();
+        metrics.record(Some(AddressType::Tcp), &Direction::Outgoing, &Ok(()));
+        metrics.record(Some(AddressType::Tcp), &Direction::Outgoing, &Ok(()));
+        metrics.record(Some(AddressType::Tcp), &Direction::Incoming, &Ok(()));
+        metrics.record(
+            Some(AddressType::Worker),
+            &Direction::Incoming,
+            &Err(RouteError::NoHandler),
+        );
+        metrics.observe_queue_depth(4);
+        metrics.observe_queue_depth(1);
+
+        let stats = metrics.snapshot(&vec![None; 256], 0);
+        assert_eq!(
+            stats.routed,
+            vec![RoutedCount {
+                address_type: AddressType::Tcp,
+                incoming: 1,
+                outgoing: 2
+            }]
+        );
+        assert_eq!(stats.errors, vec![("no_handler".to_string(), 1)]);
+        assert_eq!(stats.queue_depth, 1);
+        assert_eq!(stats.queue_depth_max, 4);
+    }
+
+    #[test]
+    fn prometheus_format() {
+        let stats = RouterStats {
+            routed: vec![RoutedCount {
+                address_type: AddressType::Channel,
+                incoming: 3,
+                outgoing: 5,
+            }],
+            errors: vec![("queue_full".to_string(), 2)],
+            dropped: 2,
+            queue_depth: 0,
+            queue_depth_max: 7,
+            handlers: vec![AddressType::Channel],
+        };
+        let text = prometheus_text(&stats);
+        assert!(text.contains(
+            "ockam_router_messages_routed_total{address_type=\"channel\",direction=\"incoming\"} 3\n"
+        ));
+        assert!(text.contains(
+            "ockam_router_messages_routed_total{address_type=\"channel\",direction=\"outgoing\"} 5\n"
+        ));
+        assert!(text.contains("ockam_router_errors_total{cause=\"queue_full\"} 2\n"));
+        assert!(text.contains("ockam_router_messages_dropped_total 2\n"));
+        assert!(text.contains("ockam_router_queue_depth_max 7\n"));
+        assert!(text.contains("ockam_router_handlers{address_type=\"channel\"} 1\n"));
+    }
+}
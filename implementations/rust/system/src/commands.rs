@@ -1,8 +1,96 @@
+use crate::stats::{ChannelStats, RouterStats};
 #[allow(unused)]
 //pub mod commands {
 use ockam_message::message::*;
 use ockam_vault::types::SecretKeyContext;
 
+/// Where a component receives its commands. Blocking components hand the
+/// router a std Sender, or a SyncSender if their queue is bounded; async
+/// components hand it a bounded tokio sender.
+#[derive(Debug, Clone)]
+pub enum CommandSender {
+    Blocking(std::sync::mpsc::Sender<OckamCommand>),
+    Bounded(std::sync::mpsc::SyncSender<OckamCommand>),
+    #[cfg(feature = "async")]
+    Async(tokio::sync::mpsc::Sender<OckamCommand>),
+}
+
+impl CommandSender {
+    /// Queue a command, blocking while a bounded queue is full. This is how
+    /// backpressure reaches the component that produced the command.
+    ///
+    /// Blocking on an async queue parks the calling thread, so tasks running
+    /// on the runtime that drains the queue use `send_async` instead.
+    pub fn send(&self, c: OckamCommand) -> Result<(), std::sync::mpsc::SendError<OckamCommand>> {
+        match self {
+            CommandSender::Blocking(tx) => tx.send(c),
+            CommandSender::Bounded(tx) => tx.send(c),
+            #[cfg(feature = "async")]
+            CommandSender::Async(tx) => futures::executor::block_on(tx.clone().send(c))
+                .map_err(|e| std::sync::mpsc::SendError(e.0)),
+        }
+    }
+
+    /// Queue a command, waiting while an async queue is full. Blocking and
+    /// bounded std queues behave as with `send`.
+    #[cfg(feature = "async")]
+    pub async fn send_async(
+        &self,
+        c: OckamCommand,
+    ) -> Result<(), std::sync::mpsc::SendError<OckamCommand>> {
+        match self {
+            CommandSender::Async(tx) => tx
+                .clone()
+                .send(c)
+                .await
+                .map_err(|e| std::sync::mpsc::SendError(e.0)),
+            _ => self.send(c),
+        }
+    }
+
+    /// Queue a command without blocking. Fails with Full if a bounded queue
+    /// has no room.
+    pub fn try_send(
+        &self,
+        c: OckamCommand,
+    ) -> Result<(), std::sync::mpsc::TrySendError<OckamCommand>> {
+        match self {
+            CommandSender::Blocking(tx) => tx
+                .send(c)
+                .map_err(|e| std::sync::mpsc::TrySendError::Disconnected(e.0)),
+            CommandSender::Bounded(tx) => tx.try_send(c),
+            #[cfg(feature = "async")]
+            CommandSender::Async(tx) => tx.clone().try_send(c).map_err(|e| match e {
+                tokio::sync::mpsc::error::TrySendError::Full(c) => {
+                    std::sync::mpsc::TrySendError::Full(c)
+                }
+                tokio::sync::mpsc::error::TrySendError::Closed(c) => {
+                    std::sync::mpsc::TrySendError::Disconnected(c)
+                }
+            }),
+        }
+    }
+}
+
+impl From<std::sync::mpsc::Sender<OckamCommand>> for CommandSender {
+    fn from(tx: std::sync::mpsc::Sender<OckamCommand>) -> Self {
+        CommandSender::Blocking(tx)
+    }
+}
+
+impl From<std::sync::mpsc::SyncSender<OckamCommand>> for CommandSender {
+    fn from(tx: std::sync::mpsc::SyncSender<OckamCommand>) -> Self {
+        CommandSender::Bounded(tx)
+    }
+}
+
+#[cfg(feature = "async")]
+impl From<tokio::sync::mpsc::Sender<OckamCommand>> for CommandSender {
+    fn from(tx: tokio::sync::mpsc::Sender<OckamCommand>) -> Self {
+        CommandSender::Async(tx)
+    }
+}
+
 #[derive(Debug)]
 pub enum OckamCommand {
     Transport(TransportCommand),
@@ -17,6 +105,9 @@ pub enum OckamCommand {
 pub enum TransportCommand {
     Stop,
     SendMessage(Message),
+    AddConnection(std::net::TcpStream),
+    /// The peer at this address closed its connection
+    ConnectionClosed(String),
 }
 
 // Router commands - these can be sent to the
@@ -24,9 +115,14 @@ pub enum TransportCommand {
 #[derive(Debug)]
 pub enum RouterCommand {
     Stop,
-    Register(AddressType, std::sync::mpsc::Sender<OckamCommand>),
+    Register(AddressType, CommandSender),
+    /// Register one worker by its address. Messages whose next hop is that
+    /// address are delivered to it directly, including replies from other
+    /// workers on the same node.
+    RegisterWorker(Address, CommandSender),
     SendMessage(Message),
     ReceiveMessage(Message),
+    Stats(std::sync::mpsc::Sender<RouterStats>),
 }
 
 // Channel commands - these can be sent to the
@@ -35,8 +131,25 @@ pub enum RouterCommand {
 pub enum ChannelCommand {
     Initiate(Route, Address, Option<SecretKeyContext>), /* route to destination, return local
                                                          * address */
+    /// Establish a channel from a responder's published prekey bundle: route
+    /// to the responder's node, return local address, encoded bundle
+    InitiateWithBundle(Route, Address, Vec<u8>),
     SendMessage(Message),
     ReceiveMessage(Message),
+    Stats(std::sync::mpsc::Sender<ChannelStats>),
+    /// Derive keying material from the channel with this address: label,
+    /// context and length as for `ChannelManager::export_key`. The reply
+    /// is None if the channel isn't established or the request is invalid.
+    ExportKey(
+        Address,
+        Vec<u8>,
+        Vec<u8>,
+        usize,
+        std::sync::mpsc::Sender<Option<Vec<u8>>>,
+    ),
+    /// Close the channel with this (cleartext or ciphertext) address: tell
+    /// the peer, tell the bound worker and destroy the channel's keys
+    Close(Address),
     Stop,
 }
 
@@ -1 +1,3 @@
 pub mod commands;
+pub mod runtime;
+pub mod stats;
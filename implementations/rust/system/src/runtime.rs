@@ -0,0 +1,147 @@
+use crate::commands::OckamCommand;
+use std::sync::mpsc::{Receiver, Sender, SyncSender};
+use std::thread;
+
+/// Command queue capacity used when none is configured
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+/// Capacities of the bounded command queues between node components. A
+/// component producing into a full queue blocks until there is room. The
+/// router does too, unless told to drop and count what doesn't fit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueueConfig {
+    pub router: usize,
+    pub transport: usize,
+    pub channel: usize,
+    pub worker: usize,
+}
+
+impl QueueConfig {
+    /// Use the same capacity for every queue
+    pub fn uniform(capacity: usize) -> QueueConfig {
+        QueueConfig {
+            router: capacity,
+            transport: capacity,
+            channel: capacity,
+            worker: capacity,
+        }
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig::uniform(DEFAULT_QUEUE_CAPACITY)
+    }
+}
+
+/// Create a bounded command queue
+pub fn bounded_queue(capacity: usize) -> (SyncSender<OckamCommand>, Receiver<OckamCommand>) {
+    std::sync::mpsc::sync_channel(capacity)
+}
+
+// Each node component (router, transports, channel manager, workers) runs on
+// its own thread, blocking on its command queue or socket until there is
+// work. The node waits on a single exit queue and shuts down as soon as any
+// component stops, which is what the old polling loop did when any poll()
+// returned false.
+
+/// Sends the component name on the exit queue when dropped, so a component
+/// thread that panics still wakes the node
+struct ExitNotifier {
+    name: &'static str,
+    tx: Sender<&'static str>,
+}
+
+impl Drop for ExitNotifier {
+    fn drop(&mut self) {
+        let _ = self.tx.send(self.name);
+    }
+}
+
+/// Collects component threads and waits for the first one to exit
+pub struct NodeRuntime {
+    exit_tx: Sender<&'static str>,
+    exit_rx: Receiver<&'static str>,
+}
+
+impl NodeRuntime {
+    pub fn new() -> NodeRuntime {
+        let (exit_tx, exit_rx) = std::sync::mpsc::channel();
+        NodeRuntime { exit_tx, exit_rx }
+    }
+
+    /// Run f on a new thread named after the component
+    pub fn spawn<F>(&self, name: &'static str, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let notifier = ExitNotifier {
+            name,
+            tx: self.exit_tx.clone(),
+        };
+        thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                let _notifier = notifier;
+                f();
+            })
+            .expect("failed to spawn component thread");
+    }
+
+    /// Block until any component exits and return its name
+    pub fn wait(self) -> &'static str {
+        let NodeRuntime { exit_tx, exit_rx } = self;
+        drop(exit_tx);
+        exit_rx.recv().unwrap_or("none")
+    }
+}
+
+impl Default for NodeRuntime {
+    fn default() -> Self {
+        NodeRuntime::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_returns_first_exit() {
+        let runtime = NodeRuntime::new();
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        runtime.spawn("blocked", move || {
+            let _ = rx.recv();
+        });
+        runtime.spawn("done", || {});
+        assert_eq!(runtime.wait(), "done");
+        drop(tx);
+    }
+
+    #[test]
+    fn bounded_queue_reports_full() {
+        use crate::commands::{CommandSender, WorkerCommand};
+        use std::sync::mpsc::TrySendError;
+
+        let (tx, rx) = bounded_queue(1);
+        let tx: CommandSender = tx.into();
+        assert!(tx
+            .try_send(OckamCommand::Worker(WorkerCommand::Test))
+            .is_ok());
+        assert!(matches!(
+            tx.try_send(OckamCommand::Worker(WorkerCommand::Test)),
+            Err(TrySendError::Full(_))
+        ));
+        assert!(rx.try_recv().is_ok());
+        assert!(tx
+            .try_send(OckamCommand::Worker(WorkerCommand::Test))
+            .is_ok());
+    }
+
+    #[test]
+    fn wait_wakes_on_panic() {
+        let runtime = NodeRuntime::new();
+        runtime.spawn("panics", || panic!("component failed"));
+        assert_eq!(runtime.wait(), "panics");
+    }
+}
@@ -0,0 +1,53 @@
+use ockam_message::message::AddressType;
+
+/// Messages a router has handed to the handler for one address type
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RoutedCount {
+    pub address_type: AddressType,
+    pub incoming: u64,
+    pub outgoing: u64,
+}
+
+/// A point-in-time copy of a router's counters and gauges, returned by
+/// RouterCommand::Stats
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RouterStats {
+    /// Messages successfully handed to a handler
+    pub routed: Vec<RoutedCount>,
+    /// Messages that could not be routed, by cause
+    pub errors: Vec<(String, u64)>,
+    /// Messages dropped because a handler's queue was full
+    pub dropped: u64,
+    /// Commands waiting in the router's queue when it last looked
+    pub queue_depth: usize,
+    /// Largest queue_depth seen so far
+    pub queue_depth_max: usize,
+    /// Address types with a registered handler
+    pub handlers: Vec<AddressType>,
+}
+
+/// A point-in-time copy of a channel manager's counters, returned by
+/// ChannelCommand::Stats
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChannelStats {
+    /// Channels currently known, complete or still in key exchange
+    pub channels: usize,
+    /// Payloads encrypted and sent
+    pub messages_sent: u64,
+    /// Messages returned to their sender because a channel couldn't take or
+    /// deliver them
+    pub messages_bounced: u64,
+    /// Payloads decrypted and delivered
+    pub messages_received: u64,
+    /// Payloads dropped because their nonce had been seen before or was too
+    /// old to check
+    pub replays_rejected: u64,
+    /// Payloads dropped because they failed to authenticate or parse
+    pub payloads_rejected: u64,
+    /// Handshakes abandoned after timing out or failing
+    pub handshakes_failed: u64,
+    /// Handshakes refused because too many were already in progress
+    pub handshakes_refused: u64,
+    /// Channels closed because the trust policy refused the peer
+    pub peers_rejected: u64,
+}
@@ -0,0 +1,288 @@
+use crate::tcp::{decode_incoming, encode_outgoing, is_transport_hop};
+use ockam_message::message::*;
+use ockam_system::commands::RouterCommand::ReceiveMessage;
+use ockam_system::commands::{CommandSender, OckamCommand, RouterCommand, TransportCommand};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+struct Connection {
+    writer: OwnedWriteHalf,
+    local_addr: SocketAddr,
+}
+
+/// TCP transport for nodes embedded in a tokio application. Each connection
+/// gets a reader task that forwards received messages to the router.
+pub struct AsyncTcpManager {
+    rx: Receiver<OckamCommand>,
+    tx: Sender<OckamCommand>,
+    router_tx: CommandSender,
+    listener: Option<TcpListener>,
+    connections: HashMap<String, Connection>,
+}
+
+enum Event {
+    Command(Option<OckamCommand>),
+    Accepted(std::io::Result<(TcpStream, SocketAddr)>),
+}
+
+impl AsyncTcpManager {
+    /// Register with the router, and listen on `listen_addr` if given. The
+    /// manager's command queue holds `capacity` commands.
+    pub async fn new(
+        router_tx: CommandSender,
+        listen_addr: Option<SocketAddr>,
+        capacity: usize,
+    ) -> Result<AsyncTcpManager, String> {
+        let (tx, rx) = channel(capacity);
+        router_tx
+            .send_async(OckamCommand::Router(RouterCommand::Register(
+                AddressType::Tcp,
+                CommandSender::Async(tx.clone()),
+            )))
+            .await
+            .map_err(|_| "failed to register with router".to_string())?;
+
+        let listener = match listen_addr {
+            Some(la) => match TcpListener::bind(la).await {
+                Ok(l) => Some(l),
+                Err(_) => return Err("failed to bind tcp listener".into()),
+            },
+            None => None,
+        };
+
+        Ok(AsyncTcpManager {
+            rx,
+            tx,
+            router_tx,
+            listener,
+            connections: HashMap::new(),
+        })
+    }
+
+    /// The sender for commands to this transport
+    pub fn sender(&self) -> Sender<OckamCommand> {
+        self.tx.clone()
+    }
+
+    /// The address we're listening on, if any
+    pub fn listen_addr(&self) -> Option<SocketAddr> {
+        self.listener.as_ref().and_then(|l| l.local_addr().ok())
+    }
+
+    pub async fn connect(&mut self, address: SocketAddr) -> Result<Address, String> {
+        match TcpStream::connect(address).await {
+            Ok(stream) => {
+                self.add_connection(stream)?;
+                Ok(Address::TcpAddress(address))
+            }
+            Err(e) => Err(format!("tcp failed to connect: {}", e)),
+        }
+    }
+
+    /// Send a message to the peer named by its first onward address
+    pub async fn send(&mut self, m: Message) -> Result<(), String> {
+        let addr = match m.onward_route.addresses.get(0) {
+            Some(a) => a.address.as_string(),
+            None => return Err("no route supplied".to_string()),
+        };
+        let connection = match self.connections.get_mut(&addr) {
+            Some(c) => c,
+            None => return Err(format!("can't find connection {}", addr)),
+        };
+        let v = encode_outgoing(m, Address::TcpAddress(connection.local_addr))?;
+        connection
+            .writer
+            .write_all(v.as_slice())
+            .await
+            .map_err(|_| "tcp write failed".to_string())
+    }
+
+    /// Accept connections and handle commands until told to stop
+    pub async fn run(mut self) {
+        loop {
+            let event = tokio::select! {
+                c = self.rx.recv() => Event::Command(c),
+                a = accept(&mut self.listener) => Event::Accepted(a),
+            };
+            match event {
+                Event::Command(Some(OckamCommand::Transport(TransportCommand::SendMessage(m)))) => {
+                    if let Err(s) = self.send(m).await {
+                        println!("send_message failed: {}", s);
+                    }
+                }
+                Event::Command(Some(OckamCommand::Transport(TransportCommand::Stop)))
+                | Event::Command(None) => {
+                    break;
+                }
+                Event::Command(Some(_)) => {
+                    println!("unrecognized command");
+                }
+                Event::Accepted(Ok((stream, _))) => {
+                    if let Err(s) = self.add_connection(stream) {
+                        println!("{}", s);
+                    }
+                }
+                Event::Accepted(Err(e)) => {
+                    println!("tcp listen error: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn add_connection(&mut self, stream: TcpStream) -> Result<(), String> {
+        stream.set_nodelay(true).map_err(|e| e.to_string())?;
+        let peer_addr = stream.peer_addr().map_err(|e| e.to_string())?;
+        let local_addr = stream.local_addr().map_err(|e| e.to_string())?;
+        let (reader, writer) = stream.into_split();
+        tokio::spawn(read_messages(
+            reader,
+            peer_addr,
+            self.router_tx.clone(),
+            self.tx.clone(),
+        ));
+        self.connections
+            .insert(peer_addr.to_string(), Connection { writer, local_addr });
+        Ok(())
+    }
+}
+
+/// Wait for the next connection, or forever if we aren't listening
+async fn accept(listener: &mut Option<TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(l) => l.accept().await,
+        None => futures::future::pending().await,
+    }
+}
+
+async fn read_messages(
+    mut reader: OwnedReadHalf,
+    peer_addr: SocketAddr,
+    router_tx: CommandSender,
+    mut manager_tx: Sender<OckamCommand>,
+) {
+    let mut buff = [0u8; 16348];
+    loop {
+        let len = match reader.read(&mut buff).await {
+            Ok(0) => {
+                println!("tcp connection {} closed", peer_addr);
+                break;
+            }
+            Ok(len) => len,
+            Err(_) => {
+                println!("***tcp receive failed");
+                break;
+            }
+        };
+        let m = match decode_incoming(&buff[0..len], Address::TcpAddress(peer_addr)) {
+            Ok(m) => m,
+            Err(s) => {
+                println!("tcp receive failed {}", s);
+                break;
+            }
+        };
+        let sent = if is_transport_hop(&m) {
+            manager_tx
+                .send(OckamCommand::Transport(TransportCommand::SendMessage(m)))
+                .await
+                .is_ok()
+        } else {
+            router_tx
+                .send_async(OckamCommand::Router(ReceiveMessage(m)))
+                .await
+                .is_ok()
+        };
+        if !sent {
+            println!("send to router failed");
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Register with a router that is just a queue, and return the queue
+    async fn manager(listen_addr: Option<SocketAddr>) -> (AsyncTcpManager, Receiver<OckamCommand>) {
+        let (router_tx, mut router_rx) = channel(16);
+        let manager = AsyncTcpManager::new(CommandSender::Async(router_tx), listen_addr, 16)
+            .await
+            .unwrap();
+        match router_rx.recv().await {
+            Some(OckamCommand::Router(RouterCommand::Register(AddressType::Tcp, _))) => {}
+            other => panic!("router got {:?}", other),
+        }
+        (manager, router_rx)
+    }
+
+    async fn received(router_rx: &mut Receiver<OckamCommand>) -> Message {
+        match router_rx.recv().await {
+            Some(OckamCommand::Router(RouterCommand::ReceiveMessage(m))) => m,
+            other => panic!("router got {:?}", other),
+        }
+    }
+
+    fn message(to: RouterAddress, body: &[u8]) -> Message {
+        Message {
+            onward_route: Route {
+                addresses: vec![
+                    to,
+                    RouterAddress::worker_router_address_from_str("00010203").unwrap(),
+                ],
+            },
+            return_route: Route {
+                addresses: vec![RouterAddress::worker_router_address_from_str("0a0b0c0d").unwrap()],
+            },
+            message_type: MessageType::Payload,
+            message_body: body.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_send_and_reply() {
+        let (server, mut server_router) = manager(Some("127.0.0.1:0".parse().unwrap())).await;
+        let server_addr = server.listen_addr().unwrap();
+        let mut server_tx = server.sender();
+        tokio::spawn(server.run());
+        let (mut client, mut client_router) = manager(None).await;
+
+        let address = client.connect(server_addr).await.unwrap();
+        let to_server = RouterAddress::from_address(address).unwrap();
+        client.send(message(to_server, b"ping")).await.unwrap();
+
+        // our hop is taken off the front, and the client's put on the back
+        let request = received(&mut server_router).await;
+        assert_eq!(request.message_body, b"ping".to_vec());
+        assert_eq!(
+            request.onward_route.addresses,
+            vec![RouterAddress::worker_router_address_from_str("00010203").unwrap()]
+        );
+        assert_eq!(request.return_route.addresses.len(), 2);
+        assert_eq!(request.return_route.addresses[0].a_type, AddressType::Tcp);
+
+        // the server answers on the connection the client opened
+        let to_client = request.return_route.addresses[0].clone();
+        server_tx
+            .send(OckamCommand::Transport(TransportCommand::SendMessage(
+                message(to_client, b"pong"),
+            )))
+            .await
+            .unwrap();
+        let reply = received(&mut client_router).await;
+        assert_eq!(reply.message_body, b"pong".to_vec());
+        assert_eq!(
+            reply.return_route.addresses[0],
+            RouterAddress::from_address(Address::TcpAddress(server_addr)).unwrap()
+        );
+
+        server_tx
+            .send(OckamCommand::Transport(TransportCommand::Stop))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,242 @@
+use crate::tcp::is_transport_hop;
+use ockam_message::message::*;
+use ockam_system::commands::RouterCommand::ReceiveMessage;
+use ockam_system::commands::{CommandSender, OckamCommand, RouterCommand, TransportCommand};
+use std::net::SocketAddr;
+use tokio::net::udp::{RecvHalf, SendHalf};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+/// UDP transport for nodes embedded in a tokio application. Datagrams are
+/// read by their own task and forwarded to the router.
+pub struct AsyncUdpTransport {
+    socket: UdpSocket,
+    local_addr: SocketAddr,
+    rx: Receiver<OckamCommand>,
+    tx: Sender<OckamCommand>,
+    router_tx: CommandSender,
+}
+
+impl AsyncUdpTransport {
+    /// Bind `local_udp_socket` and register with the router. The transport's
+    /// command queue holds `capacity` commands.
+    pub async fn new(
+        router_tx: CommandSender,
+        local_udp_socket: SocketAddr,
+        capacity: usize,
+    ) -> Result<AsyncUdpTransport, String> {
+        let socket = match UdpSocket::bind(local_udp_socket).await {
+            Ok(s) => s,
+            Err(_unused) => return Err("failed to create socket".to_string()),
+        };
+        let local_addr = socket
+            .local_addr()
+            .map_err(|_| "failed to create socket".to_string())?;
+        let (tx, rx) = channel(capacity);
+        router_tx
+            .send_async(OckamCommand::Router(RouterCommand::Register(
+                AddressType::Udp,
+                CommandSender::Async(tx.clone()),
+            )))
+            .await
+            .map_err(|_| "failed to register with router".to_string())?;
+
+        Ok(AsyncUdpTransport {
+            socket,
+            local_addr,
+            rx,
+            tx,
+            router_tx,
+        })
+    }
+
+    /// The sender for commands to this transport
+    pub fn sender(&self) -> Sender<OckamCommand> {
+        self.tx.clone()
+    }
+
+    /// The address the socket is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Handle commands until told to stop, reading datagrams on a separate
+    /// task
+    pub async fn run(self) {
+        let AsyncUdpTransport {
+            socket,
+            local_addr,
+            mut rx,
+            tx,
+            router_tx,
+        } = self;
+        let (recv_half, mut send_half) = socket.split();
+        tokio::spawn(read_messages(recv_half, router_tx, tx));
+
+        while let Some(tc) = rx.recv().await {
+            match tc {
+                OckamCommand::Transport(TransportCommand::SendMessage(m)) => {
+                    if let Err(s) = send(&mut send_half, local_addr, m).await {
+                        println!("udp send_message failed: {}", s);
+                        break;
+                    }
+                }
+                OckamCommand::Transport(TransportCommand::Stop) => {
+                    break;
+                }
+                _ => {
+                    println!("unrecognized command");
+                }
+            }
+        }
+    }
+}
+
+async fn send(socket: &mut SendHalf, local_addr: SocketAddr, mut m: Message) -> Result<(), String> {
+    let remote_address = m.onward_route.addresses.remove(0);
+    let remote: SocketAddr = remote_address
+        .address
+        .as_string()
+        .parse()
+        .map_err(|_| "send_message error".to_string())?;
+    match RouterAddress::from_address(Address::UdpAddress(local_addr)) {
+        Some(ra) => {
+            m.return_route.addresses.insert(0, ra);
+            let mut v = vec![];
+            Message::encode(&m, &mut v)?;
+            match socket.send_to(v.as_slice(), &remote).await {
+                Ok(_) => Ok(()),
+                Err(s) => {
+                    println!("send_message failed {}", s.to_string());
+                    Err("send_message error".to_string())
+                }
+            }
+        }
+        None => Err("send_message error".to_string()),
+    }
+}
+
+async fn read_messages(
+    mut socket: RecvHalf,
+    router_tx: CommandSender,
+    mut transport_tx: Sender<OckamCommand>,
+) {
+    let mut buff = [0; 16348];
+    loop {
+        let s = match socket.recv_from(&mut buff).await {
+            Ok((s, _)) => s,
+            Err(_unused) => {
+                println!("udp socket receive failed");
+                break;
+            }
+        };
+        let m = match Message::decode(&buff[0..s]) {
+            Ok((m, _unused)) => m,
+            _ => {
+                println!("udp decode failed");
+                continue;
+            }
+        };
+        let sent = if is_transport_hop(&m) {
+            transport_tx
+                .send(OckamCommand::Transport(TransportCommand::SendMessage(m)))
+                .await
+                .is_ok()
+        } else {
+            router_tx
+                .send_async(OckamCommand::Router(ReceiveMessage(m)))
+                .await
+                .is_ok()
+        };
+        if !sent {
+            println!("send to router failed");
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bind a transport registered with a router that is just a queue, and
+    /// return the queue
+    async fn transport() -> (AsyncUdpTransport, Receiver<OckamCommand>) {
+        let (router_tx, mut router_rx) = channel(16);
+        let transport = AsyncUdpTransport::new(
+            CommandSender::Async(router_tx),
+            "127.0.0.1:0".parse().unwrap(),
+            16,
+        )
+        .await
+        .unwrap();
+        match router_rx.recv().await {
+            Some(OckamCommand::Router(RouterCommand::Register(AddressType::Udp, _))) => {}
+            other => panic!("router got {:?}", other),
+        }
+        (transport, router_rx)
+    }
+
+    async fn received(router_rx: &mut Receiver<OckamCommand>) -> Message {
+        match router_rx.recv().await {
+            Some(OckamCommand::Router(RouterCommand::ReceiveMessage(m))) => m,
+            other => panic!("router got {:?}", other),
+        }
+    }
+
+    fn message(to: RouterAddress, body: &[u8]) -> Message {
+        Message {
+            onward_route: Route {
+                addresses: vec![
+                    to,
+                    RouterAddress::worker_router_address_from_str("00010203").unwrap(),
+                ],
+            },
+            return_route: Route {
+                addresses: vec![RouterAddress::worker_router_address_from_str("0a0b0c0d").unwrap()],
+            },
+            message_type: MessageType::Payload,
+            message_body: body.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn send_and_reply() {
+        let (a, mut a_router) = transport().await;
+        let (b, mut b_router) = transport().await;
+        let a_addr = RouterAddress::from_address(Address::UdpAddress(a.local_addr())).unwrap();
+        let b_addr = RouterAddress::from_address(Address::UdpAddress(b.local_addr())).unwrap();
+        let mut a_tx = a.sender();
+        let mut b_tx = b.sender();
+        tokio::spawn(a.run());
+        tokio::spawn(b.run());
+
+        a_tx.send(OckamCommand::Transport(TransportCommand::SendMessage(
+            message(b_addr.clone(), b"ping"),
+        )))
+        .await
+        .unwrap();
+        let request = received(&mut b_router).await;
+        assert_eq!(request.message_body, b"ping".to_vec());
+        assert_eq!(
+            request.onward_route.addresses,
+            vec![RouterAddress::worker_router_address_from_str("00010203").unwrap()]
+        );
+        assert_eq!(request.return_route.addresses[0], a_addr);
+
+        b_tx.send(OckamCommand::Transport(TransportCommand::SendMessage(
+            message(request.return_route.addresses[0].clone(), b"pong"),
+        )))
+        .await
+        .unwrap();
+        let reply = received(&mut a_router).await;
+        assert_eq!(reply.message_body, b"pong".to_vec());
+        assert_eq!(reply.return_route.addresses[0], b_addr);
+
+        for tx in vec![&mut a_tx, &mut b_tx] {
+            tx.send(OckamCommand::Transport(TransportCommand::Stop))
+                .await
+                .unwrap();
+        }
+    }
+}
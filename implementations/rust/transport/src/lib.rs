@@ -1,2 +1,7 @@
 pub mod tcp;
 pub mod udp;
+
+#[cfg(feature = "async")]
+pub mod async_tcp;
+#[cfg(feature = "async")]
+pub mod async_udp;
@@ -1,23 +1,28 @@
-use futures::io::Error;
 #[allow(unused)]
 use ockam_message::message::*;
 use ockam_system::commands::RouterCommand::ReceiveMessage;
-use ockam_system::commands::{OckamCommand, RouterCommand, TransportCommand};
+use ockam_system::commands::{CommandSender, OckamCommand, RouterCommand, TransportCommand};
 use std::collections::HashMap;
-use std::io;
-use std::io::{Read, Write};
+use std::io::{ErrorKind, Read, Write};
 use std::net::TcpStream;
 use std::net::{SocketAddr, TcpListener};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
 
+use mio::{Events, Interest, Poll, Token, Waker};
+
+const WAKER: Token = Token(0);
+const LISTENER: Token = Token(1);
+const FIRST_CONNECTION: usize = 2;
+
 pub struct TcpManager {
     rx: std::sync::mpsc::Receiver<OckamCommand>,
-    _tx: std::sync::mpsc::Sender<OckamCommand>,
-    router_tx: std::sync::mpsc::Sender<OckamCommand>,
-    _timeout: Duration,
-    listener: Option<TcpListener>,
+    _tx: CommandSender,
+    timeout: Duration,
     connections: HashMap<String, TcpTransport>,
-    addresses: Vec<String>,
+    reader: ReaderHandle,
 }
 
 impl TcpManager {
@@ -25,9 +30,7 @@ impl TcpManager {
         let stream = TcpStream::connect(address);
         match stream {
             Ok(stream) => {
-                stream.set_nonblocking(true).unwrap();
-                stream.set_nodelay(true).unwrap();
-                self.add_connection(stream);
+                self.add_connection(stream)?;
                 Ok(Address::TcpAddress(address))
             }
             Err(e) => Err(format!("tcp failed to connect: {}", e)),
@@ -36,11 +39,13 @@ impl TcpManager {
 
     pub fn new(
         rx: std::sync::mpsc::Receiver<OckamCommand>,
-        tx: std::sync::mpsc::Sender<OckamCommand>,
-        router_tx: std::sync::mpsc::Sender<OckamCommand>,
+        tx: impl Into<CommandSender>,
+        router_tx: impl Into<CommandSender>,
         listen_addr: Option<SocketAddr>,
         tmo: Option<Duration>,
     ) -> Result<TcpManager, String> {
+        let tx = tx.into();
+        let router_tx = router_tx.into();
         router_tx
             .send(OckamCommand::Router(RouterCommand::Register(
                 AddressType::Tcp,
@@ -51,187 +56,404 @@ impl TcpManager {
 
         let timeout = tmo.unwrap_or(Duration::new(5, 0));
 
-        return match listen_addr {
-            Some(la) => {
-                if let Ok(l) = TcpListener::bind(la) {
-                    l.set_nonblocking(true).unwrap();
-                    Ok(TcpManager {
-                        rx,
-                        _tx: tx,
-                        router_tx,
-                        _timeout: timeout,
-                        listener: Some(l),
-                        connections,
-                        addresses: vec![],
-                    })
-                } else {
-                    Err("failed to bind tcp listener".into())
-                }
-            }
-            _ => Ok(TcpManager {
-                rx,
-                _tx: tx,
-                router_tx,
-                _timeout: timeout,
-                listener: None,
-                connections,
-                addresses: vec![],
-            }),
+        let listener = match listen_addr {
+            Some(la) => match TcpListener::bind(la) {
+                Ok(l) => Some(l),
+                Err(_) => return Err("failed to bind tcp listener".into()),
+            },
+            None => None,
         };
+        let reader = TcpReader::spawn(listener, tx.clone(), router_tx)
+            .map_err(|e| format!("failed to start tcp reader: {}", e))?;
+
+        Ok(TcpManager {
+            rx,
+            _tx: tx,
+            timeout,
+            connections,
+            reader,
+        })
     }
 
-    fn add_connection(&mut self, stream: TcpStream) -> bool {
-        stream.set_nonblocking(true).unwrap();
+    /// Keep the stream for sending and hand a clone of it to the reader,
+    /// which reads every connection as data arrives and forwards it to the
+    /// router
+    fn add_connection(&mut self, stream: TcpStream) -> Result<(), String> {
         stream.set_nodelay(true).unwrap();
-        let peer_addr = stream.peer_addr().unwrap().clone();
-        let tcp_xport = TcpTransport::new(stream, self.router_tx.clone()).unwrap();
-        self.connections.insert(peer_addr.to_string(), tcp_xport);
-        self.addresses.push(peer_addr.to_string());
-        true
-    }
-
-    pub fn poll(&mut self) -> bool {
-        let mut got: bool = true;
-        let mut keep_going = true;
-
-        while got && keep_going {
-            // listen for connect
-            got = false;
-            if let Some(listener) = &self.listener {
-                for s in listener.incoming() {
-                    match s {
-                        Ok(stream) => {
-                            keep_going = self.add_connection(stream);
-                            break;
-                        }
-                        Err(e) => match e.kind() {
-                            io::ErrorKind::WouldBlock => {
-                                break;
-                            }
-                            _ => {
-                                println!("tcp listen error");
-                                keep_going = false;
-                                break;
-                            }
-                        },
-                    }
-                }
-            }
+        let peer_addr = stream.peer_addr().unwrap().to_string();
+        let reader_stream = match stream.try_clone() {
+            Ok(s) => s,
+            Err(e) => return Err(format!("tcp failed to clone stream: {}", e)),
+        };
+        // both halves share the socket, so this makes both non-blocking
+        if let Err(e) = stream.set_nonblocking(true) {
+            return Err(format!("tcp failed to set non-blocking: {}", e));
+        }
+        let tcp_xport = TcpTransport::new(stream, self.timeout)?;
+        self.reader.add(peer_addr.clone(), reader_stream)?;
+        self.connections.insert(peer_addr, tcp_xport);
+        Ok(())
+    }
 
-            if let Ok(tc) = self.rx.try_recv() {
-                match tc {
-                    OckamCommand::Transport(TransportCommand::SendMessage(mut m)) => {
-                        let addr = m.onward_route.addresses.get_mut(0).unwrap();
-                        let addr = addr.address.as_string();
-                        if let Some(tcp_xport) = self.connections.get_mut(&addr) {
-                            match tcp_xport.send_message(m) {
-                                Err(e) => {
-                                    println!("send_message failed: {}", e);
-                                    keep_going = false;
-                                }
-                                _ => {}
-                            }
-                        } else {
-                            println!("can't find connection {}", addr);
-                            println!("{} connections in hashmap", self.connections.len());
-                            for (c, t) in &self.connections {
-                                println!("{}", c);
-                            }
+    /// Block on the command queue until told to stop. Receiving happens on
+    /// the reader thread, which waits on all the connections at once.
+    pub fn run(&mut self) {
+        while let Ok(tc) = self.rx.recv() {
+            match tc {
+                OckamCommand::Transport(TransportCommand::SendMessage(mut m)) => {
+                    let addr = m.onward_route.addresses.get_mut(0).unwrap();
+                    let addr = addr.address.as_string();
+                    if let Some(tcp_xport) = self.connections.get_mut(&addr) {
+                        if let Err(e) = tcp_xport.send_message(m) {
+                            // the reader sees the connection close too, but
+                            // nothing more can be sent on it either way
+                            println!("send_message failed: {}", e);
+                            self.connections.remove(&addr);
+                        }
+                    } else {
+                        println!("can't find connection {}", addr);
+                        println!("{} connections in hashmap", self.connections.len());
+                        for c in self.connections.keys() {
+                            println!("{}", c);
                         }
-                    }
-                    OckamCommand::Transport(TransportCommand::Stop) => {
-                        keep_going = false;
-                        break;
-                    }
-                    _ => {
-                        println!("unrecognized command");
                     }
                 }
-            } // end match rx.try_recv()
-
-            // check for receives
-            for a in &self.addresses {
-                match self.connections.get_mut(&a.to_string()) {
-                    Some(t) => {
-                        if let Err(s) = t.receive_message() {
-                            println!("tcp receive failed {}", s);
-                            return false;
-                        }
+                OckamCommand::Transport(TransportCommand::AddConnection(stream)) => {
+                    if let Err(s) = self.add_connection(stream) {
+                        println!("{}", s);
                     }
-                    None => {}
+                }
+                OckamCommand::Transport(TransportCommand::ConnectionClosed(addr)) => {
+                    self.connections.remove(&addr);
+                }
+                OckamCommand::Transport(TransportCommand::Stop) => {
+                    break;
+                }
+                _ => {
+                    println!("unrecognized command");
                 }
             }
         }
+    }
+}
 
-        keep_going
+impl Drop for TcpManager {
+    fn drop(&mut self) {
+        self.reader.stop();
     }
 }
 
+/// The sending half of a connection. Writes go straight to the socket and
+/// only wait, up to the manager's timeout, when the peer isn't keeping up.
 pub struct TcpTransport {
-    stream: TcpStream,
-    router_tx: std::sync::mpsc::Sender<OckamCommand>,
+    stream: mio::net::TcpStream,
+    writable: Poll,
+    timeout: Duration,
 }
 
 impl TcpTransport {
-    pub fn new(
-        stream: TcpStream,
-        router_tx: std::sync::mpsc::Sender<OckamCommand>,
-    ) -> Result<TcpTransport, String> {
-        let local_address = Address::TcpAddress(stream.local_addr().unwrap());
-        Ok(TcpTransport { stream, router_tx })
-    }
-
-    pub fn send_message(&mut self, mut m: Message) -> Result<(), String> {
-        m.onward_route.addresses.remove(0);
-        let local_address = Address::TcpAddress(self.stream.local_addr().unwrap());
-        m.return_route
-            .addresses
-            .insert(0, RouterAddress::from_address(local_address).unwrap());
-        let mut v = vec![];
-        Message::encode(&m, &mut v)?;
-        return match self.stream.write(v.as_slice()) {
-            Ok(_) => Ok(()),
-            Err(_) => Err("tcp write failed".into()),
+    /// Wrap a non-blocking stream
+    pub fn new(stream: TcpStream, timeout: Duration) -> Result<TcpTransport, String> {
+        let mut stream = mio::net::TcpStream::from_std(stream);
+        let writable = Poll::new().map_err(|e| format!("tcp failed to create poll: {}", e))?;
+        writable
+            .registry()
+            .register(&mut stream, Token(0), Interest::WRITABLE)
+            .map_err(|e| format!("tcp failed to register stream: {}", e))?;
+        Ok(TcpTransport {
+            stream,
+            writable,
+            timeout,
+        })
+    }
+
+    pub fn send_message(&mut self, m: Message) -> Result<(), String> {
+        let local_address = match self.stream.local_addr() {
+            Ok(la) => Address::TcpAddress(la),
+            Err(e) => return Err(format!("tcp connection lost: {}", e)),
         };
+        let v = encode_outgoing(m, local_address)?;
+        self.write_all(&v)
     }
 
-    pub fn receive_message(&mut self) -> Result<bool, String> {
-        let mut buff = [0u8; 16348];
-        self.stream.set_nonblocking(true);
-        self.stream.set_nodelay(true);
-        match self.stream.read(&mut buff) {
-            Ok(len) => {
-                if len == 0 {
-                    return Ok(true);
+    fn write_all(&mut self, mut data: &[u8]) -> Result<(), String> {
+        let mut events = Events::with_capacity(1);
+        while !data.is_empty() {
+            match self.stream.write(data) {
+                Ok(0) => return Err("tcp write failed".into()),
+                Ok(n) => data = &data[n..],
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    match self.writable.poll(&mut events, Some(self.timeout)) {
+                        Ok(()) if events.is_empty() => return Err("tcp write timed out".into()),
+                        Ok(()) => {}
+                        Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                        Err(e) => return Err(format!("tcp poll failed: {}", e)),
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(format!("tcp write failed: {}", e)),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How the manager reaches its reader thread
+struct ReaderHandle {
+    new_connections: Option<Sender<(String, TcpStream)>>,
+    waker: Arc<Waker>,
+}
+
+impl ReaderHandle {
+    fn add(&self, peer_addr: String, stream: TcpStream) -> Result<(), String> {
+        let sent = match &self.new_connections {
+            Some(tx) => tx.send((peer_addr, stream)).is_ok(),
+            None => false,
+        };
+        if !sent || self.waker.wake().is_err() {
+            return Err("tcp reader has stopped".into());
+        }
+        Ok(())
+    }
+
+    /// Dropping the queue of new connections tells the reader to stop
+    fn stop(&mut self) {
+        self.new_connections = None;
+        let _ = self.waker.wake();
+    }
+}
+
+/// A connection as the reader sees it
+struct Connection {
+    peer_addr: String,
+    stream: mio::net::TcpStream,
+}
+
+/// Waits on the listener and every connection at once, on a single thread.
+/// Accepted streams go to the manager as AddConnection commands, received
+/// messages to the router. Handing a message to a full router queue blocks,
+/// which stops reading from every connection until there is room and lets
+/// TCP flow control push back on the senders.
+struct TcpReader {
+    poll: Poll,
+    listener: Option<(TcpListener, mio::net::TcpListener)>,
+    connections: HashMap<Token, Connection>,
+    new_connections: Receiver<(String, TcpStream)>,
+    next_token: usize,
+    tx: CommandSender,
+    router_tx: CommandSender,
+}
+
+impl TcpReader {
+    fn spawn(
+        listener: Option<TcpListener>,
+        tx: CommandSender,
+        router_tx: CommandSender,
+    ) -> std::io::Result<ReaderHandle> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let listener = match listener {
+            Some(l) => {
+                // accept on the std listener, so the manager gets std streams
+                l.set_nonblocking(true)?;
+                let mut registered = mio::net::TcpListener::from_std(l.try_clone()?);
+                poll.registry()
+                    .register(&mut registered, LISTENER, Interest::READABLE)?;
+                Some((l, registered))
+            }
+            None => None,
+        };
+        let (new_tx, new_rx) = channel();
+        let mut reader = TcpReader {
+            poll,
+            listener,
+            connections: HashMap::new(),
+            new_connections: new_rx,
+            next_token: FIRST_CONNECTION,
+            tx,
+            router_tx,
+        };
+        thread::spawn(move || reader.run());
+        Ok(ReaderHandle {
+            new_connections: Some(new_tx),
+            waker,
+        })
+    }
+
+    fn run(&mut self) {
+        let mut events = Events::with_capacity(128);
+        loop {
+            if let Err(e) = self.poll.poll(&mut events, None) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
                 }
-                return match Message::decode(&buff[0..len]) {
-                    Ok((mut m, _)) => {
-                        // fix up return tcp address with nat-ed address
-                        let tcp_return = Address::TcpAddress(self.stream.peer_addr().unwrap());
-                        m.return_route.addresses[0] =
-                            RouterAddress::from_address(tcp_return).unwrap();
-                        if !m.onward_route.addresses.is_empty()
-                            && ((m.onward_route.addresses[0].a_type == AddressType::Udp)
-                                || (m.onward_route.addresses[0].a_type == AddressType::Tcp))
-                        {
-                            match self.send_message(m) {
-                                Err(s) => Err(s),
-                                Ok(()) => Ok(true),
-                            }
-                        } else {
-                            match self.router_tx.send(OckamCommand::Router(ReceiveMessage(m))) {
-                                Ok(_unused) => Ok(true),
-                                Err(_) => Err("send to router failed".to_string()),
-                            }
+                println!("tcp poll failed: {}", e);
+                // stop the manager too, so the node notices
+                let _ = self
+                    .tx
+                    .send(OckamCommand::Transport(TransportCommand::Stop));
+                return;
+            }
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => {
+                        if !self.register_new() {
+                            return;
                         }
                     }
-                    _ => Err("decode failed".to_string()),
-                };
+                    LISTENER => self.accept(),
+                    token => self.read(token),
+                }
+            }
+        }
+    }
+
+    /// Start waiting on the connections the manager added. False once the
+    /// manager is gone.
+    fn register_new(&mut self) -> bool {
+        loop {
+            match self.new_connections.try_recv() {
+                Ok((peer_addr, stream)) => {
+                    let token = Token(self.next_token);
+                    self.next_token += 1;
+                    let mut stream = mio::net::TcpStream::from_std(stream);
+                    match self
+                        .poll
+                        .registry()
+                        .register(&mut stream, token, Interest::READABLE)
+                    {
+                        Ok(()) => {
+                            self.connections
+                                .insert(token, Connection { peer_addr, stream });
+                        }
+                        Err(e) => println!("tcp failed to watch {}: {}", peer_addr, e),
+                    }
+                }
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => return false,
+            }
+        }
+    }
+
+    fn accept(&mut self) {
+        let listener = match &self.listener {
+            Some((l, _)) => l,
+            None => return,
+        };
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let cmd = OckamCommand::Transport(TransportCommand::AddConnection(stream));
+                    if self.tx.send(cmd).is_err() {
+                        return;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    println!("tcp listen error: {}", e);
+                    return;
+                }
             }
-            Err(e) => match e.kind() {
-                io::ErrorKind::WouldBlock => Ok(false),
-                _ => Err("***tcp receive failed".to_string()),
-            },
         }
     }
+
+    /// Read until the socket has nothing more, as readiness is only
+    /// reported when new data arrives
+    fn read(&mut self, token: Token) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(c) => c,
+            None => return,
+        };
+        let mut buff = [0u8; 16348];
+        let open = loop {
+            match connection.stream.read(&mut buff) {
+                Ok(0) => break false,
+                Ok(len) => {
+                    let peer_addr = match connection.stream.peer_addr() {
+                        Ok(a) => a,
+                        Err(_) => break false,
+                    };
+                    if let Err(s) = dispatch(&buff[0..len], peer_addr, &self.tx, &self.router_tx) {
+                        println!("tcp receive from {} failed: {}", connection.peer_addr, s);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break true,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    println!("tcp receive from {} failed: {}", connection.peer_addr, e);
+                    break false;
+                }
+            }
+        };
+        if open {
+            return;
+        }
+        if let Some(mut connection) = self.connections.remove(&token) {
+            println!("tcp connection {} closed", connection.peer_addr);
+            let _ = self.poll.registry().deregister(&mut connection.stream);
+            let cmd =
+                OckamCommand::Transport(TransportCommand::ConnectionClosed(connection.peer_addr));
+            let _ = self.tx.send(cmd);
+        }
+    }
+}
+
+/// Hand a message read off a connection to the router, or back to the
+/// manager if it is on its way to another transport
+fn dispatch(
+    buff: &[u8],
+    peer_addr: SocketAddr,
+    tx: &CommandSender,
+    router_tx: &CommandSender,
+) -> Result<(), String> {
+    let m = decode_incoming(buff, Address::TcpAddress(peer_addr))?;
+    let sent = if is_transport_hop(&m) {
+        tx.send(OckamCommand::Transport(TransportCommand::SendMessage(m)))
+    } else {
+        router_tx.send(OckamCommand::Router(ReceiveMessage(m)))
+    };
+    sent.map_err(|_| "send to router failed".to_string())
+}
+
+/// Drop our own address from the onward route, add the local transport
+/// address to the return route and encode the message for the wire
+pub(crate) fn encode_outgoing(mut m: Message, local_address: Address) -> Result<Vec<u8>, String> {
+    m.onward_route.addresses.remove(0);
+    m.return_route
+        .addresses
+        .insert(0, RouterAddress::from_address(local_address).unwrap());
+    let mut v = vec![];
+    Message::encode(&m, &mut v)?;
+    Ok(v)
+}
+
+/// Decode a message off the wire, fixing up the return address with the
+/// (possibly nat-ed) address of the peer it actually came from
+pub(crate) fn decode_incoming(buff: &[u8], peer_address: Address) -> Result<Message, String> {
+    let mut m = decode_message(buff)?;
+    let peer_address = RouterAddress::from_address(peer_address).unwrap();
+    match m.return_route.addresses.first_mut() {
+        Some(a) => *a = peer_address,
+        None => m.return_route.addresses.push(peer_address),
+    }
+    Ok(m)
+}
+
+/// Decode whatever arrived from the network. The message codec indexes
+/// without checking lengths, so a short or garbled message is turned into
+/// an error here rather than taking the reader down.
+pub(crate) fn decode_message(buff: &[u8]) -> Result<Message, String> {
+    match std::panic::catch_unwind(|| Message::decode(buff).map(|(m, _)| m)) {
+        Ok(Ok(m)) => Ok(m),
+        _ => Err("decode failed".to_string()),
+    }
+}
+
+/// True if the next hop is another transport, so the message is relayed
+/// rather than handed to the router
+pub(crate) fn is_transport_hop(m: &Message) -> bool {
+    !m.onward_route.addresses.is_empty()
+        && ((m.onward_route.addresses[0].a_type == AddressType::Udp)
+            || (m.onward_route.addresses[0].a_type == AddressType::Tcp))
 }
@@ -1,29 +1,31 @@
+use crate::tcp::{decode_message, is_transport_hop};
 #[allow(unused)]
 use ockam_message::message::*;
 use ockam_system::commands::RouterCommand::ReceiveMessage;
-use ockam_system::commands::{OckamCommand, RouterCommand, TransportCommand};
-use std::io;
+use ockam_system::commands::{CommandSender, OckamCommand, RouterCommand, TransportCommand};
 use std::net::SocketAddr;
 use std::net::UdpSocket;
+use std::thread;
 
 pub struct UdpTransport {
     socket: UdpSocket,
     rx: std::sync::mpsc::Receiver<OckamCommand>,
-    _tx: std::sync::mpsc::Sender<OckamCommand>,
-    router_tx: std::sync::mpsc::Sender<OckamCommand>,
+    _tx: CommandSender,
+    router_tx: CommandSender,
 }
 
 impl UdpTransport {
     pub fn new(
         rx: std::sync::mpsc::Receiver<OckamCommand>,
-        tx: std::sync::mpsc::Sender<OckamCommand>,
-        router_tx: std::sync::mpsc::Sender<OckamCommand>,
+        tx: impl Into<CommandSender>,
+        router_tx: impl Into<CommandSender>,
         local_udp_socket: SocketAddr,
     ) -> Result<UdpTransport, String> {
+        let tx = tx.into();
+        let router_tx = router_tx.into();
         // Try to create socket at given address
         match UdpSocket::bind(local_udp_socket) {
             Ok(socket) => {
-                socket.set_nonblocking(true).unwrap();
                 // Register address type with Router
                 router_tx
                     .send(OckamCommand::Router(RouterCommand::Register(
@@ -32,6 +34,23 @@ impl UdpTransport {
                     )))
                     .unwrap();
 
+                // Datagrams are read on their own thread, which blocks until
+                // one arrives. A bad datagram is skipped; if the socket
+                // itself fails the transport is stopped, so the node notices.
+                let reader_socket = match socket.try_clone() {
+                    Ok(s) => s,
+                    Err(_unused) => return Err("failed to clone socket".to_string()),
+                };
+                let reader_router_tx = router_tx.clone();
+                let reader_tx = tx.clone();
+                thread::spawn(move || loop {
+                    if let Err(s) = receive_on(&reader_socket, &reader_router_tx) {
+                        println!("udp receive failed {}", s);
+                        let _ = reader_tx.send(OckamCommand::Transport(TransportCommand::Stop));
+                        break;
+                    }
+                });
+
                 Ok(UdpTransport {
                     socket,
                     rx,
@@ -46,97 +65,82 @@ impl UdpTransport {
         }
     }
 
-    pub fn send_message(&mut self, mut m: Message) -> Result<(), String> {
-        let remote_address = m.onward_route.addresses.remove(0);
-
-        match self.socket.local_addr() {
-            Ok(la) => match RouterAddress::from_address(Address::UdpAddress(la)) {
-                Some(ra) => {
-                    m.return_route.addresses.insert(0, ra);
-                    let mut v = vec![];
-                    Message::encode(&m, &mut v)?;
-                    match self
-                        .socket
-                        .send_to(v.as_slice(), remote_address.address.as_string())
-                    {
-                        Ok(_) => Ok(()),
-                        Err(s) => {
-                            println!("send_message failed {}", s.to_string());
-                            Err("send_message error".to_string())
-                        }
-                    }
-                }
-                None => Err("send_message error".to_string()),
-            },
-            Err(_unused) => Err("send_message".to_string()),
-        }
+    pub fn send_message(&mut self, m: Message) -> Result<(), String> {
+        send_on(&self.socket, m)
     }
 
+    /// Block until a datagram arrives and dispatch it. Errors only when
+    /// nothing more can be received.
     pub fn receive_message(&mut self) -> Result<bool, String> {
-        let mut buff = [0; 16348];
-        match self.socket.recv_from(&mut buff) {
-            Ok((s, _)) => match Message::decode(&buff[0..s]) {
-                Ok((m, _unused)) => {
-                    if !m.onward_route.addresses.is_empty()
-                        && ((m.onward_route.addresses[0].a_type == AddressType::Udp)
-                            || (m.onward_route.addresses[0].a_type == AddressType::Tcp))
-                    {
-                        match self.send_message(m) {
-                            Err(s) => Err(s),
-                            Ok(()) => Ok(true),
-                        }
-                    } else {
-                        match self.router_tx.send(OckamCommand::Router(ReceiveMessage(m))) {
-                            Ok(_unused) => Ok(true),
-                            Err(_) => Err("send to router failed".to_string()),
-                        }
-                    }
-                }
-                _ => Err("decode failed".to_string()),
-            },
-            Err(e) => match e.kind() {
-                io::ErrorKind::WouldBlock => Ok(false),
-                _ => Err("socket receive failed".to_string()),
-            },
-        }
+        receive_on(&self.socket, &self.router_tx)
     }
 
-    pub fn poll(&mut self) -> bool {
-        let mut got: bool = true;
-        let mut keep_going = true;
-
-        while got && keep_going {
-            match self.receive_message() {
-                Ok(b) => {
-                    got = b;
+    /// Block on the command queue until told to stop. Receiving happens on
+    /// the reader thread started by new().
+    pub fn run(&mut self) {
+        while let Ok(tc) = self.rx.recv() {
+            match tc {
+                OckamCommand::Transport(TransportCommand::SendMessage(m)) => {
+                    if let Err(s) = self.send_message(m) {
+                        println!("udp send_message failed: {}", s);
+                    }
+                }
+                OckamCommand::Transport(TransportCommand::Stop) => {
+                    break;
                 }
-                Err(_) => {
-                    keep_going = false;
+                _ => {
+                    println!("unrecognized command");
                 }
             }
         }
+    }
+}
 
-        got = true;
-        while got && keep_going {
-            got = false;
-            if let Ok(tc) = self.rx.try_recv() {
-                match tc {
-                    OckamCommand::Transport(TransportCommand::SendMessage(m)) => {
-                        if let Err(s) = self.send_message(m) {
-                            println!("udp send_message failed: {}", s);
-                            return false;
-                        }
-                    }
-                    OckamCommand::Transport(TransportCommand::Stop) => {
-                        keep_going = false;
-                        break;
-                    }
-                    _ => {
-                        println!("unrecognized command");
+fn send_on(socket: &UdpSocket, mut m: Message) -> Result<(), String> {
+    let remote_address = m.onward_route.addresses.remove(0);
+
+    match socket.local_addr() {
+        Ok(la) => match RouterAddress::from_address(Address::UdpAddress(la)) {
+            Some(ra) => {
+                m.return_route.addresses.insert(0, ra);
+                let mut v = vec![];
+                Message::encode(&m, &mut v)?;
+                match socket.send_to(v.as_slice(), remote_address.address.as_string()) {
+                    Ok(_) => Ok(()),
+                    Err(s) => {
+                        println!("send_message failed {}", s.to_string());
+                        Err("send_message error".to_string())
                     }
                 }
-            } // end match rx.try_recv()
+            }
+            None => Err("send_message error".to_string()),
+        },
+        Err(_unused) => Err("send_message".to_string()),
+    }
+}
+
+fn receive_on(socket: &UdpSocket, router_tx: &CommandSender) -> Result<bool, String> {
+    let mut buff = [0; 16348];
+    let (s, peer) = match socket.recv_from(&mut buff) {
+        Ok(r) => r,
+        Err(_unused) => return Err("socket receive failed".to_string()),
+    };
+    let m = match decode_message(&buff[0..s]) {
+        Ok(m) => m,
+        Err(s) => {
+            println!("udp datagram from {} dropped: {}", peer, s);
+            return Ok(true);
+        }
+    };
+    if is_transport_hop(&m) {
+        if let Err(s) = send_on(socket, m) {
+            println!("udp relay from {} failed: {}", peer, s);
+        }
+        Ok(true)
+    } else {
+        match router_tx.send(OckamCommand::Router(ReceiveMessage(m))) {
+            Ok(_unused) => Ok(true),
+            Err(_) => Err("send to router failed".to_string()),
         }
-        keep_going
     }
 }
@@ -12,6 +12,14 @@
 extern "C" {
 #endif
 
+/**
+ * @brief   Handle to a vault.
+ *
+ * Key exchangers created with a vault handle (see kex.h) share the vault
+ * with it rather than copying it. Calls on a vault are serialized by a lock,
+ * so a vault may be used from several threads, and a call blocks while
+ * another call or a key exchanger is using the vault.
+ */
 typedef uint64_t ockam_vault_t;
 typedef uint64_t ockam_vault_secret_t;
 
@@ -25,6 +33,8 @@ typedef enum {
     OCKAM_VAULT_SECRET_TYPE_AES256_KEY,
     OCKAM_VAULT_SECRET_TYPE_CURVE25519_PRIVATEKEY,
     OCKAM_VAULT_SECRET_TYPE_P256_PRIVATEKEY,
+    OCKAM_VAULT_SECRET_TYPE_CHACHA20POLY1305_KEY,
+    OCKAM_VAULT_SECRET_TYPE_KYBER768_PRIVATEKEY,
 } ockam_vault_secret_type_t;
 
 /**
@@ -249,8 +259,63 @@ uint32_t ockam_vault_aead_aes_gcm_decrypt(ockam_vault_t       vault,
                                          size_t               plaintext_size,
                                          size_t*              plaintext_length);
 
+/**
+ * @brief   Encrypt a payload using ChaCha20-Poly1305.
+ * @param   vault[in]                       Vault object to use for encryption.
+ * @param   key[in]                         Ockam secret key to use for encryption.
+ * @param   nonce[in]                       Nonce counter, encoded as four zero bytes and the 64-bit little-endian value.
+ * @param   additional_data[in]             Additional data to use for encryption.
+ * @param   additional_data_length[in]      Length of the additional data.
+ * @param   plaintext[in]                   Buffer containing plaintext data to encrypt.
+ * @param   plaintext_length[in]            Length of plaintext data to encrypt.
+ * @param   ciphertext_and_tag[in]          Buffer containing the generated ciphertext and tag data.
+ * @param   ciphertext_and_tag_size[in]     Size of the ciphertext + tag buffer. Must be plaintext_size + 16.
+ * @param   ciphertext_and_tag_length[out]  Amount of data placed in the ciphertext + tag buffer.
+ * @return  OCKAM_ERROR_NONE on success.
+ */
+uint32_t ockam_vault_aead_chacha20_poly1305_encrypt(ockam_vault_t        vault,
+                                                    ockam_vault_secret_t key,
+                                                    uint64_t             nonce,
+                                                    const uint8_t*       additional_data,
+                                                    size_t               additional_data_length,
+                                                    const uint8_t*       plaintext,
+                                                    size_t               plaintext_length,
+                                                    uint8_t*             ciphertext_and_tag,
+                                                    size_t               ciphertext_and_tag_size,
+                                                    size_t*              ciphertext_and_tag_length);
+
+/**
+ * @brief   Decrypt a payload using ChaCha20-Poly1305.
+ * @param   vault[in]                     Vault object to use for decryption.
+ * @param   key[in]                       Ockam secret key to use for decryption.
+ * @param   nonce[in]                     Nonce counter, encoded as four zero bytes and the 64-bit little-endian value.
+ * @param   additional_data[in]           Additional data to use for decryption.
+ * @param   additional_data_length[in]    Length of the additional data.
+ * @param   ciphertext_and_tag[in]        The ciphertext + tag data to decrypt.
+ * @param   ciphertext_and_tag_length[in] Length of the ciphertext + tag data to decrypt.
+ * @param   plaintext[out]                Buffer to place the decrypted data in.
+ * @param   plaintext_size[in]            Size of the plaintext buffer. Must be ciphertext_tag_size - 16.
+ * @param   plaintext_length[out]         Amount of data placed in the plaintext buffer.
+ * @return  OCKAM_ERROR_NONE on success.
+ */
+uint32_t ockam_vault_aead_chacha20_poly1305_decrypt(ockam_vault_t        vault,
+                                                    ockam_vault_secret_t key,
+                                                    uint64_t             nonce,
+                                                    const uint8_t*       additional_data,
+                                                    size_t               additional_data_length,
+                                                    const uint8_t*       ciphertext_and_tag,
+                                                    size_t               ciphertext_and_tag_length,
+                                                    uint8_t*             plaintext,
+                                                    size_t               plaintext_size,
+                                                    size_t*              plaintext_length);
+
 /**
  * @brief   Deinitialize the specified ockam vault object
+ *
+ * The handle is invalid afterwards. Key exchangers created with the vault
+ * keep it, and its secrets, alive until they are deinitialized too, so
+ * deinitialize them first.
+ *
  * @param   vault[in] The ockam vault object to deinitialize.
  * @return  OCKAM_ERROR_NONE on success.
  */
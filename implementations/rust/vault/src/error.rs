@@ -44,6 +44,18 @@ pub enum VaultFailErrorKind {
     /// Could not use the AES-GCM cipher scheme
     #[fail(display = "Could not use the AES-GCM cipher scheme")]
     AeadAesGcm,
+    /// Failed to encrypt data with ChaCha20-Poly1305
+    #[fail(display = "Failed to encrypt data with ChaCha20-Poly1305")]
+    AeadChaChaPolyEncrypt,
+    /// Failed to decrypt data with ChaCha20-Poly1305
+    #[fail(display = "Failed to decrypt data with ChaCha20-Poly1305")]
+    AeadChaChaPolyDecrypt,
+    /// Failed to encapsulate a secret with a KEM
+    #[fail(display = "Failed to encapsulate a secret with a KEM")]
+    KemEncapsulate,
+    /// Failed to decapsulate a secret with a KEM
+    #[fail(display = "Failed to decapsulate a secret with a KEM")]
+    KemDecapsulate,
     /// An invalid parameter was supplied: {}
     #[fail(display = "An invalid parameter was supplied: {}", 0)]
     InvalidParam(usize),
@@ -112,6 +124,10 @@ impl VaultFailErrorKind {
             VaultFailErrorKind::AeadAesGcmEncrypt => Self::ERROR_INTERFACE_VAULT | 11,
             VaultFailErrorKind::AeadAesGcmDecrypt => Self::ERROR_INTERFACE_VAULT | 12,
             VaultFailErrorKind::AeadAesGcm => Self::ERROR_INTERFACE_VAULT | 13,
+            VaultFailErrorKind::AeadChaChaPolyEncrypt => Self::ERROR_INTERFACE_VAULT | 14,
+            VaultFailErrorKind::AeadChaChaPolyDecrypt => Self::ERROR_INTERFACE_VAULT | 15,
+            VaultFailErrorKind::KemEncapsulate => Self::ERROR_INTERFACE_VAULT | 16,
+            VaultFailErrorKind::KemDecapsulate => Self::ERROR_INTERFACE_VAULT | 17,
             VaultFailErrorKind::InvalidParam(..) => Self::ERROR_INTERFACE_VAULT | 20,
             VaultFailErrorKind::InvalidAttributes => Self::ERROR_INTERFACE_VAULT | 21,
             VaultFailErrorKind::InvalidContext => Self::ERROR_INTERFACE_VAULT | 22,
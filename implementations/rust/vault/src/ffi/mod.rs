@@ -4,12 +4,14 @@ use crate::{error::*, ffi::types::*, file::FilesystemVault, software::DefaultVau
 use ffi_support::{ByteBuffer, ConcurrentHandleMap, ErrorCode, ExternError, FfiStr, IntoFfi};
 use std::convert::TryInto;
 use std::slice;
+use std::sync::{Arc, Mutex};
 
 mod types;
 
-/// Wraps a vault that can be used as a trait object
+/// Wraps a vault that can be used as a trait object, and shared with the
+/// other Ockam FFIs
 struct BoxVault {
-    vault: Box<dyn DynVault + Send>,
+    vault: Arc<Mutex<dyn DynVault + Send>>,
 }
 
 lazy_static! {
@@ -22,7 +24,7 @@ pub extern "C" fn ockam_vault_default_init(context: &mut u64) -> VaultError {
     let mut err = ExternError::success();
     // TODO: handle logging
     *context = VAULTS.insert_with_output(&mut err, || BoxVault {
-        vault: Box::new(DefaultVault::default()),
+        vault: Arc::new(Mutex::new(DefaultVault::default())),
     });
     ERROR_NONE
 }
@@ -34,7 +36,9 @@ pub extern "C" fn ockam_vault_file_init(context: &mut u64, path: FfiStr<'_>) ->
     let path = path.into_string();
     *context = VAULTS.insert_with_result(&mut err, || {
         match FilesystemVault::new(std::path::PathBuf::from(path)) {
-            Ok(v) => Ok(BoxVault { vault: Box::new(v) }),
+            Ok(v) => Ok(BoxVault {
+                vault: Arc::new(Mutex::new(v)),
+            }),
             Err(_) => Err(ExternError::new_error(ErrorCode::new(1), "")),
         }
     });
@@ -63,7 +67,7 @@ pub extern "C" fn ockam_vault_random_bytes_generate(
         context,
         |v| -> Result<ByteBuffer, VaultFailError> {
             let mut data = vec![0u8; buffer_len as usize];
-            v.vault.random(data.as_mut_slice())?;
+            v.vault.lock().unwrap().random(data.as_mut_slice())?;
             let byte_buffer = ByteBuffer::from_vec(data);
             Ok(byte_buffer)
         },
@@ -98,7 +102,7 @@ pub extern "C" fn ockam_vault_sha256(
         &mut err,
         context,
         |v| -> Result<ByteBuffer, VaultFailError> {
-            let d = v.vault.sha256(input)?;
+            let d = v.vault.lock().unwrap().sha256(input)?;
             let byte_buffer = ByteBuffer::from_vec(d.to_vec());
             Ok(byte_buffer)
         },
@@ -128,7 +132,7 @@ pub extern "C" fn ockam_vault_secret_generate(
         &mut err,
         context,
         |v| -> Result<SecretKeyHandle, VaultFailError> {
-            let ctx = v.vault.secret_generate(atts)?;
+            let ctx = v.vault.lock().unwrap().secret_generate(atts)?;
             Ok(ctx.into_ffi_value())
         },
     );
@@ -164,7 +168,7 @@ pub extern "C" fn ockam_vault_secret_import(
             };
 
             let sk: SecretKey = ffi_sk.try_into()?;
-            let ctx = v.vault.secret_import(&sk, atts)?;
+            let ctx = v.vault.lock().unwrap().secret_import(&sk, atts)?;
             Ok(ctx.into_ffi_value())
         },
     );
@@ -192,7 +196,7 @@ pub extern "C" fn ockam_vault_secret_export(
         context,
         |v| -> Result<ByteBuffer, VaultFailError> {
             let ctx = get_memory_id(secret);
-            let key = v.vault.secret_export(ctx)?;
+            let key = v.vault.lock().unwrap().secret_export(ctx)?;
             Ok(ByteBuffer::from_vec(key.as_ref().to_vec()))
         },
     );
@@ -228,7 +232,7 @@ pub extern "C" fn ockam_vault_secret_publickey_get(
         context,
         |v| -> Result<ByteBuffer, VaultFailError> {
             let ctx = get_memory_id(secret);
-            let key = v.vault.secret_public_key_get(ctx)?;
+            let key = v.vault.lock().unwrap().secret_public_key_get(ctx)?;
             Ok(ByteBuffer::from_vec(key.as_ref().to_vec()))
         },
     );
@@ -261,7 +265,7 @@ pub extern "C" fn ockam_vault_secret_attributes_get(
         context,
         |v| -> Result<FfiSecretKeyAttributes, VaultFailError> {
             let ctx = get_memory_id(secret_handle);
-            let atts = v.vault.secret_attributes_get(ctx)?;
+            let atts = v.vault.lock().unwrap().secret_attributes_get(ctx)?;
             Ok(atts.into())
         },
     );
@@ -279,7 +283,7 @@ pub extern "C" fn ockam_vault_secret_destroy(context: u64, secret: u64) -> Vault
     let mut err = ExternError::success();
     VAULTS.call_with_result_mut(&mut err, context, |v| -> Result<(), VaultFailError> {
         let ctx = get_memory_id(secret);
-        v.vault.secret_destroy(ctx)?;
+        v.vault.lock().unwrap().secret_destroy(ctx)?;
         Ok(())
     });
     if err.get_code().is_success() {
@@ -308,7 +312,7 @@ pub extern "C" fn ockam_vault_ecdh(
         context,
         |v| -> Result<SecretKeyHandle, VaultFailError> {
             let ctx = get_memory_id(secret);
-            let atts = v.vault.secret_attributes_get(ctx)?;
+            let atts = v.vault.lock().unwrap().secret_attributes_get(ctx)?;
             let pubkey = match atts.xtype {
                 SecretKeyType::Curve25519 => {
                     if peer_publickey.len() != 32 {
@@ -326,7 +330,7 @@ pub extern "C" fn ockam_vault_ecdh(
                 }
                 _ => Err(VaultFailError::from(VaultFailErrorKind::Ecdh)),
             }?;
-            let shared_ctx = v.vault.ec_diffie_hellman(ctx, pubkey)?;
+            let shared_ctx = v.vault.lock().unwrap().ec_diffie_hellman(ctx, pubkey)?;
             Ok(shared_ctx.into_ffi_value())
         },
     );
@@ -381,6 +385,8 @@ pub extern "C" fn ockam_vault_hkdf_sha256(
             // Either way, I don't want to change the API until this decision is finalized.
             let hkdf_output = v
                 .vault
+                .lock()
+                .unwrap()
                 .hkdf_sha256(salt_ctx, b"", ikm_ctx, output_attributes)?
                 .iter()
                 .map(|x| x.into_ffi_value())
@@ -428,9 +434,12 @@ pub extern "C" fn ockam_vault_aead_aes_gcm_encrypt(
             let ctx = get_memory_id(secret);
             let mut nonce_vec = vec![0; 12 - 2];
             nonce_vec.extend_from_slice(&nonce.to_be_bytes());
-            let ciphertext =
-                v.vault
-                    .aead_aes_gcm_encrypt(ctx, plaintext, &nonce_vec, additional_data)?;
+            let ciphertext = v.vault.lock().unwrap().aead_aes_gcm_encrypt(
+                ctx,
+                plaintext,
+                &nonce_vec,
+                additional_data,
+            )?;
             Ok(ByteBuffer::from_vec(ciphertext))
         },
     );
@@ -480,7 +489,7 @@ pub extern "C" fn ockam_vault_aead_aes_gcm_decrypt(
             let ctx = get_memory_id(secret);
             let mut nonce_vec = vec![0; 12 - 2];
             nonce_vec.extend_from_slice(&nonce.to_be_bytes());
-            let plain = v.vault.aead_aes_gcm_decrypt(
+            let plain = v.vault.lock().unwrap().aead_aes_gcm_decrypt(
                 ctx,
                 ciphertext_and_tag,
                 &nonce_vec,
@@ -503,6 +512,122 @@ pub extern "C" fn ockam_vault_aead_aes_gcm_decrypt(
     }
 }
 
+///   Encrypt a payload using ChaCha20-Poly1305.
+///   The nonce is encoded as in Noise: four zero bytes followed by the
+///   64-bit little-endian counter.
+#[no_mangle]
+pub extern "C" fn ockam_vault_aead_chacha20_poly1305_encrypt(
+    context: u64,
+    secret: u64,
+    nonce: u64,
+    additional_data: *const u8,
+    additional_data_length: u32,
+    plaintext: *const u8,
+    plaintext_length: u32,
+    ciphertext_and_tag: &mut u8,
+    ciphertext_and_tag_size: u32,
+    ciphertext_and_tag_length: &mut u32,
+) -> VaultError {
+    check_buffer!(additional_data);
+    check_buffer!(plaintext);
+    *ciphertext_and_tag_length = 0;
+    let mut err = ExternError::success();
+    let additional_data =
+        unsafe { std::slice::from_raw_parts(additional_data, additional_data_length as usize) };
+    let plaintext = unsafe { std::slice::from_raw_parts(plaintext, plaintext_length as usize) };
+    let output = VAULTS.call_with_result_mut(
+        &mut err,
+        context,
+        |v| -> Result<ByteBuffer, VaultFailError> {
+            let ctx = get_memory_id(secret);
+            let nonce_vec = chacha_nonce(nonce);
+            let ciphertext = v.vault.lock().unwrap().aead_chacha20_poly1305_encrypt(
+                ctx,
+                plaintext,
+                &nonce_vec,
+                additional_data,
+            )?;
+            Ok(ByteBuffer::from_vec(ciphertext))
+        },
+    );
+    if err.get_code().is_success() {
+        let buffer = output.destroy_into_vec();
+        if ciphertext_and_tag_size < buffer.len() as u32 {
+            VaultFailErrorKind::AeadChaChaPolyEncrypt.into()
+        } else {
+            *ciphertext_and_tag_length = buffer.len() as u32;
+            unsafe {
+                std::ptr::copy_nonoverlapping(buffer.as_ptr(), ciphertext_and_tag, buffer.len())
+            };
+            ERROR_NONE
+        }
+    } else {
+        VaultFailErrorKind::AeadChaChaPolyEncrypt.into()
+    }
+}
+
+/// Decrypt a payload using ChaCha20-Poly1305.
+#[no_mangle]
+pub extern "C" fn ockam_vault_aead_chacha20_poly1305_decrypt(
+    context: u64,
+    secret: u64,
+    nonce: u64,
+    additional_data: *const u8,
+    additional_data_length: u32,
+    ciphertext_and_tag: *const u8,
+    ciphertext_and_tag_length: u32,
+    plaintext: &mut u8,
+    plaintext_size: u32,
+    plaintext_length: &mut u32,
+) -> VaultError {
+    check_buffer!(ciphertext_and_tag, ciphertext_and_tag_length);
+    check_buffer!(additional_data);
+    *plaintext_length = 0;
+    let mut err = ExternError::success();
+    let additional_data =
+        unsafe { std::slice::from_raw_parts(additional_data, additional_data_length as usize) };
+    let ciphertext_and_tag = unsafe {
+        std::slice::from_raw_parts(ciphertext_and_tag, ciphertext_and_tag_length as usize)
+    };
+    let output = VAULTS.call_with_result_mut(
+        &mut err,
+        context,
+        |v| -> Result<ByteBuffer, VaultFailError> {
+            let ctx = get_memory_id(secret);
+            let nonce_vec = chacha_nonce(nonce);
+            let plain = v.vault.lock().unwrap().aead_chacha20_poly1305_decrypt(
+                ctx,
+                ciphertext_and_tag,
+                &nonce_vec,
+                additional_data,
+            )?;
+            Ok(ByteBuffer::from_vec(plain))
+        },
+    );
+    if err.get_code().is_success() {
+        let buffer = output.destroy_into_vec();
+        if plaintext_size < buffer.len() as u32 {
+            VaultFailErrorKind::AeadChaChaPolyDecrypt.into()
+        } else {
+            *plaintext_length = buffer.len() as u32;
+            unsafe { std::ptr::copy_nonoverlapping(buffer.as_ptr(), plaintext, buffer.len()) };
+            ERROR_NONE
+        }
+    } else {
+        VaultFailErrorKind::AeadChaChaPolyDecrypt.into()
+    }
+}
+
+/// The vault behind a handle, for the other Ockam FFIs to work with the
+/// secrets of the vault the caller created
+pub fn shared_vault(context: u64) -> Result<Arc<Mutex<dyn DynVault + Send>>, VaultFailError> {
+    VAULTS
+        .get_u64(context, |v| -> Result<_, ExternError> {
+            Ok(v.vault.clone())
+        })
+        .map_err(|_| VaultFailErrorKind::InvalidContext.into())
+}
+
 /// Deinitialize an Ockam vault
 #[no_mangle]
 pub extern "C" fn ockam_vault_deinit(context: u64) -> VaultError {
@@ -515,6 +640,13 @@ pub extern "C" fn ockam_vault_deinit(context: u64) -> VaultError {
 
 define_string_destructor!(string_free);
 
+#[inline]
+fn chacha_nonce(nonce: u64) -> Vec<u8> {
+    let mut nonce_vec = vec![0; 4];
+    nonce_vec.extend_from_slice(&nonce.to_le_bytes());
+    nonce_vec
+}
+
 #[inline]
 fn get_memory_id(secret_handle: u64) -> SecretKeyContext {
     SecretKeyContext::Memory(secret_handle as usize)
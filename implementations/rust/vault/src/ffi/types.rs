@@ -49,6 +49,14 @@ impl From<SecretKey> for FfiSecretKey {
                 let buf = a.to_vec().into_boxed_slice();
                 (SecretKeyType::P256, buf.len() as u32, buf)
             }
+            SecretKey::ChaCha20Poly1305(a) => {
+                let buf = a.to_vec().into_boxed_slice();
+                (SecretKeyType::ChaCha20Poly1305, buf.len() as u32, buf)
+            }
+            SecretKey::Kyber768(a) => {
+                let buf = a.to_vec().into_boxed_slice();
+                (SecretKeyType::Kyber768, buf.len() as u32, buf)
+            }
         };
         let s = FfiSecretKey {
             xtype: xtype.into(),
@@ -77,6 +85,8 @@ impl TryFrom<FfiSecretKey> for SecretKey {
             SecretKeyType::Aes256 => SecretKey::Aes256(*array_ref![a, 0, 32]),
             SecretKeyType::P256 => SecretKey::P256(*array_ref![a, 0, 32]),
             SecretKeyType::Curve25519 => SecretKey::Curve25519(*array_ref![a, 0, 32]),
+            SecretKeyType::ChaCha20Poly1305 => SecretKey::ChaCha20Poly1305(*array_ref![a, 0, 32]),
+            SecretKeyType::Kyber768 => SecretKey::Kyber768(a),
         };
         Ok(s)
     }
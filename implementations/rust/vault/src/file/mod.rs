@@ -30,7 +30,11 @@ impl FilesystemVault {
 
             let mut attrs = [0u8; ATTRS_BYTE_LENGTH];
             attrs.copy_from_slice(&data[0..ATTRS_BYTE_LENGTH]);
-            let attributes = SecretKeyAttributes::try_from(attrs)?;
+            let mut attributes = SecretKeyAttributes::try_from(attrs)?;
+            // the attributes don't record a buffer's length, the file does
+            if let SecretKeyType::Buffer(..) = attributes.xtype {
+                attributes.xtype = SecretKeyType::Buffer(data.len() - ATTRS_BYTE_LENGTH);
+            }
 
             Ok((
                 SecretKey::new(&data[ATTRS_BYTE_LENGTH..], attributes.xtype),
@@ -194,6 +198,29 @@ impl DynVault for FilesystemVault {
         Ok(())
     }
 
+    /// Return the public key of a KEM secret key
+    fn kem_public_key_get(&mut self, context: SecretKeyContext) -> Result<Vec<u8>, VaultFailError> {
+        self.v.kem_public_key_get(context)
+    }
+
+    /// Encapsulate a fresh shared secret to a KEM public key
+    fn kem_encapsulate(
+        &mut self,
+        xtype: SecretKeyType,
+        public_key: &[u8],
+    ) -> Result<(Vec<u8>, SecretKeyContext), VaultFailError> {
+        self.v.kem_encapsulate(xtype, public_key)
+    }
+
+    /// Recover the shared secret a ciphertext encapsulates to a KEM secret key
+    fn kem_decapsulate(
+        &mut self,
+        context: SecretKeyContext,
+        ciphertext: &[u8],
+    ) -> Result<SecretKeyContext, VaultFailError> {
+        self.v.kem_decapsulate(context, ciphertext)
+    }
+
     /// Compute Elliptic-Curve Diffie-Hellman using this secret key
     ///
     /// and the specified uncompressed public key
@@ -263,6 +290,30 @@ impl DynVault for FilesystemVault {
             .aead_aes_gcm_decrypt(context, cipher_text, nonce, aad)
     }
 
+    /// Encrypt a payload using ChaCha20-Poly1305
+    fn aead_chacha20_poly1305_encrypt(
+        &mut self,
+        context: SecretKeyContext,
+        plaintext: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, VaultFailError> {
+        self.v
+            .aead_chacha20_poly1305_encrypt(context, plaintext, nonce, aad)
+    }
+
+    /// Decrypt a payload using ChaCha20-Poly1305
+    fn aead_chacha20_poly1305_decrypt(
+        &mut self,
+        context: SecretKeyContext,
+        cipher_text: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, VaultFailError> {
+        self.v
+            .aead_chacha20_poly1305_decrypt(context, cipher_text, nonce, aad)
+    }
+
     /// Close and release all resources in use by the vault
     fn deinit(&mut self) {
         self.v.deinit()
@@ -311,6 +362,15 @@ mod tests {
         let sk1 = vault.secret_generate(atts).unwrap();
         let sk2 = vault.secret_generate(atts).unwrap();
         let sk3 = vault.secret_generate(atts).unwrap();
+        let buffer = vault
+            .secret_import(
+                &SecretKey::Buffer(vec![7u8; 45]),
+                SecretKeyAttributes {
+                    xtype: SecretKeyType::Buffer(45),
+                    ..atts
+                },
+            )
+            .unwrap();
 
         let sk_data1 = vault.secret_export(sk1).unwrap();
         let sk_data2 = vault.secret_export(sk2).unwrap();
@@ -326,5 +386,9 @@ mod tests {
         assert_eq!(sk_data1, sk2_data_1);
         assert_eq!(sk_data2, sk2_data_2);
         assert_eq!(sk_data3, sk2_data_3);
+        assert_eq!(
+            vault2.secret_export(buffer).unwrap(),
+            SecretKey::Buffer(vec![7u8; 45])
+        );
     }
 }
@@ -92,6 +92,24 @@ pub trait Vault: Zeroize {
     ) -> Result<PublicKey, VaultFailError>;
     /// Remove a secret key from the vault
     fn secret_destroy(&mut self, context: SecretKeyContext) -> Result<(), VaultFailError>;
+    /// Return the public key of a KEM secret key, e.g. Kyber768, for the
+    /// peer to encapsulate a secret to
+    fn kem_public_key_get(&mut self, context: SecretKeyContext) -> Result<Vec<u8>, VaultFailError>;
+    /// Encapsulate a fresh shared secret to the `xtype` KEM public key.
+    /// Returns the ciphertext for the holder of the secret key and the shared
+    /// secret as a 32 byte buffer secret
+    fn kem_encapsulate(
+        &mut self,
+        xtype: SecretKeyType,
+        public_key: &[u8],
+    ) -> Result<(Vec<u8>, SecretKeyContext), VaultFailError>;
+    /// Recover the shared secret that the ciphertext encapsulates to this
+    /// KEM secret key
+    fn kem_decapsulate(
+        &mut self,
+        context: SecretKeyContext,
+        ciphertext: &[u8],
+    ) -> Result<SecretKeyContext, VaultFailError>;
     /// Compute Elliptic-Curve Diffie-Hellman using this secret key
     /// and the specified uncompressed public key
     fn ec_diffie_hellman(
@@ -135,6 +153,22 @@ pub trait Vault: Zeroize {
         nonce: C,
         aad: D,
     ) -> Result<Vec<u8>, VaultFailError>;
+    /// Encrypt a payload using ChaCha20-Poly1305
+    fn aead_chacha20_poly1305_encrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        plaintext: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError>;
+    /// Decrypt a payload using ChaCha20-Poly1305
+    fn aead_chacha20_poly1305_decrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        cipher_text: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError>;
     /// Close and release all resources in use by the vault
     fn deinit(&mut self);
     /// Generate a signature
@@ -184,6 +218,24 @@ pub trait DynVault {
     ) -> Result<PublicKey, VaultFailError>;
     /// Remove a secret key from the vault
     fn secret_destroy(&mut self, context: SecretKeyContext) -> Result<(), VaultFailError>;
+    /// Return the public key of a KEM secret key, e.g. Kyber768, for the
+    /// peer to encapsulate a secret to
+    fn kem_public_key_get(&mut self, context: SecretKeyContext) -> Result<Vec<u8>, VaultFailError>;
+    /// Encapsulate a fresh shared secret to the `xtype` KEM public key.
+    /// Returns the ciphertext for the holder of the secret key and the shared
+    /// secret as a 32 byte buffer secret
+    fn kem_encapsulate(
+        &mut self,
+        xtype: SecretKeyType,
+        public_key: &[u8],
+    ) -> Result<(Vec<u8>, SecretKeyContext), VaultFailError>;
+    /// Recover the shared secret that the ciphertext encapsulates to this
+    /// KEM secret key
+    fn kem_decapsulate(
+        &mut self,
+        context: SecretKeyContext,
+        ciphertext: &[u8],
+    ) -> Result<SecretKeyContext, VaultFailError>;
     /// Compute Elliptic-Curve Diffie-Hellman using this secret key
     /// and the specified uncompressed public key
     fn ec_diffie_hellman(
@@ -227,6 +279,22 @@ pub trait DynVault {
         nonce: &[u8],
         aad: &[u8],
     ) -> Result<Vec<u8>, VaultFailError>;
+    /// Encrypt a payload using ChaCha20-Poly1305
+    fn aead_chacha20_poly1305_encrypt(
+        &mut self,
+        context: SecretKeyContext,
+        plaintext: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, VaultFailError>;
+    /// Decrypt a payload using ChaCha20-Poly1305
+    fn aead_chacha20_poly1305_decrypt(
+        &mut self,
+        context: SecretKeyContext,
+        cipher_text: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, VaultFailError>;
     /// Close and release all resources in use by the vault
     fn deinit(&mut self);
     /// Generate a signature
@@ -290,6 +358,26 @@ impl<D: Vault + Send + Sync + 'static> DynVault for D {
         Vault::secret_destroy(self, context)
     }
 
+    fn kem_public_key_get(&mut self, context: SecretKeyContext) -> Result<Vec<u8>, VaultFailError> {
+        Vault::kem_public_key_get(self, context)
+    }
+
+    fn kem_encapsulate(
+        &mut self,
+        xtype: SecretKeyType,
+        public_key: &[u8],
+    ) -> Result<(Vec<u8>, SecretKeyContext), VaultFailError> {
+        Vault::kem_encapsulate(self, xtype, public_key)
+    }
+
+    fn kem_decapsulate(
+        &mut self,
+        context: SecretKeyContext,
+        ciphertext: &[u8],
+    ) -> Result<SecretKeyContext, VaultFailError> {
+        Vault::kem_decapsulate(self, context, ciphertext)
+    }
+
     fn ec_diffie_hellman(
         &mut self,
         context: SecretKeyContext,
@@ -346,6 +434,26 @@ impl<D: Vault + Send + Sync + 'static> DynVault for D {
         Vault::aead_aes_gcm_decrypt(self, context, cipher_text, nonce, aad)
     }
 
+    fn aead_chacha20_poly1305_encrypt(
+        &mut self,
+        context: SecretKeyContext,
+        plaintext: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, VaultFailError> {
+        Vault::aead_chacha20_poly1305_encrypt(self, context, plaintext, nonce, aad)
+    }
+
+    fn aead_chacha20_poly1305_decrypt(
+        &mut self,
+        context: SecretKeyContext,
+        cipher_text: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, VaultFailError> {
+        Vault::aead_chacha20_poly1305_decrypt(self, context, cipher_text, nonce, aad)
+    }
+
     fn deinit(&mut self) {
         Vault::deinit(self)
     }
@@ -117,7 +117,7 @@ impl Vault for OsxVault {
                     let key = p256::SecretKey::generate();
                     swkey_insert(attributes, key.secret_scalar().as_ref())
                 }
-                SecretKeyType::Aes256 => {
+                SecretKeyType::Aes256 | SecretKeyType::ChaCha20Poly1305 => {
                     let mut key = [0u8; 32];
                     rng.fill_bytes(&mut key);
                     swkey_insert(attributes, key.as_ref())
@@ -132,6 +132,7 @@ impl Vault for OsxVault {
                     rng.fill_bytes(key.as_mut_slice());
                     swkey_insert(attributes, key.as_slice())
                 }
+                SecretKeyType::Kyber768 => Err(VaultFailErrorKind::InvalidSecretType.into()),
             },
         }
     }
@@ -203,6 +204,10 @@ impl Vault for OsxVault {
                             }
                             SecretKeyType::Aes128 => SecretKey::Aes128(*array_ref![bytes, 6, 16]),
                             SecretKeyType::Aes256 => SecretKey::Aes256(*array_ref![bytes, 6, 32]),
+                            SecretKeyType::ChaCha20Poly1305 => {
+                                SecretKey::ChaCha20Poly1305(*array_ref![bytes, 6, 32])
+                            }
+                            SecretKeyType::Kyber768 => SecretKey::Kyber768(bytes[6..].to_vec()),
                         })
                     }
                     OsxContext::Enclave => Err(VaultFailErrorKind::AccessDenied.into()),
@@ -331,6 +336,29 @@ impl Vault for OsxVault {
         }
     }
 
+    fn kem_public_key_get(
+        &mut self,
+        _context: SecretKeyContext,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        Err(VaultFailErrorKind::InvalidSecretType.into())
+    }
+
+    fn kem_encapsulate(
+        &mut self,
+        _xtype: SecretKeyType,
+        _public_key: &[u8],
+    ) -> Result<(Vec<u8>, SecretKeyContext), VaultFailError> {
+        Err(VaultFailErrorKind::InvalidSecretType.into())
+    }
+
+    fn kem_decapsulate(
+        &mut self,
+        _context: SecretKeyContext,
+        _ciphertext: &[u8],
+    ) -> Result<SecretKeyContext, VaultFailError> {
+        Err(VaultFailErrorKind::InvalidSecretType.into())
+    }
+
     fn ec_diffie_hellman(
         &mut self,
         context: SecretKeyContext,
@@ -453,6 +481,28 @@ impl Vault for OsxVault {
         unimplemented!()
     }
 
+    fn aead_chacha20_poly1305_encrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        _context: SecretKeyContext,
+        _plaintext: B,
+        _nonce: C,
+        _aad: D,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        // the keychain has no ChaCha20-Poly1305 keys
+        Err(VaultFailErrorKind::AeadChaChaPolyEncrypt.into())
+    }
+
+    fn aead_chacha20_poly1305_decrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        _context: SecretKeyContext,
+        _cipher_text: B,
+        _nonce: C,
+        _aad: D,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        // the keychain has no ChaCha20-Poly1305 keys
+        Err(VaultFailErrorKind::AeadChaChaPolyDecrypt.into())
+    }
+
     fn deinit(&mut self) {
         self.zeroize();
     }
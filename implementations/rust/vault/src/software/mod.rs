@@ -5,16 +5,34 @@ use crate::{
 };
 use aead::{generic_array::GenericArray, Aead, NewAead, Payload};
 use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use chacha20poly1305::ChaCha20Poly1305;
 use p256::{
+    ecdsa::{
+        signature::{Signer, Verifier},
+        Signature as EcdsaSignature, SigningKey, VerifyKey,
+    },
     elliptic_curve::{sec1::FromEncodedPoint, Group},
     AffinePoint, ProjectivePoint, Scalar,
 };
+#[cfg(feature = "kyber")]
+use pqcrypto_kyber::kyber768;
+#[cfg(feature = "kyber")]
+use pqcrypto_traits::kem::{
+    Ciphertext as KemCiphertext, PublicKey as KemPublicKey, SecretKey as KemSecretKey,
+    SharedSecret as KemSharedSecret,
+};
 use rand::{prelude::*, rngs::OsRng};
 use sha2::{Digest, Sha256};
 use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use xeddsa::*;
 use zeroize::Zeroize;
 
+/// A Kyber768 secret key is the secret polynomial vector, the public key,
+/// the public key's hash and the implicit rejection value
+#[cfg(feature = "kyber")]
+const KYBER768_PUBLIC_KEY_OFFSET: usize = 1152;
+
 /// A pure rust implementation of a vault.
 /// Is not thread-safe i.e. if multiple threads
 /// add values to the vault there may be collisions
@@ -96,6 +114,9 @@ impl DefaultVault {
                 }
                 SecretKeyType::Aes256 => Ok(SecretKey::Aes256(*array_ref!(okm, index, 32))),
                 SecretKeyType::Aes128 => Ok(SecretKey::Aes128(*array_ref!(okm, index, 16))),
+                SecretKeyType::ChaCha20Poly1305 => {
+                    Ok(SecretKey::ChaCha20Poly1305(*array_ref!(okm, index, 32)))
+                }
                 _ => Err(VaultFailError::from_msg(
                     VaultFailErrorKind::HkdfSha256,
                     "Unknown key type",
@@ -110,6 +131,16 @@ impl DefaultVault {
         Ok(secrets)
     }
 
+    /// Import a KEM shared secret as a 32 byte buffer
+    fn kem_shared_secret_import(&mut self, ss: &[u8]) -> Result<SecretKeyContext, VaultFailError> {
+        let attributes = SecretKeyAttributes {
+            xtype: SecretKeyType::Buffer(ss.len()),
+            purpose: SecretPurposeType::KeyAgreement,
+            persistence: SecretPersistenceType::Ephemeral,
+        };
+        self.secret_import(&SecretKey::Buffer(ss.to_vec()), attributes)
+    }
+
     pub(crate) fn get_ids(&self) -> Vec<usize> {
         self.entries.keys().map(|i| *i).collect()
     }
@@ -173,6 +204,26 @@ macro_rules! encrypt_impl {
     }};
 }
 
+macro_rules! chacha_impl {
+    ($entry:expr, $aad:expr, $nonce: expr, $text:expr, $op:ident, $err:expr) => {{
+        if $nonce.as_ref().len() != 12 {
+            return Err(VaultFailErrorKind::InvalidParam(2).into());
+        }
+        match $entry.key {
+            SecretKey::ChaCha20Poly1305(a) => {
+                let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(a.as_ref()));
+                let nonce = GenericArray::from_slice($nonce.as_ref());
+                let payload = Payload {
+                    aad: $aad.as_ref(),
+                    msg: $text.as_ref(),
+                };
+                cipher.$op(nonce, payload).map_err(|_| $err.into())
+            }
+            _ => Err($err.into()),
+        }
+    }};
+}
+
 impl Vault for DefaultVault {
     fn random(&mut self, data: &mut [u8]) -> Result<(), VaultFailError> {
         let mut rng = OsRng {};
@@ -205,6 +256,11 @@ impl Vault for DefaultVault {
                 rng.fill_bytes(&mut key);
                 SecretKey::Aes256(key)
             }
+            SecretKeyType::ChaCha20Poly1305 => {
+                let mut key = [0u8; 32];
+                rng.fill_bytes(&mut key);
+                SecretKey::ChaCha20Poly1305(key)
+            }
             SecretKeyType::P256 => {
                 let key = p256::SecretKey::random(&mut rng);
                 let mut value = [0u8; 32];
@@ -216,6 +272,13 @@ impl Vault for DefaultVault {
                 rng.fill_bytes(key.as_mut_slice());
                 SecretKey::Buffer(key)
             }
+            #[cfg(feature = "kyber")]
+            SecretKeyType::Kyber768 => {
+                let (_, sk) = kyber768::keypair();
+                SecretKey::Kyber768(sk.as_bytes().to_vec())
+            }
+            #[cfg(not(feature = "kyber"))]
+            SecretKeyType::Kyber768 => return Err(VaultFailErrorKind::InvalidSecretType.into()),
         };
         self.next_id += 1;
         self.entries.insert(
@@ -304,6 +367,73 @@ impl Vault for DefaultVault {
         }
     }
 
+    fn kem_public_key_get(&mut self, context: SecretKeyContext) -> Result<Vec<u8>, VaultFailError> {
+        let entry = self.get_entry(context, VaultFailErrorKind::PublicKey)?;
+
+        match &entry.key {
+            #[cfg(feature = "kyber")]
+            SecretKey::Kyber768(a) => {
+                let start = KYBER768_PUBLIC_KEY_OFFSET;
+                Ok(a[start..start + KYBER768_PUBLIC_KEY_SIZE].to_vec())
+            }
+            _ => Err(VaultFailErrorKind::PublicKey.into()),
+        }
+    }
+
+    #[cfg_attr(not(feature = "kyber"), allow(unused_variables))]
+    fn kem_encapsulate(
+        &mut self,
+        xtype: SecretKeyType,
+        public_key: &[u8],
+    ) -> Result<(Vec<u8>, SecretKeyContext), VaultFailError> {
+        match xtype {
+            #[cfg(feature = "kyber")]
+            SecretKeyType::Kyber768 => {
+                let pk = kyber768::PublicKey::from_bytes(public_key).map_err(|_| {
+                    VaultFailError::from_msg(
+                        VaultFailErrorKind::KemEncapsulate,
+                        "Invalid Kyber768 public key",
+                    )
+                })?;
+                let (ss, ct) = kyber768::encapsulate(&pk);
+                let secret = self.kem_shared_secret_import(ss.as_bytes())?;
+                Ok((ct.as_bytes().to_vec(), secret))
+            }
+            _ => Err(VaultFailErrorKind::InvalidSecretType.into()),
+        }
+    }
+
+    #[cfg_attr(not(feature = "kyber"), allow(unused_variables))]
+    fn kem_decapsulate(
+        &mut self,
+        context: SecretKeyContext,
+        ciphertext: &[u8],
+    ) -> Result<SecretKeyContext, VaultFailError> {
+        let entry = self.get_entry(context, VaultFailErrorKind::KemDecapsulate)?;
+
+        let ss: Vec<u8> = match &entry.key {
+            #[cfg(feature = "kyber")]
+            SecretKey::Kyber768(a) => {
+                let sk = kyber768::SecretKey::from_bytes(a)
+                    .map_err(|_| VaultFailError::from(VaultFailErrorKind::InvalidSecret))?;
+                let ct = kyber768::Ciphertext::from_bytes(ciphertext).map_err(|_| {
+                    VaultFailError::from_msg(
+                        VaultFailErrorKind::KemDecapsulate,
+                        "Invalid Kyber768 ciphertext",
+                    )
+                })?;
+                // an altered ciphertext decapsulates to an unrelated secret
+                // rather than failing, so the handshake fails further on
+                Ok(kyber768::decapsulate(&ct, &sk).as_bytes().to_vec())
+            }
+            _ => Err(VaultFailError::from_msg(
+                VaultFailErrorKind::KemDecapsulate,
+                "Unknown key type",
+            )),
+        }?;
+        self.kem_shared_secret_import(&ss)
+    }
+
     fn ec_diffie_hellman(
         &mut self,
         context: SecretKeyContext,
@@ -456,6 +586,42 @@ impl Vault for DefaultVault {
         )
     }
 
+    fn aead_chacha20_poly1305_encrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        plaintext: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        let entry = self.get_entry(context, VaultFailErrorKind::AeadChaChaPolyEncrypt)?;
+        chacha_impl!(
+            entry,
+            aad,
+            nonce,
+            plaintext,
+            encrypt,
+            VaultFailErrorKind::AeadChaChaPolyEncrypt
+        )
+    }
+
+    fn aead_chacha20_poly1305_decrypt<B: AsRef<[u8]>, C: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        context: SecretKeyContext,
+        cipher_text: B,
+        nonce: C,
+        aad: D,
+    ) -> Result<Vec<u8>, VaultFailError> {
+        let entry = self.get_entry(context, VaultFailErrorKind::AeadChaChaPolyDecrypt)?;
+        chacha_impl!(
+            entry,
+            aad,
+            nonce,
+            cipher_text,
+            decrypt,
+            VaultFailErrorKind::AeadChaChaPolyDecrypt
+        )
+    }
+
     fn deinit(&mut self) {
         self.zeroize();
     }
@@ -474,11 +640,13 @@ impl Vault for DefaultVault {
                 let sig = x25519_dalek::StaticSecret::from(k).sign(data.as_ref(), &nonce);
                 Ok(sig)
             }
-            // SecretKey::P256(k) => {
-            //     let sign_key = SigningKey::new(&k)?;
-            //     let sig = sign_key.sign(data.as_ref());
-            //     Ok(*array_ref![sig.as_ref(), 0, 64])
-            // }
+            // ECDSA over SHA-256, with the deterministic nonce of RFC 6979
+            SecretKey::P256(k) => {
+                let sign_key = SigningKey::new(&k)
+                    .map_err(|e| VaultFailError::from_msg(VaultFailErrorKind::InvalidSecret, e))?;
+                let sig: EcdsaSignature = sign_key.sign(data.as_ref());
+                Ok(*array_ref![sig.as_ref(), 0, 64])
+            }
             _ => Err(VaultFailError::from_msg(
                 VaultFailErrorKind::Ecdh,
                 "Unhandled key type",
@@ -500,12 +668,14 @@ impl Vault for DefaultVault {
                     Err(VaultFailErrorKind::PublicKey.into())
                 }
             }
-            // PublicKey::P256(k) => {
-            //     let key = VerifyKey::new(&k)?;
-            //     let sig = Signature::try_from(&signature[..])?;
-            //     key.verify(data.as_ref(), &sig)?;
-            //     Ok(())
-            // }
+            PublicKey::P256(k) => {
+                let key = VerifyKey::new(&k)
+                    .map_err(|e| VaultFailError::from_msg(VaultFailErrorKind::PublicKey, e))?;
+                let sig = EcdsaSignature::try_from(&signature[..])
+                    .map_err(|e| VaultFailError::from_msg(VaultFailErrorKind::PublicKey, e))?;
+                key.verify(data.as_ref(), &sig)
+                    .map_err(|e| VaultFailError::from_msg(VaultFailErrorKind::PublicKey, e))
+            }
             _ => Err(VaultFailErrorKind::PublicKey.into()),
         }
     }
@@ -568,7 +738,10 @@ mod tests {
             (SecretKeyType::P256, 32),
             (SecretKeyType::Aes256, 32),
             (SecretKeyType::Aes128, 16),
+            (SecretKeyType::ChaCha20Poly1305, 32),
             (SecretKeyType::Buffer(24), 24),
+            #[cfg(feature = "kyber")]
+            (SecretKeyType::Kyber768, KYBER768_SECRET_KEY_SIZE),
         ];
         for (t, s) in &types {
             attributes.xtype = *t;
@@ -582,6 +755,49 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "kyber")]
+    #[test]
+    fn kyber768_encapsulation() {
+        let mut vault = DefaultVault::default();
+        let attributes = SecretKeyAttributes {
+            xtype: SecretKeyType::Kyber768,
+            persistence: SecretPersistenceType::Ephemeral,
+            purpose: SecretPurposeType::KeyAgreement,
+        };
+        let sk_ctx = vault.secret_generate(attributes).unwrap();
+        let pk = vault.kem_public_key_get(sk_ctx).unwrap();
+        assert_eq!(pk.len(), KYBER768_PUBLIC_KEY_SIZE);
+
+        let (ct, ss1) = vault.kem_encapsulate(SecretKeyType::Kyber768, &pk).unwrap();
+        assert_eq!(ct.len(), KYBER768_CIPHERTEXT_SIZE);
+        let ss2 = vault.kem_decapsulate(sk_ctx, &ct).unwrap();
+        let ss1 = vault.secret_export(ss1).unwrap();
+        let ss2 = vault.secret_export(ss2).unwrap();
+        assert_eq!(ss1.as_ref().len(), 32);
+        assert_eq!(ss1, ss2);
+
+        let mut altered = ct.clone();
+        altered[0] ^= 1;
+        let ss3 = vault.kem_decapsulate(sk_ctx, &altered).unwrap();
+        assert_ne!(vault.secret_export(ss3).unwrap(), ss1);
+
+        assert!(vault.kem_decapsulate(sk_ctx, &ct[1..]).is_err());
+        assert!(vault
+            .kem_encapsulate(SecretKeyType::Kyber768, &pk[1..])
+            .is_err());
+        assert!(vault
+            .kem_encapsulate(SecretKeyType::Curve25519, &pk)
+            .is_err());
+        let x25519 = vault
+            .secret_generate(SecretKeyAttributes {
+                xtype: SecretKeyType::Curve25519,
+                ..attributes
+            })
+            .unwrap();
+        assert!(vault.kem_public_key_get(x25519).is_err());
+        assert!(vault.kem_decapsulate(x25519, &ct).is_err());
+    }
+
     #[test]
     fn sha256() {
         let vault = DefaultVault::default();
@@ -885,6 +1101,59 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn chacha20_poly1305_encryption() {
+        let mut vault = DefaultVault::default();
+        let attributes = SecretKeyAttributes {
+            xtype: SecretKeyType::ChaCha20Poly1305,
+            persistence: SecretPersistenceType::Ephemeral,
+            purpose: SecretPurposeType::KeyAgreement,
+        };
+        // RFC 8439 section 2.8.2
+        let key = (0x80u8..0xa0).collect::<Vec<u8>>();
+        let nonce = hex::decode("070000004041424344454647").unwrap();
+        let aad = hex::decode("50515253c0c1c2c3c4c5c6c7").unwrap();
+        let message = b"Ladies and Gentlemen of the class of '99: If I could offer you \
+only one tip for the future, sunscreen would be it.";
+        let ctx = vault
+            .secret_import(
+                &SecretKey::new(&key, SecretKeyType::ChaCha20Poly1305),
+                attributes,
+            )
+            .unwrap();
+        let mut ciphertext = vault
+            .aead_chacha20_poly1305_encrypt(ctx, message.as_ref(), &nonce, &aad)
+            .unwrap();
+        assert_eq!(
+            hex::encode(&ciphertext[message.len()..]),
+            "1ae10b594f09e26a7e902ecbd0600691"
+        );
+        assert_eq!(
+            hex::encode(&ciphertext[..16]),
+            "d31a8d34648e60db7b86afbc53ef7ec2"
+        );
+        let plaintext = vault
+            .aead_chacha20_poly1305_decrypt(ctx, ciphertext.as_slice(), &nonce, &aad)
+            .unwrap();
+        assert_eq!(plaintext, message.to_vec());
+
+        ciphertext[0] ^= 1;
+        let res = vault.aead_chacha20_poly1305_decrypt(ctx, ciphertext.as_slice(), &nonce, &aad);
+        assert!(res.is_err());
+
+        // AES-GCM keys are not usable with ChaCha20-Poly1305 and vice versa
+        let res = vault.aead_aes_gcm_encrypt(ctx, message.as_ref(), &nonce, &aad);
+        assert!(res.is_err());
+        let aes = vault
+            .secret_generate(SecretKeyAttributes {
+                xtype: SecretKeyType::Aes256,
+                ..attributes
+            })
+            .unwrap();
+        let res = vault.aead_chacha20_poly1305_encrypt(aes, message.as_ref(), &nonce, &aad);
+        assert!(res.is_err());
+    }
+
     #[test]
     fn sign() {
         let mut vault = DefaultVault::default();
@@ -902,4 +1171,34 @@ mod tests {
         let res = vault.verify(signature, pubkey, b"hello world!");
         assert!(res.is_ok());
     }
+
+    #[test]
+    fn sign_p256() {
+        let mut vault = DefaultVault::default();
+        let secret = vault
+            .secret_generate(SecretKeyAttributes {
+                persistence: SecretPersistenceType::Ephemeral,
+                purpose: SecretPurposeType::KeyAgreement,
+                xtype: SecretKeyType::P256,
+            })
+            .unwrap();
+        let signature = vault.sign(secret, b"hello world!").unwrap();
+        let pubkey = vault.secret_public_key_get(secret).unwrap();
+        assert!(vault.verify(signature, pubkey, b"hello world!").is_ok());
+        assert!(vault.verify(signature, pubkey, b"hello world?").is_err());
+
+        let mut tampered = signature;
+        tampered[63] ^= 1;
+        assert!(vault.verify(tampered, pubkey, b"hello world!").is_err());
+
+        let other = vault
+            .secret_generate(SecretKeyAttributes {
+                persistence: SecretPersistenceType::Ephemeral,
+                purpose: SecretPurposeType::KeyAgreement,
+                xtype: SecretKeyType::P256,
+            })
+            .unwrap();
+        let other = vault.secret_public_key_get(other).unwrap();
+        assert!(vault.verify(signature, other, b"hello world!").is_err());
+    }
 }
@@ -17,6 +17,12 @@ pub enum SecretKeyType {
     Curve25519,
     /// NIST P-256 (secp256r1, prime256v1) secret key
     P256,
+    /// ChaCha20-Poly1305 256 bit key
+    ChaCha20Poly1305,
+    /// Kyber768 key encapsulation secret key, from round 3 of the NIST
+    /// competition. Not wire-compatible with the ML-KEM-768 standardized
+    /// in FIPS 203. The software vault needs the `kyber` feature for it.
+    Kyber768,
 }
 
 impl SecretKeyType {
@@ -28,6 +34,8 @@ impl SecretKeyType {
             SecretKeyType::Aes256 => 2,
             SecretKeyType::Curve25519 => 3,
             SecretKeyType::P256 => 4,
+            SecretKeyType::ChaCha20Poly1305 => 5,
+            SecretKeyType::Kyber768 => 6,
         }
     }
 
@@ -39,6 +47,8 @@ impl SecretKeyType {
             2 => Ok(SecretKeyType::Aes256),
             3 => Ok(SecretKeyType::Curve25519),
             4 => Ok(SecretKeyType::P256),
+            5 => Ok(SecretKeyType::ChaCha20Poly1305),
+            6 => Ok(SecretKeyType::Kyber768),
             _ => Err(VaultFailErrorKind::InvalidParam(0).into()),
         }
     }
@@ -280,6 +290,14 @@ unsafe impl IntoFfi for SecretKeyContext {
     }
 }
 
+/// Bytes in a Kyber768 public key
+pub const KYBER768_PUBLIC_KEY_SIZE: usize = 1184;
+/// Bytes in a Kyber768 secret key, which carries its public key at
+/// `[1152..2336]` as in the Kyber specification
+pub const KYBER768_SECRET_KEY_SIZE: usize = 2400;
+/// Bytes in a Kyber768 ciphertext
+pub const KYBER768_CIPHERTEXT_SIZE: usize = 1088;
+
 /// Represents specific secrets employable by the vault
 #[derive(Clone, Debug)]
 pub enum SecretKey {
@@ -293,6 +311,10 @@ pub enum SecretKey {
     Curve25519([u8; 32]),
     /// NIST P-256 (secp256r1, prime256v1) secret key
     P256([u8; 32]),
+    /// ChaCha20-Poly1305 256 bit key
+    ChaCha20Poly1305([u8; 32]),
+    /// Kyber768 secret key, `KYBER768_SECRET_KEY_SIZE` bytes
+    Kyber768(Vec<u8>),
 }
 
 impl SecretKey {
@@ -304,6 +326,12 @@ impl SecretKey {
             SecretKeyType::Aes256 => SecretKey::Aes256(*array_ref![data.as_ref(), 0, 32]),
             SecretKeyType::P256 => SecretKey::P256(*array_ref![data.as_ref(), 0, 32]),
             SecretKeyType::Curve25519 => SecretKey::Curve25519(*array_ref![data.as_ref(), 0, 32]),
+            SecretKeyType::ChaCha20Poly1305 => {
+                SecretKey::ChaCha20Poly1305(*array_ref![data.as_ref(), 0, 32])
+            }
+            SecretKeyType::Kyber768 => {
+                SecretKey::Kyber768(data.as_ref()[..KYBER768_SECRET_KEY_SIZE].to_vec())
+            }
         }
     }
 }
@@ -316,6 +344,8 @@ impl AsRef<[u8]> for SecretKey {
             SecretKey::Aes256(a) => a.as_ref(),
             SecretKey::Curve25519(a) => a.as_ref(),
             SecretKey::P256(a) => a.as_ref(),
+            SecretKey::ChaCha20Poly1305(a) => a.as_ref(),
+            SecretKey::Kyber768(a) => a.as_slice(),
         }
     }
 }
@@ -329,6 +359,10 @@ impl PartialEq for SecretKey {
             (Aes256(a), Aes256(b)) => a.as_ref().ct_eq(b.as_ref()).unwrap_u8() == 1u8,
             (Curve25519(a), Curve25519(b)) => a.as_ref().ct_eq(b.as_ref()).unwrap_u8() == 1u8,
             (P256(a), P256(b)) => a.as_ref().ct_eq(b.as_ref()).unwrap_u8() == 1u8,
+            (ChaCha20Poly1305(a), ChaCha20Poly1305(b)) => {
+                a.as_ref().ct_eq(b.as_ref()).unwrap_u8() == 1u8
+            }
+            (Kyber768(a), Kyber768(b)) => a.as_slice().ct_eq(b.as_slice()).unwrap_u8() == 1u8,
             (_, _) => false,
         }
     }
@@ -345,6 +379,8 @@ impl Zeroize for SecretKey {
             Aes256(ref mut a) => a.zeroize(),
             Curve25519(ref mut a) => a.zeroize(),
             P256(ref mut a) => a.zeroize(),
+            ChaCha20Poly1305(ref mut a) => a.zeroize(),
+            Kyber768(ref mut a) => a.zeroize(),
         }
     }
 }
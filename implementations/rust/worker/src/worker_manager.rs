@@ -27,7 +27,7 @@ impl WorkerManager {
         router_tx
             .send(OckamCommand::Router(RouterCommand::Register(
                 AddressType::Worker,
-                tx.clone(),
+                tx.clone().into(),
             )))
             .unwrap();
         WorkerManager {